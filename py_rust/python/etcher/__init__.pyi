@@ -1,6 +1,24 @@
 import typing as tp
 
-def register_function(func: tp.Callable) -> None:
+@tp.overload
+def register_function(
+    func: tp.Callable,
+    *,
+    name: str | None = None,
+    needs_context: bool = False,
+    overwrite: bool = False,
+) -> None: ...
+@tp.overload
+def register_function(
+    *, name: str | None = None, needs_context: bool = False, overwrite: bool = False
+) -> tp.Callable[[tp.Callable], None]: ...
+def register_function(
+    func: tp.Callable | None = None,
+    *,
+    name: str | None = None,
+    needs_context: bool = False,
+    overwrite: bool = False,
+) -> tp.Callable[[tp.Callable], None] | None:
     """Register a custom function to be available in the template context.
 
     Example:
@@ -11,8 +29,95 @@ def register_function(func: tp.Callable) -> None:
         >>> "{{ foo(3, b=5) }}"
         8
 
+        >>> @etch.register_function(needs_context=True)
+        ... def greet(ctx: dict[str, tp.Any], punctuation: str) -> str:
+        ...     return ctx["greeting"] + punctuation
+        ...
+        >>> "{{ greet('!') }}" # Assuming the config has greeting = {"value": "hi"}
+        "hi!"
+
+    Args:
+        func (tp.Callable): The function to register, omit to get a decorator applying the options.
+        name (str | None): The name to use in templates, required for callables without a __name__ e.g. functools.partial.
+        needs_context (bool): Pass a copy of the context dict as the first argument, mutating it doesn't affect the render.
+        overwrite (bool): Replace an already registered function of the same name rather than erroring.
+    """
+    ...
+
+def clear_registered_functions() -> None:
+    """Forget all registered custom functions, filters, tests and context processors, e.g. between tests or notebook runs where modules are re-imported."""
+    ...
+
+@tp.overload
+def register_filter(
+    func: tp.Callable, *, name: str | None = None, overwrite: bool = False
+) -> None: ...
+@tp.overload
+def register_filter(
+    *, name: str | None = None, overwrite: bool = False
+) -> tp.Callable[[tp.Callable], None]: ...
+def register_filter(
+    func: tp.Callable | None = None, *, name: str | None = None, overwrite: bool = False
+) -> tp.Callable[[tp.Callable], None] | None:
+    """Register a custom filter to be available in templates, the piped value is passed as the first argument.
+
+    Example:
+        >>> @etch.register_filter
+        ... def shout(value: str, suffix: str = "!") -> str:
+        ...     return value.upper() + suffix
+        ...
+        >>> "{{ 'hello' | shout(suffix='?') }}"
+        "HELLO?"
+
+    Args:
+        func (tp.Callable): The function to register, omit to get a decorator applying the options.
+        name (str | None): The name to use in templates, defaults to the function's __name__.
+        overwrite (bool): Replace an already registered filter of the same name rather than erroring.
+    """
+    ...
+
+@tp.overload
+def register_test(func: tp.Callable, *, name: str | None = None, overwrite: bool = False) -> None: ...
+@tp.overload
+def register_test(
+    *, name: str | None = None, overwrite: bool = False
+) -> tp.Callable[[tp.Callable], None]: ...
+def register_test(
+    func: tp.Callable | None = None, *, name: str | None = None, overwrite: bool = False
+) -> tp.Callable[[tp.Callable], None] | None:
+    """Register a custom test to be available in templates for `is` expressions, the tested value is passed as the first argument and a bool must be returned.
+
+    Example:
+        >>> @etch.register_test
+        ... def internal(service: dict) -> bool:
+        ...     return service["host"].endswith(".internal")
+        ...
+        >>> "{% if service is internal %}private{% endif %}"
+        "private"
+
+    Args:
+        func (tp.Callable): The function to register, omit to get a decorator applying the options.
+        name (str | None): The name to use in templates, defaults to the function's __name__.
+        overwrite (bool): Replace an already registered test of the same name rather than erroring.
+    """
+    ...
+
+def register_context_processor(func: tp.Callable, *, name: str | None = None) -> None:
+    """Register a function to transform the whole context before any template renders, e.g. to compute derived values or validate constraints across keys.
+
+    Processors run in registration order, each receiving the previous one's result. A mapping must be returned, it becomes the new context.
+
+    Example:
+        >>> @etch.register_context_processor
+        ... def add_url(ctx: dict) -> dict:
+        ...     return {**ctx, "url": "https://{}:{}".format(ctx["host"], ctx["port"])}
+        ...
+        >>> "{{ url }}"
+        "https://localhost:8080"
+
     Args:
-        func (tp.Callable): The function to register.
+        func (tp.Callable): The function to register, receiving and returning the context dict.
+        name (str | None): The name to use in errors, defaults to the function's __name__.
     """
     ...
 
@@ -32,11 +137,32 @@ def context() -> dict[str, tp.Any]:
     """
     ...
 
+def current_template() -> str:
+    """Return the path of the template currently being rendered relative to the root, can be run during custom extensions.
+
+    Example:
+        >>> @etch.register_function
+        ... def header() -> str:
+        ...     return "# Generated from {}".format(etch.current_template())
+        ...
+        >>> "{{ header() }}" # Assuming rendering the template at foo/bar.etch.py
+        "# Generated from foo/bar.etch.py"
+
+    Returns:
+        str: The relative path of the template being rendered.
+    """
+    ...
+
+def _render(args: list[str]) -> bool: ...
 def _toml_update(
-    initial: str, update: tp.Any | None = None, remove: list[list[str]] | None = None
+    initial: str,
+    update: tp.Any | None = None,
+    remove: list[list[str]] | None = None,
+    array_strategy: tp.Literal["replace", "append", "merge-by-index"] | None = None,
+    sort_keys: bool = False,
 ) -> str: ...
-def _hash_contents(contents: str) -> str: ...
+def _hash_contents(contents: str, algo: str = "fnv1a") -> str: ...
 
 __version__: str
 
-__all__ = ["__version__", "_hash_contents", "_toml_update"]
+__all__ = ["__version__", "_hash_contents", "_render", "_toml_update"]
@@ -1,10 +1,12 @@
 use std::path::PathBuf;
 
 use bitbazaar::{err, errors::TracedErr};
-use clap::{command, Parser};
+use clap::Parser;
 use pyo3::Python;
 
 pub static DEFAULT_CONFIG_PATH: &str = "./etch.config.toml";
+// Used as the config when the default config file doesn't exist, reading its [tool.etch] table:
+pub static PYPROJECT_CONFIG_PATH: &str = "./pyproject.toml";
 
 /// Get the args from python rather than rust, works better:
 pub fn get_py_args() -> Result<Vec<String>, TracedErr> {
@@ -56,11 +58,23 @@ pub enum Command {
     Render(RenderCommand),
     /// Initialize the config file in the current directory.
     Init(InitCommand),
-    /// Display Etch's version
+    /// List the templates that would be rendered and their output paths, without rendering.
+    List(ListCommand),
+    /// Explain why a file is or isn't a template, and where it would render to.
+    Explain(ExplainCommand),
+    /// Display Etch's version, json output includes machine readable capabilities.
     Version {
-        #[arg(long, value_enum, default_value = "text")]
+        #[arg(long, visible_alias = "output", value_enum, default_value = "text")]
         output_format: HelpFormat,
     },
+    /// Print the shell completion script, which completes --only with the templates under the root.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Hidden dynamic completion protocol called by shell completion scripts, prints candidates one per line.
+    #[command(name = "__complete", hide = true)]
+    Complete(CompleteCommand),
 }
 
 #[derive(Clone, Debug, clap::Parser)]
@@ -71,14 +85,26 @@ pub struct RenderCommand {
         help = "The target directory to search and compile."
     )]
     pub root: PathBuf,
+    /// Further directories to render with the same config and context.
+    #[clap(
+        help = "Further directories to render with the same config and context, which is only processed once. Each root keeps its own lockfile."
+    )]
+    pub extra_roots: Vec<PathBuf>,
     /// The config file to use.
     #[arg(
         short,
         long,
         default_value = DEFAULT_CONFIG_PATH,
-        help = "The config file to use."
+        help = "The config file to use, overriding the ETCH_CONFIG env var. Falls back to the [tool.etch] table of pyproject.toml when the default is missing."
     )]
     pub config: PathBuf,
+    /// Never search parent directories for the config.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Only look for the default config at the root, rather than also searching parent directories for it when missing."
+    )]
+    pub no_discover: bool,
     /// Force write all rendered files, ignore existing lockfile.
     #[arg(
         short,
@@ -87,6 +113,101 @@ pub struct RenderCommand {
         help = "Force write all rendered files, ignore existing lockfile."
     )]
     pub force: bool,
+    /// Always write, without reading, creating or updating the lockfile.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Write all rendered files without reading, creating or updating the lockfile, for pipelines where it's just noise."
+    )]
+    pub no_lockfile: bool,
+    /// Where to read and write the lockfile, overriding the lockfile config option.
+    #[arg(
+        long,
+        help = "Where to read and write the lockfile, e.g. to keep the lockfiles of multiple roots in one directory. Overrides the lockfile config option, defaults to '.etch.lock' at the root."
+    )]
+    pub lockfile: Option<PathBuf>,
+    /// Where to write outputs, mirroring the directories of their templates, overriding the out_dir config option.
+    #[arg(
+        long,
+        help = "Write outputs under this directory, mirroring the directories of their templates relative to the root, rather than next to them. Overrides the out_dir config option."
+    )]
+    pub out_dir: Option<PathBuf>,
+    /// The config profile to merge over the base config.
+    #[arg(
+        short,
+        long,
+        help = "The config profile to merge over the base config, falls back to the ETCH_PROFILE env var."
+    )]
+    pub profile: Option<String>,
+    /// The lockfile variant to namespace entries under, when rendering the same templates multiple ways.
+    #[arg(
+        long,
+        help = "The lockfile variant to namespace entries under, when rendering the same templates multiple ways. Defaults to the profile and out_dir e.g. 'prod@dist', or 'default' when neither is set."
+    )]
+    pub variant: Option<String>,
+    /// Skip checking config paths exist and are readable upfront.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Skip checking ignore_files and custom_extensions paths exist and are readable, for setups where they're created or mounted later."
+    )]
+    pub skip_path_checks: bool,
+    /// Error rather than warn when a write might be unintended or the lockfile can't be trusted.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Error rather than warn when a write might be unintended or the lockfile can't be trusted, e.g. overwriting a file that isn't tracked in the lockfile, or an existing lockfile that fails to read or parse."
+    )]
+    pub strict: bool,
+    /// Let outputs overwrite templates, config files or the lockfile.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Let outputs overwrite templates, config files or the lockfile, which otherwise errors, e.g. when deliberately bootstrapping etch's own config."
+    )]
+    pub allow_overwrite_sources: bool,
+    /// Warn with every undefined variable each template references before rendering.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Before rendering, warn with every variable each template references that isn't defined, rather than only finding out from the first to fail. Guarded references like 'x is defined' are reported too."
+    )]
+    pub check_vars: bool,
+    /// Skip whatever git skips, the cli equivalent of the use_gitignore config option.
+    #[arg(
+        long,
+        visible_alias = "use-gitignore",
+        default_value = "false",
+        help = "Skip whatever git skips when searching for templates, the cli equivalent of the use_gitignore config option."
+    )]
+    pub gitignore: bool,
+    /// Follow symlinks when searching for templates, the cli equivalent of the follow_symlinks config option.
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Follow symlinks when searching for templates, the cli equivalent of the follow_symlinks config option."
+    )]
+    pub follow_symlinks: bool,
+    /// Keep running, re-rendering whenever a file under the root or the config changes.
+    #[arg(
+        short,
+        long,
+        default_value = "false",
+        help = "Keep running after the first render, re-rendering whenever a file under the root or the config changes. Custom extensions aren't reloaded, restart to pick up changes to them."
+    )]
+    pub watch: bool,
+    /// Only render templates whose path relative to the root matches one of these globs.
+    #[arg(
+        long,
+        help = "Only render templates whose path relative to the root matches this glob, can be repeated. Other templates and all lockfile entries are left untouched, run without it to prune deleted templates."
+    )]
+    pub only: Vec<String>,
+    /// Dotenv files to load before resolving env context, taking precedence over the env_files config option.
+    #[arg(
+        long,
+        help = "A dotenv file to load before resolving env context, can be repeated. Variables already in the environment are kept, and these take precedence over the env_files config option."
+    )]
+    pub env_file: Vec<PathBuf>,
     /// Hidden test flag, writes some json output to the root dir.
     #[arg(
         long,
@@ -100,6 +221,74 @@ pub struct RenderCommand {
 #[derive(Clone, Debug, clap::Parser)]
 pub struct InitCommand {}
 
+#[derive(Clone, Debug, clap::Parser)]
+pub struct ListCommand {
+    /// The target directory to search.
+    #[clap(default_value = ".", help = "The target directory to search.")]
+    pub root: PathBuf,
+    /// The config file to use.
+    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH, help = "The config file to use.")]
+    pub config: PathBuf,
+    /// The config profile to merge over the base config.
+    #[arg(
+        short,
+        long,
+        help = "The config profile to merge over the base config, falls back to the ETCH_PROFILE env var."
+    )]
+    pub profile: Option<String>,
+    #[arg(long, visible_alias = "output", value_enum, default_value = "text")]
+    pub output_format: HelpFormat,
+}
+
+#[derive(Clone, Debug, clap::Parser)]
+pub struct ExplainCommand {
+    /// The file to explain.
+    #[clap(help = "The file to explain, relative to the current directory.")]
+    pub path: PathBuf,
+    /// The target directory to search.
+    #[arg(long, default_value = ".", help = "The target directory to search.")]
+    pub root: PathBuf,
+    /// The config file to use.
+    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH, help = "The config file to use.")]
+    pub config: PathBuf,
+    /// The config profile to merge over the base config.
+    #[arg(
+        short,
+        long,
+        help = "The config profile to merge over the base config, falls back to the ETCH_PROFILE env var."
+    )]
+    pub profile: Option<String>,
+}
+
+#[derive(Clone, Debug, clap::Parser)]
+pub struct CompleteCommand {
+    /// The option being completed.
+    #[arg(value_enum)]
+    pub target: CompleteTarget,
+    /// The partial value typed so far.
+    #[arg(default_value = "", allow_hyphen_values = true)]
+    pub prefix: String,
+    /// The target directory the completed command would render.
+    #[arg(long, default_value = ".")]
+    pub root: PathBuf,
+    /// The config file the completed command would use.
+    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum CompleteTarget {
+    /// Template paths relative to the root, for `--only`.
+    Only,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
 pub enum HelpFormat {
     Text,
@@ -0,0 +1,122 @@
+use clap::CommandFactory;
+
+use crate::args::{get_version_info, Args};
+
+// NOTE: when changing any of the below, make sure to update tests/version/test_version.py.
+
+/// Bumped whenever the lockfile format changes, 2 being the introduction of variants, 3 tracking config hashes per variant.
+pub static LOCKFILE_SCHEMA_VERSION: u32 = 3;
+/// Bumped whenever the hidden --debug json output changes shape.
+pub static DEBUG_SCHEMA_VERSION: u32 = 1;
+/// Bumped whenever a config change isn't backwards compatible, additive changes are tracked as capabilities.
+pub static CONFIG_SCHEMA_VERSION: u32 = 1;
+
+/// Feature identifiers, appended to as features land so automation can detect support without parsing versions.
+pub static CAPABILITIES: &[&str] = &[
+    "auto_escape",
+    "binary_guard",
+    "builtin_globals",
+    "check_vars",
+    "cli_multi",
+    "complete",
+    "completions",
+    "config_discovery",
+    "config_hash",
+    "context_copy",
+    "context_processors",
+    "current_template",
+    "engine_overrides",
+    "env_files",
+    "env_function",
+    "env_interpolation",
+    "error_location",
+    "etchignore",
+    "explain",
+    "extends",
+    "extension_hashes",
+    "extension_modules",
+    "fail_warn",
+    "fixed_timestamp",
+    "follow_symlinks",
+    "gitignore",
+    "glob",
+    "hash",
+    "hash_algo",
+    "in_place",
+    "include_paths",
+    "int_fidelity",
+    "junk_filter",
+    "line_endings",
+    "line_statements",
+    "list",
+    "lockfile_path",
+    "lockfile_variants",
+    "lstrip_blocks",
+    "managed_regions",
+    "matrix_directive",
+    "max_fuel",
+    "multiple_roots",
+    "needs_context",
+    "no_lockfile",
+    "only",
+    "out_dir",
+    "partials",
+    "path_checks",
+    "permissions",
+    "post_write",
+    "profiles",
+    "protected_outputs",
+    "py_traceback",
+    "pyproject",
+    "read_file",
+    "read_file_confined",
+    "register_name",
+    "register_overwrite",
+    "source_hash",
+    "strict",
+    "strict_lockfile",
+    "symlink_cycles",
+    "template_directives",
+    "template_marker",
+    "template_markers",
+    "template_matchers",
+    "templated_output_names",
+    "trim_blocks",
+    "uuid",
+    "version_json",
+    "watch",
+];
+
+#[derive(Debug, serde::Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub version_info: String,
+    pub capabilities: &'static [&'static str],
+    pub subcommands: Vec<String>,
+    pub lockfile_schema_version: u32,
+    pub debug_schema_version: u32,
+    pub config_schema_version: u32,
+}
+
+impl VersionInfo {
+    pub fn new() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            version_info: get_version_info(),
+            capabilities: CAPABILITIES,
+            subcommands: subcommands(),
+            lockfile_schema_version: LOCKFILE_SCHEMA_VERSION,
+            debug_schema_version: DEBUG_SCHEMA_VERSION,
+            config_schema_version: CONFIG_SCHEMA_VERSION,
+        }
+    }
+}
+
+/// The public subcommand names, extracted from the clap definition so they can't drift.
+fn subcommands() -> Vec<String> {
+    Args::command()
+        .get_subcommands()
+        .filter(|cmd| !cmd.is_hide_set())
+        .map(|cmd| cmd.get_name().to_string())
+        .collect()
+}
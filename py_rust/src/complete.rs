@@ -0,0 +1,101 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bitbazaar::errors::TracedErr;
+
+use crate::{
+    args::{CompleteCommand, CompleteTarget, RenderCommand, Shell},
+    config::{self, RawConfig},
+    render::{junk::JunkFilter, walker},
+};
+
+/// Completion runs on every tab press, so the walk has to give up quickly on large trees.
+const WALK_BUDGET: Duration = Duration::from_millis(200);
+
+/// The completion script for the shell, each calls back into `etch __complete` for the dynamic candidates.
+pub fn script(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => include_str!("completions/etch.bash"),
+        Shell::Zsh => include_str!("completions/etch.zsh"),
+        Shell::Fish => include_str!("completions/etch.fish"),
+    }
+}
+
+/// Print completion candidates one per line.
+/// Side-effect free: the config is only parsed, setup and cli commands are never run.
+/// Any error silently produces no suggestions, the shell should never show a stack trace mid-completion.
+pub fn complete(args: CompleteCommand) {
+    if let Ok(candidates) = candidates(&args) {
+        for candidate in candidates {
+            println!("{}", candidate);
+        }
+    }
+}
+
+fn candidates(args: &CompleteCommand) -> Result<Vec<String>, TracedErr> {
+    let mut render_args = RenderCommand {
+        root: args.root.clone(),
+        extra_roots: vec![],
+        config: args.config.clone(),
+        no_discover: false,
+        force: false,
+        no_lockfile: false,
+        lockfile: None,
+        out_dir: None,
+        profile: None,
+        variant: None,
+        skip_path_checks: false,
+        strict: false,
+        allow_overwrite_sources: false,
+        check_vars: false,
+        gitignore: false,
+        follow_symlinks: false,
+        watch: false,
+        only: vec![],
+        env_file: vec![],
+        debug: false,
+    };
+    config::apply_config_env(&mut render_args);
+
+    let raw_conf = RawConfig::from_toml(&render_args)?;
+    render_args.out_dir = raw_conf.out_dir.as_deref().map(PathBuf::from);
+
+    let mut candidates = match args.target {
+        CompleteTarget::Only => {
+            let walker = walker::create(
+                &render_args,
+                &raw_conf.exclude,
+                &raw_conf.ignore_files,
+                raw_conf.use_gitignore,
+                raw_conf.follow_symlinks,
+                raw_conf.max_depth,
+                raw_conf.lockfile.as_deref().map(Path::new),
+            )?;
+            let junk = JunkFilter::new(raw_conf.ignore_junk, &raw_conf.junk_patterns)?;
+            let marker = walker::MarkerMatcher::new(
+                &raw_conf.template_marker.markers(),
+                &raw_conf.template_matchers,
+            )?;
+            walker::find_templates(
+                &render_args,
+                walker,
+                &junk,
+                &marker,
+                &raw_conf.partial_prefix,
+                Some(WALK_BUDGET),
+            )?
+            .templates
+            .into_iter()
+            .map(|template| template.rel_path)
+            .collect::<Vec<_>>()
+        }
+    };
+
+    candidates.retain(|candidate| candidate.starts_with(&args.prefix));
+    candidates.sort();
+    candidates.dedup();
+
+    Ok(candidates)
+}
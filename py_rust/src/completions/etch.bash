@@ -0,0 +1,21 @@
+# Bash completion for etch, load it with: eval "$(etch completions bash)"
+_etch() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD - 1]}" root="." config=() i word
+    # The root and config the completed command would render with:
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${COMP_WORDS[i]}"
+        case "$word" in
+        -c | --config) config=(--config "${COMP_WORDS[i + 1]}") ;;
+        -*) ;;
+        *) [[ -d "$word" && "${COMP_WORDS[i - 1]}" != -* ]] && root="$word" ;;
+        esac
+    done
+
+    if [[ "$prev" == "--only" ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($(etch __complete only "$cur" --root "$root" "${config[@]}" 2>/dev/null))
+        return
+    fi
+    COMPREPLY=($(compgen -f -- "$cur"))
+}
+complete -o default -F _etch etch
@@ -0,0 +1,21 @@
+# Fish completion for etch, load it with: etch completions fish | source
+function __etch_complete_only
+    set -l tokens (commandline -opc)
+    set -l root .
+    set -l config
+    # The root and config the completed command would render with:
+    for i in (seq 2 (count $tokens))
+        switch $tokens[$i]
+            case -c --config
+                set config --config $tokens[(math $i + 1)]
+            case '-*'
+            case '*'
+                if test -d $tokens[$i]; and not string match -q -- '-*' $tokens[(math $i - 1)]
+                    set root $tokens[$i]
+                end
+        end
+    end
+    etch __complete only (commandline -ct) --root $root $config 2>/dev/null
+end
+
+complete -c etch -l only -r -f -a '(__etch_complete_only)'
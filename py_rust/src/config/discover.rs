@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use bitbazaar::errors::TracedErr;
+use log::{debug, info};
+
+use crate::args::{RenderCommand, DEFAULT_CONFIG_PATH, PYPROJECT_CONFIG_PATH};
+
+/// Set by scripts to avoid passing --config everywhere.
+pub static CONFIG_ENV_VAR: &str = "ETCH_CONFIG";
+
+/// Use the ETCH_CONFIG env var as the config when --config is left at its default.
+///
+/// The precedence is --config, then ETCH_CONFIG, then the default config (searched for in parents when missing at the root).
+/// Relative paths are resolved from the root, just like --config.
+pub fn apply_config_env(render_args: &mut RenderCommand) {
+    if render_args.config != Path::new(DEFAULT_CONFIG_PATH) {
+        return;
+    }
+    if let Some(config) = std::env::var_os(CONFIG_ENV_VAR).filter(|config| !config.is_empty()) {
+        debug!(
+            "Using config '{}' from the {} env var.",
+            Path::new(&config).display(),
+            CONFIG_ENV_VAR
+        );
+        render_args.config = PathBuf::from(config);
+    }
+}
+
+/// When the config is left at its default and can't be found at the root, search parent directories for it.
+///
+/// Stops at the first directory containing `.git`, or the filesystem root.
+/// If found and the root was also left at its default, the config's directory becomes the render root.
+/// An explicitly passed config is never searched for, so a missing one still errors when read, nor is any config with --no-discover.
+pub fn discover_config(render_args: &mut RenderCommand) -> Result<(), TracedErr> {
+    apply_config_env(render_args);
+    if render_args.no_discover
+        || render_args.config != Path::new(DEFAULT_CONFIG_PATH)
+        || render_args.root.join(&render_args.config).exists()
+        || render_args.root.join(PYPROJECT_CONFIG_PATH).exists()
+    {
+        return Ok(());
+    }
+
+    let root = render_args.root.canonicalize()?;
+    if let Some(found) = search_parents(&root) {
+        info!("Discovered config at '{}'.", found.display());
+        if render_args.root == Path::new(".") {
+            if let Some(parent) = found.parent() {
+                render_args.root = parent.to_path_buf();
+            }
+        }
+        render_args.config = found;
+    }
+
+    Ok(())
+}
+
+fn search_parents(root: &Path) -> Option<PathBuf> {
+    // The root itself has already been checked, only continue if it's not the top of a repo:
+    if root.join(".git").exists() {
+        return None;
+    }
+
+    for dir in root.ancestors().skip(1) {
+        let candidate = dir.join(DEFAULT_CONFIG_PATH);
+        if candidate.exists() {
+            return Some(candidate.components().collect());
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
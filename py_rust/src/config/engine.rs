@@ -1,57 +1,312 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     fs, io,
-    ops::Deref,
-    path::Path,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 
 use bitbazaar::{err, errors::TracedErr};
-use log::debug;
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use log::{debug, warn};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use pyo3::{
     prelude::*,
-    types::{PyDict, PyList, PyTuple},
+    types::{
+        PyBool, PyBytes, PyDate, PyDict, PyFloat, PyFrozenSet, PyList, PyLong, PyMapping, PySet,
+        PyString, PyTime, PyTuple,
+    },
 };
 use pythonize::{depythonize, pythonize};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::HashAlgo;
+use crate::render::walker::{MarkerMatcher, WalkedRoot};
+
+/// The reserved global name the process environment is exposed under when `expose_process_env` is enabled.
+pub static ENV_GLOBAL_NAME: &str = "env";
+
+/// The render-time ISO timestamp global, added when `builtin_globals` is enabled.
+pub static NOW_GLOBAL_NAME: &str = "__now__";
+
+/// When set (to integer unix seconds) and `fixed_timestamp` isn't, pins the render time for reproducible builds.
+/// See https://reproducible-builds.org/specs/source-date-epoch/
+pub static SOURCE_DATE_EPOCH_VAR: &str = "SOURCE_DATE_EPOCH";
+
+/// What a single include level costs against minijinja's recursion limit, which is capped at 500.
+pub static INCLUDE_RECURSION_COST: usize = 10;
+/// The deepest max_include_depth allowed, NOTE: when changing make sure to update the schema.json maximum.
+pub static MAX_INCLUDE_DEPTH: usize = 49;
+
+/// The reserved global name the active profile is exposed under, none when no profile is selected.
+pub static PROFILE_GLOBAL_NAME: &str = "etch_profile";
+
+// Env vars matching these are never exposed by "all" mode, they must be explicitly allowlisted:
+static SECRET_ENV_PATTERNS: &[&str] = &["*TOKEN*", "*SECRET*", "*PASSWORD*"];
 
 pub static PY_CONTEXT: Lazy<Mutex<Option<PyObject>>> = Lazy::new(Mutex::default);
+
+thread_local! {
+    // The rel path of the template being rendered on this thread, thread local so parallel renders can't see each other's:
+    static CURRENT_TEMPLATE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Clears the current template when dropped, so it's reset even if rendering errors.
+pub struct CurrentTemplateGuard;
+
+impl Drop for CurrentTemplateGuard {
+    fn drop(&mut self) {
+        CURRENT_TEMPLATE.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// Set the template being rendered on this thread, until the returned guard is dropped.
+pub fn set_current_template(rel_path: &str) -> CurrentTemplateGuard {
+    CURRENT_TEMPLATE.with(|current| *current.borrow_mut() = Some(rel_path.to_string()));
+    CurrentTemplateGuard
+}
+
+pub fn current_template() -> Option<String> {
+    CURRENT_TEMPLATE.with(|current| current.borrow().clone())
+}
 static PY_USER_FUNCS: Lazy<Mutex<HashMap<String, PyObject>>> = Lazy::new(Mutex::default);
+static PY_USER_FILTERS: Lazy<Mutex<HashMap<String, PyObject>>> = Lazy::new(Mutex::default);
+static PY_USER_TESTS: Lazy<Mutex<HashMap<String, PyObject>>> = Lazy::new(Mutex::default);
+// The names of registered functions that receive the context as their first argument:
+static PY_CONTEXT_FUNCS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Mutex::default);
+// Run over the resolved context in registration order before anything renders:
+static PY_CONTEXT_PROCESSORS: Lazy<Mutex<Vec<(String, PyObject)>>> = Lazy::new(Mutex::default);
+// The contents of each extension file when first hashed. Extensions are only imported once per process,
+// so e.g. in watch mode an edit shouldn't be recorded as rendered until the process restarts and actually imports it:
+static EXTENSION_SOURCES: Lazy<Mutex<HashMap<PathBuf, Vec<u8>>>> = Lazy::new(Mutex::default);
 
-pub fn register_py_func(py: Python, py_fn: &PyAny) -> Result<(), TracedErr> {
-    let module_name = py_fn.getattr("__module__")?.extract::<String>()?;
-    let fn_name = py_fn.getattr("__name__")?.extract::<String>()?;
+/// `name` overrides the function's `__name__`, needed for callables without one e.g. a functools.partial.
+/// `overwrite` replaces an existing function of the same name rather than erroring, e.g. when re-running a notebook cell.
+pub fn register_py_func(
+    py: Python,
+    py_fn: &PyAny,
+    name: Option<String>,
+    needs_context: bool,
+    overwrite: bool,
+) -> Result<(), TracedErr> {
+    let fn_name = register_py_callable(py, py_fn, name, overwrite, "function", &PY_USER_FUNCS)?;
+    let mut context_funcs = PY_CONTEXT_FUNCS.lock();
+    if needs_context {
+        context_funcs.insert(fn_name);
+    } else {
+        // An overwritten function might previously have needed the context:
+        context_funcs.remove(&fn_name);
+    }
+    Ok(())
+}
 
-    debug!("Registering custom function: '{}.{}'", module_name, fn_name);
+/// Forget everything registered by custom extensions, functions, filters, tests and context processors alike,
+/// so a long-lived process can register them afresh.
+pub fn clear_py_funcs() {
+    PY_USER_FUNCS.lock().clear();
+    PY_CONTEXT_FUNCS.lock().clear();
+    PY_USER_FILTERS.lock().clear();
+    PY_USER_TESTS.lock().clear();
+    PY_CONTEXT_PROCESSORS.lock().clear();
+}
 
-    // Confirm it's a function:
+/// Re-registering the same processor replaces it in place, so e.g. re-running a notebook cell doesn't apply it twice.
+pub fn register_py_context_processor(py_fn: &PyAny, name: Option<String>) -> Result<(), TracedErr> {
     if !py_fn.is_callable() {
         return Err(err!(
-            "Failed to register custom function: '{}.{}' as it's not a function",
-            module_name,
-            fn_name
+            "Failed to register context processor: '{}' as it's not a function",
+            py_fn.repr()?
         ));
     }
+    let fn_name = match name {
+        Some(name) => name,
+        None => py_fn
+            .getattr("__name__")
+            .and_then(|name| name.extract::<String>())
+            .map_err(|_| {
+                err!(
+                    "Failed to register context processor: '{}' as it has no __name__, pass name= explicitly.",
+                    py_fn.repr().map(|repr| repr.to_string()).unwrap_or_default()
+                )
+            })?,
+    };
+    let qualified = qualified_name(py_fn, &fn_name);
+
+    debug!("Registering context processor: '{}'", qualified);
+    let mut processors = PY_CONTEXT_PROCESSORS.lock();
+    match processors.iter_mut().find(|(name, _)| name == &qualified) {
+        Some(existing) => existing.1 = py_fn.into(),
+        None => processors.push((qualified, py_fn.into())),
+    }
+    Ok(())
+}
+
+/// `overwrite` replaces an existing filter of the same name rather than erroring, just like for functions.
+pub fn register_py_filter(
+    py: Python,
+    py_fn: &PyAny,
+    name: Option<String>,
+    overwrite: bool,
+) -> Result<(), TracedErr> {
+    register_py_callable(py, py_fn, name, overwrite, "filter", &PY_USER_FILTERS).map(|_| ())
+}
+
+/// `overwrite` replaces an existing test of the same name rather than erroring, just like for functions.
+pub fn register_py_test(
+    py: Python,
+    py_fn: &PyAny,
+    name: Option<String>,
+    overwrite: bool,
+) -> Result<(), TracedErr> {
+    register_py_callable(py, py_fn, name, overwrite, "test", &PY_USER_TESTS).map(|_| ())
+}
+
+/// The name prefixed with the callable's module when it has one, for error messages.
+fn qualified_name(py_fn: &PyAny, name: &str) -> String {
+    match py_fn
+        .getattr("__module__")
+        .and_then(|module| module.extract::<String>())
+    {
+        Ok(module) => format!("{}.{}", module, name),
+        Err(_) => name.to_string(),
+    }
+}
+
+fn copy_registry(
+    py: Python,
+    store: &Mutex<HashMap<String, PyObject>>,
+) -> HashMap<String, PyObject> {
+    store
+        .lock()
+        .iter()
+        .map(|(name, py_fn)| (name.clone(), py_fn.clone_ref(py)))
+        .collect()
+}
 
-    let mut func_store = PY_USER_FUNCS.lock();
+/// Returns the name the callable was registered under.
+fn register_py_callable(
+    py: Python,
+    py_fn: &PyAny,
+    name: Option<String>,
+    overwrite: bool,
+    kind: &str,
+    store: &Mutex<HashMap<String, PyObject>>,
+) -> Result<String, TracedErr> {
+    let repr = || {
+        py_fn
+            .repr()
+            .map(|repr| repr.to_string())
+            .unwrap_or_else(|_| "<unknown>".to_string())
+    };
 
-    // Raise error if something with the same name already registered:
-    if let Entry::Vacant(e) = func_store.entry(fn_name.clone()) {
-        e.insert(py_fn.to_object(py));
-    } else {
+    // Confirm it's a function:
+    if !py_fn.is_callable() {
         return Err(err!(
-            "Failed to register custom function: '{}.{}' as '{}' is already registered.",
-            module_name,
-            fn_name,
+            "Failed to register custom {}: '{}' as it's not a function",
+            kind,
+            repr()
+        ));
+    }
+
+    // Only a name is required, e.g. callable class instances and partials have no __name__ and might not have a __module__:
+    let fn_name = match name {
+        Some(name) => name,
+        None => py_fn
+            .getattr("__name__")
+            .and_then(|name| name.extract::<String>())
+            .map_err(|_| {
+                err!(
+                    "Failed to register custom {}: '{}' as it has no __name__, pass name= explicitly.",
+                    kind,
+                    repr()
+                )
+            })?,
+    };
+    let qualified = qualified_name(py_fn, &fn_name);
+
+    debug!("Registering custom {}: '{}'", kind, qualified);
+
+    // Functions, filters and tests live in separate template namespaces,
+    // but one python name doing different things depending on syntax is almost certainly a mistake:
+    for (other_kind, other_store) in [
+        ("function", &PY_USER_FUNCS),
+        ("filter", &PY_USER_FILTERS),
+        ("test", &PY_USER_TESTS),
+    ] {
+        if other_kind != kind && other_store.lock().contains_key(&fn_name) {
+            return Err(err!(
+                "Failed to register custom {}: '{}' as '{}' is already registered as a custom {}.",
+                kind,
+                qualified,
+                fn_name,
+                other_kind
+            ));
+        }
+    }
+
+    let mut func_store = store.lock();
+
+    // Raise error if something with the same name already registered, unless replacing it was asked for:
+    match func_store.entry(fn_name.clone()) {
+        Entry::Vacant(e) => {
+            e.insert(py_fn.to_object(py));
+        }
+        Entry::Occupied(mut e) if overwrite => {
+            debug!("Overwriting custom {}: '{}'", kind, qualified);
+            e.insert(py_fn.to_object(py));
+        }
+        Entry::Occupied(_) => {
+            return Err(err!(
+            "Failed to register custom {}: '{}' as '{}' is already registered, pass overwrite=True to replace it.",
+            kind,
+            qualified,
             fn_name
         ));
+        }
     }
 
-    Ok(())
+    Ok(fn_name)
+}
+
+// String literal of none, allowlist, all:
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExposeProcessEnv {
+    None,
+    Allowlist,
+    All,
+}
+
+// String literal of lf, crlf, preserve:
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEndings {
+    Lf,
+    Crlf,
+    Preserve,
+}
+
+impl LineEndings {
+    /// Normalize the newlines of compiled output, existing \r\n endings are recognised so they're never double converted.
+    pub fn apply(self, compiled: String) -> String {
+        match self {
+            LineEndings::Preserve => compiled,
+            LineEndings::Lf => {
+                if compiled.contains("\r\n") {
+                    compiled.replace("\r\n", "\n")
+                } else {
+                    compiled
+                }
+            }
+            LineEndings::Crlf => compiled.replace("\r\n", "\n").replace('\n', "\r\n"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Engine {
     #[serde(default = "default_block_start")]
     block_start: String,
@@ -65,12 +320,90 @@ pub struct Engine {
     comment_start: String,
     #[serde(default = "default_comment_end")]
     comment_end: String,
+    #[serde(default = "default_line_statement_prefix")]
+    line_statement_prefix: Option<String>,
+    #[serde(default = "default_line_comment_prefix")]
+    line_comment_prefix: Option<String>,
     #[serde(default = "default_keep_trailing_newline")]
     keep_trailing_newline: bool,
+    #[serde(default = "default_trim_blocks")]
+    trim_blocks: bool,
+    #[serde(default = "default_lstrip_blocks")]
+    lstrip_blocks: bool,
     #[serde(default = "default_allow_undefined")]
-    allow_undefined: bool,
+    pub allow_undefined: bool,
+    #[serde(default = "default_max_undefined")]
+    pub max_undefined: Option<usize>,
+    #[serde(default = "default_max_fuel")]
+    pub max_fuel: Option<u64>,
+    #[serde(default = "default_max_include_depth")]
+    pub max_include_depth: Option<usize>,
     #[serde(default = "default_custom_extensions")]
     pub custom_extensions: Vec<String>,
+    #[serde(default = "default_include_paths", alias = "include_dirs")]
+    pub include_paths: Vec<String>,
+    #[serde(default = "default_expose_process_env")]
+    pub expose_process_env: ExposeProcessEnv,
+    #[serde(default = "default_env_allowlist")]
+    pub env_allowlist: Vec<String>,
+    #[serde(default = "default_builtin_globals")]
+    pub builtin_globals: bool,
+    #[serde(default = "default_allow_env_in_templates")]
+    pub allow_env_in_templates: bool,
+    #[serde(default = "default_fixed_timestamp")]
+    fixed_timestamp: Option<String>,
+    #[serde(default = "default_line_endings")]
+    pub line_endings: LineEndings,
+    #[serde(default = "default_auto_escape")]
+    pub auto_escape: BTreeMap<String, bool>,
+    #[serde(default = "default_overrides")]
+    pub overrides: Vec<EngineOverride>,
+    #[serde(default = "default_managed_regions")]
+    pub managed_regions: Vec<ManagedRegion>,
+}
+
+/// Syntax overrides for templates matching the glob, any fields left unset fall back to the base engine config.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineOverride {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    block_start: Option<String>,
+    block_end: Option<String>,
+    variable_start: Option<String>,
+    variable_end: Option<String>,
+    comment_start: Option<String>,
+    comment_end: Option<String>,
+    line_statement_prefix: Option<String>,
+    line_comment_prefix: Option<String>,
+}
+
+impl EngineOverride {
+    pub fn matcher(&self) -> Result<GlobMatcher, TracedErr> {
+        Ok(Glob::new(&self.pattern)
+            .map_err(|e| err!("Invalid glob '{}': '{}'", self.pattern, e))?
+            .compile_matcher())
+    }
+}
+
+/// The lines marking the generated region of an otherwise hand-edited output, one pair per comment syntax.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManagedRegion {
+    pub begin: String,
+    pub end: String,
+}
+
+/// The fully resolved syntax of an environment, the base engine config with any override applied.
+struct Syntax {
+    block_start: String,
+    block_end: String,
+    variable_start: String,
+    variable_end: String,
+    comment_start: String,
+    comment_end: String,
+    line_statement_prefix: Option<String>,
+    line_comment_prefix: Option<String>,
 }
 
 impl Engine {
@@ -83,16 +416,379 @@ impl Engine {
             variable_end: default_variable_end(),
             comment_start: default_comment_start(),
             comment_end: default_comment_end(),
+            line_statement_prefix: default_line_statement_prefix(),
+            line_comment_prefix: default_line_comment_prefix(),
             keep_trailing_newline: default_keep_trailing_newline(),
+            trim_blocks: default_trim_blocks(),
+            lstrip_blocks: default_lstrip_blocks(),
             allow_undefined: default_allow_undefined(),
+            max_undefined: default_max_undefined(),
+            max_fuel: default_max_fuel(),
+            max_include_depth: default_max_include_depth(),
             custom_extensions: default_custom_extensions(),
+            include_paths: default_include_paths(),
+            expose_process_env: default_expose_process_env(),
+            env_allowlist: default_env_allowlist(),
+            builtin_globals: default_builtin_globals(),
+            allow_env_in_templates: default_allow_env_in_templates(),
+            fixed_timestamp: default_fixed_timestamp(),
+            line_endings: default_line_endings(),
+            auto_escape: default_auto_escape(),
+            overrides: default_overrides(),
+            managed_regions: default_managed_regions(),
         }
     }
 
-    pub fn create_minijinja_env<'a>(
+    fn syntax(&self, syntax_override: Option<&EngineOverride>) -> Syntax {
+        let pick = |base: &String, over: Option<&Option<String>>| -> String {
+            over.and_then(|over| over.clone())
+                .unwrap_or_else(|| base.clone())
+        };
+        let pick_opt = |base: &Option<String>, over: Option<&Option<String>>| -> Option<String> {
+            over.and_then(|over| over.clone()).or_else(|| base.clone())
+        };
+        Syntax {
+            block_start: pick(&self.block_start, syntax_override.map(|o| &o.block_start)),
+            block_end: pick(&self.block_end, syntax_override.map(|o| &o.block_end)),
+            variable_start: pick(
+                &self.variable_start,
+                syntax_override.map(|o| &o.variable_start),
+            ),
+            variable_end: pick(&self.variable_end, syntax_override.map(|o| &o.variable_end)),
+            comment_start: pick(
+                &self.comment_start,
+                syntax_override.map(|o| &o.comment_start),
+            ),
+            comment_end: pick(&self.comment_end, syntax_override.map(|o| &o.comment_end)),
+            line_statement_prefix: pick_opt(
+                &self.line_statement_prefix,
+                syntax_override.map(|o| &o.line_statement_prefix),
+            ),
+            line_comment_prefix: pick_opt(
+                &self.line_comment_prefix,
+                syntax_override.map(|o| &o.line_comment_prefix),
+            ),
+        }
+    }
+
+    /// The variable delimiters templates use, with any override applied.
+    pub fn variable_delimiters(
+        &self,
+        syntax_override: Option<&EngineOverride>,
+    ) -> (String, String) {
+        let resolved = self.syntax(syntax_override);
+        (resolved.variable_start, resolved.variable_end)
+    }
+
+    /// The comment delimiters templates use, with any override applied.
+    pub fn comment_delimiters(&self, syntax_override: Option<&EngineOverride>) -> (String, String) {
+        let resolved = self.syntax(syntax_override);
+        (resolved.comment_start, resolved.comment_end)
+    }
+
+    /// The index of the override applying to the template, when multiple match the last wins.
+    pub fn override_index(matchers: &[GlobMatcher], rel_path: &str) -> Option<usize> {
+        let matched = matchers
+            .iter()
+            .enumerate()
+            .filter(|(_, matcher)| matcher.is_match(rel_path))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if matched.len() > 1 {
+            debug!(
+                "Multiple [engine.overrides] match '{}': {:?}, using the last.",
+                rel_path, matched
+            );
+        }
+        matched.last().copied()
+    }
+
+    /// Check the syntax config is unambiguous, minijinja only fails deep inside parsing the first template otherwise.
+    pub fn validate_syntax(&self) -> Result<(), TracedErr> {
+        self.syntax(None).validate("engine")?;
+        for (index, syntax_override) in self.overrides.iter().enumerate() {
+            let location = format!("engine.overrides.{}", index);
+            syntax_override
+                .matcher()
+                .map_err(|e| e.modify_msg(|msg| format!("[{}.match]: {}", location, msg)))?;
+            self.syntax(Some(syntax_override)).validate(&location)?;
+        }
+        Ok(())
+    }
+
+    /// The time exposed to templates through now(), today() and __now__.
+    ///
+    /// `fixed_timestamp` takes precedence over `SOURCE_DATE_EPOCH`, the live time is only used when neither is set.
+    /// Pinned times keep repeated renders byte identical, so the lockfile sees them as unchanged.
+    pub fn render_time(&self) -> Result<chrono::DateTime<chrono::Utc>, TracedErr> {
+        if let Some(fixed) = &self.fixed_timestamp {
+            return Ok(chrono::DateTime::parse_from_rfc3339(fixed)
+                .map_err(|e| {
+                    err!(
+                        "[engine.fixed_timestamp]: Invalid RFC 3339 timestamp '{}': {}",
+                        fixed,
+                        e
+                    )
+                })?
+                .with_timezone(&chrono::Utc));
+        }
+
+        if let Ok(epoch) = std::env::var(SOURCE_DATE_EPOCH_VAR) {
+            return epoch
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+                .ok_or_else(|| {
+                    err!(
+                        "Invalid {} '{}', expected an integer unix timestamp.",
+                        SOURCE_DATE_EPOCH_VAR,
+                        epoch
+                    )
+                });
+        }
+
+        Ok(chrono::Utc::now())
+    }
+
+    /// Explain a render failure caused by one of the execution limits, so it's clear which config to change.
+    pub fn limit_exceeded(&self, e: &minijinja::Error) -> Option<String> {
+        // Errors inside includes are wrapped, so the whole chain is checked:
+        let chain = std::iter::successors(Some(e), |e| {
+            std::error::Error::source(*e).and_then(|source| source.downcast_ref())
+        })
+        .collect::<Vec<&minijinja::Error>>();
+
+        if chain
+            .iter()
+            .any(|e| e.kind() == minijinja::ErrorKind::OutOfFuel)
+        {
+            return self
+                .max_fuel
+                .map(|max_fuel| format!("exceeded [engine.max_fuel] of {}", max_fuel));
+        }
+        if chain
+            .iter()
+            .any(|e| e.detail() == Some("recursion limit exceeded"))
+        {
+            return Some(match self.max_include_depth {
+                Some(max_include_depth) => format!(
+                    "exceeded [engine.max_include_depth] of {}",
+                    max_include_depth
+                ),
+                None => format!(
+                    "exceeded the default nesting limit, equivalent to [engine.max_include_depth] of {}",
+                    MAX_INCLUDE_DEPTH
+                ),
+            });
+        }
+        None
+    }
+
+    /// Collect the process environment variables that should be exposed to templates under the reserved `env` global.
+    ///
+    /// In "all" mode, anything that looks like a secret is still only included when explicitly allowlisted.
+    pub fn exposed_env(&self) -> Result<BTreeMap<String, String>, TracedErr> {
+        let mut exposed = BTreeMap::new();
+
+        if self.expose_process_env == ExposeProcessEnv::None {
+            return Ok(exposed);
+        }
+
+        let allowlist = build_globset(&self.env_allowlist, false)?;
+        let secrets = build_globset(SECRET_ENV_PATTERNS, true)?;
+
+        // Non unicode vars can't be represented in the context so are skipped:
+        for (name, value) in std::env::vars_os() {
+            let (Some(name), Some(value)) = (name.to_str(), value.to_str()) else {
+                continue;
+            };
+
+            let allowlisted = allowlist.is_match(name);
+            let include = match self.expose_process_env {
+                ExposeProcessEnv::None => false,
+                ExposeProcessEnv::Allowlist => allowlisted,
+                ExposeProcessEnv::All => allowlisted || !secrets.is_match(name),
+            };
+
+            if include {
+                exposed.insert(name.to_string(), value.to_string());
+            }
+        }
+
+        debug!(
+            "Exposing {} environment variables to templates under '{}'.",
+            exposed.len(),
+            ENV_GLOBAL_NAME
+        );
+
+        Ok(exposed)
+    }
+
+    /// Hash the source of each custom extension, so editing an extension's logic rewrites the outputs that may depend on it.
+    ///
+    /// Keyed by path relative to the root where possible, packages hash every python file they contain.
+    /// Extensions that don't exist (e.g. with --skip-path-checks) are left out, they'll fail on import instead.
+    pub fn extension_hashes(
         &self,
         root: &Path,
+        hash_algo: HashAlgo,
+    ) -> Result<BTreeMap<String, String>, TracedErr> {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let mut hashes = BTreeMap::new();
+        // Installed modules are managed by the environment rather than the project, so aren't tracked:
+        for extension_path in self
+            .custom_extensions
+            .iter()
+            .filter(|extension| !is_extension_module(extension))
+        {
+            let mut files = vec![];
+            python_files(Path::new(extension_path), &mut files)?;
+            for file in files {
+                let key = file
+                    .strip_prefix(&root)
+                    .unwrap_or(&file)
+                    .to_string_lossy()
+                    .to_string();
+                let hashed = match EXTENSION_SOURCES.lock().entry(file) {
+                    Entry::Occupied(entry) => hash_algo.hash(entry.get()),
+                    Entry::Vacant(entry) => {
+                        let source = fs::read(entry.key())?;
+                        hash_algo.hash(entry.insert(source))
+                    }
+                };
+                hashes.insert(key, hashed);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Import the custom extensions, registering their functions, filters, tests and context processors.
+    ///
+    /// The context is exposed to them through etch.context() first, python caches imports so only the first call per process runs them.
+    fn import_extensions(&self, ctx: &HashMap<String, serde_json::Value>) -> Result<(), TracedErr> {
+        Python::with_gil(|py| {
+            // Pythonize a copy of the context and add to the global PY_CONTEXT so its usable from etch.context(),
+            // kept for backwards compatibility now functions can opt into receiving it with needs_context=True:
+            *PY_CONTEXT.lock() = Some(pythonize(py, ctx)?);
+
+            let syspath: &PyList = py.import("sys")?.getattr("path")?.downcast().map_err(|e| {
+                err!(
+                    "Failed to get sys.path whilst importing custom extension: '{}'",
+                    e
+                )
+            })?;
+            for extension_path in self.custom_extensions.iter() {
+                let result: Result<(), TracedErr> = (|| {
+                    // Module names are already importable from the environment, no sys.path changes needed:
+                    if is_extension_module(extension_path) {
+                        py.import(extension_path.as_str())?;
+                        return Ok(());
+                    }
+
+                    // Get the parent dir of the file/package:
+                    let path = Path::new(extension_path);
+                    let parent = path
+                        .parent()
+                        .ok_or_else(|| err!("Failed to get parent of path '{}'", extension_path))?;
+                    // Packages are imported by their directory name, files by their name without the .py:
+                    let name = match path.is_dir() {
+                        true => path.file_name(),
+                        false => path.file_stem(),
+                    }
+                    .ok_or_else(|| err!("Failed to get module name of path '{}'", extension_path))?
+                    .to_str()
+                    .ok_or_else(|| {
+                        err!(
+                            "Failed to convert module name to string of path '{}'",
+                            extension_path
+                        )
+                    })?;
+                    // Environments are created per render, so avoid growing sys.path each time:
+                    if !syspath.contains(parent)? {
+                        syspath.insert(0, parent)?;
+                    }
+                    py.import(name)?;
+                    Ok(())
+                })();
+
+                if let Err(e) = result {
+                    return Err(e.modify_msg(|msg| {
+                        format!(
+                            "Failed to import custom extension '{}'. Error: '{}'",
+                            extension_path, msg
+                        )
+                    }));
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Run the registered context processors over the resolved context in registration order, each receiving the previous one's result.
+    pub fn process_context(
+        &self,
+        ctx: HashMap<String, serde_json::Value>,
+    ) -> Result<HashMap<String, serde_json::Value>, TracedErr> {
+        // Processors can only be registered by extensions:
+        if self.custom_extensions.is_empty() {
+            return Ok(ctx);
+        }
+        self.import_extensions(&ctx)?;
+
+        Python::with_gil(|py| {
+            let processors = PY_CONTEXT_PROCESSORS
+                .lock()
+                .iter()
+                .map(|(name, py_fn)| (name.clone(), py_fn.clone_ref(py)))
+                .collect::<Vec<_>>();
+            if processors.is_empty() {
+                return Ok(ctx);
+            }
+
+            let mut ctx = ctx;
+            for (name, processor) in processors {
+                let add_name = |e: TracedErr| {
+                    e.modify_msg(|msg| format!("Context processor '{}' failed: {}", name, msg))
+                };
+                let result = processor
+                    .call1(py, (pythonize(py, &ctx)?,))
+                    .map_err(
+                        |e: PyErr| match e.traceback(py).and_then(|tb| tb.format().ok()) {
+                            Some(tb) => err!("{}{}", tb, e),
+                            None => err!("{}", e),
+                        },
+                    )
+                    .map_err(add_name)?;
+                let result = result.as_ref(py);
+                if !result.is_instance_of::<PyDict>() && result.downcast::<PyMapping>().is_err() {
+                    return Err(err!(
+                        "Context processor '{}' returned a '{}', expected a mapping of context keys to values.",
+                        name,
+                        result.get_type().name()?
+                    ));
+                }
+                ctx = py_result_to_value(result)
+                    .and_then(|value| Ok(serde_json::from_value(serde_json::to_value(value)?)?))
+                    .map_err(add_name)?;
+                debug!("Context processor '{}' applied.", name);
+            }
+
+            // So etch.context() sees the processed context too:
+            *PY_CONTEXT.lock() = Some(pythonize(py, &ctx)?);
+            Ok(ctx)
+        })
+    }
+
+    pub fn create_minijinja_env<'a>(
+        &self,
+        root: &WalkedRoot,
         ctx: &'a HashMap<String, serde_json::Value>,
+        exposed_env: &BTreeMap<String, String>,
+        profile: Option<&str>,
+        marker: &MarkerMatcher,
+        syntax_override: Option<&EngineOverride>,
     ) -> Result<minijinja::Environment<'a>, TracedErr> {
         let mut env: minijinja::Environment<'a> = minijinja::Environment::new();
         // Adding in extra builtins like urlencode, tojson and pluralize:
@@ -100,167 +796,199 @@ impl Engine {
 
         // User configurable options added below:
 
-        env.set_syntax(minijinja::Syntax {
-            block_start: self.block_start.clone().into(),
-            block_end: self.block_end.clone().into(),
-            variable_start: self.variable_start.clone().into(),
-            variable_end: self.variable_end.clone().into(),
-            comment_start: self.comment_start.clone().into(),
-            comment_end: self.comment_end.clone().into(),
-        })?;
+        let resolved = self.syntax(syntax_override);
+        let mut syntax = minijinja::syntax::SyntaxConfig::builder();
+        syntax
+            .block_delimiters(resolved.block_start, resolved.block_end)
+            .variable_delimiters(resolved.variable_start, resolved.variable_end)
+            .comment_delimiters(resolved.comment_start, resolved.comment_end);
+        if let Some(prefix) = resolved.line_statement_prefix {
+            syntax.line_statement_prefix(prefix);
+        }
+        if let Some(prefix) = resolved.line_comment_prefix {
+            syntax.line_comment_prefix(prefix);
+        }
+        env.set_syntax(syntax.build()?);
         env.set_keep_trailing_newline(self.keep_trailing_newline);
+        env.set_trim_blocks(self.trim_blocks);
+        env.set_lstrip_blocks(self.lstrip_blocks);
+        // Guards against runaway templates, e.g. accidentally unbounded recursive macros, unlimited by default:
+        env.set_fuel(self.max_fuel);
+        if let Some(max_include_depth) = self.max_include_depth {
+            // An extra level is allowed for the template itself, plus any blocks nested in the deepest include:
+            env.set_recursion_limit((max_include_depth + 1) * INCLUDE_RECURSION_COST);
+        }
         env.set_undefined_behavior(if self.allow_undefined {
             minijinja::UndefinedBehavior::Lenient
         } else {
             minijinja::UndefinedBehavior::Strict
         });
 
-        // Disable all default auto escaping, this caused problems with e.g. adding strings around values in json files:
-        env.set_auto_escape_callback(|_: &str| -> minijinja::AutoEscape {
-            minijinja::AutoEscape::None
+        // Auto escaping is disabled by default, this caused problems with e.g. adding strings around values in json files.
+        // Can be enabled per output file extension, e.g. { html = true }:
+        let auto_escape = self.auto_escape.clone();
+        let marker = marker.clone();
+        env.set_auto_escape_callback(move |name: &str| -> minijinja::AutoEscape {
+            match output_extension(name, &marker) {
+                Some(ext) if auto_escape.get(&ext) == Some(&true) => match ext.as_str() {
+                    "json" => minijinja::AutoEscape::Json,
+                    _ => minijinja::AutoEscape::Html,
+                },
+                _ => minijinja::AutoEscape::None,
+            }
         });
 
-        // This will allow loading files from templates using the relative root e.g. ./template where . is the root dir:
-        env.set_loader(custom_loader(root));
+        // This will allow loading files from templates using the relative root e.g. ./template where . is the root dir,
+        // falling back to the include paths in order, e.g. for shared partials living outside the root:
+        env.set_loader(custom_loader(
+            std::iter::once(root.path.clone())
+                .chain(self.include_paths.iter().map(PathBuf::from))
+                .collect(),
+        ));
+
+        // Builtin functions, added before the context so user context takes precedence:
+        env.add_function("read_file", read_file_fn(&root.path));
+        env.add_function("glob", glob_fn(root));
+        env.add_function("hash", hash_fn);
+        env.add_function("uuid4", uuid4_fn);
+        env.add_function("uuid5", uuid5_fn);
+        env.add_function("fail", fail_fn);
+        env.add_function("warn", warn_fn);
+        // Replaces the minijinja-contrib now(), which ignores pinning. The date filters accept the RFC 3339 string just the same,
+        // the numeric unix timestamp the contrib version returned is still available with format='timestamp':
+        let render_time = self.render_time()?;
+        let now = render_time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let today = render_time.format("%Y-%m-%d").to_string();
+        env.add_function("now", now_fn(render_time));
+        env.add_function("today", move || today.clone());
+        // The env() function and the variables exposed by expose_process_env share the env global, so both can be used at once.
+        // Added before the context, which can only take the name when neither is enabled:
+        let expose_env = self.expose_process_env != ExposeProcessEnv::None;
+        if self.allow_env_in_templates || expose_env {
+            env.add_global(
+                ENV_GLOBAL_NAME,
+                minijinja::Value::from_object(EnvGlobal {
+                    callable: self.allow_env_in_templates,
+                    exposed: expose_env.then(|| exposed_env.clone()),
+                }),
+            );
+        }
 
         // Load in the context:
         for (name, value) in ctx {
-            env.add_global(name, minijinja::Value::from_serializable(value));
+            env.add_global(name, minijinja::Value::from_serialize(value));
         }
 
-        // Load in any custom extensions to the PY_USER_FUNCS global:
-        if !self.custom_extensions.is_empty() {
-            Python::with_gil(|py| {
-                // Pythonize a copy of the context and add to the global PY_CONTEXT so its usable from etch.context():
-                let mut py_ctx = PY_CONTEXT.lock();
-                *py_ctx = Some(pythonize(py, &ctx)?);
-
-                let syspath: &PyList =
-                    py.import("sys")?.getattr("path")?.downcast().map_err(|e| {
-                        err!(
-                            "Failed to get sys.path whilst importing custom extension: '{}'",
-                            e
-                        )
-                    })?;
-                for extension_path in self.custom_extensions.iter() {
-                    let result: Result<(), TracedErr> = (|| {
-                        // Get the parent dir of the file/module:
-                        let path = Path::new(extension_path);
-                        let parent = path.parent().ok_or_else(|| {
-                            err!("Failed to get parent of path '{}'", extension_path)
-                        })?;
-                        let name = path
-                            .file_stem()
-                            .ok_or_else(|| {
-                                err!("Failed to get file stem of path '{}'", extension_path)
-                            })?
-                            .to_str()
-                            .ok_or_else(|| {
-                                err!(
-                                    "Failed to convert file stem to string of path '{}'",
-                                    extension_path
-                                )
-                            })?;
-                        syspath.insert(0, parent)?;
-                        py.import(name)?;
-                        Ok(())
-                    })();
-
-                    if let Err(e) = result {
-                        return Err(e.modify_msg(|msg| {
-                            format!(
-                                "Failed to import custom extension '{}'. Error: '{}'",
-                                extension_path, msg
-                            )
-                        }));
-                    }
-                }
+        // User context keys take precedence over the builtins:
+        if self.builtin_globals && !ctx.contains_key(NOW_GLOBAL_NAME) {
+            env.add_global(NOW_GLOBAL_NAME, now);
+        }
 
-                Ok::<_, TracedErr>(())
-            })?;
+        env.add_global(
+            PROFILE_GLOBAL_NAME,
+            minijinja::Value::from_serialize(profile),
+        );
 
-            // Consume current contents of PY_USER_FUNCS and add to minijinja env:
-            let mut custom_funcs_global = PY_USER_FUNCS.lock();
+        // Load in any custom extensions to the PY_USER_FUNCS, PY_USER_FILTERS and PY_USER_TESTS globals:
+        if !self.custom_extensions.is_empty() {
+            self.import_extensions(ctx)?;
 
-            // Extract all the loaded funcs from the global mutex to be passed to individual closures:
-            let custom_funcs = std::mem::take(&mut *custom_funcs_global);
-            *custom_funcs_global = HashMap::new();
+            // Copy rather than consume the registries, extensions are only imported once per process,
+            // so a drained registry would leave later environments (e.g. in a long-lived process) without the callables:
+            let (custom_funcs, custom_filters, custom_tests) = Python::with_gil(|py| {
+                (
+                    copy_registry(py, &PY_USER_FUNCS),
+                    copy_registry(py, &PY_USER_FILTERS),
+                    copy_registry(py, &PY_USER_TESTS),
+                )
+            });
+            let context_funcs = PY_CONTEXT_FUNCS.lock().clone();
+            // Converted to a fresh python dict on every call, so mutations by one function can't leak into the render or other calls:
+            let ctx_value = minijinja::Value::from_serialize(ctx);
 
             for (name, py_fn) in custom_funcs.into_iter() {
                 // Confirm doesn't clash with config var:
                 if ctx.contains_key(&name) {
                     return Err(err!(
-                        "Failed to register custom function: '{}.{}' as it clashes with a context key.",
-                        Python::with_gil(|py| {py_fn.getattr(py, "__module__")?.extract::<String>(py)})?,
-                        name
+                        "Failed to register custom function: '{}' as it clashes with a context key.",
+                        Python::with_gil(|py| qualified_name(py_fn.as_ref(py), &name))
+                    ));
+                }
+                // Nor with the builtin env() function or the reserved env object, whichever is enabled:
+                if name == ENV_GLOBAL_NAME
+                    && (self.allow_env_in_templates
+                        || self.expose_process_env != ExposeProcessEnv::None)
+                {
+                    return Err(err!(
+                        "Failed to register custom function: '{}' as it clashes with the built in '{}'.",
+                        Python::with_gil(|py| qualified_name(py_fn.as_ref(py), &name)),
+                        ENV_GLOBAL_NAME
                     ));
                 }
 
+                let ctx_value = context_funcs.contains(&name).then(|| ctx_value.clone());
                 env.add_function(
                     name.clone(),
-                    move |
-                          values: minijinja::value::Rest<minijinja::Value>|
-                          -> Result<minijinja::Value, minijinja::Error> {
-                        // Loop over the values and extract the args and kwargs given to the func:
-                        let mut args = vec![];
-                        let mut kwargs: HashMap<String, minijinja::Value> = HashMap::new();
-                        for value in values.deref().iter() {
-                            if value.is_kwargs() {
-                                for key in value.try_iter()? {
-                                    let kwarg_val = value.get_item(&key)?;
-                                    kwargs.insert(key.into(), kwarg_val);
-                                }
-                            } else {
-                                args.push(value);
+                    move |values: minijinja::value::Rest<minijinja::Value>| {
+                        let values = match &ctx_value {
+                            Some(ctx_value) => {
+                                std::iter::once(ctx_value.clone()).chain(values.0).collect()
                             }
-                        }
+                            None => values.0,
+                        };
+                        call_py_fn("function", &name, &py_fn, values, py_result_to_value)
+                    },
+                )
+            }
 
-                        let result =
-                            Python::with_gil(|py| -> Result<serde_json::Value, TracedErr> {
-                                let py_args = PyTuple::new(
-                                    py,
-                                    args.into_iter()
-                                        .map(|v| {
-                                            let py_val = pythonize(py, v)?;
-                                            Ok(py_val)
-                                        })
-                                        .collect::<Result<Vec<_>, TracedErr>>()?,
-                                );
+            for (name, py_fn) in custom_filters.into_iter() {
+                // Silently replacing a built in filter would change the meaning of existing templates:
+                if is_builtin_filter(&env, &name) {
+                    return Err(err!(
+                        "Failed to register custom filter: '{}' as it clashes with the built in filter '{}'.",
+                        Python::with_gil(|py| qualified_name(py_fn.as_ref(py), &name)),
+                        name
+                    ));
+                }
 
-                                let py_kwargs = match kwargs.is_empty() {
-                                    true => Ok::<_, TracedErr>(None),
-                                    false => {
-                                        let dic = PyDict::new(py);
-                                        for (key, value) in kwargs {
-                                            let py_val = pythonize(py, &value)?;
-                                            dic.set_item(key, py_val)?;
-                                        }
-                                        Ok(Some(dic))
-                                    }
-                                }?;
-
-                                let py_result = py_fn
-                                    .call(py, py_args, py_kwargs)
-                                    .map_err(|e: PyErr| err!("{}", e))?;
-
-                                let rustified: serde_json::Value =
-                                    depythonize(py_result.as_ref(py)).map_err(|e| {
-                                        err!(
-                                    "Failed to convert python result to a rust-like value: '{}'",
-                                    e
-                                )
-                                    })?;
-
-                                Ok(rustified)
-                            });
-
-                        match result {
-                            Err(e) => Err(minijinja::Error::new(
-                                minijinja::ErrorKind::InvalidOperation,
-                                format!("{}", e.modify_msg(|msg| format!("Failed to call custom filter '{}'. Err: '{}'", name, msg))),
-                            )),
-                            Ok(result) => Ok(minijinja::Value::from_serializable(&result)),
-                        }
+                // The piped value is always passed as the first positional arg, matching jinja semantics:
+                env.add_filter(
+                    name.clone(),
+                    move |value: minijinja::Value,
+                          values: minijinja::value::Rest<minijinja::Value>| {
+                        call_py_fn(
+                            "filter",
+                            &name,
+                            &py_fn,
+                            std::iter::once(value).chain(values.0).collect(),
+                            py_result_to_value,
+                        )
+                    },
+                )
+            }
+
+            for (name, py_fn) in custom_tests.into_iter() {
+                if is_builtin_test(&env, &name) {
+                    return Err(err!(
+                        "Failed to register custom test: '{}' as it clashes with the built in test '{}'.",
+                        Python::with_gil(|py| qualified_name(py_fn.as_ref(py), &name)),
+                        name
+                    ));
+                }
+
+                // The tested value is passed as the first positional arg, the result is coerced with python truthiness:
+                env.add_test(
+                    name.clone(),
+                    move |value: minijinja::Value,
+                          values: minijinja::value::Rest<minijinja::Value>|
+                          -> Result<bool, minijinja::Error> {
+                        call_py_fn(
+                            "test",
+                            &name,
+                            &py_fn,
+                            std::iter::once(value).chain(values.0).collect(),
+                            |py_result| Ok(py_result.is_true()?),
+                        )
                     },
                 )
             }
@@ -270,6 +998,372 @@ impl Engine {
     }
 }
 
+impl Syntax {
+    fn validate(&self, location: &str) -> Result<(), TracedErr> {
+        let starts = [
+            ("block_start", &self.block_start),
+            ("variable_start", &self.variable_start),
+            ("comment_start", &self.comment_start),
+        ];
+        let delimiters = [
+            (starts[0], ("block_end", &self.block_end)),
+            (starts[1], ("variable_end", &self.variable_end)),
+            (starts[2], ("comment_end", &self.comment_end)),
+        ];
+
+        for (name, delimiter) in delimiters.iter().flat_map(|(start, end)| [start, end]) {
+            if delimiter.trim().is_empty() {
+                return Err(err!(
+                    "[{}.{}]: Cannot be empty or whitespace.",
+                    location,
+                    name
+                ));
+            }
+            if delimiter.contains(['\n', '\r']) {
+                return Err(err!("[{}.{}]: Cannot contain newlines.", location, name));
+            }
+        }
+
+        // Each tag type must be distinguishable from the others by its start:
+        for (index, (name, start)) in starts.iter().enumerate() {
+            for (other_name, other) in starts[index + 1..].iter() {
+                if start == other {
+                    return Err(err!(
+                        "[{}.{}] and [{}.{}] are both '{}', starts must be distinct.",
+                        location,
+                        name,
+                        location,
+                        other_name,
+                        start
+                    ));
+                }
+            }
+        }
+
+        // When one start is a prefix of another the longest match wins, so a tag of the shorter kind
+        // whose contents begin with the rest of the longer start (e.g. '-' whitespace control) is misparsed:
+        for (name, start) in starts.iter() {
+            for (other_name, other) in starts.iter() {
+                if name != other_name && other.starts_with(start.as_str()) {
+                    return Err(err!(
+                        "[{}.{}] '{}' is a prefix of [{}.{}] '{}', which makes parsing ambiguous.",
+                        location,
+                        name,
+                        start,
+                        location,
+                        other_name,
+                        other
+                    ));
+                }
+            }
+        }
+
+        for ((start_name, start), (end_name, end)) in delimiters.iter() {
+            if start == end {
+                return Err(err!(
+                    "[{}.{}] and [{}.{}] are both '{}', a start must differ from its end.",
+                    location,
+                    start_name,
+                    location,
+                    end_name,
+                    start
+                ));
+            }
+        }
+
+        let prefixes = [
+            ("line_statement_prefix", &self.line_statement_prefix),
+            ("line_comment_prefix", &self.line_comment_prefix),
+        ]
+        .into_iter()
+        .filter_map(|(name, prefix)| prefix.as_ref().map(|prefix| (name, prefix)))
+        .collect::<Vec<_>>();
+
+        for (index, (name, prefix)) in prefixes.iter().enumerate() {
+            if prefix.trim().is_empty() {
+                return Err(err!(
+                    "[{}.{}]: Cannot be empty or whitespace.",
+                    location,
+                    name
+                ));
+            }
+            if prefix.contains(['\n', '\r']) {
+                return Err(err!("[{}.{}]: Cannot contain newlines.", location, name));
+            }
+
+            // Line prefixes can't be confused with the start of a tag:
+            for (other_name, other) in starts.iter() {
+                if prefix.starts_with(other.as_str()) || other.starts_with(prefix.as_str()) {
+                    return Err(err!(
+                        "[{}.{}]: '{}' collides with [{}.{}] '{}'.",
+                        location,
+                        name,
+                        prefix,
+                        location,
+                        other_name,
+                        other
+                    ));
+                }
+            }
+
+            // Matching is longest first, so e.g. '#' and '##' together are fine, just not identical:
+            for (other_name, other) in prefixes[index + 1..].iter() {
+                if prefix == other {
+                    return Err(err!(
+                        "[{}.{}]: '{}' collides with [{}.{}] '{}'.",
+                        location,
+                        name,
+                        prefix,
+                        location,
+                        other_name,
+                        other
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Environments don't expose their filters, so probe by evaluating the filter, unknown filters fail with a dedicated error kind.
+fn is_builtin_filter(env: &minijinja::Environment, name: &str) -> bool {
+    match env.compile_expression(&format!("none | {}", name)) {
+        Ok(expr) => match expr.eval(()) {
+            Ok(_) => true,
+            Err(e) => e.kind() != minijinja::ErrorKind::UnknownFilter,
+        },
+        // Not a valid identifier, so can't be a built in either:
+        Err(_) => false,
+    }
+}
+
+/// The test equivalent of is_builtin_filter.
+fn is_builtin_test(env: &minijinja::Environment, name: &str) -> bool {
+    match env.compile_expression(&format!("none is {}", name)) {
+        Ok(expr) => match expr.eval(()) {
+            Ok(_) => true,
+            Err(e) => e.kind() != minijinja::ErrorKind::UnknownTest,
+        },
+        // Not a valid identifier, so can't be a built in either:
+        Err(_) => false,
+    }
+}
+
+/// Whether a custom extension is the dotted name of an importable module e.g. `mypkg.etch_ext`, rather than a path.
+/// Paths are absolute once validated, so can't be mistaken for one.
+pub fn is_extension_module(extension: &str) -> bool {
+    !extension.ends_with(".py")
+        && extension.split('.').all(|part| {
+            part.chars()
+                .next()
+                .is_some_and(|c| c.is_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+        })
+}
+
+/// Collect the python files making up an extension, recursing into packages but skipping bytecode caches.
+fn python_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), TracedErr> {
+    let Ok(path) = path.canonicalize() else {
+        return Ok(());
+    };
+    if path.is_dir() {
+        let mut entries = fs::read_dir(&path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() && entry.file_name().is_some_and(|name| name != "__pycache__") {
+                python_files(&entry, files)?;
+            } else if entry.extension().is_some_and(|ext| ext == "py") {
+                files.push(entry);
+            }
+        }
+    } else {
+        files.push(path);
+    }
+    Ok(())
+}
+
+/// Call a registered python callable with the values given from a template, handling the args and kwargs split and the conversions to and from python.
+fn call_py_fn<T>(
+    kind: &str,
+    name: &str,
+    py_fn: &PyObject,
+    values: Vec<minijinja::Value>,
+    convert: impl FnOnce(&PyAny) -> Result<T, TracedErr>,
+) -> Result<T, minijinja::Error> {
+    // Loop over the values and extract the args and kwargs given to the func:
+    let mut args = vec![];
+    let mut kwargs: HashMap<String, minijinja::Value> = HashMap::new();
+    for value in values.iter() {
+        if value.is_kwargs() {
+            for key in value.try_iter()? {
+                let kwarg_val = value.get_item(&key)?;
+                kwargs.insert(key.into(), kwarg_val);
+            }
+        } else {
+            args.push(value);
+        }
+    }
+
+    // Filled in when the python call raises, so the full traceback can be shown rather than just the final line:
+    let mut traceback = None;
+    let result = Python::with_gil(|py| -> Result<T, TracedErr> {
+        let py_args = PyTuple::new(
+            py,
+            args.into_iter()
+                .map(|v| {
+                    let py_val = pythonize(py, v)?;
+                    Ok(py_val)
+                })
+                .collect::<Result<Vec<_>, TracedErr>>()?,
+        );
+
+        let py_kwargs = match kwargs.is_empty() {
+            true => Ok::<_, TracedErr>(None),
+            false => {
+                let dic = PyDict::new(py);
+                for (key, value) in kwargs {
+                    let py_val = pythonize(py, &value)?;
+                    dic.set_item(key, py_val)?;
+                }
+                Ok(Some(dic))
+            }
+        }?;
+
+        let py_result = py_fn.call(py, py_args, py_kwargs).map_err(|e: PyErr| {
+            traceback = e
+                .traceback(py)
+                .and_then(|tb| tb.format().ok())
+                .map(|tb| format!("{}{}", tb, e));
+            err!("{}", e)
+        })?;
+
+        convert(py_result.as_ref(py))
+    });
+
+    match result {
+        Err(e) => {
+            let error = minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!(
+                    "{}",
+                    e.modify_msg(|msg| format!(
+                        "Failed to call custom {} '{}'. Err: '{}'",
+                        kind, name, msg
+                    ))
+                ),
+            );
+            Err(match traceback {
+                Some(traceback) => error.with_source(PyTraceback(traceback)),
+                None => error,
+            })
+        }
+        Ok(result) => Ok(result),
+    }
+}
+
+/// The formatted traceback of an exception raised by a custom extension, attached as the source of the template error.
+#[derive(Debug)]
+struct PyTraceback(String);
+
+impl std::fmt::Display for PyTraceback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PyTraceback {}
+
+/// The python traceback behind a render error, when it was caused by a custom extension raising.
+/// Includes are nested errors, so the whole source chain is searched.
+pub fn py_traceback(e: &minijinja::Error) -> Option<&str> {
+    std::iter::successors(Some(e as &dyn std::error::Error), |e| e.source())
+        .find_map(|e| e.downcast_ref::<PyTraceback>())
+        .map(|traceback| traceback.0.trim_end())
+}
+
+/// Convert a python callable's result to a template value, used for functions and filters.
+/// Converted directly rather than via json so integers keep their full 64 bit range and never turn into floats.
+fn py_result_to_value(py_result: &PyAny) -> Result<minijinja::Value, TracedErr> {
+    if py_result.is_none() {
+        return Ok(minijinja::Value::from(()));
+    }
+    // Bools are ints in python, so must be checked first:
+    if let Ok(b) = py_result.downcast::<PyBool>() {
+        return Ok(minijinja::Value::from(b.is_true()));
+    }
+    if py_result.is_instance_of::<PyLong>() {
+        if let Ok(i) = py_result.extract::<i64>() {
+            return Ok(minijinja::Value::from(i));
+        }
+        if let Ok(u) = py_result.extract::<u64>() {
+            return Ok(minijinja::Value::from(u));
+        }
+        return Err(err!(
+            "Integer '{}' is too large, only integers that fit in 64 bits are supported. Return it as a string instead.",
+            py_result
+        ));
+    }
+    if let Ok(f) = py_result.downcast::<PyFloat>() {
+        return Ok(minijinja::Value::from(f.value()));
+    }
+    if let Ok(s) = py_result.downcast::<PyString>() {
+        return Ok(minijinja::Value::from(s.to_str()?));
+    }
+    if let Ok(b) = py_result.downcast::<PyBytes>() {
+        return Ok(minijinja::Value::from_bytes(b.as_bytes().to_vec()));
+    }
+    if let Ok(dict) = py_result.downcast::<PyDict>() {
+        return dict
+            .iter()
+            .map(|(k, v)| Ok((py_result_to_value(k)?, py_result_to_value(v)?)))
+            .collect();
+    }
+    if py_result.is_instance_of::<PyList>()
+        || py_result.is_instance_of::<PyTuple>()
+        || py_result.is_instance_of::<PySet>()
+        || py_result.is_instance_of::<PyFrozenSet>()
+    {
+        return py_result.iter()?.map(|v| py_result_to_value(v?)).collect();
+    }
+
+    // No template equivalent, so stringified rather than failing, dates and times in their iso format:
+    let type_name = py_result.get_type().name()?;
+    let stringified =
+        if py_result.is_instance_of::<PyDate>() || py_result.is_instance_of::<PyTime>() {
+            Some(py_result.call_method0("isoformat")?.str()?)
+        } else if type_name == "Decimal"
+            && py_result
+                .get_type()
+                .getattr("__module__")?
+                .str()?
+                .to_str()?
+                == "decimal"
+        {
+            Some(py_result.str()?)
+        } else {
+            None
+        };
+    if let Some(stringified) = stringified {
+        warn!(
+            "Python {} '{}' has no template equivalent, it was converted to a string.",
+            type_name, stringified
+        );
+        return Ok(minijinja::Value::from(stringified.to_str()?));
+    }
+
+    // Anything else, e.g. custom mappings and sequences, falls back to pythonize's handling:
+    let rustified: serde_json::Value = depythonize(py_result).map_err(|e| {
+        err!(
+            "Failed to convert python result to a rust-like value: '{}'",
+            e
+        )
+    })?;
+    Ok(minijinja::Value::from_serialize(&rustified))
+}
+
 fn default_block_start() -> String {
     // NOTE: when changing make sure to update schema.json default for config hinting
     "{%".to_string()
@@ -306,27 +1400,535 @@ fn default_keep_trailing_newline() -> bool {
     true
 }
 
+fn default_line_statement_prefix() -> Option<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_line_comment_prefix() -> Option<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_trim_blocks() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    false
+}
+
+fn default_lstrip_blocks() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    false
+}
+
 fn default_allow_undefined() -> bool {
     // NOTE: when changing make sure to update schema.json default for config hinting
     false
 }
 
+fn default_max_undefined() -> Option<usize> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_max_fuel() -> Option<u64> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_max_include_depth() -> Option<usize> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
 fn default_custom_extensions() -> Vec<String> {
     // NOTE: when changing make sure to update schema.json default for config hinting
     vec![]
 }
 
-fn custom_loader<'x, P: AsRef<Path> + 'x>(
-    dir: P,
-) -> impl for<'a> Fn(&'a str) -> Result<Option<String>, minijinja::Error> + Send + Sync + 'static {
-    let dir = dir.as_ref().to_path_buf();
-    move |name| match fs::read_to_string(dir.join(name)) {
-        Ok(result) => Ok(Some(result)),
-        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
-        Err(err) => Err(minijinja::Error::new(
+fn default_include_paths() -> Vec<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    vec![]
+}
+
+fn default_expose_process_env() -> ExposeProcessEnv {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    ExposeProcessEnv::None
+}
+
+fn default_env_allowlist() -> Vec<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    vec![]
+}
+
+fn default_builtin_globals() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    true
+}
+
+fn default_allow_env_in_templates() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    true
+}
+
+fn default_fixed_timestamp() -> Option<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_auto_escape() -> BTreeMap<String, bool> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    BTreeMap::new()
+}
+
+fn default_line_endings() -> LineEndings {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    LineEndings::Preserve
+}
+
+fn default_overrides() -> Vec<EngineOverride> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    vec![]
+}
+
+fn default_managed_regions() -> Vec<ManagedRegion> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    vec![]
+}
+
+/// The lowercase extension of the file a template renders to, rather than of the template itself.
+/// E.g. both "index.etch.html" and "index.html.etch" are "html".
+fn output_extension(name: &str, marker: &MarkerMatcher) -> Option<String> {
+    let filename = Path::new(name).file_name()?.to_str()?;
+    let filename = marker
+        .get_match(filename)
+        .unwrap_or_else(|| filename.to_string());
+    match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => Some(ext.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn build_globset<S: AsRef<str>>(
+    patterns: &[S],
+    case_insensitive: bool,
+) -> Result<GlobSet, TracedErr> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(pattern.as_ref())
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(|e| err!("Invalid env pattern '{}': '{}'", pattern.as_ref(), e))?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+/// Read a file relative to the root verbatim, without passing it through the template engine.
+///
+/// Paths resolving outside the root (including through symlinks) are refused. Missing files error unless `missing_ok` is set, in which case they're empty.
+fn read_file_fn(
+    root: &Path,
+) -> impl Fn(String, Option<bool>, minijinja::value::Kwargs) -> Result<String, minijinja::Error>
+       + Send
+       + Sync
+       + 'static {
+    let root = root.to_path_buf();
+    move |path, missing_ok, kwargs| {
+        let missing_ok = match missing_ok {
+            Some(missing_ok) => missing_ok,
+            None => kwargs.get::<Option<bool>>("missing_ok")?.unwrap_or(false),
+        };
+        kwargs.assert_all_used()?;
+
+        let fail = |msg: String| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("read_file('{}') failed: {}", path, msg),
+            )
+        };
+
+        // Checked lexically first, so even missing paths can't be used to probe outside the root:
+        if !is_lexically_within(Path::new(&path)) {
+            return Err(fail(
+                "paths must be relative to the root and can't escape it.".to_string(),
+            ));
+        }
+
+        let full_path = root.join(&path);
+        if !full_path.exists() {
+            return if missing_ok {
+                Ok(String::new())
+            } else {
+                Err(fail(format!("'{}' does not exist.", full_path.display())))
+            };
+        }
+
+        // Symlinks could still point outside the root:
+        let canonical_root = root.canonicalize().map_err(|e| fail(e.to_string()))?;
+        let canonical = full_path.canonicalize().map_err(|e| fail(e.to_string()))?;
+        if !canonical.starts_with(&canonical_root) {
+            return Err(fail(format!(
+                "'{}' resolves outside the root.",
+                full_path.display()
+            )));
+        }
+
+        fs::read_to_string(&canonical)
+            .map_err(|e| fail(format!("failed to read '{}': {}", full_path.display(), e)))
+    }
+}
+
+/// Whether a relative path stays within its base without touching the filesystem, i.e. is relative and never climbs above its start with '..'.
+fn is_lexically_within(path: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(new_depth) => depth = new_depth,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Hash the stringified value, fnv1a is the same hash the lockfile uses.
+fn hash_fn(
+    value: minijinja::Value,
+    algorithm: Option<String>,
+    kwargs: minijinja::value::Kwargs,
+) -> Result<String, minijinja::Error> {
+    let algorithm = match algorithm {
+        Some(algorithm) => algorithm,
+        None => kwargs
+            .get::<Option<String>>("algorithm")?
+            .unwrap_or_else(|| "fnv1a".to_string()),
+    };
+    kwargs.assert_all_used()?;
+
+    let contents = value.to_string();
+    match algorithm.as_str() {
+        "fnv1a" => Ok(bitbazaar::hash::fnv1a(contents.as_bytes()).to_string()),
+        "sha256" => Ok(format!("{:x}", Sha256::digest(contents.as_bytes()))),
+        _ => Err(minijinja::Error::new(
             minijinja::ErrorKind::InvalidOperation,
-            "could not read template",
+            format!(
+                "hash() failed: unknown algorithm '{}', expected 'fnv1a' or 'sha256'.",
+                algorithm
+            ),
+        )),
+    }
+}
+
+/// The render time, an RFC 3339 string by default or the unix timestamp in seconds with format='timestamp'.
+fn now_fn(
+    render_time: chrono::DateTime<chrono::Utc>,
+) -> impl Fn(Option<String>, minijinja::value::Kwargs) -> Result<minijinja::Value, minijinja::Error>
+       + Send
+       + Sync
+       + 'static {
+    move |format, kwargs| {
+        let format = match format {
+            Some(format) => format,
+            None => kwargs
+                .get::<Option<String>>("format")?
+                .unwrap_or_else(|| "rfc3339".to_string()),
+        };
+        kwargs.assert_all_used()?;
+
+        match format.as_str() {
+            "rfc3339" => Ok(render_time
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                .into()),
+            "timestamp" => Ok((render_time.timestamp() as f64
+                + f64::from(render_time.timestamp_subsec_nanos()) / 1e9)
+                .into()),
+            _ => Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!(
+                    "now() failed: unknown format '{}', expected 'rfc3339' or 'timestamp'.",
+                    format
+                ),
+            )),
+        }
+    }
+}
+
+/// A random uuid, different on every render so the output will never be identical in the lockfile.
+fn uuid4_fn() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// A deterministic uuid derived from the name, the namespace is a uuid or one of the standard dns, url, oid or x500 namespaces.
+fn uuid5_fn(namespace: String, name: String) -> Result<String, minijinja::Error> {
+    let namespace_uuid = match namespace.as_str() {
+        "dns" => uuid::Uuid::NAMESPACE_DNS,
+        "url" => uuid::Uuid::NAMESPACE_URL,
+        "oid" => uuid::Uuid::NAMESPACE_OID,
+        "x500" => uuid::Uuid::NAMESPACE_X500,
+        _ => uuid::Uuid::parse_str(&namespace).map_err(|e| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!(
+                    "uuid5() failed: namespace '{}' isn't a uuid or one of 'dns', 'url', 'oid' or 'x500': {}",
+                    namespace, e
+                ),
+            )
+        })?,
+    };
+    Ok(uuid::Uuid::new_v5(&namespace_uuid, name.as_bytes()).to_string())
+}
+
+/// Abort the whole render with a message, e.g. on an unsupported combination of context values.
+fn fail_fn(
+    msg: String,
+    args: minijinja::value::Rest<minijinja::Value>,
+) -> Result<String, minijinja::Error> {
+    Err(minijinja::Error::new(
+        minijinja::ErrorKind::InvalidOperation,
+        format!("fail(): {}", interpolate("fail", &msg, &args)?),
+    ))
+}
+
+/// Log a warning without affecting the output, which is always empty.
+fn warn_fn(
+    msg: String,
+    args: minijinja::value::Rest<minijinja::Value>,
+) -> Result<String, minijinja::Error> {
+    let msg = interpolate("warn", &msg, &args)?;
+    match current_template() {
+        Some(rel_path) => warn!("[{}]: {}", rel_path, msg),
+        None => warn!("{}", msg),
+    }
+    Ok(String::new())
+}
+
+/// Replace each '{}' placeholder in the message with the next arg, in order.
+fn interpolate(
+    fn_name: &str,
+    msg: &str,
+    args: &[minijinja::Value],
+) -> Result<String, minijinja::Error> {
+    let placeholders = msg.matches("{}").count();
+    if placeholders != args.len() {
+        return Err(minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!(
+                "{}() failed: the message has {} '{{}}' placeholder{} but {} arg{} given.",
+                fn_name,
+                placeholders,
+                if placeholders == 1 { "" } else { "s" },
+                args.len(),
+                if args.len() == 1 { " was" } else { "s were" },
+            ),
+        ));
+    }
+
+    let mut parts = msg.split("{}");
+    let mut result = parts.next().unwrap_or_default().to_string();
+    for (part, arg) in parts.zip(args) {
+        result.push_str(&arg.to_string());
+        result.push_str(part);
+    }
+    Ok(result)
+}
+
+/// The env global, calling it reads any process environment variable with env(), its attributes are the exposed variables.
+///
+/// Missing attributes behave like any other undefined.
+#[derive(Debug)]
+struct EnvGlobal {
+    callable: bool,
+    exposed: Option<BTreeMap<String, String>>,
+}
+
+impl minijinja::value::Object for EnvGlobal {
+    fn get_value(self: &Arc<Self>, key: &minijinja::Value) -> Option<minijinja::Value> {
+        self.exposed
+            .as_ref()?
+            .get(key.as_str()?)
+            .map(|value| minijinja::Value::from(value.as_str()))
+    }
+
+    fn enumerate(self: &Arc<Self>) -> minijinja::value::Enumerator {
+        minijinja::value::Enumerator::Values(
+            self.exposed
+                .iter()
+                .flat_map(|exposed| exposed.keys())
+                .map(|name| minijinja::Value::from(name.as_str()))
+                .collect(),
         )
-        .with_source(err)),
+    }
+
+    fn call(
+        self: &Arc<Self>,
+        _state: &minijinja::State<'_, '_>,
+        args: &[minijinja::Value],
+    ) -> Result<minijinja::Value, minijinja::Error> {
+        if !self.callable {
+            return Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                "env() failed: the function is disabled by [engine.allow_env_in_templates].",
+            ));
+        }
+        let (name, default) = minijinja::value::from_args(args)?;
+        env_fn(name, default).map(minijinja::Value::from)
+    }
+}
+
+/// Read a process environment variable, falling back to the default when given.
+fn env_fn(name: String, default: Option<String>) -> Result<String, minijinja::Error> {
+    match (std::env::var(&name), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => Ok(default),
+        (Err(_), None) => Err(minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!(
+                "env() failed: environment variable '{}' is not set and no default was given.",
+                name
+            ),
+        )),
+    }
+}
+
+/// Match files relative to the root against a glob pattern, returning sorted forward-slash paths.
+///
+/// Patterns can't be absolute or contain `..`, so matching is always bound to the root.
+/// Only the pattern's literal directory prefix is walked, with the same excludes and ignore files as the template search.
+/// The files under each walked directory are cached for the lifetime of the environment, so repeated calls don't walk again.
+fn glob_fn(
+    root: &WalkedRoot,
+) -> impl Fn(String) -> Result<Vec<String>, minijinja::Error> + Send + Sync + 'static {
+    let root = root.clone();
+    let cache: Mutex<HashMap<PathBuf, Vec<String>>> = Mutex::default();
+    move |pattern| {
+        let make_err = |msg: String| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("glob('{}') failed: {}", pattern, msg),
+            )
+        };
+
+        if Path::new(&pattern).is_absolute() || pattern.split(['/', '\\']).any(|part| part == "..")
+        {
+            return Err(make_err(
+                "patterns must be relative to the root and can't contain '..'.".to_string(),
+            ));
+        }
+
+        let trimmed = pattern.trim_start_matches("./");
+        let matcher = GlobBuilder::new(trimmed)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| make_err(e.to_string()))?
+            .compile_matcher();
+
+        let start = root.path.join(glob_literal_prefix(trimmed));
+        let mut cache = cache.lock();
+        let files = cache
+            .entry(start)
+            .or_insert_with_key(|start| glob_files(&root, start));
+        Ok(files
+            .iter()
+            .filter(|rel_path| matcher.is_match(rel_path.as_str()))
+            .cloned()
+            .collect())
+    }
+}
+
+/// The leading directories of a glob pattern without any glob syntax, nothing outside them can match.
+fn glob_literal_prefix(pattern: &str) -> PathBuf {
+    let mut parts = pattern.split('/').collect::<Vec<_>>();
+    // The last part is matched against the filenames:
+    parts.pop();
+    parts
+        .into_iter()
+        .take_while(|part| !part.contains(['*', '?', '[', ']', '{', '}', '\\']))
+        .collect()
+}
+
+/// The sorted forward-slash paths relative to the root of the files under the start directory.
+///
+/// Above the start only the directories leading to it are entered. Entries that can't be read are skipped rather than failing the render.
+fn glob_files(root: &WalkedRoot, start: &Path) -> Vec<String> {
+    let mut walker = root.walker.clone();
+    let start_dir = start.to_path_buf();
+    walker.filter_entry(move |entry| {
+        start_dir.starts_with(entry.path()) || entry.path().starts_with(&start_dir)
+    });
+
+    let mut files = vec![];
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("glob() skipping an entry it couldn't read: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+        if let Ok(rel_path) = entry.path().strip_prefix(&root.path) {
+            files.push(
+                rel_path
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            );
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Load templates from the first dir containing the name, dirs are tried in order.
+///
+/// Contents are cached by path for the lifetime of the environment, so a partial included under different names
+/// (e.g. "./partial.txt" and "partial.txt") is only read once. Nothing is invalidated, environments are short-lived per render.
+fn custom_loader(
+    dirs: Vec<PathBuf>,
+) -> impl for<'a> Fn(&'a str) -> Result<Option<String>, minijinja::Error> + Send + Sync + 'static {
+    let cache: Mutex<HashMap<PathBuf, String>> = Mutex::default();
+    move |name| {
+        for (index, dir) in dirs.iter().enumerate() {
+            let path = dir.join(name);
+            if let Some(cached) = cache.lock().get(&path) {
+                return Ok(Some(cached.clone()));
+            }
+            match fs::read_to_string(&path) {
+                Ok(result) => {
+                    // Same named files in later dirs are shadowed, which might not be intended:
+                    if log::log_enabled!(log::Level::Debug) {
+                        for shadowed in dirs[index + 1..].iter().map(|dir| dir.join(name)) {
+                            if shadowed.exists() {
+                                debug!(
+                                    "Template '{}' resolved to '{}', shadowing '{}'.",
+                                    name,
+                                    path.display(),
+                                    shadowed.display()
+                                );
+                            }
+                        }
+                    }
+                    cache.lock().insert(path, result.clone());
+                    return Ok(Some(result));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(minijinja::Error::new(
+                        minijinja::ErrorKind::InvalidOperation,
+                        "could not read template",
+                    )
+                    .with_source(err))
+                }
+            }
+        }
+        Ok(None)
     }
 }
@@ -0,0 +1,240 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bitbazaar::{err, errors::TracedErr};
+use serde_json::{Map, Value};
+
+use super::engine::is_extension_module;
+
+static EXTENDS_KEY: &str = "extends";
+static PYPROJECT_FILENAME: &str = "pyproject.toml";
+
+/// pyproject.toml files hold the config in their [tool.etch] table rather than at the top level.
+pub fn is_pyproject(config_path: &Path) -> bool {
+    config_path
+        .file_name()
+        .map(|name| name == PYPROJECT_FILENAME)
+        == Some(true)
+}
+
+/// Load the config at the path, recursively loading any configs it extends first and deep merging on top.
+/// Returns the merged json, plus the resolution chain of config files in the order they were merged.
+pub fn load_with_extends(config_path: &Path) -> Result<(Value, Vec<PathBuf>), TracedErr> {
+    let mut chain = vec![];
+    let json = load_recursive(config_path, &mut vec![], &mut chain)?;
+    Ok((json, chain))
+}
+
+fn load_recursive(
+    config_path: &Path,
+    stack: &mut Vec<PathBuf>,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Value, TracedErr> {
+    let is_parent = !stack.is_empty();
+    let mut json = read_toml_as_json(config_path).map_err(|e| {
+        if is_parent {
+            e.modify_msg(|msg| {
+                format!(
+                    "Error reading extended config from '{}'.\n{}",
+                    config_path.display(),
+                    msg
+                )
+            })
+        } else {
+            e
+        }
+    })?;
+
+    // Env vars are substituted per file before anything else, so interpolated paths are resolved relative to their own config:
+    super::interpolate::interpolate_env(&mut json)?;
+
+    let canonical = config_path.canonicalize()?;
+    if let Some(index) = stack.iter().position(|p| p == &canonical) {
+        return Err(err!(
+            "Cycle detected in config extends: {}",
+            stack[index..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| format!("'{}'", p.display()))
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        ));
+    }
+
+    // Already merged through another branch (e.g. two parents sharing a base), don't apply twice:
+    if chain.contains(&canonical) {
+        return Ok(Value::Object(Map::new()));
+    }
+
+    let config_dir = canonical
+        .parent()
+        .ok_or_else(|| err!("Config path has no parent: '{}'.", canonical.display()))?
+        .to_path_buf();
+
+    let extends = take_extends(&mut json)?;
+
+    // Paths are resolved relative to the config file declaring them, so absolutize before merging loses that information:
+    absolutize_paths(&mut json, &config_dir);
+
+    stack.push(canonical.clone());
+    let mut merged = Value::Object(Map::new());
+    for parent in extends {
+        let parent_path = if Path::new(&parent).is_absolute() {
+            PathBuf::from(&parent)
+        } else {
+            config_dir.join(&parent)
+        };
+        let parent_json = load_recursive(&parent_path, stack, chain)?;
+        merge_configs(&mut merged, parent_json);
+    }
+    merge_configs(&mut merged, json);
+    stack.pop();
+
+    chain.push(canonical);
+
+    Ok(merged)
+}
+
+fn read_toml_as_json(config_path: &Path) -> Result<Value, TracedErr> {
+    let contents = match fs::read_to_string(config_path) {
+        Ok(c) => c,
+        Err(e) => return Err(err!("Failed file read: '{}'.", e)),
+    };
+
+    // Decode directly the toml directly into serde/json, using that internally:
+    let json: Value = match toml::from_str(&contents) {
+        Ok(toml) => toml,
+        Err(e) => return Err(err!("Invalid toml formatting: '{}'.", e)),
+    };
+
+    if is_pyproject(config_path) {
+        match json.get("tool").and_then(|tool| tool.get("etch")) {
+            Some(table) => Ok(table.clone()),
+            None => Err(err!("No [tool.etch] table found.")),
+        }
+    } else {
+        Ok(json)
+    }
+}
+
+/// Remove the extends key, returning the parent configs in the order they should be merged.
+fn take_extends(json: &mut Value) -> Result<Vec<String>, TracedErr> {
+    let extends = match json.as_object_mut() {
+        Some(obj) => obj.remove(EXTENDS_KEY),
+        None => None,
+    };
+
+    match extends {
+        None => Ok(vec![]),
+        Some(Value::String(parent)) => Ok(vec![parent]),
+        Some(Value::Array(parents)) => parents
+            .into_iter()
+            .map(|parent| match parent {
+                Value::String(parent) => Ok(parent),
+                _ => Err(err!("[extends]: Expected a string or an array of strings.")),
+            })
+            .collect(),
+        Some(_) => Err(err!("[extends]: Expected a string or an array of strings.")),
+    }
+}
+
+fn absolutize_paths(json: &mut Value, config_dir: &Path) {
+    let absolutize = |value: &mut Value| {
+        if let Value::String(path) = value {
+            if !Path::new(path).is_absolute() {
+                *path = config_dir.join(&path).to_string_lossy().to_string();
+            }
+        }
+    };
+
+    if let Some(ignore_files) = json.get_mut("ignore_files").and_then(Value::as_array_mut) {
+        ignore_files.iter_mut().for_each(absolutize);
+    }
+
+    if let Some(env_files) = json.get_mut("env_files").and_then(Value::as_array_mut) {
+        env_files.iter_mut().for_each(absolutize);
+    }
+
+    if let Some(lockfile) = json.get_mut("lockfile") {
+        absolutize(lockfile);
+    }
+
+    if let Some(out_dir) = json.get_mut("out_dir") {
+        absolutize(out_dir);
+    }
+
+    if let Some(extensions) = json
+        .get_mut("engine")
+        .and_then(|engine| engine.get_mut("custom_extensions"))
+        .and_then(Value::as_array_mut)
+    {
+        // Module names are imported from the environment, so only left alone when there's no path of the same name:
+        extensions
+            .iter_mut()
+            .filter(|extension| {
+                extension.as_str().is_none_or(|extension| {
+                    !is_extension_module(extension) || config_dir.join(extension).exists()
+                })
+            })
+            .for_each(absolutize);
+    }
+
+    // include_dirs is an alias of include_paths:
+    for include_key in ["include_paths", "include_dirs"] {
+        if let Some(include_paths) = json
+            .get_mut("engine")
+            .and_then(|engine| engine.get_mut(include_key))
+            .and_then(Value::as_array_mut)
+        {
+            include_paths.iter_mut().for_each(absolutize);
+        }
+    }
+
+    for commands_key in ["setup_commands", "finish_commands"] {
+        if let Some(commands) = json.get_mut(commands_key).and_then(Value::as_array_mut) {
+            for command in commands.iter_mut() {
+                if let Some(cwd) = command.get_mut("cwd") {
+                    absolutize(cwd);
+                }
+            }
+        }
+    }
+}
+
+/// Deep merge the child config on top of the base.
+/// Context vars are replaced wholesale (across all context types) rather than merged, lists concatenate.
+pub fn merge_configs(base: &mut Value, child: Value) {
+    if let (Some(base_ctx), Some(child_ctx)) = (
+        base.get_mut("context").and_then(Value::as_object_mut),
+        child.get("context").and_then(Value::as_object),
+    ) {
+        for child_vars in child_ctx.values().filter_map(Value::as_object) {
+            for key in child_vars.keys() {
+                for base_vars in base_ctx.values_mut().filter_map(Value::as_object_mut) {
+                    base_vars.remove(key);
+                }
+            }
+        }
+    }
+
+    deep_merge(base, child);
+}
+
+fn deep_merge(base: &mut Value, child: Value) {
+    match (base, child) {
+        (Value::Object(base), Value::Object(child)) => {
+            for (key, value) in child {
+                match base.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(child)) => base.extend(child),
+        (base, child) => *base = child,
+    }
+}
@@ -0,0 +1,88 @@
+use bitbazaar::{err, errors::TracedErr};
+use serde_json::Value;
+
+/// Substitute `${VAR}` and `${VAR:-default}` in all string values of the config with environment variables.
+///
+/// `$${` escapes to a literal `${`. Only string leaves are touched, never keys.
+pub fn interpolate_env(json: &mut Value) -> Result<(), TracedErr> {
+    interpolate_recursive(json, &mut vec![])
+}
+
+fn interpolate_recursive(value: &mut Value, path: &mut Vec<String>) -> Result<(), TracedErr> {
+    match value {
+        Value::String(s) if s.contains('$') => {
+            *s = interpolate_str(s).map_err(|e| {
+                e.modify_msg(|msg| {
+                    format!(
+                        "[{}]: {}",
+                        if path.is_empty() {
+                            "root".to_string()
+                        } else {
+                            path.join(".")
+                        },
+                        msg
+                    )
+                })
+            })?;
+        }
+        Value::Array(arr) => {
+            for (index, item) in arr.iter_mut().enumerate() {
+                path.push(index.to_string());
+                interpolate_recursive(item, path)?;
+                path.pop();
+            }
+        }
+        Value::Object(obj) => {
+            for (key, item) in obj.iter_mut() {
+                path.push(key.clone());
+                interpolate_recursive(item, path)?;
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(input: &str) -> Result<String, TracedErr> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(index) = rest.find('$') {
+        output.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            output.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| err!("Unterminated '${{' in '{}'.", input))?;
+            let inner = &after[..end];
+            let (name, default) = match inner.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (inner, None),
+            };
+            if name.is_empty() {
+                return Err(err!("Empty environment variable name in '{}'.", input));
+            }
+            match (std::env::var(name), default) {
+                (Ok(val), _) => output.push_str(&val),
+                (Err(_), Some(default)) => output.push_str(default),
+                (Err(_), None) => {
+                    return Err(err!(
+                        "Environment variable '{}' is not set and has no default. Use '${{{}:-default}}' to provide one, or '$${{' for a literal '${{'.",
+                        name,
+                        name
+                    ))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
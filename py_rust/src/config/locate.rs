@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use toml_edit::ImDocument;
+
+use super::extends::is_pyproject;
+
+/// Find where a config location (e.g. ["engine", "keep_trailing_newline"]) is defined in the original toml source,
+/// returning the file, line:column and a caret annotated snippet of the offending line.
+///
+/// Configs are checked in reverse merge order, so the config that took precedence is reported first.
+/// When the exact location doesn't exist (e.g. a missing required property) the closest defined parent is used.
+pub fn locate(config_files: &[PathBuf], loc_parts: &[String]) -> Option<String> {
+    config_files
+        .iter()
+        .rev()
+        .find_map(|config_file| locate_in_file(config_file, loc_parts))
+}
+
+fn locate_in_file(config_file: &Path, loc_parts: &[String]) -> Option<String> {
+    let contents = fs::read_to_string(config_file).ok()?;
+    let doc = ImDocument::parse(contents.as_str()).ok()?;
+
+    // pyproject.toml configs are nested under [tool.etch]:
+    let prefix = if is_pyproject(config_file) {
+        vec!["tool".to_string(), "etch".to_string()]
+    } else {
+        vec![]
+    };
+
+    let mut item = doc.as_item();
+    let mut span = None;
+    for part in prefix.iter().chain(loc_parts.iter()) {
+        let next = match part.parse::<usize>() {
+            Ok(index) if item.is_array() || item.is_array_of_tables() => item.get(index),
+            _ => item.get(part),
+        };
+        match next {
+            Some(next) => {
+                item = next;
+                // Implicit tables (e.g. context in [context.static]) have no span of their own:
+                if let Some(next_span) = next.span() {
+                    span = Some(next_span);
+                }
+            }
+            None => break,
+        }
+    }
+
+    span.map(|span| format_snippet(config_file, &contents, span))
+}
+
+fn format_snippet(config_file: &Path, contents: &str, span: Range<usize>) -> String {
+    let line_start = contents[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = contents[span.start..]
+        .find('\n')
+        .map_or(contents.len(), |i| span.start + i);
+    let line = &contents[line_start..line_end];
+    let line_num = contents[..span.start].matches('\n').count() + 1;
+    let col = contents[line_start..span.start].chars().count() + 1;
+
+    // Only underline the part of the span on the first line:
+    let caret_len = contents[span.start..span.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    let gutter = " ".repeat(line_num.to_string().len());
+    format!(
+        "{gutter}--> {}:{}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+        config_file.display(),
+        line_num,
+        col,
+        line_num,
+        line.trim_end_matches('\r'),
+        " ".repeat(col - 1),
+        "^".repeat(caret_len),
+    )
+}
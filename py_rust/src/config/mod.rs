@@ -1,10 +1,23 @@
 mod coerce;
+mod discover;
 mod engine;
+mod extends;
+mod interpolate;
+mod locate;
 mod process;
+mod profiles;
 mod raw_conf;
 mod validate;
 
 pub use coerce::coerce;
-pub use engine::{register_py_func, PY_CONTEXT};
+pub use discover::{apply_config_env, discover_config};
+pub use engine::{
+    clear_py_funcs, current_template, py_traceback, register_py_context_processor,
+    register_py_filter, register_py_func, register_py_test, set_current_template, Engine,
+    ManagedRegion, PY_CONTEXT,
+};
 pub use process::{process, Config};
-pub use raw_conf::RawConfig;
+pub use raw_conf::{
+    FinishCommand, HashAlgo, InPlace, OnBinary, PostWriteHook, RawConfig, TemplateMatcher,
+};
+pub use validate::{validate_lockfile_path, validate_out_dir};
@@ -1,39 +1,107 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use bitbazaar::{cli::run_cmd, err, errors::TracedErr, timeit};
-use log::{debug, info};
+use bitbazaar::{err, errors::TracedErr, timeit};
+use log::{debug, info, warn};
 use serde::Serialize;
 
-use super::{engine::Engine, raw_conf::RawConfig};
+use super::{
+    engine::Engine,
+    raw_conf::{
+        FinishCommand, HashAlgo, InPlace, OnBinary, PostWriteHook, RawConfig, SetupCommand,
+        TemplateMatcher,
+    },
+};
+use crate::utils::cmd::run_cmd_in;
 
 #[derive(Debug, Serialize)]
 pub struct Config {
     pub context: HashMap<String, serde_json::Value>,
     pub exclude: Vec<String>,
     pub engine: Engine,
+    pub profile: Option<String>,
     pub ignore_files: Vec<String>,
-    pub setup_commands: Vec<String>,
+    pub use_gitignore: bool,
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    pub ignore_junk: bool,
+    pub junk_patterns: Vec<String>,
+    pub template_markers: Vec<String>,
+    // Left out when unused, so configs from before it existed keep the same hash:
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub template_matchers: Vec<TemplateMatcher>,
+    pub hash_algo: HashAlgo,
+    // Only decide which templates are rendered at all, which the lockfile already tracks:
+    #[serde(skip)]
+    pub partial_prefix: String,
+    #[serde(skip)]
+    pub max_template_size: u64,
+    #[serde(skip)]
+    pub on_binary: OnBinary,
+    // Where the lockfile lives doesn't change what's rendered:
+    #[serde(skip)]
+    pub lockfile: Option<PathBuf>,
+    // Each output's path is tracked in the lockfile instead, so moving them is noticed whether from the config or the cli:
+    #[serde(skip)]
+    pub out_dir: Option<PathBuf>,
+    pub setup_commands: Vec<SetupCommand>,
+    pub finish_commands: Vec<FinishCommand>,
+    pub post_write: Vec<PostWriteHook>,
+    pub in_place: Vec<InPlace>,
+    // Values not included in serialization, the exposed names are reported separately by the debug output:
+    #[serde(skip)]
+    pub exposed_env: BTreeMap<String, String>,
+    // Reported separately by the debug output:
+    #[serde(skip)]
+    pub extends_chain: Vec<PathBuf>,
+}
+
+impl Config {
+    /// A stable hash of the resolved config, going through a json value sorts the context keys.
+    pub fn hash(&self) -> Result<String, TracedErr> {
+        Ok(self
+            .hash_algo
+            .hash(serde_json::to_value(self)?.to_string().as_bytes()))
+    }
 }
 
 pub fn process(raw: RawConfig) -> Result<Config, TracedErr> {
     let mut context: HashMap<String, serde_json::Value> = HashMap::new();
 
-    // Before anything else, run the setup commands:
-    for command in raw.setup_commands.iter() {
-        info!("Running command: {}", command);
-        let cmd_out = timeit!(format!("Setup cmd: {}", command).as_str(), {
-            run_cmd(command)
-        })?;
-
-        info!("{}", cmd_out.stdout);
+    // Before anything else, run the setup commands.
+    // Consecutive commands sharing a parallel_group run concurrently, validation ensures groups aren't split by other commands:
+    for batch in batch_setup_commands(&raw.setup_commands) {
+        if batch.len() == 1 {
+            run_setup_command(&batch[0])?;
+        } else {
+            let handles = batch
+                .into_iter()
+                .map(|command| std::thread::spawn(move || run_setup_command(&command)))
+                .collect::<Vec<_>>();
+            for handle in handles {
+                handle.join().unwrap()?;
+            }
+        }
+    }
 
-        if cmd_out.code != 0 {
-            return Err(err!(
-                "Setup command '{}' returned non zero exit code: {}",
-                command,
-                cmd_out.code
-            ));
+    // Env files only fill in what the environment doesn't already have, so the real environment always wins:
+    for env_file in raw.env_files.iter() {
+        let vars = crate::utils::dotenv::parse(Path::new(env_file))?;
+        let mut loaded = 0;
+        for (key, value) in vars.iter() {
+            if std::env::var_os(key).is_none() {
+                std::env::set_var(key, value);
+                loaded += 1;
+            }
         }
+        debug!(
+            "Loaded {} of {} variables from env file '{}', the rest were already set.",
+            loaded,
+            vars.len(),
+            env_file
+        );
     }
 
     for (key, value) in raw.context.stat {
@@ -49,27 +117,109 @@ pub fn process(raw: RawConfig) -> Result<Config, TracedErr> {
     let mut handles = vec![];
     for (key, value) in raw.context.cli {
         handles.push(std::thread::spawn(
-            move || -> Result<(String, serde_json::Value), TracedErr> {
+            move || -> Result<Vec<(String, serde_json::Value)>, TracedErr> {
                 let value = value.consume()?;
-                Ok((key, value))
+                Ok(vec![(key, value)])
             },
         ));
     }
 
+    // Multi vars run their commands once, producing multiple context vars:
+    for (name, value) in raw.context.cli_multi {
+        handles.push(std::thread::spawn(
+            move || -> Result<Vec<(String, serde_json::Value)>, TracedErr> { value.consume(&name) },
+        ));
+    }
+
     for handle in handles {
-        let (key, value) = handle.join().unwrap()?;
-        context.insert(key, value);
+        for (key, value) in handle.join().unwrap()? {
+            context.insert(key, value);
+        }
     }
 
+    // Last, so processors see the fully resolved context:
+    let context = raw.engine.process_context(context)?;
+
+    let exposed_env = raw.engine.exposed_env()?;
+
     let config = Config {
         context,
         exclude: raw.exclude,
         engine: raw.engine,
+        profile: raw.profile,
         ignore_files: raw.ignore_files,
+        use_gitignore: raw.use_gitignore,
+        follow_symlinks: raw.follow_symlinks,
+        max_depth: raw.max_depth,
+        ignore_junk: raw.ignore_junk,
+        junk_patterns: raw.junk_patterns,
+        template_markers: raw.template_marker.markers(),
+        template_matchers: raw.template_matchers,
+        hash_algo: raw.hash_algo,
+        partial_prefix: raw.partial_prefix,
+        max_template_size: raw.max_template_size,
+        on_binary: raw.on_binary,
+        lockfile: raw.lockfile.map(PathBuf::from),
+        out_dir: raw.out_dir.map(PathBuf::from),
         setup_commands: raw.setup_commands,
+        finish_commands: raw.finish_commands,
+        post_write: raw.post_write,
+        in_place: raw.in_place,
+        exposed_env,
+        extends_chain: raw.extends_chain,
     };
 
     debug!("Processed config: \n{:#?}", config);
 
     Ok(config)
 }
+
+/// Group the setup commands into ordered batches, each batch can be run in parallel.
+///
+/// Only consecutive commands of a group are batched together, so nothing ever runs before a command listed ahead of it.
+fn batch_setup_commands(commands: &[SetupCommand]) -> Vec<Vec<SetupCommand>> {
+    let mut batches: Vec<Vec<SetupCommand>> = vec![];
+
+    for command in commands {
+        match (command.parallel_group(), batches.last_mut()) {
+            (Some(group), Some(batch)) if batch[0].parallel_group() == Some(group) => {
+                batch.push(command.clone())
+            }
+            _ => batches.push(vec![command.clone()]),
+        }
+    }
+
+    batches
+}
+
+fn run_setup_command(command: &SetupCommand) -> Result<(), TracedErr> {
+    info!("Running command: {}", command.command());
+    let result = timeit!(format!("Setup cmd: {}", command.command()).as_str(), {
+        run_cmd_in(command.command(), command.cwd().map(Path::new), &[])
+    });
+
+    let result = result.and_then(|cmd_out| {
+        info!("{}", cmd_out.stdout);
+        if cmd_out.code != 0 {
+            return Err(err!(
+                "Setup command '{}' returned non zero exit code: {}\n{}",
+                command.command(),
+                cmd_out.code,
+                cmd_out.stderr
+            ));
+        }
+        Ok(())
+    });
+
+    match result {
+        Err(e) if command.allow_error() => {
+            warn!(
+                "Setup command '{}' failed but allow_error is set, continuing: {}",
+                command.command(),
+                e
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,52 @@
+use bitbazaar::{err, errors::TracedErr};
+use serde_json::Value;
+
+use super::extends::merge_configs;
+
+static PROFILES_KEY: &str = "profiles";
+pub static PROFILE_ENV_VAR: &str = "ETCH_PROFILE";
+
+/// Remove the profiles table from the config, merging the selected profile (if any) over the base.
+/// The cli option takes precedence over the ETCH_PROFILE env var.
+///
+/// Returns the name of the applied profile.
+pub fn apply_profile(
+    json: &mut Value,
+    cli_profile: Option<&str>,
+) -> Result<Option<String>, TracedErr> {
+    let mut profiles = match json
+        .as_object_mut()
+        .and_then(|obj| obj.remove(PROFILES_KEY))
+    {
+        Some(Value::Object(profiles)) => profiles,
+        _ => serde_json::Map::new(),
+    };
+
+    let selected = match cli_profile {
+        Some(profile) => Some(profile.to_string()),
+        None => std::env::var(PROFILE_ENV_VAR)
+            .ok()
+            .filter(|profile| !profile.trim().is_empty()),
+    };
+
+    if let Some(selected) = &selected {
+        let profile = profiles.remove(selected).ok_or_else(|| {
+            err!(
+                "Profile '{}' not found in [profiles]. Available profiles: {}",
+                selected,
+                if profiles.is_empty() {
+                    "none".to_string()
+                } else {
+                    profiles
+                        .keys()
+                        .map(|name| format!("'{}'", name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            )
+        })?;
+        merge_configs(json, profile);
+    }
+
+    Ok(selected)
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use bitbazaar::{
     cli::{run_cmd, CmdOut},
@@ -6,11 +9,17 @@ use bitbazaar::{
     errors::TracedErr,
     timeit,
 };
-use log::info;
+use globset::{Glob, GlobMatcher};
+use log::{debug, info};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use super::{coerce, engine::Engine};
-use crate::args::RenderCommand;
+use crate::{
+    args::{RenderCommand, DEFAULT_CONFIG_PATH, PYPROJECT_CONFIG_PATH},
+    render::walker::DEFAULT_TEMPLATE_MARKER,
+};
 
 // String literal of json, str, int, float, bool:
 #[derive(Debug, Deserialize, Serialize)]
@@ -24,6 +33,7 @@ pub enum Coerce {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CtxStaticVar {
     pub value: serde_json::Value,
     pub coerce: Option<Coerce>,
@@ -36,6 +46,7 @@ impl CtxStaticVar {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CtxEnvVar {
     pub env_name: Option<String>,
     pub default: Option<serde_json::Value>,
@@ -69,6 +80,7 @@ impl CtxEnvVar {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CtxCliVar {
     pub commands: Vec<String>,
     pub coerce: Option<Coerce>,
@@ -76,43 +88,133 @@ pub struct CtxCliVar {
 
 impl CtxCliVar {
     pub fn consume(self) -> Result<serde_json::Value, TracedErr> {
-        let commands = self.commands;
-
-        let runner = |command: &str| -> Result<CmdOut, TracedErr> {
-            info!("Running command: {}", command);
-            let cmd_out = timeit!(format!("Cmd: {}", command).as_str(), { run_cmd(command) })?;
-
-            if cmd_out.code != 0 {
-                return Err(err!(
-                    "Command '{}' returned non zero exit code: {}",
-                    command,
-                    cmd_out.code
-                ));
-            }
+        let value = serde_json::Value::String(run_ctx_commands(&self.commands)?);
 
-            Ok(cmd_out)
-        };
+        coerce(value, self.coerce)
+    }
+}
 
-        // Run each command before the last:
-        for command in commands[..commands.len() - 1].iter() {
-            runner(command)?;
-        }
+/// Run each command in order, returning the stdout of the last.
+fn run_ctx_commands(commands: &[String]) -> Result<String, TracedErr> {
+    let runner = |command: &str| -> Result<CmdOut, TracedErr> {
+        info!("Running command: {}", command);
+        let cmd_out = timeit!(format!("Cmd: {}", command).as_str(), { run_cmd(command) })?;
 
-        // Run the last and store its stdout as the value:
-        let cmd_out = runner(&commands[commands.len() - 1])?;
-        if cmd_out.stdout.trim().is_empty() {
+        if cmd_out.code != 0 {
             return Err(err!(
-                "Implicit None. Final cli script returned nothing. Command '{}'.",
-                &commands[commands.len() - 1]
+                "Command '{}' returned non zero exit code: {}",
+                command,
+                cmd_out.code
             ));
         }
-        let value = serde_json::Value::String(cmd_out.stdout);
 
-        coerce(value, self.coerce)
+        Ok(cmd_out)
+    };
+
+    // Run each command before the last:
+    for command in commands[..commands.len() - 1].iter() {
+        runner(command)?;
     }
+
+    // Run the last and return its stdout:
+    let cmd_out = runner(&commands[commands.len() - 1])?;
+    if cmd_out.stdout.trim().is_empty() {
+        return Err(err!(
+            "Implicit None. Final cli script returned nothing. Command '{}'.",
+            &commands[commands.len() - 1]
+        ));
+    }
+
+    Ok(cmd_out.stdout)
+}
+
+// String literal of json, yaml, toml:
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// A key extracted from the parsed output, either just the dotted path or a table with extra options.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CliMultiKey {
+    Simple(String),
+    Detailed(CliMultiKeyOpts),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CliMultiKeyOpts {
+    pub path: String,
+    pub coerce: Option<Coerce>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CtxCliMultiVar {
+    pub commands: Vec<String>,
+    pub parse: ParseFormat,
+    pub keys: HashMap<String, CliMultiKey>,
+}
+
+impl CtxCliMultiVar {
+    /// Run the commands once, extracting each mapped key from the parsed output as a separate context var.
+    pub fn consume(self, name: &str) -> Result<Vec<(String, serde_json::Value)>, TracedErr> {
+        let stdout = run_ctx_commands(&self.commands)?;
+
+        let parsed: serde_json::Value = match self.parse {
+            ParseFormat::Json => serde_json::from_str(&stdout)
+                .map_err(|e| err!("Failed to parse output as json: '{}'", e)),
+            ParseFormat::Yaml => serde_yaml::from_str(&stdout)
+                .map_err(|e| err!("Failed to parse output as yaml: '{}'", e)),
+            ParseFormat::Toml => {
+                toml::from_str(&stdout).map_err(|e| err!("Failed to parse output as toml: '{}'", e))
+            }
+        }
+        .map_err(|e| e.modify_msg(|msg| format!("[context.cli_multi.{}]: {}", name, msg)))?;
+
+        let mut values = vec![];
+        for (key, key_opts) in self.keys {
+            let (path, c_type) = match key_opts {
+                CliMultiKey::Simple(path) => (path, None),
+                CliMultiKey::Detailed(opts) => (opts.path, opts.coerce),
+            };
+
+            let value = lookup_dotted(&parsed, &path).ok_or_else(|| {
+                let dumped = parsed.to_string();
+                err!(
+                    "[context.cli_multi.{}.keys.{}]: Path '{}' not found in parsed output: '{}'",
+                    name,
+                    key,
+                    path,
+                    // Max out at 300 chars, adding ... at the end:
+                    dumped.chars().take(300).collect::<String>()
+                        + if dumped.len() > 300 { "..." } else { "" }
+                )
+            })?;
+
+            values.push((key, coerce(value.clone(), c_type)?));
+        }
+
+        Ok(values)
+    }
+}
+
+/// Lookup a dotted path e.g. "build.sha", numeric parts index into arrays.
+fn lookup_dotted<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(value, |current, part| match current {
+            serde_json::Value::Object(map) => map.get(part),
+            serde_json::Value::Array(arr) => part.parse::<usize>().ok().and_then(|i| arr.get(i)),
+            _ => None,
+        })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Context {
     #[serde(rename(deserialize = "static"))]
     #[serde(default = "HashMap::new")]
@@ -123,6 +225,9 @@ pub struct Context {
 
     #[serde(default = "HashMap::new")]
     pub cli: HashMap<String, CtxCliVar>,
+
+    #[serde(default = "HashMap::new")]
+    pub cli_multi: HashMap<String, CtxCliMultiVar>,
 }
 
 impl Context {
@@ -131,11 +236,231 @@ impl Context {
             stat: HashMap::new(),
             env: HashMap::new(),
             cli: HashMap::new(),
+            cli_multi: HashMap::new(),
+        }
+    }
+}
+
+/// A setup command, either just the command string or a table with extra options.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SetupCommand {
+    Simple(String),
+    Detailed(SetupCommandOpts),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SetupCommandOpts {
+    pub command: String,
+    #[serde(default)]
+    pub allow_error: bool,
+    pub parallel_group: Option<String>,
+    pub cwd: Option<String>,
+}
+
+impl SetupCommand {
+    pub fn command(&self) -> &str {
+        match self {
+            Self::Simple(command) => command,
+            Self::Detailed(opts) => &opts.command,
+        }
+    }
+
+    pub fn allow_error(&self) -> bool {
+        match self {
+            Self::Simple(_) => false,
+            Self::Detailed(opts) => opts.allow_error,
+        }
+    }
+
+    pub fn parallel_group(&self) -> Option<&str> {
+        match self {
+            Self::Simple(_) => None,
+            Self::Detailed(opts) => opts.parallel_group.as_deref(),
+        }
+    }
+
+    pub fn cwd(&self) -> Option<&str> {
+        match self {
+            Self::Simple(_) => None,
+            Self::Detailed(opts) => opts.cwd.as_deref(),
+        }
+    }
+}
+
+/// A command run after all templates are written, either just the command string or a table with extra options.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FinishCommand {
+    Simple(String),
+    Detailed(FinishCommandOpts),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FinishCommandOpts {
+    pub command: String,
+    #[serde(default)]
+    pub only_if_written: bool,
+    pub cwd: Option<String>,
+}
+
+impl FinishCommand {
+    pub fn command(&self) -> &str {
+        match self {
+            Self::Simple(command) => command,
+            Self::Detailed(opts) => &opts.command,
+        }
+    }
+
+    pub fn only_if_written(&self) -> bool {
+        match self {
+            Self::Simple(_) => false,
+            Self::Detailed(opts) => opts.only_if_written,
+        }
+    }
+
+    pub fn cwd(&self) -> Option<&str> {
+        match self {
+            Self::Simple(_) => None,
+            Self::Detailed(opts) => opts.cwd.as_deref(),
+        }
+    }
+}
+
+/// A command run on each written template whose out path matches the glob, `{path}` is substituted with the out path.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PostWriteHook {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub command: String,
+}
+
+impl PostWriteHook {
+    pub fn matcher(&self) -> Result<GlobMatcher, TracedErr> {
+        Ok(Glob::new(&self.pattern)
+            .map_err(|e| err!("Invalid glob '{}': '{}'", self.pattern, e))?
+            .compile_matcher())
+    }
+}
+
+/// A file rendered in place: each region between the markers is re-rendered, everything outside is left untouched.
+///
+/// The region's template lives inside the start marker, between `start` and `template_end`, so it survives being rendered.
+/// The rendered template verbatim replaces whatever is between `template_end` and `end`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InPlace {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    #[serde(default = "default_in_place_start")]
+    pub start: String,
+    #[serde(default = "default_in_place_template_end")]
+    pub template_end: String,
+    #[serde(default = "default_in_place_end")]
+    pub end: String,
+    // Stripped from each line of the template, e.g. '# ' so the template can be commented out in a toml file:
+    #[serde(default)]
+    pub line_prefix: Option<String>,
+}
+
+impl InPlace {
+    pub fn matcher(&self) -> Result<GlobMatcher, TracedErr> {
+        Ok(Glob::new(&self.pattern)
+            .map_err(|e| err!("Invalid glob '{}': '{}'", self.pattern, e))?
+            .compile_matcher())
+    }
+}
+
+fn default_in_place_start() -> String {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    "<!-- etch:start".to_string()
+}
+
+fn default_in_place_template_end() -> String {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    "-->".to_string()
+}
+
+fn default_in_place_end() -> String {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    "<!-- etch:end -->".to_string()
+}
+
+// String literal of fnv1a, sha256:
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    Fnv1a,
+    Sha256,
+}
+
+// String literal of error, skip:
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnBinary {
+    Error,
+    Skip,
+}
+
+impl HashAlgo {
+    pub fn parse(name: &str) -> Result<Self, TracedErr> {
+        match name {
+            "fnv1a" => Ok(HashAlgo::Fnv1a),
+            "sha256" => Ok(HashAlgo::Sha256),
+            _ => Err(err!(
+                "Unknown hash algorithm '{}', expected 'fnv1a' or 'sha256'.",
+                name
+            )),
+        }
+    }
+
+    /// Hash the rendered contents, fnv1a as a decimal integer, sha256 as hex.
+    pub fn hash(&self, contents: &[u8]) -> String {
+        match self {
+            HashAlgo::Fnv1a => bitbazaar::hash::fnv1a(contents).to_string(),
+            HashAlgo::Sha256 => format!("{:x}", Sha256::digest(contents)),
+        }
+    }
+
+    pub fn is_fnv1a(&self) -> bool {
+        *self == HashAlgo::Fnv1a
+    }
+}
+
+/// A regex identifying templates by their whole filename, with the replacement producing the output filename.
+/// E.g. `(.*)\.j2$` replaced with `$1` renders "config.yml.j2" to "config.yml".
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateMatcher {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+impl TemplateMatcher {
+    pub fn regex(&self) -> Result<Regex, TracedErr> {
+        Regex::new(&self.pattern).map_err(|e| err!("Invalid regex '{}': {}", self.pattern, e))
+    }
+}
+
+/// Either a single marker, or multiple tried in order, e.g. when migrating between markers.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TemplateMarker {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl TemplateMarker {
+    pub fn markers(&self) -> Vec<String> {
+        match self {
+            TemplateMarker::Single(marker) => vec![marker.clone()],
+            TemplateMarker::Multiple(markers) => markers.clone(),
         }
     }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct RawConfig {
     // All should be optional to allow empty config file, even though it wouldn't make too much sense!
     #[serde(default = "Context::default")]
@@ -146,50 +471,194 @@ pub struct RawConfig {
     pub engine: Engine,
     #[serde(default = "Vec::new")]
     pub ignore_files: Vec<String>,
+    #[serde(default = "default_use_gitignore")]
+    pub use_gitignore: bool,
+    #[serde(default = "default_follow_symlinks")]
+    pub follow_symlinks: bool,
+    #[serde(default = "default_max_depth")]
+    pub max_depth: Option<usize>,
+    #[serde(default = "default_ignore_junk")]
+    pub ignore_junk: bool,
     #[serde(default = "Vec::new")]
-    pub setup_commands: Vec<String>,
+    pub junk_patterns: Vec<String>,
+    #[serde(default = "default_template_marker")]
+    pub template_marker: TemplateMarker,
+    #[serde(default = "Vec::new")]
+    pub template_matchers: Vec<TemplateMatcher>,
+    #[serde(default = "default_partial_prefix")]
+    pub partial_prefix: String,
+    #[serde(default = "default_hash_algo")]
+    pub hash_algo: HashAlgo,
+    #[serde(default = "default_max_template_size")]
+    pub max_template_size: u64,
+    #[serde(default = "default_on_binary")]
+    pub on_binary: OnBinary,
+    #[serde(default = "default_lockfile")]
+    pub lockfile: Option<String>,
+    #[serde(default = "default_out_dir")]
+    pub out_dir: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub env_files: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub setup_commands: Vec<SetupCommand>,
+    #[serde(default = "Vec::new")]
+    pub finish_commands: Vec<FinishCommand>,
+    #[serde(default = "Vec::new")]
+    pub post_write: Vec<PostWriteHook>,
+    #[serde(default = "Vec::new")]
+    pub in_place: Vec<InPlace>,
+    // The config files merged to produce this config, extended parents first:
+    #[serde(skip)]
+    pub extends_chain: Vec<PathBuf>,
+    // The profile merged over the base config, if one was selected:
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+fn default_use_gitignore() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    false
+}
+
+fn default_follow_symlinks() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    false
+}
+
+fn default_max_depth() -> Option<usize> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_lockfile() -> Option<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_out_dir() -> Option<String> {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    None
+}
+
+fn default_ignore_junk() -> bool {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    true
+}
+
+fn default_template_marker() -> TemplateMarker {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    TemplateMarker::Single(DEFAULT_TEMPLATE_MARKER.to_string())
+}
+
+fn default_partial_prefix() -> String {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    "_".to_string()
+}
+
+fn default_hash_algo() -> HashAlgo {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    HashAlgo::Fnv1a
+}
+
+fn default_max_template_size() -> u64 {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    5 * 1024 * 1024
+}
+
+fn default_on_binary() -> OnBinary {
+    // NOTE: when changing make sure to update schema.json default for config hinting
+    OnBinary::Error
 }
 
 impl RawConfig {
     pub fn from_toml(render_args: &RenderCommand) -> Result<Self, TracedErr> {
         // If the config path is relative, make relative to the root:
-        let config_path = match render_args.config.is_relative() {
+        let mut config_path = match render_args.config.is_relative() {
             true => render_args.root.join(&render_args.config),
             false => render_args.config.clone(),
         };
 
-        match RawConfig::from_toml_inner(&config_path) {
+        // Python projects can keep the config in pyproject.toml, used when the default config file doesn't exist:
+        if render_args.config == Path::new(DEFAULT_CONFIG_PATH) && !config_path.exists() {
+            let pyproject_path = render_args.root.join(PYPROJECT_CONFIG_PATH);
+            if pyproject_path.exists() {
+                config_path = pyproject_path;
+            }
+        }
+
+        debug!("Using config '{}'.", config_path.display());
+        match RawConfig::from_toml_inner(
+            &config_path,
+            render_args.profile.as_deref(),
+            render_args.skip_path_checks,
+        ) {
             Ok(config) => Ok(config),
             Err(e) => Err(e.modify_msg(|msg| {
-                format!(
-                    "Error reading config file from '{}'.\n{}",
-                    config_path.display(),
-                    msg
-                )
+                if super::extends::is_pyproject(&config_path) {
+                    format!(
+                        "Error reading config from the [tool.etch] table of '{}'.\n{}",
+                        config_path.display(),
+                        msg
+                    )
+                } else {
+                    format!(
+                        "Error reading config file from '{}'.\n{}",
+                        config_path.display(),
+                        msg
+                    )
+                }
             })),
         }
     }
 
-    fn from_toml_inner(config_path: &PathBuf) -> Result<Self, TracedErr> {
-        let contents = match fs::read_to_string(config_path) {
-            Ok(c) => c,
-            Err(e) => return Err(err!("Failed file read: '{}'.", e)),
-        };
-
-        // Decode directly the toml directly into serde/json, using that internally:
-        let json: serde_json::Value = match toml::from_str(&contents) {
-            Ok(toml) => toml,
-            Err(e) => return Err(err!("Invalid toml formatting: '{}'.", e)),
-        };
+    fn from_toml_inner(
+        config_path: &Path,
+        profile: Option<&str>,
+        skip_path_checks: bool,
+    ) -> Result<Self, TracedErr> {
+        // Load the config, merging any configs it extends underneath:
+        let (mut json, extends_chain) = super::extends::load_with_extends(config_path)?;
 
         // This will check against the json schema,
         // can produce much better errors than the toml decoder can, so prevalidate first:
-        super::validate::pre_validate(&json)?;
+        super::validate::pre_validate(&json, &extends_chain)?;
+
+        // Profiles are validated as part of the schema above, so merging in the selected one keeps the config valid:
+        let profile = super::profiles::apply_profile(&mut json, profile)?;
 
-        // Now deserialize after validation:
-        let mut config: RawConfig = serde_json::from_value(json)?;
+        // Now deserialize after validation, tracking the path to locate any failure in the source:
+        let mut config: RawConfig = match serde_path_to_error::deserialize(json) {
+            Ok(config) => config,
+            Err(e) => {
+                let loc_parts = e
+                    .path()
+                    .iter()
+                    .filter_map(|segment| match segment {
+                        serde_path_to_error::Segment::Seq { index } => Some(index.to_string()),
+                        serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+                        serde_path_to_error::Segment::Enum { variant } => Some(variant.clone()),
+                        serde_path_to_error::Segment::Unknown => None,
+                    })
+                    .collect::<Vec<_>>();
+                let msg = format!(
+                    "[{}]: {}",
+                    if loc_parts.is_empty() {
+                        "root".to_string()
+                    } else {
+                        loc_parts.join(".")
+                    },
+                    e.inner()
+                );
+                return Err(match super::locate::locate(&extends_chain, &loc_parts) {
+                    Some(snippet) => err!("{}\n{}", msg, snippet),
+                    None => err!("{}", msg),
+                });
+            }
+        };
+        config.extends_chain = extends_chain;
+        config.profile = profile;
 
-        super::validate::post_validate(&mut config, config_path)?;
+        super::validate::post_validate(&mut config, config_path, skip_path_checks)?;
 
         Ok(config)
     }
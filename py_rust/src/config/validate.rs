@@ -1,15 +1,30 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+};
 
 use bitbazaar::{err, errors::TracedErr};
+use ignore::overrides::OverrideBuilder;
+use log::warn;
 use once_cell::sync::Lazy;
 use regex::Regex;
 
-use super::RawConfig;
+use super::{
+    engine::{
+        is_extension_module, ExposeProcessEnv, ENV_GLOBAL_NAME, MAX_INCLUDE_DEPTH,
+        PROFILE_GLOBAL_NAME,
+    },
+    raw_conf::{FinishCommand, SetupCommand, TemplateMarker},
+    RawConfig,
+};
+use crate::render::walker::invert_exclude;
 
 // Include the schema in the binary to use at runtime:
 static JSON_SCHEMA: &str = include_str!(r"./schema.json");
 
-pub fn pre_validate(value: &serde_json::Value) -> Result<(), TracedErr> {
+/// Validate against the json schema, `config_files` are the source files (in merge order) used to locate errors.
+pub fn pre_validate(value: &serde_json::Value, config_files: &[PathBuf]) -> Result<(), TracedErr> {
     let state = run_against_schema(value)?;
     if !state.is_strictly_valid() {
         return Err(err!(
@@ -17,7 +32,7 @@ pub fn pre_validate(value: &serde_json::Value) -> Result<(), TracedErr> {
             state
                 .errors
                 .into_iter()
-                .map(format_err)
+                .map(|e| format_err(e, config_files))
                 .collect::<Vec<String>>()
                 .join("\n")
         ));
@@ -27,7 +42,11 @@ pub fn pre_validate(value: &serde_json::Value) -> Result<(), TracedErr> {
 }
 
 /// Extra validation & cleaning to do on the created config object.
-pub fn post_validate(conf: &mut RawConfig, config_path: &Path) -> Result<(), TracedErr> {
+pub fn post_validate(
+    conf: &mut RawConfig,
+    config_path: &Path,
+    skip_path_checks: bool,
+) -> Result<(), TracedErr> {
     // Check stat.value is not empty string, plus same for env.default (if provided):
     for (key, value) in conf.context.stat.iter() {
         validate_not_empty_string(format!("[context.static.{}.value]", key), &value.value)?;
@@ -39,68 +58,386 @@ pub fn post_validate(conf: &mut RawConfig, config_path: &Path) -> Result<(), Tra
         }
     }
 
-    // ignore_files and engine.custom_extensions should be resolved relative to the config file, so rewrite the paths if needed and make sure they exist:
+    conf.engine.validate_syntax()?;
+    if let Some(max_include_depth) = conf.engine.max_include_depth {
+        if max_include_depth == 0 || max_include_depth > MAX_INCLUDE_DEPTH {
+            return Err(err!(
+                "[engine.max_include_depth]: Must be between 1 and {}, got {}.",
+                MAX_INCLUDE_DEPTH,
+                max_include_depth
+            ));
+        }
+    }
+    // Fail before rendering on an invalid fixed_timestamp or SOURCE_DATE_EPOCH:
+    conf.engine.render_time()?;
+
+    let markers = conf.template_marker.markers();
+    if markers.is_empty() {
+        return Err(err!("[template_marker]: At least one marker is required."));
+    }
+    for (index, marker) in markers.iter().enumerate() {
+        let location = match conf.template_marker {
+            TemplateMarker::Single(_) => "template_marker".to_string(),
+            TemplateMarker::Multiple(_) => format!("template_marker.{}", index),
+        };
+        if marker.trim().is_empty() || marker.contains(['.', '/', '\\']) {
+            return Err(err!(
+                "[{}]: '{}' must be non-empty and can't contain '.' or path separators.",
+                location,
+                marker
+            ));
+        }
+        if markers[..index].contains(marker) {
+            return Err(err!(
+                "[{}]: '{}' is listed more than once.",
+                location,
+                marker
+            ));
+        }
+    }
+
+    for (index, matcher) in conf.template_matchers.iter().enumerate() {
+        let add_loc =
+            |e: TracedErr| e.modify_msg(|msg| format!("[template_matchers.{}]: {}", index, msg));
+        let regex = matcher.regex().map_err(add_loc)?;
+        if regex.is_match("") {
+            return Err(add_loc(err!(
+                "Pattern '{}' matches an empty filename, so would match every file.",
+                matcher.pattern
+            )));
+        }
+        if matcher.replacement.is_empty() {
+            return Err(add_loc(err!(
+                "The replacement can't be empty, it produces the output filename."
+            )));
+        }
+    }
+
+    let ctx_keys = context_keys(conf);
+
+    // Keys produced by more than one source would silently override each other depending on processing order:
+    let mut sources: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (location, key) in ctx_keys.iter() {
+        sources.entry(key).or_default().push(location);
+    }
+    let duplicates = sources
+        .into_iter()
+        .filter(|(_, locations)| locations.len() > 1)
+        .map(|(key, locations)| {
+            format!(
+                "'{}' is defined in: {}",
+                key,
+                locations
+                    .iter()
+                    .map(|loc| format!("[{}]", loc))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+    if !duplicates.is_empty() {
+        return Err(err!(
+            "Duplicate context keys, each key must only be defined once:\n{}",
+            duplicates.join("\n")
+        ));
+    }
+
+    // The profile global is always reserved:
+    if let Some((location, _)) = ctx_keys.iter().find(|(_, key)| *key == PROFILE_GLOBAL_NAME) {
+        return Err(err!(
+            "[{}]: '{}' is reserved for the active profile name.",
+            location,
+            PROFILE_GLOBAL_NAME
+        ));
+    }
+
+    // The env global is reserved when the process environment is exposed, so can't also be a context key:
+    if conf.engine.expose_process_env != ExposeProcessEnv::None {
+        if let Some((location, _)) = ctx_keys.iter().find(|(_, key)| *key == ENV_GLOBAL_NAME) {
+            return Err(err!(
+                "[{}]: '{}' is reserved when [engine.expose_process_env] is enabled.",
+                location,
+                ENV_GLOBAL_NAME
+            ));
+        }
+    }
+
+    // ignore_files, engine.custom_extensions and engine.include_paths should be resolved relative to the config file, so rewrite the paths if needed and make sure they exist:
+    let config_dir = config_path
+        .parent()
+        .ok_or_else(|| err!("Config path has no parent: '{}'.", config_path.display()))?;
+    let config_dir = config_dir
+        .canonicalize()
+        .unwrap_or(config_dir.to_path_buf());
     let validate_and_rewrite = |in_path: String| -> Result<String, TracedErr> {
         // Make relative to config file if not absolute:
         let path = if !PathBuf::from(&in_path).is_absolute() {
-            config_path
-                .parent()
-                .unwrap()
-                .join(in_path)
-                .to_str()
-                .unwrap()
-                .to_string()
+            config_dir.join(in_path).to_string_lossy().to_string()
         } else {
             in_path
         };
 
         // Make sure exists:
-        if !PathBuf::from(&path).exists() {
+        if !skip_path_checks && !PathBuf::from(&path).exists() {
             return Err(err!("Path '{}' does not exist. Note relative paths are resolved from the config file directory.", path));
         }
 
         Ok(path)
     };
 
-    for ignore_file in conf.ignore_files.iter_mut() {
-        *ignore_file = validate_and_rewrite(ignore_file.clone())?;
+    // Catch permission issues upfront rather than as an obscure failure mid render:
+    let validate_readable = |path: &str| -> Result<(), TracedErr> {
+        let result = if Path::new(path).is_dir() {
+            fs::read_dir(path).map(|_| ())
+        } else {
+            fs::File::open(path).map(|_| ())
+        };
+        result.map_err(|e| err!("Path '{}' is not readable: {}", path, e))
+    };
+
+    for (index, ignore_file) in conf.ignore_files.iter_mut().enumerate() {
+        let add_loc =
+            |e: TracedErr| e.modify_msg(|msg| format!("[ignore_files.{}]: {}", index, msg));
+        *ignore_file = validate_and_rewrite(ignore_file.clone()).map_err(add_loc)?;
+        if skip_path_checks {
+            continue;
+        }
 
         // Make sure is a file:
         if !PathBuf::from(&ignore_file).is_file() {
-            return Err(err!("Path '{}' is not a file.", ignore_file));
+            return Err(add_loc(err!("Path '{}' is not a file.", ignore_file)));
+        }
+        validate_readable(ignore_file).map_err(add_loc)?;
+    }
+
+    for (index, env_file) in conf.env_files.iter_mut().enumerate() {
+        let add_loc = |e: TracedErr| e.modify_msg(|msg| format!("[env_files.{}]: {}", index, msg));
+        *env_file = validate_and_rewrite(env_file.clone()).map_err(add_loc)?;
+        if skip_path_checks {
+            continue;
+        }
+
+        if !PathBuf::from(&env_file).is_file() {
+            return Err(add_loc(err!("Path '{}' is not a file.", env_file)));
+        }
+        validate_readable(env_file).map_err(add_loc)?;
+    }
+
+    // The lockfile is created on the first render, so only its directory needs to exist:
+    if let Some(lockfile) = conf.lockfile.as_mut() {
+        let add_loc = |e: TracedErr| e.modify_msg(|msg| format!("[lockfile]: {}", msg));
+        if !Path::new(lockfile.as_str()).is_absolute() {
+            *lockfile = config_dir.join(&lockfile).to_string_lossy().to_string();
+        }
+        validate_lockfile_path(Path::new(lockfile.as_str()), skip_path_checks).map_err(add_loc)?;
+    }
+
+    // Created as outputs are written, so needn't exist yet:
+    if let Some(out_dir) = conf.out_dir.as_mut() {
+        let add_loc = |e: TracedErr| e.modify_msg(|msg| format!("[out_dir]: {}", msg));
+        if !Path::new(out_dir.as_str()).is_absolute() {
+            *out_dir = config_dir.join(&out_dir).to_string_lossy().to_string();
+        }
+        validate_out_dir(Path::new(out_dir.as_str())).map_err(add_loc)?;
+    }
+
+    let validate_and_rewrite_cwd = |cwd: &mut Option<String>| -> Result<(), TracedErr> {
+        if let Some(in_cwd) = cwd.take() {
+            let out_cwd = validate_and_rewrite(in_cwd)?;
+
+            // Make sure is a directory:
+            if !PathBuf::from(&out_cwd).is_dir() {
+                return Err(err!("Command cwd '{}' is not a directory.", out_cwd));
+            }
+
+            *cwd = Some(out_cwd);
+        }
+        Ok(())
+    };
+
+    // A parallel group runs as one batch, so its commands must be consecutive, any listed between them would otherwise run out of order:
+    let mut finished_groups = HashSet::new();
+    let mut current_group = None;
+    for (index, setup_command) in conf.setup_commands.iter().enumerate() {
+        let group = setup_command.parallel_group();
+        if group == current_group {
+            continue;
+        }
+        finished_groups.extend(current_group);
+        if let Some(group) = group.filter(|group| finished_groups.contains(group)) {
+            return Err(err!(
+                "[setup_commands.{}]: Commands in parallel_group '{}' must be listed consecutively, they run together so the commands between them would run out of order.",
+                index,
+                group
+            ));
         }
+        current_group = group;
     }
 
-    for user_extension in conf.engine.custom_extensions.iter_mut() {
-        *user_extension = validate_and_rewrite(user_extension.clone())?;
+    for setup_command in conf.setup_commands.iter_mut() {
+        if let SetupCommand::Detailed(opts) = setup_command {
+            validate_and_rewrite_cwd(&mut opts.cwd)?;
+        }
+    }
+
+    for finish_command in conf.finish_commands.iter_mut() {
+        if let FinishCommand::Detailed(opts) = finish_command {
+            validate_and_rewrite_cwd(&mut opts.cwd)?;
+        }
+    }
+
+    // Compile the excludes upfront so a malformed pattern is reported against its config location, rather than mid walk:
+    let mut overrider = OverrideBuilder::new(&config_dir);
+    for (index, exclude) in conf.exclude.iter().enumerate() {
+        overrider
+            .add(&invert_exclude(exclude)?)
+            .map_err(|e| err!("[exclude.{}]: Invalid pattern '{}': {}", index, exclude, e))?;
+
+        // Patterns are matched against paths relative to the root, so some can be valid but never match anything:
+        let trimmed = exclude.trim().trim_start_matches('!');
+        if Path::new(trimmed)
+            .components()
+            .any(|c| c == Component::ParentDir)
+        {
+            warn!(
+                "[exclude.{}]: Pattern '{}' can never match, excludes are matched relative to the root so can't contain '..'.",
+                index, exclude
+            );
+        } else if Path::new(trimmed).is_absolute() && Path::new(trimmed).exists() {
+            warn!(
+                "[exclude.{}]: Pattern '{}' looks like an absolute filesystem path, but a leading '/' anchors the pattern to the root, so it will only match '<root>{}'.",
+                index, exclude, trimmed
+            );
+        }
+    }
+
+    // Make sure the post write globs are valid upfront, rather than only erroring once something is written:
+    for (index, hook) in conf.post_write.iter().enumerate() {
+        hook.matcher()
+            .map_err(|e| e.modify_msg(|msg| format!("[post_write.{}.match]: {}", index, msg)))?;
+    }
+
+    for (index, in_place) in conf.in_place.iter().enumerate() {
+        let add_loc = |e: TracedErr| e.modify_msg(|msg| format!("[in_place.{}]: {}", index, msg));
+        in_place.matcher().map_err(add_loc)?;
+        let markers = [&in_place.start, &in_place.template_end, &in_place.end];
+        if markers.iter().any(|marker| marker.is_empty()) {
+            return Err(add_loc(err!(
+                "The start, template_end and end markers can't be empty."
+            )));
+        }
+        // The end marker is searched for after template_end, so the two can't be confused:
+        if in_place.start == in_place.end || in_place.template_end == in_place.end {
+            return Err(add_loc(err!(
+                "The end marker '{}' must differ from the start and template_end markers.",
+                in_place.end
+            )));
+        }
+    }
+
+    for (index, region) in conf.engine.managed_regions.iter().enumerate() {
+        let add_loc = |e: TracedErr| {
+            e.modify_msg(|msg| format!("[engine.managed_regions.{}]: {}", index, msg))
+        };
+        if region.begin.trim().is_empty() || region.end.trim().is_empty() {
+            return Err(add_loc(err!("The begin and end markers can't be empty.")));
+        }
+        // Markers are found by line, so one containing the other would be ambiguous:
+        if region.begin.contains(&region.end) || region.end.contains(&region.begin) {
+            return Err(add_loc(err!(
+                "The begin marker '{}' and end marker '{}' can't contain each other.",
+                region.begin,
+                region.end
+            )));
+        }
+    }
+
+    for (index, user_extension) in conf.engine.custom_extensions.iter_mut().enumerate() {
+        let add_loc = |e: TracedErr| {
+            e.modify_msg(|msg| format!("[engine.custom_extensions.{}]: {}", index, msg))
+        };
+        // Module names are imported from the environment as is, unless a path relative to the config has the same name:
+        if is_extension_module(user_extension) && !config_dir.join(&*user_extension).exists() {
+            continue;
+        }
+        *user_extension = validate_and_rewrite(user_extension.clone()).map_err(add_loc)?;
+        if skip_path_checks {
+            continue;
+        }
 
         let path = PathBuf::from(&user_extension);
         // If it's a dir, make sure it has an __init__.py file:
         if path.is_dir() {
             let init_file = path.join("__init__.py");
             if !init_file.exists() {
-                return Err(err!(
+                return Err(add_loc(err!(
                     "Custom extension '{}' is a directory but does not contain an __init__.py file, not a valid package.",
                     user_extension
-                ));
+                )));
             }
+            validate_readable(&init_file.to_string_lossy()).map_err(add_loc)?;
         } else {
             // Otherwise make sure its a .py file:
             let extension = path.extension().unwrap_or_default();
             if extension != "py" {
-                return Err(err!(
+                return Err(add_loc(err!(
                     "Custom extension '{}' is not a .py file.",
                     user_extension
-                ));
+                )));
             }
         }
+        validate_readable(user_extension).map_err(add_loc)?;
+    }
+
+    for (index, include_path) in conf.engine.include_paths.iter_mut().enumerate() {
+        let add_loc =
+            |e: TracedErr| e.modify_msg(|msg| format!("[engine.include_paths.{}]: {}", index, msg));
+        *include_path = validate_and_rewrite(include_path.clone()).map_err(add_loc)?;
+        if skip_path_checks {
+            continue;
+        }
+
+        if !PathBuf::from(&include_path).is_dir() {
+            return Err(add_loc(err!("Path '{}' is not a directory.", include_path)));
+        }
+        validate_readable(include_path).map_err(add_loc)?;
     }
 
     Ok(())
 }
 
+/// All the context var names the config will produce, alongside their config location.
+fn context_keys(conf: &RawConfig) -> Vec<(String, &str)> {
+    let ctx = &conf.context;
+    ctx.stat
+        .keys()
+        .map(|key| (format!("context.static.{}", key), key.as_str()))
+        .chain(
+            ctx.env
+                .keys()
+                .map(|key| (format!("context.env.{}", key), key.as_str())),
+        )
+        .chain(
+            ctx.cli
+                .keys()
+                .map(|key| (format!("context.cli.{}", key), key.as_str())),
+        )
+        .chain(ctx.cli_multi.iter().flat_map(|(name, multi)| {
+            multi.keys.keys().map(move |key| {
+                (
+                    format!("context.cli_multi.{}.keys.{}", name, key),
+                    key.as_str(),
+                )
+            })
+        }))
+        .collect()
+}
+
 /// Because we're hacking together toml validation using a json parser, format the errors a bit more applicably where possible.
-fn format_err(err: Box<dyn valico::common::error::ValicoError>) -> String {
+fn format_err(
+    err: Box<dyn valico::common::error::ValicoError>,
+    config_files: &[PathBuf],
+) -> String {
     // Want the actual detail, only use title if detail is missing (crates cli seems to state title is always available but detail not so. But detail seems to always be there.)
     let info = if let Some(detail) = err.get_detail() {
         detail
@@ -122,8 +459,21 @@ fn format_err(err: Box<dyn valico::common::error::ValicoError>) -> String {
 
     let mut desc = info.to_string();
 
-    if let Some(extra) = err_extra_property(&desc) {
-        desc = format!("Unknown property: '{}'.", extra);
+    // Unknown properties are reported against their parent, but the property itself is more useful to point at:
+    let mut source_loc = loc_parts.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let extra_property = err_extra_property(&desc);
+    if let Some(extra) = &extra_property {
+        source_loc.push(extra.clone());
+    }
+
+    if let Some(extra) = extra_property {
+        desc = match did_you_mean(&extra, &known_properties(&loc_parts)) {
+            Some(suggestion) => format!(
+                "Unknown property: '{}'. Did you mean '{}'?",
+                extra, suggestion
+            ),
+            None => format!("Unknown property: '{}'.", extra),
+        };
     } else if let Some(invalid_type) = err_invalid_type(&desc) {
         desc = format!(
             "Expected {}.",
@@ -137,12 +487,80 @@ fn format_err(err: Box<dyn valico::common::error::ValicoError>) -> String {
         desc = "Expected one of ['json', 'str', 'int', 'float', 'bool'].".to_string();
     }
 
-    format!(
+    let formatted = format!(
         "{}{}{}",
         loc_str,
         desc,
-        if desc.ends_with('.') { "" } else { "." }
-    )
+        if desc.ends_with('.') || desc.ends_with('?') {
+            ""
+        } else {
+            "."
+        }
+    );
+
+    match super::locate::locate(config_files, &source_loc) {
+        Some(snippet) => format!("{}\n{}", formatted, snippet),
+        None => formatted,
+    }
+}
+
+/// The property names the schema allows at the given location, used to suggest fixes for typos.
+fn known_properties(loc_parts: &[&str]) -> Vec<String> {
+    let schema: serde_json::Value = match serde_json::from_str(JSON_SCHEMA) {
+        Ok(schema) => schema,
+        Err(_) => return vec![],
+    };
+
+    // Follow "#/properties/..." style refs, as used by profiles:
+    let resolve = |node: &serde_json::Value| -> Option<serde_json::Value> {
+        match node.get("$ref").and_then(|r| r.as_str()) {
+            Some(reference) => reference
+                .strip_prefix('#')
+                .and_then(|pointer| schema.pointer(pointer))
+                .cloned(),
+            None => Some(node.clone()),
+        }
+    };
+
+    let mut node = schema.clone();
+    for part in loc_parts {
+        let next = if let Some(prop) = node.get("properties").and_then(|p| p.get(*part)) {
+            prop.clone()
+        } else if let Some(pattern) = node
+            .get("patternProperties")
+            .and_then(|p| p.as_object())
+            .and_then(|p| p.values().next())
+        {
+            pattern.clone()
+        } else if let Some(additional) = node.get("additionalProperties").filter(|a| a.is_object())
+        {
+            additional.clone()
+        } else if let Some(items) = node.get("items") {
+            items.clone()
+        } else {
+            return vec![];
+        };
+        node = match resolve(&next) {
+            Some(node) => node,
+            None => return vec![],
+        };
+    }
+
+    node.get("properties")
+        .and_then(|p| p.as_object())
+        .map(|p| p.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// The closest known name by edit distance, if close enough to plausibly be a typo.
+fn did_you_mean(unknown: &str, known: &[String]) -> Option<String> {
+    let max_distance = (unknown.chars().count() / 3).max(2);
+    known
+        .iter()
+        .map(|candidate| (strsim::levenshtein(unknown, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.clone())
 }
 
 static RE_ENUM_UNMATCHED: Lazy<Regex> =
@@ -184,6 +602,38 @@ fn run_against_schema(
     Ok(schema.validate(json))
 }
 
+/// Shared with the --lockfile flag, the path can't be a directory and its parent directory must exist.
+pub fn validate_lockfile_path(path: &Path, skip_path_checks: bool) -> Result<(), TracedErr> {
+    if path.is_dir() {
+        return Err(err!(
+            "Lockfile path '{}' is a directory, it should be a file path.",
+            path.display()
+        ));
+    }
+    if !skip_path_checks
+        && !path
+            .parent()
+            .is_some_and(|dir| dir.as_os_str().is_empty() || dir.is_dir())
+    {
+        return Err(err!(
+            "The directory of the lockfile path '{}' does not exist.",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Shared with the --out-dir flag, the path can't be an existing file.
+pub fn validate_out_dir(path: &Path) -> Result<(), TracedErr> {
+    if path.is_file() {
+        return Err(err!(
+            "Out dir '{}' is a file, it should be a directory.",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
 fn validate_not_empty_string(context: String, value: &serde_json::Value) -> Result<(), TracedErr> {
     let valid = match &value {
         serde_json::Value::String(s) => !s.trim().is_empty(),
@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+
+use bitbazaar::{err, errors::TracedErr};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{
+    args::{ExplainCommand, RenderCommand},
+    config::{self, RawConfig},
+    render::{junk::JunkFilter, walker},
+};
+
+/// Print why a path is or isn't a template: whether it's excluded and by what, whether it's junk, which marker it matched and where it would render to.
+/// Side-effect free like list: the config is only parsed, setup and cli commands are never run.
+pub fn explain(args: ExplainCommand) -> Result<(), TracedErr> {
+    let mut render_args = RenderCommand {
+        root: args.root,
+        extra_roots: vec![],
+        config: args.config,
+        no_discover: false,
+        force: false,
+        no_lockfile: false,
+        lockfile: None,
+        out_dir: None,
+        profile: args.profile,
+        variant: None,
+        skip_path_checks: false,
+        strict: false,
+        allow_overwrite_sources: false,
+        check_vars: false,
+        gitignore: false,
+        follow_symlinks: false,
+        watch: false,
+        only: vec![],
+        env_file: vec![],
+        debug: false,
+    };
+    config::discover_config(&mut render_args)?;
+    let raw_conf = RawConfig::from_toml(&render_args)?;
+    render_args.out_dir = raw_conf.out_dir.as_deref().map(PathBuf::from);
+
+    // Resolving both so the path can be given relative to the cwd rather than the root:
+    render_args.root = canonicalize(&render_args.root)?;
+    let path = canonicalize(&args.path)?;
+    let rel_path = path.strip_prefix(&render_args.root).map_err(|_| {
+        err!(
+            "Path '{}' is not inside the root '{}'.",
+            args.path.display(),
+            render_args.root.display()
+        )
+    })?;
+    if !path.is_file() {
+        return Err(err!("Path '{}' is not a file.", args.path.display()));
+    }
+
+    let excluded = match excluded_by(&render_args, &raw_conf, &path)? {
+        Some(reason) => Some(reason),
+        // Git ignore rules, max_depth and symlinks are left to the walker itself:
+        None => (!visited_by_walker(&render_args, &raw_conf, &path)?).then(|| {
+            "skipped by the walker, e.g. by git ignore rules, max_depth or an unfollowed symlink"
+                .to_string()
+        }),
+    };
+
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let junk = JunkFilter::new(raw_conf.ignore_junk, &raw_conf.junk_patterns)?.is_junk(&filename);
+    let markers = raw_conf.template_marker.markers();
+    let matcher = walker::MarkerMatcher::new(&markers, &raw_conf.template_matchers)?;
+    let matched = matcher.get_match_described(&filename);
+
+    println!("Path:     {}", rel_path.display());
+    println!(
+        "Excluded: {}",
+        excluded
+            .as_ref()
+            .map_or("no".to_string(), |reason| format!("yes, {}", reason))
+    );
+    println!("Junk:     {}", if junk { "yes" } else { "no" });
+    match &matched {
+        Some((matched_by, compiled_name)) => {
+            println!("Template: yes, matched {}", matched_by);
+            let output = rel_path.with_file_name(compiled_name);
+            match &render_args.out_dir {
+                Some(out_dir) => println!("Output:   {}", out_dir.join(output).display()),
+                None => println!("Output:   {}", output.display()),
+            }
+        }
+        None => println!(
+            "Template: no, the filename contains none of the markers: {}{}",
+            markers
+                .iter()
+                .map(|marker| format!("'{}'", marker))
+                .collect::<Vec<_>>()
+                .join(", "),
+            if raw_conf.template_matchers.is_empty() {
+                ""
+            } else {
+                ", nor matches any template_matchers pattern"
+            }
+        ),
+    }
+    println!(
+        "Renders:  {}",
+        if excluded.is_none() && !junk && matched.is_some() {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+
+    Ok(())
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, TracedErr> {
+    path.canonicalize()
+        .map_err(|e| err!("Failed to resolve '{}': {}", path.display(), e))
+}
+
+/// Why the path is excluded by the config or ignore files, checking its parent directories first as the walker never descends into an excluded directory.
+fn excluded_by(
+    render_args: &RenderCommand,
+    raw_conf: &RawConfig,
+    path: &Path,
+) -> Result<Option<String>, TracedErr> {
+    // The same overrides the walker uses make the decision, the individual patterns are only used to name the culprit:
+    let overrides = walker::overrides(
+        render_args,
+        &raw_conf.exclude,
+        raw_conf.lockfile.as_deref().map(Path::new),
+    )?;
+    let builtin_excludes =
+        walker::builtin_excludes(render_args, raw_conf.lockfile.as_deref().map(Path::new));
+    // Relative ignore files are resolved from the config file directory, like when rendering:
+    let config_dir = render_args
+        .config
+        .parent()
+        .and_then(|dir| dir.canonicalize().ok())
+        .unwrap_or_default();
+    let ignore_files = raw_conf
+        .ignore_files
+        .iter()
+        .map(|ignore_file| config_dir.join(ignore_file))
+        .chain(walker::etchignore(render_args))
+        .map(|ignore_file| {
+            let (gitignore, e) = Gitignore::new(&ignore_file);
+            match e {
+                Some(e) => Err(err!(
+                    "Failed to read ignore file at '{}': {}",
+                    ignore_file.display(),
+                    e
+                )),
+                None => Ok((ignore_file, gitignore)),
+            }
+        })
+        .collect::<Result<Vec<_>, TracedErr>>()?;
+
+    let mut ancestors = path
+        .ancestors()
+        .take_while(|ancestor| *ancestor != render_args.root)
+        .collect::<Vec<_>>();
+    ancestors.reverse();
+
+    for ancestor in ancestors {
+        let is_dir = ancestor != path;
+        let described = if is_dir {
+            format!(
+                " (via its parent directory '{}')",
+                rel(render_args, ancestor)
+            )
+        } else {
+            String::new()
+        };
+
+        if overrides.matched(ancestor, is_dir).is_ignore() {
+            for (index, exclude) in raw_conf.exclude.iter().enumerate() {
+                if pattern_matches(render_args, exclude, ancestor, is_dir)? {
+                    return Ok(Some(format!(
+                        "by the pattern '{}' at [exclude.{}]{}",
+                        exclude, index, described
+                    )));
+                }
+            }
+            for exclude in builtin_excludes.iter() {
+                if pattern_matches(render_args, exclude, ancestor, is_dir)? {
+                    return Ok(Some(format!(
+                        "by the built-in exclude '{}'{}",
+                        exclude, described
+                    )));
+                }
+            }
+            return Ok(Some(format!(
+                "as it isn't matched by any '!' pattern in [exclude]{}",
+                described
+            )));
+        }
+
+        for (ignore_file, gitignore) in ignore_files.iter() {
+            if let ignore::Match::Ignore(glob) = gitignore.matched(ancestor, is_dir) {
+                return Ok(Some(format!(
+                    "by the pattern '{}' in ignore file '{}'{}",
+                    glob.original(),
+                    ignore_file.display(),
+                    described
+                )));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether a single exclude pattern matches as an ignore.
+fn pattern_matches(
+    render_args: &RenderCommand,
+    exclude: &str,
+    path: &Path,
+    is_dir: bool,
+) -> Result<bool, TracedErr> {
+    let mut builder = GitignoreBuilder::new(&render_args.root);
+    builder
+        .add_line(None, exclude.trim())
+        .map_err(|e| err!("Invalid pattern '{}': {}", exclude, e))?;
+    Ok(builder.build()?.matched(path, is_dir).is_ignore())
+}
+
+fn visited_by_walker(
+    render_args: &RenderCommand,
+    raw_conf: &RawConfig,
+    path: &Path,
+) -> Result<bool, TracedErr> {
+    let walker = walker::create(
+        render_args,
+        &raw_conf.exclude,
+        &raw_conf.ignore_files,
+        raw_conf.use_gitignore,
+        raw_conf.follow_symlinks,
+        raw_conf.max_depth,
+        raw_conf.lockfile.as_deref().map(Path::new),
+    )?;
+    for entry in walker.build() {
+        if entry?.path().canonicalize().ok().as_deref() == Some(path) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn rel(render_args: &RenderCommand, path: &Path) -> String {
+    path.strip_prefix(&render_args.root)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
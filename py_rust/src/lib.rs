@@ -1,15 +1,18 @@
 #![warn(clippy::disallowed_types)]
 
-use std::ops::Deref;
-
+use clap::Parser;
 use colored::Colorize;
 use config::PY_CONTEXT;
 use pyo3::{exceptions::PyValueError, prelude::*};
 use pythonize::depythonize;
 
 mod args;
+mod capabilities;
+mod complete;
 mod config;
+mod explain;
 mod init;
+mod list;
 mod render;
 mod run;
 mod utils;
@@ -33,19 +36,123 @@ pub fn cli() {
 }
 
 #[pyfunction]
-#[pyo3(name = "register_function")]
-pub fn py_register_function(py: Python, py_fn: &PyAny) -> PyResult<()> {
-    config::register_py_func(py, py_fn)?;
+#[pyo3(
+    name = "register_function",
+    signature = (py_fn=None, *, name=None, needs_context=false, overwrite=false)
+)]
+pub fn py_register_function(
+    py: Python,
+    py_fn: Option<&PyAny>,
+    name: Option<String>,
+    needs_context: bool,
+    overwrite: bool,
+) -> PyResult<PyObject> {
+    match py_fn {
+        Some(py_fn) => {
+            config::register_py_func(py, py_fn, name, needs_context, overwrite)?;
+            Ok(py.None())
+        }
+        // Called with just options e.g. @etch.register_function(needs_context=True), return the decorator to apply:
+        None => {
+            let kwargs = pyo3::types::PyDict::new(py);
+            kwargs.set_item("name", name)?;
+            kwargs.set_item("needs_context", needs_context)?;
+            kwargs.set_item("overwrite", overwrite)?;
+            Ok(py
+                .import("functools")?
+                .getattr("partial")?
+                .call((wrap_pyfunction!(py_register_function, py)?,), Some(kwargs))?
+                .to_object(py))
+        }
+    }
+}
+
+/// Forget all registered custom functions, filters, tests and context processors, e.g. between tests or notebook runs where modules are re-imported.
+#[pyfunction]
+#[pyo3(name = "clear_registered_functions")]
+pub fn py_clear_registered_functions() {
+    config::clear_py_funcs();
+}
+
+#[pyfunction]
+#[pyo3(name = "register_filter", signature = (py_fn=None, *, name=None, overwrite=false))]
+pub fn py_register_filter(
+    py: Python,
+    py_fn: Option<&PyAny>,
+    name: Option<String>,
+    overwrite: bool,
+) -> PyResult<PyObject> {
+    match py_fn {
+        Some(py_fn) => {
+            config::register_py_filter(py, py_fn, name, overwrite)?;
+            Ok(py.None())
+        }
+        None => decorator_with_options(
+            py,
+            wrap_pyfunction!(py_register_filter, py)?,
+            name,
+            overwrite,
+        ),
+    }
+}
+
+#[pyfunction]
+#[pyo3(name = "register_test", signature = (py_fn=None, *, name=None, overwrite=false))]
+pub fn py_register_test(
+    py: Python,
+    py_fn: Option<&PyAny>,
+    name: Option<String>,
+    overwrite: bool,
+) -> PyResult<PyObject> {
+    match py_fn {
+        Some(py_fn) => {
+            config::register_py_test(py, py_fn, name, overwrite)?;
+            Ok(py.None())
+        }
+        None => {
+            decorator_with_options(py, wrap_pyfunction!(py_register_test, py)?, name, overwrite)
+        }
+    }
+}
+
+/// Called with just options e.g. @etch.register_filter(overwrite=True), the decorator to apply.
+fn decorator_with_options(
+    py: Python,
+    register: &PyAny,
+    name: Option<String>,
+    overwrite: bool,
+) -> PyResult<PyObject> {
+    let kwargs = pyo3::types::PyDict::new(py);
+    kwargs.set_item("name", name)?;
+    kwargs.set_item("overwrite", overwrite)?;
+    Ok(py
+        .import("functools")?
+        .getattr("partial")?
+        .call((register,), Some(kwargs))?
+        .to_object(py))
+}
+
+#[pyfunction]
+#[pyo3(name = "register_context_processor", signature = (py_fn, *, name=None))]
+pub fn py_register_context_processor(py_fn: &PyAny, name: Option<String>) -> PyResult<()> {
+    config::register_py_context_processor(py_fn, name)?;
     Ok(())
 }
 
 /// Get the current context as a Python dictionary to be used in custom user functions.
+/// A deep copy is returned, so user code can't mutate the live context mid-render.
 #[pyfunction]
 #[pyo3(name = "context")]
 pub fn py_context(py: Python) -> PyResult<PyObject> {
-    let py_ctx = PY_CONTEXT.lock();
-    if let Some(py_ctx) = py_ctx.deref() {
-        Ok(py_ctx.clone_ref(py))
+    let py_ctx = PY_CONTEXT
+        .lock()
+        .as_ref()
+        .map(|py_ctx| py_ctx.clone_ref(py));
+    if let Some(py_ctx) = py_ctx {
+        Ok(py
+            .import("copy")?
+            .call_method1("deepcopy", (py_ctx,))?
+            .to_object(py))
     } else {
         Err(PyValueError::new_err(
             "Context not registered. This should only be called by custom user extensions.",
@@ -53,12 +160,35 @@ pub fn py_context(py: Python) -> PyResult<PyObject> {
     }
 }
 
+/// Get the rel path of the template currently being rendered, to be used in custom user functions.
+#[pyfunction]
+#[pyo3(name = "current_template")]
+pub fn py_current_template() -> PyResult<String> {
+    config::current_template().ok_or_else(|| {
+        PyValueError::new_err(
+            "No template is being rendered. This should only be called by custom user extensions during rendering.",
+        )
+    })
+}
+
+/// Run a render in the current process rather than through the cli, used to test behaviour of long-lived processes.
+#[pyfunction]
+#[pyo3(name = "_render")]
+pub fn py_render(args: Vec<String>) -> PyResult<bool> {
+    let render_args =
+        args::RenderCommand::try_parse_from(std::iter::once("render".to_string()).chain(args))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(render::render(render_args)?)
+}
+
 #[pyfunction]
 #[pyo3(name = "_toml_update")]
 pub fn py_toml_update(
     initial: &str,
     update: Option<&PyAny>,
     remove: Option<&PyAny>,
+    array_strategy: Option<&PyAny>,
+    sort_keys: Option<bool>,
 ) -> PyResult<String> {
     let update: Option<serde_json::Value> = if let Some(update) = update {
         depythonize(update)?
@@ -70,13 +200,26 @@ pub fn py_toml_update(
     } else {
         None
     };
-    Ok(utils::toml::update(initial, update, remove)?)
+    let array_strategy: utils::toml::ArrayStrategy = if let Some(array_strategy) = array_strategy {
+        depythonize(array_strategy)?
+    } else {
+        utils::toml::ArrayStrategy::default()
+    };
+    // Failures all come from the given document or paths, so surface as ValueErrors:
+    utils::toml::update(
+        initial,
+        update,
+        remove,
+        array_strategy,
+        sort_keys.unwrap_or(false),
+    )
+    .map_err(|e| PyValueError::new_err(e.to_string()))
 }
 
 #[pyfunction]
-#[pyo3(name = "_hash_contents")]
-pub fn py_hash_contents(contents: &str) -> PyResult<String> {
-    Ok(bitbazaar::hash::fnv1a(contents.as_bytes()).to_string())
+#[pyo3(name = "_hash_contents", signature = (contents, algo="fnv1a"))]
+pub fn py_hash_contents(contents: &str, algo: &str) -> PyResult<String> {
+    Ok(config::HashAlgo::parse(algo)?.hash(contents.as_bytes()))
 }
 
 /// A Python module implemented in Rust. The name of this function must match
@@ -89,8 +232,20 @@ fn root_module(_py: Python, m: &PyModule) -> PyResult<()> {
 
     m.add_function(wrap_pyfunction!(py_register_function, m)?)?;
 
+    m.add_function(wrap_pyfunction!(py_clear_registered_functions, m)?)?;
+
+    m.add_function(wrap_pyfunction!(py_register_filter, m)?)?;
+
+    m.add_function(wrap_pyfunction!(py_register_test, m)?)?;
+
+    m.add_function(wrap_pyfunction!(py_register_context_processor, m)?)?;
+
     m.add_function(wrap_pyfunction!(py_context, m)?)?;
 
+    m.add_function(wrap_pyfunction!(py_current_template, m)?)?;
+
+    m.add_function(wrap_pyfunction!(py_render, m)?)?;
+
     m.add_function(wrap_pyfunction!(py_toml_update, m)?)?;
 
     m.add_function(wrap_pyfunction!(py_hash_contents, m)?)?;
@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+
+use bitbazaar::errors::TracedErr;
+
+use crate::{
+    args::{HelpFormat, ListCommand, RenderCommand},
+    config::{self, RawConfig},
+    render::{junk::JunkFilter, walker},
+};
+
+#[derive(Debug, serde::Serialize)]
+struct Listed {
+    template: String,
+    output: String,
+}
+
+/// Print each template and the output it renders to, relative to the root.
+/// Side-effect free like completion: the config is only parsed, setup and cli commands are never run.
+pub fn list(args: ListCommand) -> Result<(), TracedErr> {
+    let mut render_args = RenderCommand {
+        root: args.root,
+        extra_roots: vec![],
+        config: args.config,
+        no_discover: false,
+        force: false,
+        no_lockfile: false,
+        lockfile: None,
+        out_dir: None,
+        profile: args.profile,
+        variant: None,
+        skip_path_checks: false,
+        strict: false,
+        allow_overwrite_sources: false,
+        check_vars: false,
+        gitignore: false,
+        follow_symlinks: false,
+        watch: false,
+        only: vec![],
+        env_file: vec![],
+        debug: false,
+    };
+    config::discover_config(&mut render_args)?;
+
+    let raw_conf = RawConfig::from_toml(&render_args)?;
+    render_args.out_dir = raw_conf.out_dir.as_deref().map(PathBuf::from);
+    let walker = walker::create(
+        &render_args,
+        &raw_conf.exclude,
+        &raw_conf.ignore_files,
+        raw_conf.use_gitignore,
+        raw_conf.follow_symlinks,
+        raw_conf.max_depth,
+        raw_conf.lockfile.as_deref().map(Path::new),
+    )?;
+    let junk = JunkFilter::new(raw_conf.ignore_junk, &raw_conf.junk_patterns)?;
+    let marker = walker::MarkerMatcher::new(
+        &raw_conf.template_marker.markers(),
+        &raw_conf.template_matchers,
+    )?;
+
+    let mut listed = walker::find_templates(
+        &render_args,
+        walker,
+        &junk,
+        &marker,
+        &raw_conf.partial_prefix,
+        None,
+    )?
+    .templates
+    .into_iter()
+    .map(|template| Listed {
+        output: template
+            .out_path
+            .strip_prefix(&render_args.root)
+            .unwrap_or(&template.out_path)
+            .display()
+            .to_string(),
+        template: template.rel_path,
+    })
+    .collect::<Vec<_>>();
+    listed.sort_by(|a, b| a.template.cmp(&b.template));
+
+    match args.output_format {
+        HelpFormat::Text => {
+            for item in listed.iter() {
+                println!("{} -> {}", item.template, item.output);
+            }
+        }
+        HelpFormat::Json => println!("{}", serde_json::to_string_pretty(&listed)?),
+    }
+
+    Ok(())
+}
@@ -1,19 +1,41 @@
 use bitbazaar::{err, errors::TracedErr};
 
-use crate::args::RenderCommand;
+use crate::{args::RenderCommand, config};
 
 pub fn args_validate(args: &RenderCommand) -> Result<(), TracedErr> {
-    // Check the root path exists:
-    if !args.root.exists() {
-        return Err(err!("Root path does not exist: {}", args.root.display()));
+    for root in std::iter::once(&args.root).chain(args.extra_roots.iter()) {
+        // Check the root path exists:
+        if !root.exists() {
+            return Err(err!("Root path does not exist: {}", root.display()));
+        }
+
+        // Check the root path is a directory rather than a file:
+        if !root.is_dir() {
+            return Err(err!("Root path is not a directory: {}", root.display()));
+        }
+    }
+
+    if args.watch && !args.extra_roots.is_empty() {
+        return Err(err!("--watch only supports a single root."));
+    }
+
+    if let Some(lockfile) = &args.lockfile {
+        config::validate_lockfile_path(lockfile, false)
+            .map_err(|e| e.modify_msg(|msg| format!("[--lockfile]: {}", msg)))?;
+    }
+
+    for env_file in args.env_file.iter() {
+        if !env_file.is_file() {
+            return Err(err!(
+                "[--env-file]: Path '{}' is not a file.",
+                env_file.display()
+            ));
+        }
     }
 
-    // Check the root path is a directory rather than a file:
-    if !args.root.is_dir() {
-        return Err(err!(
-            "Root path is not a directory: {}",
-            args.root.display()
-        ));
+    if let Some(out_dir) = &args.out_dir {
+        config::validate_out_dir(out_dir)
+            .map_err(|e| e.modify_msg(|msg| format!("[--out-dir]: {}", msg)))?;
     }
 
     Ok(())
@@ -1,9 +1,19 @@
+use std::collections::BTreeMap;
+
+use super::undefined::UndefinedReport;
 use crate::config::Config;
 
 #[derive(Debug, serde::Serialize)]
-pub struct Debug {
-    pub config: Config,
+pub struct Debug<'a> {
+    pub config: &'a Config,
     pub written: Vec<String>,
     pub identical: Vec<String>,
+    // Templates skipped as partials, only rendered through includes:
+    pub partials: Vec<String>,
+    // The index of the [engine.overrides] entry applied to each template, templates using the base syntax are omitted:
+    pub engine_overrides: BTreeMap<String, usize>,
     pub lockfile_modified: bool,
+    pub exposed_env: Vec<String>,
+    pub extends_chain: Vec<String>,
+    pub undefined: Option<UndefinedReport>,
 }
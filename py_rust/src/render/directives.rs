@@ -0,0 +1,130 @@
+use bitbazaar::{err, errors::TracedErr};
+use minijinja::{context, value::ValueKind, Environment, Value};
+
+/// Introduces the directives inside the first line comment, e.g. `{# etch: allow_undefined #}`.
+static DIRECTIVE_PREFIX: &str = "etch:";
+
+static KNOWN_DIRECTIVES: &[&str] = &["allow_undefined", "for <name> in <list>"];
+
+/// Per-template behaviour switched on by a directive comment on the template's first line.
+#[derive(Debug, Default)]
+pub struct Directives {
+    pub allow_undefined: bool,
+    pub matrix: Option<Matrix>,
+}
+
+/// Render the template once per item of a context list, e.g. `{# etch: for env in environments #}`.
+#[derive(Debug)]
+pub struct Matrix {
+    pub var: String,
+    pub list: String,
+}
+
+impl Matrix {
+    fn parse(rel_path: &str, directive: &str) -> Result<Self, TracedErr> {
+        let parsed = directive
+            .strip_prefix("for ")
+            .and_then(|rest| rest.split_once(" in "))
+            .map(|(var, list)| (var.trim(), list.trim()))
+            .filter(|(var, list)| {
+                !list.is_empty()
+                    && var.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+        match parsed {
+            Some((var, list)) => Ok(Self {
+                var: var.to_string(),
+                list: list.to_string(),
+            }),
+            None => Err(err!(
+                "[{}]: Invalid etch directive '{}', expected 'for <name> in <list>'.",
+                rel_path,
+                directive
+            )),
+        }
+    }
+
+    /// Evaluate the list expression, the same context as the template is available.
+    pub fn items(&self, rel_path: &str, env: &Environment) -> Result<Vec<Value>, TracedErr> {
+        let list = env
+            .compile_expression(&self.list)
+            .and_then(|expr| expr.eval(context! {}))
+            .map_err(|e| {
+                err!(
+                    "[{}]: Failed to evaluate the matrix list '{}': {}",
+                    rel_path,
+                    self.list,
+                    e
+                )
+            })?;
+        if list.kind() != ValueKind::Seq {
+            return Err(err!(
+                "[{}]: The matrix list '{}' should be a list, got: {}",
+                rel_path,
+                self.list,
+                list.kind()
+            ));
+        }
+        Ok(list
+            .try_iter()
+            .map_err(|e| err!("[{}]: {}", rel_path, e))?
+            .collect())
+    }
+}
+
+/// Parse the directives from the first line of a template, using the configured comment delimiters.
+/// When present, returns them with the source minus the directive line, so it doesn't leave an empty line in the output.
+pub fn parse(
+    rel_path: &str,
+    source: &str,
+    comment_start: &str,
+    comment_end: &str,
+) -> Result<Option<(Directives, String)>, TracedErr> {
+    let (first_line, rest) = match source.find('\n') {
+        Some(index) => (&source[..index], &source[index + 1..]),
+        None => (source, ""),
+    };
+
+    let Some(inner) = first_line
+        .trim()
+        .strip_prefix(comment_start)
+        .and_then(|line| line.strip_suffix(comment_end))
+        .and_then(|inner| inner.trim().strip_prefix(DIRECTIVE_PREFIX))
+    else {
+        return Ok(None);
+    };
+
+    let mut directives = Directives::default();
+    let mut remaining = inner.trim();
+    while !remaining.is_empty() {
+        // The list can contain commas, so a matrix directive takes the rest of the line:
+        let (directive, next) = if remaining.starts_with("for ") {
+            (remaining, "")
+        } else {
+            remaining.split_once(',').unwrap_or((remaining, ""))
+        };
+        remaining = next.trim();
+        let directive = directive.trim();
+        match directive {
+            "" => continue,
+            "allow_undefined" => directives.allow_undefined = true,
+            matrix if matrix.starts_with("for ") => {
+                directives.matrix = Some(Matrix::parse(rel_path, matrix)?)
+            }
+            unknown => {
+                return Err(err!(
+                    "[{}]: Unknown etch directive '{}', expected one of: {}.",
+                    rel_path,
+                    unknown,
+                    KNOWN_DIRECTIVES
+                        .iter()
+                        .map(|d| format!("'{}'", d))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        }
+    }
+
+    Ok(Some((directives, rest.to_string())))
+}
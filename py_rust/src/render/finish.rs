@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use bitbazaar::{err, errors::TracedErr, timeit};
+use log::{debug, info};
+
+use crate::{config::FinishCommand, utils::cmd::run_cmd_in};
+
+/// Run the finish commands after all templates have been written, exposing what was written through env vars.
+pub fn run_finish_commands(
+    commands: &[FinishCommand],
+    written: &[String],
+) -> Result<(), TracedErr> {
+    let written_count = written.len().to_string();
+    let written_files = written.join("\n");
+    let envs = [
+        ("ETCH_WRITTEN_COUNT", written_count.as_str()),
+        ("ETCH_WRITTEN_FILES", written_files.as_str()),
+    ];
+
+    for command in commands {
+        if command.only_if_written() && written.is_empty() {
+            debug!(
+                "Skipping finish command '{}' as no templates were written.",
+                command.command()
+            );
+            continue;
+        }
+
+        info!("Running command: {}", command.command());
+        let cmd_out = timeit!(format!("Finish cmd: {}", command.command()).as_str(), {
+            run_cmd_in(command.command(), command.cwd().map(Path::new), &envs)
+        })?;
+
+        info!("{}", cmd_out.stdout);
+
+        if cmd_out.code != 0 {
+            return Err(err!(
+                "Finish command '{}' returned non zero exit code: {}\n{}",
+                command.command(),
+                cmd_out.code,
+                cmd_out.stderr
+            ));
+        }
+    }
+
+    Ok(())
+}
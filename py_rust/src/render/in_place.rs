@@ -0,0 +1,184 @@
+use std::{collections::HashSet, ops::Range, path::PathBuf};
+
+use bitbazaar::{err, errors::TracedErr};
+use globset::GlobMatcher;
+use ignore::WalkBuilder;
+use log::{debug, warn};
+
+use super::{junk::JunkFilter, template::Template, walker::MarkerMatcher};
+use crate::{args::RenderCommand, config::InPlace};
+
+/// A file containing regions rendered in place, with the [[in_place]] entry it matched.
+pub struct InPlaceFile {
+    pub template: Template,
+    pub entry: usize,
+}
+
+/// A region's template, and the byte range of its current output in the file.
+#[derive(Debug)]
+pub struct Region {
+    pub template: String,
+    pub output: Range<usize>,
+}
+
+/// Find the files matching the [[in_place]] globs, the first matching entry wins.
+///
+/// Templates and the outputs of templates are skipped, they're already rendered as a whole.
+pub fn find(
+    render_args: &RenderCommand,
+    walker: WalkBuilder,
+    junk: &JunkFilter,
+    marker: &MarkerMatcher,
+    in_place: &[InPlace],
+    templates: &[Template],
+) -> Result<Vec<InPlaceFile>, TracedErr> {
+    let matchers = in_place
+        .iter()
+        .map(|entry| entry.matcher())
+        .collect::<Result<Vec<GlobMatcher>, TracedErr>>()?;
+    let outputs = templates
+        .iter()
+        .map(|template| template.out_path.clone())
+        .collect::<HashSet<PathBuf>>();
+
+    let mut files = vec![];
+    for entry in walker.build() {
+        let entry = entry.map_err(super::walker::walk_error)?;
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+        let filename = entry.file_name().to_string_lossy();
+        if junk.is_junk(&filename) || marker.get_match(&filename).is_some() {
+            continue;
+        }
+
+        // Paths that aren't valid UTF-8 can't be matched by the globs, nor tracked in the lockfile:
+        let rel = entry
+            .path()
+            .strip_prefix(&render_args.root)
+            .unwrap_or(entry.path());
+        if super::template::portable_path(rel).is_none() {
+            warn!(
+                "Skipping in-place file '{}', its path isn't valid UTF-8 so can't be tracked in the lockfile.",
+                entry.path().display()
+            );
+            continue;
+        }
+        let template = Template::new(
+            render_args.root.clone(),
+            entry.path().to_path_buf(),
+            entry.path().to_path_buf(),
+        )?;
+        let Some(index) = matchers
+            .iter()
+            .position(|matcher| matcher.is_match(&template.rel_path))
+        else {
+            continue;
+        };
+        if outputs.contains(&template.out_path) {
+            warn!(
+                "Skipping in-place file '{}', it's the output of a template.",
+                template.rel_path
+            );
+            continue;
+        }
+        files.push(InPlaceFile {
+            template,
+            entry: index,
+        });
+    }
+
+    debug!("Found {} files to render in place.", files.len());
+    Ok(files)
+}
+
+/// The 1-based line a byte offset is on, for pointing at markers in errors.
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Find the regions in a file, erroring on unbalanced or nested markers.
+pub fn parse(rel_path: &str, source: &str, entry: &InPlace) -> Result<Vec<Region>, TracedErr> {
+    let mut regions = vec![];
+    let mut pos = 0;
+    loop {
+        let next_start = source[pos..].find(&entry.start).map(|idx| pos + idx);
+        let next_end = source[pos..].find(&entry.end).map(|idx| pos + idx);
+        let start = match (next_start, next_end) {
+            (None, None) => break,
+            (Some(start), Some(end)) if start < end => start,
+            (Some(start), None) => start,
+            (_, Some(end)) => {
+                return Err(err!(
+                    "[{}]: In-place end marker '{}' on line {} has no start marker.",
+                    rel_path,
+                    entry.end,
+                    line_of(source, end)
+                ))
+            }
+        };
+
+        let template_start = start + entry.start.len();
+        let template_end = source[template_start..]
+            .find(&entry.template_end)
+            .map(|idx| template_start + idx)
+            .ok_or_else(|| {
+                err!(
+                    "[{}]: In-place start marker on line {} is missing '{}' to end its template.",
+                    rel_path,
+                    line_of(source, start),
+                    entry.template_end
+                )
+            })?;
+        let output_start = template_end + entry.template_end.len();
+        let end = source[output_start..]
+            .find(&entry.end)
+            .map(|idx| output_start + idx)
+            .ok_or_else(|| {
+                err!(
+                    "[{}]: In-place start marker on line {} is never closed by an end marker '{}'.",
+                    rel_path,
+                    line_of(source, start),
+                    entry.end
+                )
+            })?;
+        if let Some(nested) = source[template_start..end]
+            .find(&entry.start)
+            .map(|idx| template_start + idx)
+        {
+            return Err(err!(
+                "[{}]: Nested in-place start marker on line {}, inside the region started on line {}.",
+                rel_path,
+                line_of(source, nested),
+                line_of(source, start)
+            ));
+        }
+
+        let template = &source[template_start..template_end];
+        regions.push(Region {
+            template: match &entry.line_prefix {
+                Some(prefix) => template
+                    .split_inclusive('\n')
+                    .map(|line| line.strip_prefix(prefix.as_str()).unwrap_or(line))
+                    .collect(),
+                None => template.to_string(),
+            },
+            output: output_start..end,
+        });
+        pos = end + entry.end.len();
+    }
+    Ok(regions)
+}
+
+/// Replace each region's output with its rendered template, everything else is kept as is.
+pub fn splice(source: &str, regions: &[Region], rendered: &[String]) -> String {
+    let mut spliced = String::with_capacity(source.len());
+    let mut pos = 0;
+    for (region, output) in regions.iter().zip(rendered) {
+        spliced.push_str(&source[pos..region.output.start]);
+        spliced.push_str(output);
+        pos = region.output.end;
+    }
+    spliced.push_str(&source[pos..]);
+    spliced
+}
@@ -0,0 +1,53 @@
+use bitbazaar::{err, errors::TracedErr};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// OS and editor droppings that should never be treated as templates, matched against the filename only.
+/// E.g. a `config.etch.yml~` editor backup would otherwise render over `config.yml`.
+pub static JUNK_PATTERNS: &[&str] = &[
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    "*.swp",
+    "*.swo",
+    "*~",
+    ".#*",
+    "#*#",
+    "*.bak",
+];
+
+pub struct JunkFilter {
+    globset: Option<GlobSet>,
+}
+
+impl JunkFilter {
+    /// When disabled nothing is considered junk, user patterns extend the builtins.
+    pub fn new(ignore_junk: bool, junk_patterns: &[String]) -> Result<Self, TracedErr> {
+        if !ignore_junk {
+            return Ok(Self { globset: None });
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in JUNK_PATTERNS
+            .iter()
+            .copied()
+            .chain(junk_patterns.iter().map(String::as_str))
+        {
+            builder.add(
+                Glob::new(pattern)
+                    .map_err(|e| err!("Invalid junk pattern '{}': '{}'", pattern, e))?,
+            );
+        }
+
+        Ok(Self {
+            globset: Some(builder.build()?),
+        })
+    }
+
+    pub fn is_junk(&self, filename: &str) -> bool {
+        self.globset
+            .as_ref()
+            .map(|globset| globset.is_match(filename))
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,107 @@
+/// The errors from the outermost template down to the culprit, an include's error is nested as the source of the outer one.
+pub fn error_chain(e: &minijinja::Error) -> Vec<&minijinja::Error> {
+    std::iter::successors(Some(e), |e| {
+        std::error::Error::source(*e).and_then(|source| source.downcast_ref())
+    })
+    .collect()
+}
+
+/// `name:line`, for pointing at where in a template an error happened.
+pub fn location(e: &minijinja::Error) -> String {
+    match (e.name(), e.line()) {
+        (Some(name), Some(line)) => format!("{}:{}", name, line),
+        (Some(name), None) => name.to_string(),
+        _ => "<unknown>".to_string(),
+    }
+}
+
+/// The source of the template an error happened in.
+/// Compile errors carry it themselves, the template won't be in the environment when it failed to compile.
+pub fn error_source(env: &minijinja::Environment, e: &minijinja::Error) -> Option<String> {
+    e.template_source()
+        .map(|source| source.to_string())
+        .or_else(|| {
+            e.name()
+                .and_then(|name| env.get_template(name).ok())
+                .map(|tmpl| tmpl.source().to_string())
+        })
+}
+
+/// The 1-based column a byte offset into the source is at.
+fn column(source: &str, offset: usize) -> Option<usize> {
+    let before = source.get(..offset)?;
+    let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    Some(before[line_start..].chars().count() + 1)
+}
+
+/// The line before and the line itself with a line number gutter, the range underlined where it starts on that line.
+pub fn snippet(source: &str, line: usize, range: Option<std::ops::Range<usize>>) -> Vec<String> {
+    let first = line.saturating_sub(1).max(1);
+    let gutter = line.to_string().len();
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .skip(first - 1)
+        .take(line + 1 - first)
+        .map(|(num, text)| format!("{:>gutter$} | {}", num + 1, text, gutter = gutter))
+        .collect::<Vec<_>>();
+
+    let underline = range.and_then(|range| {
+        if source.get(..range.start)?.matches('\n').count() + 1 != line {
+            return None;
+        }
+        let col = column(source, range.start)?;
+        // Multi-line expressions are only underlined to the end of the first line:
+        let width = source
+            .get(range)?
+            .lines()
+            .next()
+            .map_or(0, |first| first.chars().count())
+            .max(1);
+        Some(format!(
+            "{:>gutter$} | {}{}",
+            "",
+            " ".repeat(col - 1),
+            "^".repeat(width),
+            gutter = gutter
+        ))
+    });
+    lines.extend(underline);
+    lines
+}
+
+/// Where a render or compile error happened, for errors without a more specific explanation:
+/// the culprit's template, line and column (following includes down to it), then the offending lines with the failing part underlined.
+///
+/// None when minijinja didn't record where the error happened.
+pub fn describe_error_location(
+    env: &minijinja::Environment,
+    e: &minijinja::Error,
+) -> Option<String> {
+    let chain = error_chain(e);
+    let culprit = chain.last()?;
+    let line = culprit.line()?;
+    let source = error_source(env, culprit)?;
+
+    let mut header = format!("At {}", location(culprit));
+    if let Some(col) = culprit
+        .range()
+        .and_then(|range| column(&source, range.start))
+    {
+        header.push_str(&format!(":{}", col));
+    }
+
+    // The includes leading to the culprit, outermost first:
+    let included_from = chain[..chain.len() - 1]
+        .iter()
+        .map(|e| location(e))
+        .collect::<Vec<_>>();
+    if !included_from.is_empty() {
+        header.push_str(&format!(" (included from {})", included_from.join(" -> ")));
+    }
+    header.push(':');
+
+    let mut lines = vec![header];
+    lines.extend(snippet(&source, line, culprit.range()));
+    Some(lines.join("\n"))
+}
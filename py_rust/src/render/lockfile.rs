@@ -1,50 +1,277 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs,
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
 };
 
-use bitbazaar::errors::TracedErr;
-use log::{debug, warn};
+use bitbazaar::{err, errors::TracedErr};
+use log::{debug, info, warn};
 
-use super::template;
+use super::{in_place::Region, template};
+use crate::{args::RenderCommand, config::HashAlgo};
 pub static LOCKFILE_NAME: &str = ".etch.lock";
+pub static DEFAULT_VARIANT: &str = "default";
+
+/// Where the lockfile lives, the --lockfile flag beats the lockfile config option, otherwise it's at the root.
+pub fn path(render_args: &RenderCommand, configured: Option<&Path>) -> PathBuf {
+    render_args
+        .lockfile
+        .as_deref()
+        .or(configured)
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| render_args.root.join(LOCKFILE_NAME))
+}
+
+/// The variant when --variant isn't given, derived from the profile and out_dir so e.g. rendering each profile
+/// into its own out_dir doesn't churn a shared namespace, like 'prod' or 'prod@dist'. The default variant when neither is set.
+pub fn derive_variant(root: &Path, profile: Option<&str>, out_dir: Option<&Path>) -> String {
+    let profile = profile.unwrap_or(DEFAULT_VARIANT);
+    match out_dir {
+        Some(out_dir) => {
+            let out_dir = out_dir.strip_prefix(root).unwrap_or(out_dir);
+            format!(
+                "{}@{}",
+                profile,
+                template::portable_path(out_dir).unwrap_or_else(|| out_dir.display().to_string())
+            )
+        }
+        None => profile.to_string(),
+    }
+}
+
+/// The absolute lockfile path, resolved through its directory as the lockfile itself might not exist yet.
+pub fn canonical_path(path: &Path) -> Option<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Some(dir.canonicalize().ok()?.join(path.file_name()?))
+}
+
+/// Give the output the same permissions as its template, e.g. so a generated shell script stays executable.
+/// The output is always kept writable by its owner, otherwise a read-only template would block the next rewrite.
+#[cfg(unix)]
+fn copy_permissions(template_path: &Path, out_path: &Path) -> Result<(), TracedErr> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(template_path)?.permissions().mode() | 0o200;
+    let out_permissions = fs::metadata(out_path)?.permissions();
+    if out_permissions.mode() & 0o7777 != mode & 0o7777 {
+        debug!(
+            "Setting permissions of '{}' to {:o} to match its template.",
+            out_path.display(),
+            mode & 0o7777
+        );
+        fs::set_permissions(out_path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_permissions(_template_path: &Path, _out_path: &Path) -> Result<(), TracedErr> {
+    Ok(())
+}
+
+/// Whether the render saw every template, or only a subset e.g. with --only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    Full,
+    Filtered,
+}
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct Contents {
     version: String,
-    // The relative filepath to the hashed contents:
-    files: HashMap<String, String>,
+    // What each variant was last fully rendered with, per variant as e.g. a profile changes the resolved config.
+    // Missing for variants from before it was tracked, they adopt the current hashes on their next full render:
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    rendered_with: HashMap<String, RenderedWith>,
+    // The single hashes from before they were tracked per variant, only read to migrate them into rendered_with:
+    #[serde(default, skip_serializing)]
+    config_hash: Option<String>,
+    #[serde(default, skip_serializing)]
+    extension_hashes: BTreeMap<String, String>,
+    // Lockfiles from before the algorithm was configurable are fnv1a, only written when different to keep the default format stable:
+    #[serde(
+        default = "default_hash_algo",
+        skip_serializing_if = "HashAlgo::is_fnv1a"
+    )]
+    hash_algo: HashAlgo,
+    // The variant to the relative filepath to the hashes of its output and source:
+    files: HashMap<String, HashMap<String, TrackedFile>>,
+}
+
+/// The hashes of what a variant's outputs were rendered with, any change rewrites all its outputs.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct RenderedWith {
+    config_hash: String,
+    // Hashes of the custom extension files, their logic affects the outputs just like the config:
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    extension_hashes: BTreeMap<String, String>,
+}
+
+/// The hashes tracked per template, the source hash tells a changed template apart from a change in what it renders with.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "TrackedFileFormat")]
+struct TrackedFile {
+    output_hash: String,
+    // Missing for entries from before the source was tracked, filled in on their next render:
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_hash: Option<String>,
+    // Only when the output can move without the template changing, e.g. an out_dir or a templated filename, so moving it rewrites rather than finding it identical:
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out_path: Option<String>,
+}
+
+// Entries used to be just the output hash, both are accepted so existing lockfiles keep working:
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum TrackedFileFormat {
+    OutputOnly(String),
+    Hashes {
+        output_hash: String,
+        #[serde(default)]
+        source_hash: Option<String>,
+        #[serde(default)]
+        out_path: Option<String>,
+    },
+}
+
+impl From<TrackedFileFormat> for TrackedFile {
+    fn from(format: TrackedFileFormat) -> Self {
+        match format {
+            TrackedFileFormat::OutputOnly(output_hash) => Self {
+                output_hash,
+                source_hash: None,
+                out_path: None,
+            },
+            TrackedFileFormat::Hashes {
+                output_hash,
+                source_hash,
+                out_path,
+            } => Self {
+                output_hash,
+                source_hash,
+                out_path,
+            },
+        }
+    }
+}
+
+// The lockfile format from before variants were introduced, migrated into the default variant on load:
+#[derive(Debug, serde::Deserialize)]
+struct LegacyContents {
+    version: String,
+    files: HashMap<String, TrackedFile>,
+}
+
+fn default_hash_algo() -> HashAlgo {
+    HashAlgo::Fnv1a
 }
 
 impl Contents {
-    pub fn default() -> Self {
+    pub fn new(hash_algo: HashAlgo) -> Self {
         Self {
             version: env!("CARGO_PKG_VERSION").to_string(),
+            rendered_with: HashMap::new(),
+            config_hash: None,
+            extension_hashes: BTreeMap::new(),
+            hash_algo,
             files: HashMap::new(),
         }
     }
 }
 
+/// Parse the lockfile contents, transparently migrating the legacy flat format into the default variant,
+/// and a single config hash into each variant it was rendered for.
+///
+/// Returns the contents and whether a migration happened.
+fn parse_contents(str_contents: &str) -> Result<(Contents, bool), serde_json::Error> {
+    match serde_json::from_str::<Contents>(str_contents) {
+        Ok(mut contents) => match contents.config_hash.take() {
+            Some(config_hash) => {
+                debug!("Migrating the lockfile's config hash into each variant.");
+                let rendered_with = RenderedWith {
+                    config_hash,
+                    extension_hashes: std::mem::take(&mut contents.extension_hashes),
+                };
+                contents.rendered_with = contents
+                    .files
+                    .keys()
+                    .map(|variant| (variant.clone(), rendered_with.clone()))
+                    .collect();
+                Ok((contents, true))
+            }
+            None => Ok((contents, false)),
+        },
+        Err(err) => match serde_json::from_str::<LegacyContents>(str_contents) {
+            Ok(legacy) => {
+                debug!(
+                    "Migrating flat lockfile into the '{}' variant.",
+                    DEFAULT_VARIANT
+                );
+                Ok((
+                    Contents {
+                        version: legacy.version,
+                        rendered_with: HashMap::new(),
+                        config_hash: None,
+                        extension_hashes: BTreeMap::new(),
+                        hash_algo: HashAlgo::Fnv1a,
+                        files: HashMap::from([(DEFAULT_VARIANT.to_string(), legacy.files)]),
+                    },
+                    true,
+                ))
+            }
+            Err(_) => Err(err),
+        },
+    }
+}
+
 pub struct Lockfile {
     filepath: PathBuf,
+    variant: String,
+    strict: bool,
+    // Every template path tracked before this run in any variant, kept even when the contents are reset:
+    prior_template_paths: HashSet<String>,
     seen_template_paths: HashSet<String>,
+    // With --no-lockfile every output is written and nothing is read from or saved to disk:
+    disabled: bool,
+    // Set when the config or a custom extension changed since the last render, every output is rewritten even if its hash matches:
+    rewrite_all: bool,
+    config_hash: String,
+    extension_hashes: BTreeMap<String, String>,
     contents: Contents,
     pub modified: bool,
 }
 
 impl Lockfile {
-    pub fn load(root: PathBuf, force: bool) -> Self {
-        let filepath = root.join(LOCKFILE_NAME);
+    /// Load the lockfile, starting afresh when it's missing or can't be used.
+    ///
+    /// Under strict, a lockfile that exists but can't be read or parsed errors instead, rather than silently rewriting everything.
+    /// A missing lockfile is still a fresh start, any outputs it should have tracked then error as untracked overwrites.
+    pub fn load(
+        filepath: PathBuf,
+        force: bool,
+        strict: bool,
+        hash_algo: HashAlgo,
+        config_hash: String,
+        extension_hashes: BTreeMap<String, String>,
+        variant: String,
+    ) -> Result<Self, TracedErr> {
         let mut modified = false;
+        let mut prior_template_paths = HashSet::new();
 
-        let contents = if force {
-            modified = true;
-            warn!("Cli forced lockfile override.");
-            Contents::default()
-        } else {
+        let mut contents = {
             let str_contents = match fs::read_to_string(&filepath) {
                 Ok(contents) => Some(contents),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) if strict => {
+                    return Err(err!(
+                        "Failed to read the lockfile at '{}': {}. Refusing to start afresh under --strict.",
+                        filepath.display(),
+                        err
+                    ))
+                }
                 Err(err) => {
                     warn!(
                         "Starting lockfile afresh, failed to read existing at '{}': {}",
@@ -56,8 +283,17 @@ impl Lockfile {
             };
 
             match str_contents {
-                Some(str_contents) => match serde_json::from_str::<Contents>(&str_contents) {
-                    Ok(contents) => {
+                Some(str_contents) => match parse_contents(&str_contents) {
+                    Ok((contents, migrated)) => {
+                        prior_template_paths.extend(
+                            contents
+                                .files
+                                .values()
+                                .flat_map(|files| files.keys().cloned()),
+                        );
+                        if migrated {
+                            modified = true;
+                        }
                         if contents.version != env!("CARGO_PKG_VERSION") {
                             warn!(
                                 "Starting lockfile afresh, version mismatch: {} != {}",
@@ -65,7 +301,15 @@ impl Lockfile {
                                 env!("CARGO_PKG_VERSION")
                             );
                             modified = true;
-                            Contents::default()
+                            Contents::new(hash_algo)
+                        } else if contents.hash_algo != hash_algo {
+                            // Hashes from different algorithms can't be compared, so every output is rewritten:
+                            warn!(
+                                "Starting lockfile afresh, hash algorithm changed: {:?} != {:?}",
+                                contents.hash_algo, hash_algo
+                            );
+                            modified = true;
+                            Contents::new(hash_algo)
                         } else {
                             debug!(
                                 "Loaded lockfile from '{}' successfully.",
@@ -74,6 +318,13 @@ impl Lockfile {
                             contents
                         }
                     }
+                    Err(err) if strict => {
+                        return Err(err!(
+                            "Failed to parse the lockfile at '{}': {}. Refusing to start afresh under --strict, fix or delete it.",
+                            filepath.display(),
+                            err
+                        ))
+                    }
                     Err(err) => {
                         warn!(
                             "Starting lockfile afresh, failed to parse existing at '{}': {}",
@@ -81,7 +332,7 @@ impl Lockfile {
                             err
                         );
                         modified = true;
-                        Contents::default()
+                        Contents::new(hash_algo)
                     }
                 },
                 None => {
@@ -90,83 +341,389 @@ impl Lockfile {
                         filepath.display()
                     );
                     modified = true;
-                    Contents::default()
+                    Contents::new(hash_algo)
                 }
             }
         };
 
-        Self {
+        // The entries are kept rather than reset, so templates not rendered this run (e.g. with --only) stay tracked.
+        // Only the active variant's hashes are compared, other variants can render with a different config.
+        // Variants from before the hashes were tracked just adopt the current ones when synced:
+        let rendered_with = contents.rendered_with.get(&variant);
+        let config_changed = rendered_with.is_some_and(|prior| prior.config_hash != config_hash);
+        let extensions_changed =
+            rendered_with.is_some_and(|prior| prior.extension_hashes != extension_hashes);
+        if config_changed {
+            info!(
+                "Config changed since the last render of variant '{}', rewriting all outputs.",
+                variant
+            );
+        } else if extensions_changed {
+            info!(
+                "Custom extensions changed since the last render of variant '{}', rewriting all outputs.",
+                variant
+            );
+        }
+
+        // Only the active variant is reset, other variants' entries are still valid:
+        if force {
+            modified = true;
+            warn!("Cli forced lockfile override for variant '{}'.", variant);
+            contents.files.remove(&variant);
+            contents.rendered_with.remove(&variant);
+        }
+
+        Ok(Self {
             filepath,
+            variant,
+            strict,
             contents,
+            prior_template_paths,
             seen_template_paths: HashSet::new(),
+            disabled: false,
+            rewrite_all: config_changed || extensions_changed,
+            config_hash,
+            extension_hashes,
             modified,
+        })
+    }
+
+    /// A lockfile that's never read or saved, every template is written as if it had changed.
+    pub fn disabled(hash_algo: HashAlgo) -> Self {
+        debug!("Lockfile disabled, writing all templates.");
+        Self {
+            filepath: PathBuf::new(),
+            variant: DEFAULT_VARIANT.to_string(),
+            strict: false,
+            prior_template_paths: HashSet::new(),
+            seen_template_paths: HashSet::new(),
+            disabled: true,
+            rewrite_all: false,
+            config_hash: String::new(),
+            extension_hashes: BTreeMap::new(),
+            contents: Contents::new(hash_algo),
+            modified: false,
         }
     }
 
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
     /// After compiling a template run this, it will update the lockfile and write the compiled template to disk.
     ///
+    /// When spliced is given the output had managed regions, it's written instead, but the compiled template is still what's hashed,
+    /// so hand edits outside the regions never cause a rewrite.
+    ///
     /// Returns true when added, false when identical already present in lockfile.
     pub fn add_template(
         &mut self,
         template: &template::Template,
+        source: &str,
         compiled: String,
+        spliced: Option<String>,
     ) -> Result<bool, TracedErr> {
-        // To prevent bloating the filesize and readability of the lockfile, only include a hash of the compiled template rather than the full contents.
-        let hashed = bitbazaar::hash::fnv1a(compiled.as_bytes()).to_string();
-        let identical = if let Some(old_hashed) = self.contents.files.get(&template.rel_path) {
-            if old_hashed != &hashed {
+        let key = template.lockfile_key();
+        // To prevent bloating the filesize and readability of the lockfile, only include hashes of the source and compiled template rather than the full contents.
+        let tracked = TrackedFile {
+            output_hash: self.contents.hash_algo.hash(compiled.as_bytes()),
+            source_hash: Some(self.contents.hash_algo.hash(source.as_bytes())),
+            out_path: template.tracked_out_path.clone(),
+        };
+        let identical = if self.disabled {
+            false
+        } else if let Some(old_tracked) = self
+            .contents
+            .files
+            .get(&self.variant)
+            .and_then(|files| files.get(&key))
+        {
+            if self.rewrite_all {
+                debug!(
+                    "Template '{}' was rendered with a different config, rewriting.",
+                    key
+                );
+                false
+            } else if old_tracked.out_path != tracked.out_path {
+                info!(
+                    "Template '{}' now renders to '{}', the old output{} is left in place.",
+                    key,
+                    template.out_path.display(),
+                    old_tracked
+                        .out_path
+                        .as_ref()
+                        .map(|old| format!(" '{}'", old))
+                        .unwrap_or_default()
+                );
+                self.modified = true;
+                false
+            } else if old_tracked.output_hash != tracked.output_hash {
                 debug!(
-                    "Template '{}' has changed, updating lockfile and rewriting.",
-                    template.rel_path
+                    "Template '{}' has changed ({}), updating lockfile and rewriting.",
+                    key,
+                    if old_tracked.source_hash == tracked.source_hash {
+                        "the template is unchanged, so from what it renders with"
+                    } else {
+                        "the template was edited"
+                    }
                 );
                 self.modified = true;
                 false
+            } else if old_tracked.source_hash != tracked.source_hash {
+                debug!(
+                    "Template '{}' renders the same but its source hash differs, updating the lockfile.",
+                    key
+                );
+                self.insert(key.clone(), tracked.clone());
+                true
+            } else if spliced.is_none()
+                && self.shared_with_other_variant(&key, &tracked)
+                && fs::read(&template.out_path)
+                    .map(|contents| self.contents.hash_algo.hash(&contents))
+                    .ok()
+                    != Some(tracked.output_hash.clone())
+            {
+                debug!(
+                    "Template '{}' has identical hash in lockfile, but its output was since written by another variant, rewriting.",
+                    key
+                );
+                false
             } else {
                 debug!(
                     "Template '{}' has identical hash in lockfile, skipping.",
-                    template.rel_path
+                    key
                 );
                 true
             }
         } else {
             debug!(
                 "Template '{}' didn't exist in lockfile prior, updating lockfile and rewriting.",
-                template.rel_path
+                key
             );
             self.modified = true;
             false
         };
 
+        // An existing output that etch never wrote is likely maintained by hand, so overwriting it is probably a mistake.
+        // Without a lockfile there's no record of what etch wrote, so it can't be told.
+        // Managed regions are marked for etch by hand, so splicing into them is expected:
+        if !identical
+            && !self.disabled
+            && spliced.is_none()
+            && template.out_path.exists()
+            && !self.prior_template_paths.contains(&key)
+        {
+            if self.strict {
+                return Err(err!(
+                    "Template '{}' would overwrite '{}', which exists but was never written by etch. Refusing under --strict, remove the file first if it should be generated.",
+                    key,
+                    template.out_path.display()
+                ));
+            }
+            warn!(
+                "Template '{}' is overwriting '{}', which existed but was never written by etch.",
+                key,
+                template.out_path.display()
+            );
+        }
+
         // Only update if not already identical:
         if !identical {
-            self.modified = true;
-            self.contents
-                .files
-                .insert(template.rel_path.clone(), hashed);
+            if !self.disabled {
+                self.insert(key.clone(), tracked);
+            }
+
+            // Outputs mirrored into an out_dir might not have a directory yet:
+            if let Some(dir) = template.out_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
 
             // Write the compiled file:
-            fs::write(template.out_path.clone(), compiled)?;
+            fs::write(template.out_path.clone(), spliced.unwrap_or(compiled))?;
+        }
+
+        // Checked even when identical, so e.g. making a template executable carries over without needing --force:
+        if template.out_path.exists() {
+            copy_permissions(&template.path, &template.out_path)?;
         }
 
-        self.seen_template_paths.insert(template.rel_path.clone());
+        self.seen_template_paths.insert(key);
 
         Ok(!identical)
     }
 
-    /// After all compiled templates have been added, run this to close out and save the lockfile.
-    pub fn sync(&mut self) -> Result<(), TracedErr> {
-        let before_len = self.contents.files.len();
-        // Anything which isn't in the new compiled set should be removed from the lockfile:
-        self.contents
-            .files
-            .retain(|template_path, _| self.seen_template_paths.contains(template_path));
+    /// Whether another variant also tracks the template rendering to the same output, so they overwrite each other.
+    fn shared_with_other_variant(&self, key: &str, tracked: &TrackedFile) -> bool {
+        self.contents.files.iter().any(|(variant, files)| {
+            *variant != self.variant
+                && files
+                    .get(key)
+                    .is_some_and(|other| other.out_path == tracked.out_path)
+        })
+    }
+
+    /// Stop tracking a template that's become a partial, deleting the outputs it rendered before.
+    ///
+    /// An output that's changed since etch wrote it is left in place, it might have been edited by hand.
+    pub fn drop_partial(
+        &mut self,
+        root: &Path,
+        partial: &template::Template,
+    ) -> Result<(), TracedErr> {
+        let Some(files) = self.contents.files.get_mut(&self.variant) else {
+            return Ok(());
+        };
+        // Matrix templates are tracked once per instance:
+        let instance_prefix = format!("{}[", partial.rel_path);
+        let keys = files
+            .keys()
+            .filter(|key| **key == partial.rel_path || key.starts_with(&instance_prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            let Some(tracked) = files.remove(&key) else {
+                continue;
+            };
+            self.modified = true;
+            let out_path = tracked
+                .out_path
+                .as_ref()
+                .map_or_else(|| partial.out_path.clone(), |out_path| root.join(out_path));
+            match fs::read(&out_path) {
+                Ok(contents) if self.contents.hash_algo.hash(&contents) == tracked.output_hash => {
+                    fs::remove_file(&out_path)?;
+                    info!(
+                        "Template '{}' is now a partial, removed its old output '{}'.",
+                        key,
+                        out_path.display()
+                    );
+                }
+                Ok(_) => info!(
+                    "Template '{}' is now a partial, its old output '{}' has changed since it was written so is left in place.",
+                    key,
+                    out_path.display()
+                ),
+                Err(_) => debug!(
+                    "Template '{}' is now a partial, its old output '{}' is already gone.",
+                    key,
+                    out_path.display()
+                ),
+            }
+        }
 
-        if self.contents.files.len() != before_len {
+        if files.is_empty() {
+            self.contents.files.remove(&self.variant);
+        }
+        Ok(())
+    }
+
+    /// Like add_template, for a file rendered in place. Each region is tracked under the file's path and region index,
+    /// with the region's template as its source.
+    ///
+    /// The file is its own source, so it's compared against its current contents rather than the lockfile,
+    /// which also catches regions edited by hand. Returns true when the file was rewritten.
+    pub fn add_in_place(
+        &mut self,
+        template: &template::Template,
+        regions: &[Region],
+        outputs: &[String],
+        source: &str,
+        contents: String,
+    ) -> Result<bool, TracedErr> {
+        for (index, (region, output)) in regions.iter().zip(outputs).enumerate() {
+            let key = format!("{}#{}", template.rel_path, index);
+            if !self.disabled {
+                let tracked = TrackedFile {
+                    output_hash: self.contents.hash_algo.hash(output.as_bytes()),
+                    source_hash: Some(self.contents.hash_algo.hash(region.template.as_bytes())),
+                    out_path: None,
+                };
+                let unchanged = self
+                    .contents
+                    .files
+                    .get(&self.variant)
+                    .and_then(|files| files.get(&key))
+                    == Some(&tracked);
+                if !unchanged {
+                    self.insert(key.clone(), tracked);
+                }
+            }
+            self.seen_template_paths.insert(key);
+        }
+
+        if source == contents {
             debug!(
-                "Removed {} templates from lockfile which no longer exist.",
-                before_len - self.contents.files.len()
+                "In-place file '{}' is already up to date, skipping.",
+                template.rel_path
             );
-            self.modified = true;
+            return Ok(false);
+        }
+        debug!(
+            "In-place file '{}' has changed, rewriting.",
+            template.rel_path
+        );
+        fs::write(&template.path, contents)?;
+        Ok(true)
+    }
+
+    /// Track the hashes under the active variant.
+    fn insert(&mut self, rel_path: String, tracked: TrackedFile) {
+        self.modified = true;
+        self.contents
+            .files
+            .entry(self.variant.clone())
+            .or_default()
+            .insert(rel_path, tracked);
+    }
+
+    /// After all compiled templates have been added, run this to close out and save the lockfile.
+    ///
+    /// Pruning and recording the config and extension hashes are skipped for filtered renders, templates that weren't seen might just have been filtered out.
+    /// Both only touch the active variant, other variants weren't rendered this run.
+    pub fn sync(&mut self, traversal: Traversal) -> Result<(), TracedErr> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        if traversal == Traversal::Filtered {
+            debug!("Render was filtered, skipping lockfile pruning.");
+        } else if let Some(files) = self.contents.files.get_mut(&self.variant) {
+            let before_len = files.len();
+            // Anything which isn't in the new compiled set should be removed from the lockfile:
+            files.retain(|template_path, _| self.seen_template_paths.contains(template_path));
+
+            if files.len() != before_len {
+                debug!(
+                    "Removed {} templates from lockfile variant '{}' which no longer exist.",
+                    before_len - files.len(),
+                    self.variant
+                );
+                self.modified = true;
+            }
+
+            if files.is_empty() {
+                self.contents.files.remove(&self.variant);
+            }
+        }
+
+        if traversal == Traversal::Full {
+            // Only recorded after a full render, so templates filtered out are still rewritten by the next one.
+            // A variant left with no outputs has nothing rendered with them, so its hashes are dropped too:
+            if self.contents.files.contains_key(&self.variant) {
+                let rendered_with = RenderedWith {
+                    config_hash: self.config_hash.clone(),
+                    extension_hashes: self.extension_hashes.clone(),
+                };
+                if self.contents.rendered_with.get(&self.variant) != Some(&rendered_with) {
+                    self.contents
+                        .rendered_with
+                        .insert(self.variant.clone(), rendered_with);
+                    self.modified = true;
+                }
+            } else if self.contents.rendered_with.remove(&self.variant).is_some() {
+                self.modified = true;
+            }
         }
 
         if self.modified {
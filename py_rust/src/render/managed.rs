@@ -0,0 +1,131 @@
+use std::{fs, io, ops::Range, path::Path};
+
+use bitbazaar::{err, errors::TracedErr};
+use log::debug;
+
+use crate::config::ManagedRegion;
+
+/// The output with each managed region replaced by the matching region of the compiled template.
+///
+/// None when the output should be written whole, i.e. it doesn't exist yet or contains none of the configured markers.
+/// A compiled template without markers of its own fills the output's single region.
+pub fn splice(
+    rel_path: &str,
+    out_path: &Path,
+    compiled: &str,
+    markers: &[ManagedRegion],
+) -> Result<Option<String>, TracedErr> {
+    if markers.is_empty() {
+        return Ok(None);
+    }
+    let existing = match fs::read_to_string(out_path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(err!(
+                "[{}]: Failed to read output '{}' for its managed regions: {}",
+                rel_path,
+                out_path.display(),
+                e
+            ))
+        }
+    };
+    let Some(marker) = markers
+        .iter()
+        .find(|marker| existing.contains(&marker.begin))
+    else {
+        return Ok(None);
+    };
+
+    let existing_regions = regions(rel_path, "output", &existing, marker)?;
+    let compiled_regions = regions(rel_path, "rendered template", compiled, marker)?;
+    let replacements = if compiled_regions.is_empty() {
+        vec![compiled]
+    } else {
+        compiled_regions
+            .iter()
+            .map(|region| &compiled[region.clone()])
+            .collect()
+    };
+    if replacements.len() != existing_regions.len() {
+        return Err(err!(
+            "[{}]: The output has {} managed regions between '{}' and '{}', but the template renders {}.",
+            rel_path,
+            existing_regions.len(),
+            marker.begin,
+            marker.end,
+            replacements.len()
+        ));
+    }
+
+    debug!(
+        "Splicing {} managed regions into '{}'.",
+        existing_regions.len(),
+        out_path.display()
+    );
+    let mut spliced = String::with_capacity(existing.len());
+    let mut pos = 0;
+    for (region, replacement) in existing_regions.iter().zip(replacements) {
+        spliced.push_str(&existing[pos..region.start]);
+        spliced.push_str(replacement);
+        // Keep the end marker on its own line:
+        if !replacement.is_empty() && !replacement.ends_with('\n') {
+            spliced.push('\n');
+        }
+        pos = region.end;
+    }
+    spliced.push_str(&existing[pos..]);
+    Ok(Some(spliced))
+}
+
+/// The byte ranges of the lines between each begin and end marker line, erroring on unbalanced or nested markers.
+fn regions(
+    rel_path: &str,
+    what: &str,
+    contents: &str,
+    marker: &ManagedRegion,
+) -> Result<Vec<Range<usize>>, TracedErr> {
+    let mut regions = vec![];
+    // The line number of the open region's begin marker, and where its contents start:
+    let mut open: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        let line_num = index + 1;
+        if line.contains(&marker.begin) {
+            if let Some((begin_line, _)) = open {
+                return Err(err!(
+                    "[{}]: Nested managed region begin marker in the {} on line {}, inside the region started on line {}.",
+                    rel_path,
+                    what,
+                    line_num,
+                    begin_line
+                ));
+            }
+            open = Some((line_num, offset + line.len()));
+        } else if line.contains(&marker.end) {
+            match open.take() {
+                Some((_, start)) => regions.push(start..offset),
+                None => {
+                    return Err(err!(
+                        "[{}]: Managed region end marker '{}' in the {} on line {} has no begin marker.",
+                        rel_path,
+                        marker.end,
+                        what,
+                        line_num
+                    ))
+                }
+            }
+        }
+        offset += line.len();
+    }
+    if let Some((begin_line, _)) = open {
+        return Err(err!(
+            "[{}]: Managed region begin marker in the {} on line {} is never closed by an end marker '{}'.",
+            rel_path,
+            what,
+            begin_line,
+            marker.end
+        ));
+    }
+    Ok(regions)
+}
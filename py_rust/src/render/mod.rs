@@ -1,81 +1,611 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+};
+
 use bitbazaar::{
     err,
     errors::TracedErr,
     timeit,
     timing::{format_duration, GLOBAL_TIME_RECORDER},
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use minijinja::context;
 
 mod args_validate;
 mod debug;
+mod directives;
+mod finish;
+mod in_place;
+pub mod junk;
+mod location;
 mod lockfile;
+mod managed;
+mod post_write;
 mod template;
-mod walker;
+mod undefined;
+pub mod walker;
+mod watch;
 use crate::{args::RenderCommand, config};
 
 pub fn render(render_args: RenderCommand) -> Result<bool, TracedErr> {
+    let result = render_inner(render_args);
+    // The context only belongs to this render, so a later one in the same process can't see it stale,
+    // nor can etch.context() be used once the render is over:
+    *config::PY_CONTEXT.lock() = None;
+    result
+}
+
+fn render_inner(mut render_args: RenderCommand) -> Result<bool, TracedErr> {
     args_validate::args_validate(&render_args)?;
 
-    let raw_conf = timeit!("Config processing", {
-        config::RawConfig::from_toml(&render_args)
+    // Running from a nested directory should still find the repo's config:
+    config::discover_config(&mut render_args)?;
+
+    let conf = load_config(&render_args)?;
+
+    // Each root keeps its own lockfile, so one path can't be shared between them:
+    if !render_args.extra_roots.is_empty()
+        && (render_args.lockfile.is_some() || conf.lockfile.is_some())
+    {
+        return Err(err!(
+            "A custom lockfile can't be used with multiple roots, each root keeps its own '{}'.",
+            self::lockfile::LOCKFILE_NAME
+        ));
+    }
+    // The roots' outputs would be mirrored into the same directory, clobbering each other:
+    if !render_args.extra_roots.is_empty()
+        && (render_args.out_dir.is_some() || conf.out_dir.is_some())
+    {
+        return Err(err!("An out_dir can't be used with multiple roots."));
+    }
+
+    if render_args.watch {
+        watch::watch(&render_args, conf)?;
+    } else {
+        render_with_config(&render_args, &conf)?;
+    }
+
+    Ok(true)
+}
+
+/// Read, validate and process the config, including running any context commands.
+fn load_config(render_args: &RenderCommand) -> Result<config::Config, TracedErr> {
+    let mut raw_conf = timeit!("Config processing", {
+        config::RawConfig::from_toml(render_args)
     })?;
+    // Loaded first so they win, as variables already set are never overwritten:
+    raw_conf.env_files.splice(
+        0..0,
+        render_args
+            .env_file
+            .iter()
+            .map(|path| path.display().to_string()),
+    );
 
-    let conf = timeit!("Context value extraction (including scripting)", {
+    timeit!("Context value extraction (including scripting)", {
         config::process(raw_conf)
-    })?;
+    })
+}
+
+/// What rendering a single root did, for the summary.
+struct RootRender {
+    written: Vec<PathBuf>,
+    identical: usize,
+    lockfile: &'static str,
+}
+
+/// Render every root with an already processed config, returning the output paths that were written.
+fn render_with_config(
+    render_args: &RenderCommand,
+    conf: &config::Config,
+) -> Result<Vec<PathBuf>, TracedErr> {
+    let mut renders = vec![(render_args.root.clone(), render_root(render_args, conf)?)];
+    for root in render_args.extra_roots.iter() {
+        // The config is found relative to the first root, so is made absolute for the others:
+        let root_args = RenderCommand {
+            root: root.clone(),
+            extra_roots: vec![],
+            config: render_args.root.join(&render_args.config),
+            ..render_args.clone()
+        };
+        renders.push((root.clone(), render_root(&root_args, conf)?));
+    }
+    let written = renders
+        .iter()
+        .flat_map(|(_, render)| render.written.iter().cloned())
+        .collect::<Vec<_>>();
+
+    if !conf.finish_commands.is_empty() {
+        let written_paths = written
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+        timeit!("Finish commands", {
+            finish::run_finish_commands(&conf.finish_commands, &written_paths)
+        })?;
+    }
+
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    if let [(_, render)] = renders.as_slice() {
+        info!(
+            "{} template{} written, {} identical. Lockfile {}. {} elapsed.",
+            render.written.len(),
+            plural(render.written.len()),
+            render.identical,
+            render.lockfile,
+            format_duration(GLOBAL_TIME_RECORDER.total_elapsed()?)
+        );
+    } else {
+        for (root, render) in renders.iter() {
+            info!(
+                "'{}': {} template{} written, {} identical. Lockfile {}.",
+                root.display(),
+                render.written.len(),
+                plural(render.written.len()),
+                render.identical,
+                render.lockfile
+            );
+        }
+        info!(
+            "{} template{} written across {} roots. {} elapsed.",
+            written.len(),
+            plural(written.len()),
+            renders.len(),
+            format_duration(GLOBAL_TIME_RECORDER.total_elapsed()?)
+        );
+    }
+
+    Ok(written)
+}
+
+/// Render all templates under a single root.
+fn render_root(
+    render_args: &RenderCommand,
+    conf: &config::Config,
+) -> Result<RootRender, TracedErr> {
+    // The cli flag beats the config, resolved here rather than up front as a watch can reload the config:
+    let render_args = &RenderCommand {
+        out_dir: render_args.out_dir.clone().or_else(|| conf.out_dir.clone()),
+        ..render_args.clone()
+    };
+
+    // The config's out_dir still holds outputs when overridden, e.g. of another variant, so is never searched either:
+    let mut exclude = conf.exclude.clone();
+    if let Some(config_out_dir) = conf
+        .out_dir
+        .as_deref()
+        .filter(|config_out_dir| render_args.out_dir.as_deref() != Some(*config_out_dir))
+    {
+        exclude.extend(self::walker::path_exclude(render_args, config_out_dir));
+    }
 
     let walker = timeit!("Filesystem walker creation", {
-        self::walker::create(&render_args, &conf)
+        self::walker::create(
+            render_args,
+            &exclude,
+            &conf.ignore_files,
+            conf.use_gitignore,
+            conf.follow_symlinks,
+            conf.max_depth,
+            conf.lockfile.as_deref(),
+        )
     })?;
 
-    let templates = timeit!("Traversing filesystem & identifying templates", {
-        self::walker::find_templates(&render_args, walker)
+    let junk = self::junk::JunkFilter::new(conf.ignore_junk, &conf.junk_patterns)?;
+
+    let marker = self::walker::MarkerMatcher::new(&conf.template_markers, &conf.template_matchers)?;
+
+    let walked_root = self::walker::WalkedRoot {
+        path: render_args.root.clone(),
+        walker: walker.clone(),
+    };
+
+    let self::walker::Found {
+        mut templates,
+        partials,
+    } = timeit!("Traversing filesystem & identifying templates", {
+        self::walker::find_templates(
+            render_args,
+            walker,
+            &junk,
+            &marker,
+            &conf.partial_prefix,
+            None,
+        )
     })?;
 
-    let mut lockfile = timeit!("Lockfile preparation", {
-        self::lockfile::Lockfile::load(render_args.root.clone(), render_args.force)
-    });
+    // An allowlist on top of the excludes, the filtered out templates are left untouched:
+    let only = self::walker::only_filter(&render_args.only)?;
+    if let Some(only) = &only {
+        let found = templates.len();
+        templates.retain(|template| only.is_match(&template.rel_path));
+        info!("--only matched {} of {} templates.", templates.len(), found);
+    }
+    let templates =
+        self::walker::screen_templates(templates, conf.max_template_size, conf.on_binary)?;
+    let mut in_place_files = if conf.in_place.is_empty() {
+        vec![]
+    } else {
+        let walker = self::walker::create(
+            render_args,
+            &exclude,
+            &conf.ignore_files,
+            conf.use_gitignore,
+            conf.follow_symlinks,
+            conf.max_depth,
+            conf.lockfile.as_deref(),
+        )?;
+        timeit!("Identifying files to render in place", {
+            self::in_place::find(
+                render_args,
+                walker,
+                &junk,
+                &marker,
+                &conf.in_place,
+                &templates,
+            )
+        })?
+    };
+    if let Some(only) = &only {
+        in_place_files.retain(|file| only.is_match(&file.template.rel_path));
+    }
 
-    let mut identical = Vec::new();
-    let mut written = Vec::new();
+    let config_hash = conf.hash()?;
+    let extension_hashes = conf
+        .engine
+        .extension_hashes(&render_args.root, conf.hash_algo)?;
+    let mut lockfile = timeit!("Lockfile preparation", {
+        if render_args.no_lockfile {
+            Ok(self::lockfile::Lockfile::disabled(conf.hash_algo))
+        } else {
+            self::lockfile::Lockfile::load(
+                self::lockfile::path(render_args, conf.lockfile.as_deref()),
+                render_args.force,
+                render_args.strict,
+                conf.hash_algo,
+                config_hash,
+                extension_hashes,
+                render_args.variant.clone().unwrap_or_else(|| {
+                    self::lockfile::derive_variant(
+                        &render_args.root,
+                        conf.profile.as_deref(),
+                        render_args.out_dir.as_deref(),
+                    )
+                }),
+            )
+        }
+    })?;
 
-    // Create the minijinja environment with the context.
+    // Create the minijinja environments with the context, the base syntax first then one per [engine.overrides] entry.
     // A loader is set that can automatically load templates, this means it can load the main templates, and any other "includes" in user templates too.
-    let env = timeit!("Creating rendering environment", {
-        conf.engine
-            .create_minijinja_env(&render_args.root, &conf.context)
+    let mut envs = timeit!("Creating rendering environments", {
+        std::iter::once(None)
+            .chain(conf.engine.overrides.iter().map(Some))
+            .map(|syntax_override| {
+                conf.engine.create_minijinja_env(
+                    &walked_root,
+                    &conf.context,
+                    &conf.exposed_env,
+                    conf.profile.as_deref(),
+                    &marker,
+                    syntax_override,
+                )
+            })
+            .collect::<Result<Vec<_>, TracedErr>>()
     })?;
 
-    timeit!("Rendering templates & syncing files", {
+    // Globs are validated with the config, so can't fail here:
+    let override_matchers = conf
+        .engine
+        .overrides
+        .iter()
+        .map(|syntax_override| syntax_override.matcher())
+        .collect::<Result<Vec<_>, TracedErr>>()?;
+    let applied_overrides = templates
+        .iter()
+        .filter_map(|template| {
+            config::Engine::override_index(&override_matchers, &template.rel_path)
+                .map(|index| (template.rel_path.clone(), index))
+        })
+        .collect::<BTreeMap<_, _>>();
+    // When undefined values are allowed, count them to make silently empty renders visible:
+    let undefined_tracker = if conf.engine.allow_undefined {
+        let tracker = undefined::UndefinedTracker::default();
+        for env in envs.iter_mut() {
+            tracker.install(env);
+        }
+        Some(tracker)
+    } else {
+        None
+    };
+
+    let env_for =
+        |rel_path: &str| &envs[applied_overrides.get(rel_path).map_or(0, |index| index + 1)];
+
+    // Checked up front, so every undefined variable is reported rather than just the first to fail the render:
+    if render_args.check_vars {
+        timeit!("Checking template variables", {
+            let mut total = 0;
+            for template in templates.iter() {
+                let syntax_override = applied_overrides
+                    .get(&template.rel_path)
+                    .map(|index| &conf.engine.overrides[*index]);
+                let (comment_start, comment_end) = conf.engine.comment_delimiters(syntax_override);
+                let source = fs::read_to_string(&template.path)
+                    .map_err(|e| err!("[{}]: Failed to read template: {}", template.rel_path, e))?;
+                let matrix =
+                    directives::parse(&template.rel_path, &source, &comment_start, &comment_end)?
+                        .and_then(|(directives, _)| directives.matrix);
+                let mut local_names = vec![];
+                if conf.engine.builtin_globals {
+                    local_names.extend(template::Template::BUILTIN_GLOBALS);
+                }
+                if let Some(matrix) = &matrix {
+                    local_names.push(matrix.var.as_str());
+                }
+
+                let variables = undefined::check_vars(
+                    env_for(&template.rel_path),
+                    &template.rel_path,
+                    &local_names,
+                );
+                if !variables.is_empty() {
+                    warn!(
+                        "[{}]: References undefined variable{}: {}",
+                        template.rel_path,
+                        if variables.len() == 1 { "" } else { "s" },
+                        variables.join(", ")
+                    );
+                    total += variables.len();
+                }
+            }
+            if total == 0 {
+                info!("--check-vars found no undefined variables.");
+            }
+            Ok::<_, TracedErr>(())
+        })?;
+    }
+
+    // Everything is rendered before anything is written, so a failed run leaves the filesystem untouched:
+    let rendered = timeit!("Rendering templates", {
+        let mut rendered = Vec::with_capacity(templates.len());
         for template in templates.iter() {
             debug!("Rendering template: {}", template.rel_path);
-            let tmpl = env.get_template(&template.rel_path)?;
-            let compiled = match tmpl.render(context! {}) {
-                Ok(compiled) => compiled,
-                Err(e) => return Err(err!("Failed to render template: '{}'", e)),
+            // Exposed to custom extensions through etch.current_template() for the duration of the render:
+            let _current = config::set_current_template(&template.rel_path);
+
+            // A directive comment on the first line can switch this template alone to lenient undefined handling,
+            // or render it once per item of a list:
+            let mut env = env_for(&template.rel_path);
+            let syntax_override = applied_overrides
+                .get(&template.rel_path)
+                .map(|index| &conf.engine.overrides[*index]);
+            let (comment_start, comment_end) = conf.engine.comment_delimiters(syntax_override);
+            let source = fs::read_to_string(&template.path)
+                .map_err(|e| err!("[{}]: Failed to read template: {}", template.rel_path, e))?;
+            let directed_env;
+            let mut matrix = None;
+            if let Some((directives, stripped)) =
+                directives::parse(&template.rel_path, &source, &comment_start, &comment_end)?
+            {
+                let mut directed = env.clone();
+                if directives.allow_undefined {
+                    directed.set_undefined_behavior(minijinja::UndefinedBehavior::Lenient);
+                }
+                directed
+                    .add_template_owned(template.rel_path.clone(), stripped)
+                    .map_err(|e| compile_error(&directed, &template.rel_path, &e))?;
+                directed_env = directed;
+                env = &directed_env;
+                matrix = directives.matrix;
+            }
+            let tmpl = env
+                .get_template(&template.rel_path)
+                .map_err(|e| compile_error(env, &template.rel_path, &e))?;
+
+            // Each matrix item is its own instance, told apart by its templated output name:
+            let instances = match &matrix {
+                Some(matrix) => {
+                    let items = matrix.items(&template.rel_path, env)?;
+                    if items.is_empty() {
+                        debug!(
+                            "Template '{}' has an empty matrix list '{}', nothing to render.",
+                            template.rel_path, matrix.list
+                        );
+                    }
+                    items
+                        .into_iter()
+                        .map(|item| HashMap::from([(matrix.var.as_str(), item)]))
+                        .collect()
+                }
+                None => vec![HashMap::new()],
             };
-            let is_new = lockfile.add_template(template, compiled)?;
-            if is_new {
-                written.push(template);
-            } else {
-                identical.push(template);
+            for instance_ctx in instances {
+                let mut instance = template.clone();
+                instance.render_out_name(
+                    &render_args.root,
+                    env,
+                    conf.engine.variable_delimiters(syntax_override),
+                    &instance_ctx,
+                )?;
+                if matrix.is_some() {
+                    instance.instance = instance
+                        .out_path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string());
+                }
+
+                // Per-template builtins are passed as the render context, unless opted out of:
+                let mut ctx = if conf.engine.builtin_globals {
+                    instance.builtin_globals(&conf.context)
+                } else {
+                    HashMap::new()
+                };
+                ctx.extend(instance_ctx);
+                let compiled = tmpl
+                    .render(ctx)
+                    .map_err(|e| render_error(env, conf, &template.rel_path, &e))?;
+                let compiled = conf.engine.line_endings.apply(compiled);
+                // Outputs with managed regions only have those replaced, the rest is kept as edited by hand:
+                let spliced = managed::splice(
+                    &template.rel_path,
+                    &instance.out_path,
+                    &compiled,
+                    &conf.engine.managed_regions,
+                )?;
+                rendered.push((instance, source.clone(), compiled, spliced));
+            }
+        }
+        Ok::<_, TracedErr>(rendered)
+    })?;
+
+    // Each region is rendered like a template named after the file and region index, the file is then rewritten around them:
+    let rendered_in_place = timeit!("Rendering in place", {
+        let mut rendered = Vec::with_capacity(in_place_files.len());
+        for file in in_place_files.iter() {
+            let template = &file.template;
+            let source = fs::read_to_string(&template.path)
+                .map_err(|e| err!("[{}]: Failed to read file: {}", template.rel_path, e))?;
+            let regions = in_place::parse(&template.rel_path, &source, &conf.in_place[file.entry])?;
+            if regions.is_empty() {
+                warn!(
+                    "In-place file '{}' has no marked regions to render.",
+                    template.rel_path
+                );
+                continue;
             }
+
+            let env = env_for(&template.rel_path);
+            let outputs = regions
+                .iter()
+                .enumerate()
+                .map(|(index, region)| {
+                    let name = format!("{}#{}", template.rel_path, index);
+                    debug!("Rendering in place: {}", name);
+                    let _current = config::set_current_template(&name);
+                    let ctx = if conf.engine.builtin_globals {
+                        minijinja::Value::from_serialize(template.builtin_globals(&conf.context))
+                    } else {
+                        context! {}
+                    };
+                    let mut output = env
+                        .render_named_str(&name, &region.template, ctx)
+                        .map_err(|e| render_error(env, conf, &name, &e))?;
+                    // The region's closing newline belongs to the layout around it, so is always kept:
+                    if region.template.ends_with('\n') && !output.ends_with('\n') {
+                        output.push('\n');
+                    }
+                    Ok(output)
+                })
+                .collect::<Result<Vec<_>, TracedErr>>()?;
+            let contents = in_place::splice(&source, &regions, &outputs);
+            rendered.push((template, source, contents, regions, outputs));
         }
-        Ok::<_, TracedErr>(())
+        Ok::<_, TracedErr>(rendered)
     })?;
 
-    timeit!("Syncing lockfile", { lockfile.sync() })?;
+    // Only known once rendered, as output names can be templated:
+    let rendered_templates = rendered
+        .iter()
+        .map(|(template, ..)| template)
+        .collect::<Vec<_>>();
+    self::walker::check_output_collisions(render_args, &rendered_templates)?;
+    if !render_args.allow_overwrite_sources {
+        self::walker::check_protected_outputs(
+            &rendered_templates,
+            &partials,
+            &conf.extends_chain,
+            (!render_args.no_lockfile)
+                .then(|| self::lockfile::path(render_args, conf.lockfile.as_deref()))
+                .as_deref(),
+        )?;
+    }
+
+    let undefined_report = undefined_tracker.map(|tracker| tracker.report(env_for));
+    if let Some(report) = &undefined_report {
+        report.log();
+        if let Some(max_undefined) = conf.engine.max_undefined {
+            if report.total > max_undefined {
+                return Err(err!(
+                    "{} undefined values rendered empty, exceeding [engine.max_undefined] of {}. Top offenders: {}",
+                    report.total,
+                    max_undefined,
+                    report.top_offenders()
+                ));
+            }
+        }
+    }
+
+    let (synced, synced_in_place) = timeit!("Syncing files", {
+        let mut synced = Vec::with_capacity(rendered.len());
+        for (template, source, compiled, spliced) in rendered {
+            let is_new = lockfile.add_template(&template, &source, compiled, spliced)?;
+            synced.push((template, is_new));
+        }
+        let mut synced_in_place = Vec::with_capacity(rendered_in_place.len());
+        for (template, source, contents, regions, outputs) in rendered_in_place {
+            let is_new = lockfile.add_in_place(template, &regions, &outputs, &source, contents)?;
+            synced_in_place.push((template, is_new));
+        }
+        Ok::<_, TracedErr>((synced, synced_in_place))
+    })?;
+    let mut identical = Vec::new();
+    let mut written = Vec::new();
+    for (template, is_new) in synced
+        .iter()
+        .map(|(template, is_new)| (template, *is_new))
+        .chain(
+            synced_in_place
+                .iter()
+                .map(|(template, is_new)| (*template, *is_new)),
+        )
+    {
+        if is_new {
+            written.push(template);
+        } else {
+            identical.push(template);
+        }
+    }
+
+    // Run before the lockfile is synced, so if a hook fails the files are rewritten and hooks rerun next time:
+    if !conf.post_write.is_empty() {
+        timeit!("Post write hooks", {
+            post_write::run_post_write_hooks(&conf.post_write, &written)
+        })?;
+    }
+
+    // Partials were rendered on their own before becoming partials, so their old outputs are cleaned up:
+    for partial in partials.iter() {
+        lockfile.drop_partial(&render_args.root, partial)?;
+    }
+
+    let traversal = if only.is_some() {
+        self::lockfile::Traversal::Filtered
+    } else {
+        self::lockfile::Traversal::Full
+    };
+    timeit!("Syncing lockfile", { lockfile.sync(traversal) })?;
 
     // Write only when hidden cli flag --debug is set, to allow testing internals from python without having to setup custom interfaces:
     if render_args.debug {
         let debug = debug::Debug {
+            exposed_env: conf.exposed_env.keys().cloned().collect(),
+            extends_chain: conf
+                .extends_chain
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect(),
+            undefined: undefined_report,
             config: conf,
             written: written
                 .iter()
                 .map(|t| t.out_path.display().to_string())
                 .collect(),
             identical: identical.iter().map(|t| t.rel_path.clone()).collect(),
+            partials: partials.iter().map(|t| t.rel_path.clone()).collect(),
+            engine_overrides: applied_overrides,
             lockfile_modified: lockfile.modified,
         };
 
@@ -84,18 +614,56 @@ pub fn render(render_args: RenderCommand) -> Result<bool, TracedErr> {
         std::fs::write(render_args.root.join("etcher_debug.json"), debug_json)?;
     }
 
-    info!(
-        "{} template{} written, {} identical. Lockfile {}. {} elapsed.",
-        written.len(),
-        if written.len() == 1 { "" } else { "s" },
-        identical.len(),
-        if lockfile.modified {
+    Ok(RootRender {
+        written: written.iter().map(|t| t.out_path.clone()).collect(),
+        identical: identical.len(),
+        lockfile: if lockfile.is_disabled() {
+            "disabled"
+        } else if lockfile.modified {
             "modified"
         } else {
             "unchanged"
         },
-        format_duration(GLOBAL_TIME_RECORDER.total_elapsed()?)
-    );
+    })
+}
 
-    Ok(true)
+/// A template that failed to render, explaining why as best it can.
+fn render_error(
+    env: &minijinja::Environment,
+    conf: &config::Config,
+    rel_path: &str,
+    e: &minijinja::Error,
+) -> TracedErr {
+    if let Some(limit) = conf.engine.limit_exceeded(e) {
+        return err!(
+            "[{}]: Failed to render template, {}: '{}'",
+            rel_path,
+            limit,
+            e
+        );
+    }
+
+    let mut msg = format!("[{}]: Failed to render template: '{}'", rel_path, e);
+    if let Some(detail) = undefined::describe_undefined_error(env, e, conf.context.keys()) {
+        msg.push('\n');
+        msg.push_str(&detail);
+    } else if let Some(detail) = location::describe_error_location(env, e) {
+        msg.push('\n');
+        msg.push_str(&detail);
+    }
+    if let Some(traceback) = config::py_traceback(e) {
+        msg.push('\n');
+        msg.push_str(traceback);
+    }
+    err!("{}", msg)
+}
+
+/// A template that failed to compile e.g. a syntax error, pointing at where it went wrong.
+fn compile_error(env: &minijinja::Environment, rel_path: &str, e: &minijinja::Error) -> TracedErr {
+    let mut msg = format!("[{}]: Failed to compile template: '{}'", rel_path, e);
+    if let Some(detail) = location::describe_error_location(env, e) {
+        msg.push('\n');
+        msg.push_str(&detail);
+    }
+    err!("{}", msg)
 }
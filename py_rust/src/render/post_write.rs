@@ -0,0 +1,57 @@
+use bitbazaar::{err, errors::TracedErr, timeit};
+use log::info;
+
+use super::template::Template;
+use crate::{config::PostWriteHook, utils::cmd::run_cmd_in};
+
+/// Run the matching post write hooks for each written template.
+/// Templates skipped as identical must never be passed in, otherwise every render would rerun every hook.
+pub fn run_post_write_hooks(
+    hooks: &[PostWriteHook],
+    written: &[&Template],
+) -> Result<(), TracedErr> {
+    let matchers = hooks
+        .iter()
+        .map(|hook| Ok((hook, hook.matcher()?)))
+        .collect::<Result<Vec<_>, TracedErr>>()?;
+
+    for template in written {
+        for (hook, matcher) in matchers.iter() {
+            if !matcher.is_match(&template.out_path) {
+                continue;
+            }
+
+            // Quote the path so it survives the shell style splitting of the command:
+            let out_path = template.out_path.display().to_string();
+            let quoted = shlex::try_quote(&out_path)
+                .map_err(|e| err!("Failed to quote path '{}': '{}'", out_path, e))?;
+            let command = hook.command.replace("{path}", &quoted);
+
+            info!("Running command: {}", command);
+            let result = timeit!(format!("Post write cmd: {}", command).as_str(), {
+                run_cmd_in(&command, None, &[])
+            })
+            .and_then(|cmd_out| {
+                if cmd_out.code != 0 {
+                    return Err(err!(
+                        "Returned non zero exit code: {}\n{}",
+                        cmd_out.code,
+                        cmd_out.stderr
+                    ));
+                }
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                return Err(e.modify_msg(|msg| {
+                    format!(
+                        "Post write hook '{}' failed for template '{}'.\n{}",
+                        command, template.rel_path, msg
+                    )
+                }));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,23 +1,155 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug)]
+use bitbazaar::{err, errors::TracedErr};
+use minijinja::{Environment, Value};
+
+#[derive(Debug, Clone)]
 pub struct Template {
     pub path: PathBuf,
     pub rel_path: String,
     pub out_path: PathBuf,
+    // When the output can move without the template changing, its path relative to the root (absolute when outside it), tracked in the lockfile:
+    pub tracked_out_path: Option<String>,
+    // Which output of a matrix template this is, each is tracked separately in the lockfile:
+    pub instance: Option<String>,
 }
 
 impl Template {
-    pub fn new(root: PathBuf, path: PathBuf, out_path: PathBuf) -> Self {
-        Self {
-            // Need to make the path relative to the root:
-            rel_path: path
-                .strip_prefix(&root)
-                .expect("Template path not relative to root")
-                .to_string_lossy()
-                .to_string(),
+    pub fn new(root: PathBuf, path: PathBuf, out_path: PathBuf) -> Result<Self, TracedErr> {
+        // Need to make the path relative to the root:
+        let rel = path.strip_prefix(&root).map_err(|_| {
+            err!(
+                "Template '{}' isn't under the root '{}'.",
+                path.display(),
+                root.display()
+            )
+        })?;
+        let rel_path = portable_path(rel).ok_or_else(|| {
+            err!(
+                "Template '{}' has a path that isn't valid UTF-8, so can't be tracked in the lockfile.",
+                path.display()
+            )
+        })?;
+        Ok(Self {
+            rel_path,
             path,
             out_path,
+            tracked_out_path: None,
+            instance: None,
+        })
+    }
+
+    /// The key the output is tracked under in the lockfile, the template's path plus the instance for matrix templates.
+    pub fn lockfile_key(&self) -> String {
+        match &self.instance {
+            Some(instance) => format!("{}[{}]", self.rel_path, instance),
+            None => self.rel_path.clone(),
+        }
+    }
+
+    /// Track the output path in the lockfile, for when it can move without the template changing, e.g. into an out_dir.
+    pub fn track_out_path(&mut self, root: &Path) {
+        self.tracked_out_path = Some(
+            self.out_path
+                .strip_prefix(root)
+                .ok()
+                .and_then(portable_path)
+                .unwrap_or_else(|| self.out_path.display().to_string()),
+        );
+    }
+
+    /// Render context values into the output filename, e.g. '{{ service }}.deploy.yml' to 'payments.deploy.yml'.
+    ///
+    /// Only the variable delimiters are evaluated, and undefined values always error. The ctx adds to the environment's globals, e.g. a matrix item.
+    pub fn render_out_name(
+        &mut self,
+        root: &Path,
+        env: &Environment,
+        (variable_start, variable_end): (String, String),
+        ctx: &HashMap<&str, Value>,
+    ) -> Result<(), TracedErr> {
+        let Some(name) = self
+            .out_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .filter(|name| name.contains(&variable_start))
+        else {
+            return Ok(());
+        };
+        let invalid = |reason: String| {
+            err!(
+                "[{}]: Failed to render the output filename '{}': {}",
+                self.rel_path,
+                name,
+                reason
+            )
+        };
+
+        let mut rendered = String::new();
+        let mut rest = name.as_str();
+        while let Some(start) = rest.find(&variable_start) {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + variable_start.len()..];
+            let end = after
+                .find(&variable_end)
+                .ok_or_else(|| invalid(format!("'{}' is never closed.", variable_start)))?;
+            let expr = after[..end].trim();
+            let value = env
+                .compile_expression(expr)
+                .and_then(|compiled| compiled.eval(ctx))
+                .map_err(|e| invalid(e.to_string()))?;
+            if value.is_undefined() {
+                return Err(invalid(format!("'{}' is undefined.", expr)));
+            }
+            rendered.push_str(&value.to_string());
+            rest = &after[end + variable_end.len()..];
         }
+        rendered.push_str(rest);
+
+        if rendered.is_empty() || rendered == "." || rendered == ".." {
+            return Err(invalid(format!("'{}' isn't a valid filename.", rendered)));
+        }
+        if rendered.contains(['/', '\\']) {
+            return Err(invalid(format!(
+                "'{}' contains a path separator.",
+                rendered
+            )));
+        }
+
+        self.out_path.set_file_name(rendered);
+        self.track_out_path(root);
+        Ok(())
     }
+
+    /// The names of the builtin globals, e.g. for telling them apart from undefined variables before rendering.
+    pub const BUILTIN_GLOBALS: [&'static str; 3] = ["__template__", "__output__", "__rel_path__"];
+
+    /// The per-template builtin globals, passed as the render context.
+    /// Keys already in the user context are skipped, so user context takes precedence.
+    pub fn builtin_globals(
+        &self,
+        ctx: &HashMap<String, serde_json::Value>,
+    ) -> HashMap<&str, Value> {
+        [
+            ("__template__", self.path.display().to_string()),
+            ("__output__", self.out_path.display().to_string()),
+            ("__rel_path__", self.rel_path.clone()),
+        ]
+        .into_iter()
+        .filter(|(name, _)| !ctx.contains_key(*name))
+        .map(|(name, value)| (name, Value::from(value)))
+        .collect()
+    }
+}
+
+/// A relative path joined with '/' whatever the platform, so lockfile keys match between e.g. Windows and Linux.
+/// None when it isn't valid UTF-8, as a lossy conversion could collide with another path.
+pub fn portable_path(path: &Path) -> Option<String> {
+    path.components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("/"))
 }
@@ -0,0 +1,206 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::warn;
+use parking_lot::Mutex;
+
+use super::location::{error_chain, location, snippet};
+
+// The number of offending templates to include in the summary:
+static TOP_OFFENDERS: usize = 5;
+
+/// Counts undefined values silently rendered as empty strings when undefined values are allowed.
+#[derive(Clone, Default)]
+pub struct UndefinedTracker {
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct UndefinedReport {
+    pub total: usize,
+    pub templates: Vec<UndefinedTemplateReport>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct UndefinedTemplateReport {
+    pub template: String,
+    pub count: usize,
+    // The referenced variable paths that couldn't be resolved in the template, found through static analysis:
+    pub variables: Vec<String>,
+}
+
+impl UndefinedTracker {
+    /// Install the counting formatter into the environment, otherwise formatting as normal.
+    pub fn install(&self, env: &mut minijinja::Environment) {
+        let counts = self.counts.clone();
+        env.set_formatter(move |out, state, value| {
+            if value.is_undefined() {
+                *counts.lock().entry(state.name().to_string()).or_default() += 1;
+            }
+            minijinja::escape_formatter(out, state, value)
+        });
+    }
+
+    /// Produce the report of everything counted so far, ordered by worst offender.
+    /// `env_for` gives the environment each template was rendered with.
+    pub fn report<'a>(
+        &self,
+        env_for: impl Fn(&str) -> &'a minijinja::Environment<'a>,
+    ) -> UndefinedReport {
+        let counts = self.counts.lock();
+
+        let mut templates = counts
+            .iter()
+            .map(|(name, count)| UndefinedTemplateReport {
+                template: name.clone(),
+                count: *count,
+                variables: unresolved_variables(env_for(name), name),
+            })
+            .collect::<Vec<_>>();
+        templates.sort_by(|a, b| b.count.cmp(&a.count).then(a.template.cmp(&b.template)));
+
+        UndefinedReport {
+            total: templates.iter().map(|t| t.count).sum(),
+            templates,
+        }
+    }
+}
+
+impl UndefinedReport {
+    /// Warn with the total and the top offenders when anything rendered empty.
+    pub fn log(&self) {
+        if self.total == 0 {
+            return;
+        }
+
+        warn!(
+            "{} undefined value{} silently rendered empty. Top offenders: {}",
+            self.total,
+            if self.total == 1 { "" } else { "s" },
+            self.top_offenders()
+        );
+    }
+
+    pub fn top_offenders(&self) -> String {
+        self.templates
+            .iter()
+            .take(TOP_OFFENDERS)
+            .map(|t| {
+                if t.variables.is_empty() {
+                    format!("'{}' ({})", t.template, t.count)
+                } else {
+                    format!("'{}' ({}: {})", t.template, t.count, t.variables.join(", "))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The variable paths referenced in a template that don't resolve, for the --check-vars preflight.
+///
+/// Names only defined when the template is rendered (e.g. the builtin globals or a matrix variable) are given as `local_names`.
+/// Only the template itself is checked, not what it includes.
+pub fn check_vars(env: &minijinja::Environment, name: &str, local_names: &[&str]) -> Vec<String> {
+    unresolved_variables(env, name)
+        .into_iter()
+        .filter(|path| {
+            let first = path.split('.').next().unwrap_or(path);
+            !local_names.contains(&first)
+        })
+        .collect()
+}
+
+/// Statically find the variable paths referenced in a template that don't resolve to anything.
+fn unresolved_variables(env: &minijinja::Environment, name: &str) -> Vec<String> {
+    let Ok(tmpl) = env.get_template(name) else {
+        return vec![];
+    };
+    let state = tmpl.new_state();
+
+    let mut unresolved = tmpl
+        .undeclared_variables(true)
+        .into_iter()
+        .filter(|path| {
+            let mut parts = path.split('.');
+            let mut value = parts.next().and_then(|first| state.lookup(first));
+            for part in parts {
+                value = value.and_then(|v| v.get_attr(part).ok());
+            }
+            value.filter(|v| !v.is_undefined()).is_none()
+        })
+        .collect::<Vec<_>>();
+    unresolved.sort();
+    unresolved
+}
+
+// The maximum number of near-miss context keys to suggest:
+static MAX_SUGGESTIONS: usize = 3;
+
+/// Explain a strict mode undefined error: the expression, where it is (following includes down to the culprit),
+/// a snippet of the offending lines and any similarly named context keys.
+///
+/// None when the error isn't caused by an undefined value.
+pub fn describe_undefined_error<'a>(
+    env: &minijinja::Environment,
+    e: &minijinja::Error,
+    context_keys: impl Iterator<Item = &'a String>,
+) -> Option<String> {
+    let chain = error_chain(e);
+
+    let culprit = chain.last()?;
+    if culprit.kind() != minijinja::ErrorKind::UndefinedError {
+        return None;
+    }
+    let name = culprit.name()?;
+    let source = env.get_template(name).ok()?.source().to_string();
+    let expression = culprit
+        .range()
+        .and_then(|range| source.get(range))
+        .map(|expression| expression.trim().to_string())?;
+
+    let mut lines = vec![format!(
+        "Undefined value '{}' at {}",
+        expression,
+        location(culprit)
+    )];
+
+    // The includes leading to the culprit, outermost first:
+    let included_from = chain[..chain.len() - 1]
+        .iter()
+        .map(|e| location(e))
+        .collect::<Vec<_>>();
+    if !included_from.is_empty() {
+        lines[0].push_str(&format!(" (included from {})", included_from.join(" -> ")));
+    }
+    lines[0].push(':');
+
+    if let Some(line) = culprit.line() {
+        lines.extend(snippet(&source, line, culprit.range()));
+    }
+
+    // Only the root variable can be a missing context key, e.g. 'nme' in 'nme.first':
+    let root = expression
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or_default();
+    let max_distance = (root.chars().count() / 3).max(2);
+    let mut near_misses = context_keys
+        .filter(|key| key.as_str() != root)
+        .map(|key| (strsim::levenshtein(root, key), key))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+    near_misses.sort();
+    if !near_misses.is_empty() {
+        lines.push(format!(
+            "Similar context keys: {}",
+            near_misses
+                .iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|(_, key)| format!("'{}'", key))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    Some(lines.join("\n"))
+}
@@ -1,64 +1,253 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
 use bitbazaar::{err, errors::TracedErr};
-use ignore::{overrides::OverrideBuilder, WalkBuilder};
-use log::debug;
-use once_cell::sync::Lazy;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    DirEntry, WalkBuilder,
+};
+use log::{debug, warn};
 use regex::Regex;
 
-use super::lockfile::LOCKFILE_NAME;
-use crate::{args::RenderCommand, config::Config};
+use super::{
+    junk::JunkFilter,
+    lockfile::{self, LOCKFILE_NAME},
+};
+use crate::{
+    args::RenderCommand,
+    config::{OnBinary, TemplateMatcher},
+};
+
+/// The default depth cap when following symlinks, NOTE: when changing make sure to update the follow_symlinks schema.json description.
+static SYMLINK_MAX_DEPTH: usize = 32;
+
+/// How much of a template is sniffed for a NUL byte to spot binary files, the same heuristic as git.
+/// NOTE: when changing make sure to update the on_binary schema.json description.
+static BINARY_SNIFF_BYTES: u64 = 8000;
+
+/// Gitignore syntax file at the root, automatically applied to the walk.
+pub static ETCHIGNORE_FILENAME: &str = ".etchignore";
+
+pub fn create(
+    render_args: &RenderCommand,
+    exclude: &[String],
+    ignore_files: &[String],
+    use_gitignore: bool,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    lockfile: Option<&Path>,
+) -> Result<WalkBuilder, TracedErr> {
+    // Git's own ignore rules are opt-in, either from config or the cli:
+    let use_gitignore = use_gitignore || render_args.gitignore;
+    let follow_symlinks = follow_symlinks || render_args.follow_symlinks;
 
-pub fn create(render_args: &RenderCommand, conf: &Config) -> Result<WalkBuilder, TracedErr> {
     let mut builder = WalkBuilder::new(&render_args.root);
-    builder.git_exclude(false); // Don't auto read .git/info/exclude
-    builder.git_global(false); // Don't auto use a global .gitignore file
-    builder.git_ignore(false); // Don't auto use .gitignore file
+    builder.git_exclude(use_gitignore); // Whether to auto read .git/info/exclude
+    builder.git_global(use_gitignore); // Whether to auto use a global .gitignore file
+    builder.git_ignore(use_gitignore); // Whether to auto use .gitignore files
     builder.ignore(false); // Don't auto use .ignore file
     builder.require_git(false); // Works better when not in a git repo
     builder.hidden(false); // Doesn't auto ignore hidden files
+    builder.follow_links(follow_symlinks);
 
-    for ignore_file in conf.ignore_files.iter() {
+    // Symlinks can point back up the tree, so following them is capped unless a depth is explicitly configured:
+    builder.max_depth(match (max_depth, follow_symlinks) {
+        (Some(max_depth), _) => Some(max_depth),
+        (None, true) => Some(SYMLINK_MAX_DEPTH),
+        (None, false) => None,
+    });
+
+    for ignore_file in ignore_files.iter() {
         builder.add_ignore(ignore_file);
     }
 
-    // Don't ever match the target config file or the lockfile:
-    let mut all_excludes = vec![
-        render_args.config.display().to_string(),
-        LOCKFILE_NAME.to_string(),
-    ];
+    if let Some(etchignore) = etchignore(render_args) {
+        debug!("Using ignore file at '{}'.", etchignore.display());
+        if let Some(e) = builder.add_ignore(&etchignore) {
+            return Err(err!(
+                "Failed to read ignore file at '{}': {}",
+                etchignore.display(),
+                e
+            ));
+        }
+    }
 
-    // Add in config supplied excludes:
-    all_excludes.extend(conf.exclude.iter().map(|s| s.to_string()));
+    builder.overrides(overrides(render_args, exclude, lockfile)?);
 
-    let mut overrider: OverrideBuilder = OverrideBuilder::new(&render_args.root);
-    for exclude in all_excludes.iter() {
-        // The override adder is the opposite, i.e. a match is a whitelist, so need to invert the exclude pattern provided:
-        let trimmed = exclude.trim();
-        let inverted = if trimmed.starts_with('!') {
-            // Remove the leading "!" to invert:
-            trimmed
-                .strip_prefix('!')
-                .ok_or_else(|| err!("Failed to strip leading '!' from exclude: {}", trimmed))?
-                .to_string()
-        } else {
-            // Add a leading "!" to invert:
-            format!("!{}", trimmed)
-        };
-        overrider.add(&inverted)?;
-    }
-
-    builder.overrides(overrider.build()?);
+    // Counting what git pruned needs another two walks, so is only done when it'd actually be logged:
+    if use_gitignore && log::log_enabled!(log::Level::Debug) {
+        debug!(
+            "Gitignore pruned {} files from the search.",
+            gitignore_pruned(&builder)
+        );
+    }
 
     Ok(builder)
 }
 
-static MIDDLE_MATCHER: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(.*)(\.etch\.)(.*)").expect("Regex failed to compile"));
+/// How many files the walk skips only because git ignores them, every other ignore source still applies to both walks.
+fn gitignore_pruned(builder: &WalkBuilder) -> usize {
+    let count_files = |builder: &WalkBuilder| {
+        builder
+            .build()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
+            .count()
+    };
+    let mut unpruned = builder.clone();
+    unpruned
+        .git_ignore(false)
+        .git_exclude(false)
+        .git_global(false);
+    count_files(&unpruned).saturating_sub(count_files(builder))
+}
+
+/// The .etchignore file, picked up automatically when present at the root without needing to be listed in ignore_files.
+pub fn etchignore(render_args: &RenderCommand) -> Option<PathBuf> {
+    let etchignore = render_args.root.join(ETCHIGNORE_FILENAME);
+    etchignore.is_file().then_some(etchignore)
+}
+
+/// Don't ever match the target config file, the lockfile or the out_dir.
+///
+/// The default lockfile name is excluded anywhere, a custom lockfile and the out_dir only at their own path, if that's under the root at all.
+pub fn builtin_excludes(render_args: &RenderCommand, lockfile: Option<&Path>) -> Vec<String> {
+    let mut excludes = vec![render_args.config.display().to_string()];
+    if render_args.lockfile.is_none() && lockfile.is_none() {
+        excludes.push(LOCKFILE_NAME.to_string());
+    } else if let Some(exclude) = path_exclude(render_args, &lockfile::path(render_args, lockfile))
+    {
+        excludes.push(exclude);
+    }
+    // Otherwise the outputs would be picked up as templates on the next render:
+    if let Some(exclude) = render_args
+        .out_dir
+        .as_deref()
+        .and_then(|out_dir| path_exclude(render_args, out_dir))
+    {
+        excludes.push(exclude);
+    }
+    excludes
+}
+
+/// An exclude matching only the given path, if it's under the root at all.
+pub fn path_exclude(render_args: &RenderCommand, path: &Path) -> Option<String> {
+    lockfile::canonical_path(path)
+        .zip(render_args.root.canonicalize().ok())
+        .and_then(|(path, root)| {
+            path.strip_prefix(root)
+                .ok()
+                .map(|rel| format!("/{}", rel.display()))
+        })
+}
 
-static END_MATCHER: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(.*)(\.etch)$").expect("Regex failed to compile"));
+/// The built-in and config excludes, as overrides where a match is an ignore.
+pub fn overrides(
+    render_args: &RenderCommand,
+    exclude: &[String],
+    lockfile: Option<&Path>,
+) -> Result<Override, TracedErr> {
+    let mut overrider: OverrideBuilder = OverrideBuilder::new(&render_args.root);
+    for exclude in builtin_excludes(render_args, lockfile).iter() {
+        overrider
+            .add(&invert_exclude(exclude)?)
+            .map_err(|e| err!("Invalid built-in exclude '{}': {}", exclude, e))?;
+    }
 
-fn try_regexes_get_match(filename: &str) -> Option<String> {
-    if let Some(caps) = MIDDLE_MATCHER.captures(filename) {
+    // Add in config supplied excludes, these are validated upfront when loading the config:
+    for (index, exclude) in exclude.iter().enumerate() {
+        overrider
+            .add(&invert_exclude(exclude)?)
+            .map_err(|e| err!("[exclude.{}]: Invalid pattern '{}': {}", index, exclude, e))?;
+    }
+
+    Ok(overrider.build()?)
+}
+
+/// The override adder is the opposite of an exclude, i.e. a match is a whitelist, so need to invert the exclude pattern provided.
+pub fn invert_exclude(exclude: &str) -> Result<String, TracedErr> {
+    let trimmed = exclude.trim();
+    if trimmed.starts_with('!') {
+        // Remove the leading "!" to invert:
+        Ok(trimmed
+            .strip_prefix('!')
+            .ok_or_else(|| err!("Failed to strip leading '!' from exclude: {}", trimmed))?
+            .to_string())
+    } else {
+        // Add a leading "!" to invert:
+        Ok(format!("!{}", trimmed))
+    }
+}
+
+/// The default marker identifying templates, e.g. "foo.etch.json" or "foo.json.etch".
+pub static DEFAULT_TEMPLATE_MARKER: &str = "etch";
+
+/// Identifies templates by their markers, either in the middle of the filename or at the end, then by any configured template_matchers.
+#[derive(Clone)]
+pub struct MarkerMatcher {
+    // The marker with its (middle, end) regexes, in the order they're tried:
+    markers: Vec<(String, Regex, Regex)>,
+    // The template_matchers regexes with their replacements, tried after the markers:
+    matchers: Vec<(Regex, String)>,
+}
+
+impl MarkerMatcher {
+    pub fn new(markers: &[String], matchers: &[TemplateMatcher]) -> Result<Self, TracedErr> {
+        let markers = markers
+            .iter()
+            .map(|marker| {
+                let escaped = regex::escape(marker);
+                Ok((
+                    marker.clone(),
+                    Regex::new(&format!(r"(.*)(\.{}\.)(.*)", escaped))?,
+                    Regex::new(&format!(r"(.*)(\.{})$", escaped))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, TracedErr>>()?;
+        let matchers = matchers
+            .iter()
+            .map(|matcher| Ok((matcher.regex()?, matcher.replacement.clone())))
+            .collect::<Result<Vec<_>, TracedErr>>()?;
+        Ok(Self { markers, matchers })
+    }
+
+    /// The compiled output filename if the filename is a template, with the first matching marker removed.
+    pub fn get_match(&self, filename: &str) -> Option<String> {
+        self.get_match_described(filename)
+            .map(|(_, compiled_name)| compiled_name)
+    }
+
+    /// Like get_match, but also describing the marker or template matcher that matched.
+    pub fn get_match_described(&self, filename: &str) -> Option<(String, String)> {
+        self.markers
+            .iter()
+            .find_map(|(marker, middle, end)| {
+                try_regexes_get_match(middle, end, filename)
+                    .map(|compiled_name| (format!("the '{}' marker", marker), compiled_name))
+            })
+            .or_else(|| {
+                self.matchers.iter().find_map(|(regex, replacement)| {
+                    let compiled_name = regex.replace(filename, replacement.as_str());
+                    // A replacement leaving the name as is would render the template over itself:
+                    (!compiled_name.is_empty() && compiled_name != filename).then(|| {
+                        (
+                            format!("the '{}' template matcher", regex.as_str()),
+                            compiled_name.to_string(),
+                        )
+                    })
+                })
+            })
+    }
+}
+
+fn try_regexes_get_match(middle: &Regex, end: &Regex, filename: &str) -> Option<String> {
+    if let Some(caps) = middle.captures(filename) {
         return Some(format!(
             "{}.{}",
             caps.get(1).map_or("", |m| m.as_str()),
@@ -66,40 +255,329 @@ fn try_regexes_get_match(filename: &str) -> Option<String> {
         ));
     }
 
-    if let Some(caps) = END_MATCHER.captures(filename) {
+    if let Some(caps) = end.captures(filename) {
         return Some(caps.get(1).map_or("", |m| m.as_str()).to_string());
     }
 
     None
 }
 
+/// The templates found by a walk.
+pub struct Found {
+    pub templates: Vec<super::template::Template>,
+    /// Templates whose filename starts with the partial prefix, only rendered when included by other templates.
+    pub partials: Vec<super::template::Template>,
+}
+
+/// A root along with the walk over its files, so glob() sees the same files as the template search, excludes and ignore files applied.
+#[derive(Clone)]
+pub struct WalkedRoot {
+    pub path: PathBuf,
+    pub walker: WalkBuilder,
+}
+
+/// Find all templates, when a budget is given the walk stops early once it's exceeded, returning what was found so far.
 pub fn find_templates(
     render_args: &RenderCommand,
     walker: WalkBuilder,
-) -> Result<Vec<super::template::Template>, TracedErr> {
-    let mut templates = vec![];
+    junk: &JunkFilter,
+    marker: &MarkerMatcher,
+    partial_prefix: &str,
+    budget: Option<Duration>,
+) -> Result<Found, TracedErr> {
+    let started = Instant::now();
+    let canonical_root = render_args.root.canonicalize().map_err(|e| {
+        err!(
+            "Failed to resolve root '{}': {}",
+            render_args.root.display(),
+            e
+        )
+    })?;
+    let mut templates: Vec<super::template::Template> = vec![];
+    let mut partials = vec![];
+    // Each template's real path to its index, the same file can be reached through multiple symlinks:
+    let mut resolved_indices: HashMap<PathBuf, usize> = HashMap::new();
     let mut files_checked = 0;
+    let mut junk_excluded = 0;
     for entry in walker.build() {
-        let entry = entry?;
+        if let Some(budget) = budget {
+            if started.elapsed() > budget {
+                debug!("Template discovery budget of {:?} exceeded.", budget);
+                break;
+            }
+        }
+
+        let entry = entry.map_err(walk_error)?;
         if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
             let filename = entry.file_name().to_string_lossy();
-            if let Some(compiled_name) = try_regexes_get_match(&filename) {
-                templates.push(super::template::Template::new(
-                    render_args.root.clone(),
-                    entry.path().to_path_buf(),
-                    // Replacing the name with the compiled name:
-                    entry.path().parent().unwrap().join(compiled_name),
-                ));
+
+            // Junk is skipped before the marker regexes so e.g. editor backups of templates can't match:
+            if junk.is_junk(&filename) {
+                junk_excluded += 1;
+                continue;
+            }
+
+            if let Some(compiled_name) = marker.get_match(&filename) {
+                let rel = entry
+                    .path()
+                    .strip_prefix(&render_args.root)
+                    .unwrap_or(entry.path());
+                if super::template::portable_path(rel).is_none() {
+                    warn!(
+                        "Skipping template '{}', its path isn't valid UTF-8 so can't be tracked in the lockfile.",
+                        entry.path().display()
+                    );
+                    continue;
+                }
+                let (resolved, template) =
+                    resolve_template(render_args, &canonical_root, &entry, marker, compiled_name)?;
+                if !partial_prefix.is_empty() && filename.starts_with(partial_prefix) {
+                    partials.push(template);
+                    continue;
+                }
+                match resolved_indices.get(&resolved) {
+                    // Whichever path sorts first is kept, so the lockfile key doesn't depend on walk order:
+                    Some(&index) => {
+                        debug!(
+                            "Templates '{}' and '{}' are the same file reached through a symlink, rendering once.",
+                            templates[index].rel_path, template.rel_path
+                        );
+                        if template.rel_path < templates[index].rel_path {
+                            templates[index] = template;
+                        }
+                    }
+                    None => {
+                        resolved_indices.insert(resolved, templates.len());
+                        templates.push(template);
+                    }
+                }
             }
         }
         files_checked += 1;
     }
 
     debug!(
-        "Checked {} unignored files to find {} templates.",
+        "Checked {} unignored files to find {} templates and {} partials.",
         files_checked,
-        templates.len()
+        templates.len(),
+        partials.len()
     );
+    debug!("Excluded {} junk files.", junk_excluded);
+
+    Ok(Found {
+        templates,
+        partials,
+    })
+}
+
+/// Error on or skip templates that are over the size limit or look binary, before they're read whole.
+///
+/// Only templates are opened here, the walk itself never reads the files it passes over.
+pub fn screen_templates(
+    templates: Vec<super::template::Template>,
+    max_template_size: u64,
+    on_binary: OnBinary,
+) -> Result<Vec<super::template::Template>, TracedErr> {
+    let mut screened = Vec::with_capacity(templates.len());
+    for template in templates {
+        match unrenderable_reason(&template.path, max_template_size)
+            .map_err(|e| err!("[{}]: Failed to read template: {}", template.rel_path, e))?
+        {
+            None => screened.push(template),
+            Some(reason) => match on_binary {
+                OnBinary::Error => {
+                    return Err(err!(
+                        "[{}]: Template {}. Set on_binary = \"skip\" to skip it with a warning instead.",
+                        template.rel_path,
+                        reason
+                    ))
+                }
+                OnBinary::Skip => warn!("Skipping template '{}', it {}.", template.rel_path, reason),
+            },
+        }
+    }
+    Ok(screened)
+}
+
+/// Why a template can't be rendered, if it's too large or has a NUL byte near the start.
+fn unrenderable_reason(path: &Path, max_template_size: u64) -> std::io::Result<Option<String>> {
+    let size = fs::metadata(path)?.len();
+    if size > max_template_size {
+        return Ok(Some(format!(
+            "is {} bytes, over the max_template_size of {} bytes",
+            size, max_template_size
+        )));
+    }
+
+    let mut head = vec![];
+    fs::File::open(path)?
+        .take(BINARY_SNIFF_BYTES)
+        .read_to_end(&mut head)?;
+    Ok(head
+        .contains(&0)
+        .then(|| "looks like a binary file, it contains a NUL byte".to_string()))
+}
+
+/// The template for a walked file, with the file's real path.
+///
+/// Templates reached through a symlink are keyed by their real path when it's under the root, so the lockfile key doesn't depend on the link.
+/// A symlinked template file renders next to the file it links to, rather than next to the link.
+fn resolve_template(
+    render_args: &RenderCommand,
+    canonical_root: &Path,
+    entry: &DirEntry,
+    marker: &MarkerMatcher,
+    compiled_name: String,
+) -> Result<(PathBuf, super::template::Template), TracedErr> {
+    let path = entry.path();
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| err!("Failed to resolve '{}': {}", path.display(), e))?;
+    // Named after the real file where possible, so the output is the same whichever link it was reached through:
+    let compiled_name = resolved
+        .file_name()
+        .and_then(|name| marker.get_match(&name.to_string_lossy()))
+        .unwrap_or(compiled_name);
+    let mut template = match resolved.strip_prefix(canonical_root) {
+        Ok(rel) => {
+            let path = render_args.root.join(rel);
+            let out_path = path.with_file_name(&compiled_name);
+            super::template::Template::new(render_args.root.clone(), path, out_path)?
+        }
+        Err(_) => {
+            let out_dir = if entry.path_is_symlink() {
+                resolved.parent()
+            } else {
+                path.parent()
+            };
+            let out_path = out_dir
+                .ok_or_else(|| err!("Template path has no parent: '{}'.", path.display()))?
+                .join(&compiled_name);
+            super::template::Template::new(render_args.root.clone(), path.to_path_buf(), out_path)?
+        }
+    };
+    if let Some(out_dir) = &render_args.out_dir {
+        template.out_path =
+            out_dir.join(Path::new(&template.rel_path).with_file_name(&compiled_name));
+        template.track_out_path(&render_args.root);
+    }
+    Ok((resolved, template))
+}
+
+/// Walk errors, with symlink cycles named clearly rather than in the walker's generic error.
+pub fn walk_error(e: ignore::Error) -> TracedErr {
+    match symlink_loop(&e) {
+        Some((ancestor, child)) => err!(
+            "Symlink cycle: '{}' links back to its parent directory '{}'. Exclude the link, or stop following symlinks.",
+            child.display(),
+            ancestor.display()
+        ),
+        None => e.into(),
+    }
+}
 
-    Ok(templates)
+fn symlink_loop(e: &ignore::Error) -> Option<(&Path, &Path)> {
+    match e {
+        ignore::Error::Loop { ancestor, child } => Some((ancestor, child)),
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => symlink_loop(err),
+        ignore::Error::Partial(errs) => errs.iter().find_map(symlink_loop),
+        _ => None,
+    }
+}
+
+/// Compile the --only globs, none when not given so the render covers everything.
+pub fn only_filter(only: &[String]) -> Result<Option<GlobSet>, TracedErr> {
+    if only.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in only.iter() {
+        builder
+            .add(Glob::new(pattern).map_err(|e| err!("Invalid --only glob '{}': {}", pattern, e))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Error if an output would overwrite a template, a config file or the lockfile, a template resolving to e.g. its own config is almost certainly a mistake.
+pub fn check_protected_outputs(
+    templates: &[&super::template::Template],
+    partials: &[super::template::Template],
+    config_files: &[PathBuf],
+    lockfile: Option<&Path>,
+) -> Result<(), TracedErr> {
+    // Outputs might not exist yet, so everything is compared resolved through its directory:
+    let mut protected: HashMap<PathBuf, String> = HashMap::new();
+    for source in templates.iter().copied().chain(partials.iter()) {
+        if let Some(path) = lockfile::canonical_path(&source.path) {
+            protected.insert(path, format!("the template '{}'", source.rel_path));
+        }
+    }
+    for config_file in config_files.iter() {
+        if let Some(path) = lockfile::canonical_path(config_file) {
+            protected.insert(path, "a config file".to_string());
+        }
+    }
+    if let Some(path) = lockfile.and_then(lockfile::canonical_path) {
+        protected.insert(path, "the lockfile".to_string());
+    }
+
+    for template in templates.iter() {
+        if let Some(what) =
+            lockfile::canonical_path(&template.out_path).and_then(|path| protected.get(&path))
+        {
+            return Err(err!(
+                "Template '{}' would write its output to '{}', which is {}. Pass --allow-overwrite-sources if this is deliberate.",
+                template.lockfile_key(),
+                template.out_path.display(),
+                what
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Error if multiple templates render to the same output path, otherwise whichever renders last would silently win.
+pub fn check_output_collisions(
+    render_args: &RenderCommand,
+    templates: &[&super::template::Template],
+) -> Result<(), TracedErr> {
+    let mut sources: BTreeMap<&PathBuf, Vec<String>> = BTreeMap::new();
+    for template in templates.iter() {
+        sources
+            .entry(&template.out_path)
+            .or_default()
+            .push(template.lockfile_key());
+    }
+
+    let collisions = sources
+        .into_iter()
+        .filter(|(_, rel_paths)| rel_paths.len() > 1)
+        .map(|(out_path, mut rel_paths)| {
+            rel_paths.sort();
+            format!(
+                "'{}' is rendered by: {}",
+                out_path
+                    .strip_prefix(&render_args.root)
+                    .unwrap_or(out_path)
+                    .display(),
+                rel_paths
+                    .iter()
+                    .map(|rel_path| format!("'{}'", rel_path))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(err!(
+            "Multiple templates render to the same output path:\n{}",
+            collisions.join("\n")
+        ))
+    }
 }
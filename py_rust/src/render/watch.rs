@@ -0,0 +1,153 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use bitbazaar::{err, errors::TracedErr};
+use log::{debug, error, info};
+use notify::{RecursiveMode, Watcher};
+use pyo3::Python;
+
+use super::{load_config, lockfile, render_with_config};
+use crate::{args::RenderCommand, config::Config};
+
+/// Editors often save in several steps (e.g. write to a swap file then rename), wait for them to settle before re-rendering.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How often to check for Ctrl+C whilst waiting for changes.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Written alongside the outputs by the hidden --debug flag.
+const DEBUG_FILENAME: &str = "etcher_debug.json";
+
+/// Render once, then keep re-rendering whenever a file under the root or the config changes, until interrupted.
+///
+/// The processed config (including context commands) is reused between renders, only reloaded when a config file changes.
+/// Every template is re-rendered each time, the lockfile means only those whose output actually changed are written.
+/// Custom extensions are only imported once per process, so their registered functions survive re-renders but changes to them need a restart.
+pub fn watch(render_args: &RenderCommand, mut conf: Config) -> Result<(), TracedErr> {
+    let root = render_args.root.canonicalize().map_err(|e| {
+        err!(
+            "Failed to resolve root '{}': {}",
+            render_args.root.display(),
+            e
+        )
+    })?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| err!("Failed to create file watcher: {}", e))?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(|e| err!("Failed to watch '{}': {}", root.display(), e))?;
+
+    // Configs can live outside the root, e.g. shared configs pulled in with extends:
+    let mut config_files = watch_config_files(&mut watcher, &root, render_args, &conf)?;
+
+    // Files written by etch itself shouldn't trigger another render:
+    let mut generated = rerender(render_args, &conf);
+
+    info!(
+        "Watching '{}' for changes, press Ctrl+C to stop.",
+        root.display()
+    );
+
+    loop {
+        let event = match rx.recv_timeout(SIGNAL_CHECK_INTERVAL) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Running inside python, Ctrl+C is only seen when python's signal handlers are checked:
+                if Python::with_gil(|py| py.check_signals()).is_err() {
+                    info!("Stopped watching.");
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let mut changed = event_paths(event);
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            changed.extend(event_paths(event));
+        }
+
+        // The lockfile can be moved by a config reload, so is resolved each time:
+        let lockfile =
+            lockfile::canonical_path(&lockfile::path(render_args, conf.lockfile.as_deref()));
+        changed.retain(|path| {
+            !generated.contains(path)
+                && lockfile.as_ref() != Some(path)
+                && path.file_name().is_none_or(|name| name != DEBUG_FILENAME)
+        });
+        if changed.is_empty() {
+            continue;
+        }
+        debug!("Changed: {:?}", changed);
+
+        if changed.iter().any(|path| config_files.contains(path)) {
+            info!("Config changed, reloading.");
+            match load_config(render_args) {
+                Ok(new_conf) => {
+                    conf = new_conf;
+                    config_files = watch_config_files(&mut watcher, &root, render_args, &conf)?;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            }
+        }
+
+        generated = rerender(render_args, &conf);
+    }
+}
+
+/// Render, logging rather than propagating errors so a broken template doesn't end the watch.
+fn rerender(render_args: &RenderCommand, conf: &Config) -> HashSet<PathBuf> {
+    match render_with_config(render_args, conf) {
+        Ok(written) => written
+            .into_iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect(),
+        Err(e) => {
+            error!("{}", e);
+            HashSet::new()
+        }
+    }
+}
+
+/// Watch any config files outside the root, returning all the config files so changes to them can be identified.
+fn watch_config_files(
+    watcher: &mut impl Watcher,
+    root: &Path,
+    render_args: &RenderCommand,
+    conf: &Config,
+) -> Result<HashSet<PathBuf>, TracedErr> {
+    let config_files = std::iter::once(&render_args.config)
+        .chain(conf.extends_chain.iter())
+        .filter_map(|path| path.canonicalize().ok())
+        .collect::<HashSet<_>>();
+
+    for config_file in config_files.iter() {
+        if !config_file.starts_with(root) {
+            watcher
+                .watch(config_file, RecursiveMode::NonRecursive)
+                .map_err(|e| err!("Failed to watch '{}': {}", config_file.display(), e))?;
+        }
+    }
+
+    Ok(config_files)
+}
+
+fn event_paths(event: notify::Result<notify::Event>) -> Vec<PathBuf> {
+    match event {
+        Ok(event) if !event.kind.is_access() => event.paths,
+        Ok(_) => vec![],
+        Err(e) => {
+            error!("File watcher error: {}", e);
+            vec![]
+        }
+    }
+}
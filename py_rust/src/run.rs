@@ -8,7 +8,7 @@ use log::debug;
 
 use crate::{
     args::{self, get_py_args, get_version_info},
-    init, render, ETCH_ROOT_ARGS,
+    capabilities, complete, explain, init, list, render, ETCH_ROOT_ARGS,
 };
 
 pub fn run() -> Result<(), TracedErr> {
@@ -27,6 +27,9 @@ pub fn run() -> Result<(), TracedErr> {
     };
     if add {
         py_args.insert(1, "render".into());
+    } else if py_args.len() > 2 && matches!(arg1.map(|s| s.as_str()), Some("-V" | "--version")) {
+        // Clap short circuits the version flag, so redirect to the subcommand when options are given, e.g. `etch --version --output json`:
+        py_args[1] = "version".into();
     }
 
     let args = args::Args::parse_from(py_args);
@@ -46,8 +49,24 @@ pub fn run() -> Result<(), TracedErr> {
             Ok(())
         }
         args::Command::Init(init) => Ok(init::init(init)?),
-        args::Command::Version { output_format: _ } => {
-            println!("etch {}", get_version_info());
+        args::Command::List(list) => Ok(list::list(list)?),
+        args::Command::Explain(explain) => Ok(explain::explain(explain)?),
+        args::Command::Completions { shell } => {
+            print!("{}", complete::script(shell));
+            Ok(())
+        }
+        args::Command::Complete(complete) => {
+            complete::complete(complete);
+            Ok(())
+        }
+        args::Command::Version { output_format } => {
+            match output_format {
+                args::HelpFormat::Text => println!("etch {}", get_version_info()),
+                args::HelpFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&capabilities::VersionInfo::new())?
+                ),
+            }
             Ok(())
         }
     };
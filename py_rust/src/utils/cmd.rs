@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use bitbazaar::{cli::CmdOut, err, errors::TracedErr};
+
+/// Run a command in an optional working directory with extra env vars, a variant of `bitbazaar::cli::run_cmd` that allows more process configuration.
+pub fn run_cmd_in(
+    cmd_str: &str,
+    cwd: Option<&Path>,
+    envs: &[(&str, &str)],
+) -> Result<CmdOut, TracedErr> {
+    let args = shlex::split(cmd_str).ok_or_else(|| err!("Failed to parse command string"))?;
+
+    if args.is_empty() {
+        return Err(err!("Empty command string"));
+    }
+
+    let mut command = std::process::Command::new(&args[0]);
+
+    if args.len() > 1 {
+        command.args(&args[1..]);
+    }
+
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+
+    command.envs(envs.iter().copied());
+
+    let output = command.output().map_err(|e| {
+        err!(
+            "Command returned non-zero exit status '{}'.\nCommand: '{}'.\n'Err: '{}'",
+            e.raw_os_error().unwrap_or(-1),
+            cmd_str,
+            e
+        )
+    })?;
+
+    let stdout = String::from_utf8(output.stdout).unwrap_or("Decoding stdout failed".to_string());
+    let stderr = String::from_utf8(output.stderr).unwrap_or("Decoding stderr failed".to_string());
+
+    Ok(CmdOut {
+        args,
+        stdout,
+        stderr,
+        code: output
+            .status
+            .code()
+            .ok_or_else(|| err!("Command returned no exit status"))?,
+    })
+}
@@ -0,0 +1,68 @@
+use std::{fs, path::Path};
+
+use bitbazaar::{err, errors::TracedErr};
+
+/// Parse a dotenv-style file into its variables, in file order.
+///
+/// Supports blank lines, # comments, an optional `export ` prefix, single quoted literals,
+/// and double quoted values with \n, \t, \" and \\ escapes. Unquoted values end at a ` #` comment.
+pub fn parse(path: &Path) -> Result<Vec<(String, String)>, TracedErr> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| err!("Failed to read env file '{}': {}", path.display(), e))?;
+
+    let mut vars = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let add_loc = |msg: &str| err!("[{}:{}]: {}", path.display(), index + 1, msg);
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| add_loc("Expected KEY=VALUE."))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(add_loc(&format!("Invalid variable name '{}'.", key)));
+        }
+        vars.push((
+            key.to_string(),
+            parse_value(value.trim_start()).map_err(|msg| add_loc(&msg))?,
+        ));
+    }
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+    if let Some(quoted) = value.strip_prefix('\'') {
+        let (literal, _) = quoted
+            .split_once('\'')
+            .ok_or_else(|| "Single quoted value is never closed.".to_string())?;
+        return Ok(literal.to_string());
+    }
+
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut parsed = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Ok(parsed),
+                '\\' => match chars.next() {
+                    Some('n') => parsed.push('\n'),
+                    Some('t') => parsed.push('\t'),
+                    Some(other) => parsed.push(other),
+                    None => break,
+                },
+                c => parsed.push(c),
+            }
+        }
+        return Err("Double quoted value is never closed.".to_string());
+    }
+
+    let value = match value.find(" #") {
+        Some(comment) => &value[..comment],
+        None => value,
+    };
+    Ok(value.trim_end().to_string())
+}
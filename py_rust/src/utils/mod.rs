@@ -1 +1,3 @@
+pub mod cmd;
+pub mod dotenv;
 pub mod toml;
@@ -1,38 +1,366 @@
-use bitbazaar::errors::TracedErr;
+use bitbazaar::{err, errors::TracedErr};
+use serde::Deserialize;
+use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Item, Table, TableLike, Value};
 
-/// Update a toml file with a json patch and remove some paths
+// String literal of replace, append, merge-by-index:
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArrayStrategy {
+    /// The patch's array replaces the existing one.
+    #[default]
+    Replace,
+    /// The patch's items are added to the end of the existing array.
+    Append,
+    /// Mappings are merged into the existing item at the same index, anything else replaces it. Extra items are added to the end.
+    MergeByIndex,
+}
+
+/// Update a toml file with a json patch and remove some paths, comments and formatting of untouched parts are kept as is.
 /// * `initial` - The toml input as a string
 /// * `update` - A json patch to apply to the toml input, overwrites anything existing, adds anything missing from the patch
-/// * `remove` - A list of paths to remove from the toml, applied after update. E.g. [["ctx", "foo"], ["ctx", "bar"]] would remove ctx.foo and ctx.bar from the toml file.
+/// * `array_strategy` - How arrays in the patch update existing arrays, replacing them by default
+/// * `sort_keys` - Sort the keys of every table alphabetically, recursively, so the output doesn't churn on key order
+/// * `remove` - A list of paths to remove from the toml, applied after update. E.g. [["ctx", "foo"], ["ctx", "bar"]] would remove ctx.foo and ctx.bar from the toml file. Numeric segments index into arrays, e.g. [["servers", "0"]] removes the first server.
 pub fn update(
     initial: &str,
     update: Option<serde_json::Value>,
     remove: Option<Vec<Vec<String>>>,
+    array_strategy: ArrayStrategy,
+    sort_keys: bool,
 ) -> Result<String, TracedErr> {
-    let mut jsonified: serde_json::Value = toml::from_str(initial)?;
+    let mut doc: DocumentMut = initial
+        .parse()
+        .map_err(|e| err!("Failed to parse toml: {}", e))?;
 
     if let Some(update) = update {
-        json_patch::merge(&mut jsonified, &update);
+        match update {
+            serde_json::Value::Object(patch) => merge(doc.as_table_mut(), &patch, array_strategy)?,
+            // Same as a json merge patch, a null clears everything:
+            serde_json::Value::Null => doc = DocumentMut::new(),
+            other => return Err(err!("Toml update must be a mapping, got '{}'.", other)),
+        }
     }
 
     if let Some(remove) = remove {
         for path in remove {
-            if !path.is_empty() {
-                let mut depth_obj = &mut jsonified;
-                for (index, key) in path.iter().enumerate() {
-                    if index == path.len() - 1 {
-                        if let Some(obj) = depth_obj.as_object_mut() {
-                            obj.remove(key);
+            remove_path(doc.as_table_mut(), &path)?;
+        }
+    }
+
+    if sort_keys {
+        sort_table(doc.as_table_mut(), &mut 0);
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Sort a table's keys, then its children's. Headers are ordered by their position in the document rather than their key,
+/// so they're renumbered depth first to follow the sorted keys.
+fn sort_table(table: &mut Table, position: &mut usize) {
+    let first_key = |table: &Table| {
+        table
+            .iter()
+            .find(|(_, item)| item.is_value())
+            .map(|(key, _)| key.to_string())
+    };
+    let old_first = first_key(table);
+    table.sort_values();
+
+    // A comment above the first key more likely describes the whole table, so it stays at the top:
+    if let (Some(old_first), Some(new_first)) = (old_first, first_key(table)) {
+        if old_first != new_first {
+            let prefix = |table: &Table, key: &str| {
+                table
+                    .key(key)
+                    .and_then(|key| key.leaf_decor().prefix().cloned())
+                    .unwrap_or_default()
+            };
+            let (old_prefix, new_prefix) = (prefix(table, &old_first), prefix(table, &new_first));
+            if let Some(mut key) = table.key_mut(&new_first) {
+                key.leaf_decor_mut().set_prefix(old_prefix);
+            }
+            if let Some(mut key) = table.key_mut(&old_first) {
+                key.leaf_decor_mut().set_prefix(new_prefix);
+            }
+        }
+    }
+
+    if !table.is_dotted() {
+        table.set_position(*position);
+        *position += 1;
+    }
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => sort_table(table, position),
+            Item::ArrayOfTables(tables) => tables
+                .iter_mut()
+                .for_each(|table| sort_table(table, position)),
+            Item::Value(value) => sort_value(value),
+            Item::None => {}
+        }
+    }
+}
+
+fn sort_value(value: &mut Value) {
+    match value {
+        // Inline tables can't hold comments, so are just reformatted as their spacing gets jumbled by the sort:
+        Value::InlineTable(table) => {
+            table.sort_values();
+            table.iter_mut().for_each(|(_, value)| sort_value(value));
+            table.fmt();
+        }
+        Value::Array(array) => array.iter_mut().for_each(sort_value),
+        _ => {}
+    }
+}
+
+/// Somewhere a path can lead, arrays are indexed by the path's numeric segments.
+enum Node<'a> {
+    Table(&'a mut dyn TableLike),
+    Array(&'a mut Array),
+    Tables(&'a mut ArrayOfTables),
+    Leaf,
+}
+
+impl<'a> Node<'a> {
+    fn from_item(item: &'a mut Item) -> Self {
+        match item {
+            Item::ArrayOfTables(tables) => Node::Tables(tables),
+            Item::Value(Value::Array(array)) => Node::Array(array),
+            item => item.as_table_like_mut().map_or(Node::Leaf, Node::Table),
+        }
+    }
+
+    fn from_value(value: &'a mut Value) -> Self {
+        match value {
+            Value::Array(array) => Node::Array(array),
+            Value::InlineTable(table) => Node::Table(table),
+            _ => Node::Leaf,
+        }
+    }
+}
+
+/// Remove the value at a path, missing keys are ignored but a bad array index errors.
+fn remove_path(root: &mut dyn TableLike, path: &[String]) -> Result<(), TracedErr> {
+    let Some((last, parents)) = path.split_last() else {
+        return Ok(());
+    };
+
+    let mut node = Node::Table(root);
+    for key in parents {
+        node = match node {
+            Node::Table(table) => table.get_mut(key).map(Node::from_item),
+            Node::Array(array) => {
+                let index = array_index(path, key, array.len())?;
+                array.get_mut(index).map(Node::from_value)
+            }
+            Node::Tables(tables) => {
+                let index = array_index(path, key, tables.len())?;
+                tables
+                    .get_mut(index)
+                    .map(|table| Node::Table(table as &mut dyn TableLike))
+            }
+            Node::Leaf => None,
+        }
+        .unwrap_or(Node::Leaf);
+    }
+
+    match node {
+        Node::Table(table) => {
+            table.remove(last);
+        }
+        Node::Array(array) => {
+            let index = array_index(path, last, array.len())?;
+            let removed = array.remove(index);
+            // Otherwise removing the first element would leave the second's leading space behind:
+            if let (0, Some(first)) = (index, array.get_mut(0)) {
+                first
+                    .decor_mut()
+                    .set_prefix(removed.decor().prefix().cloned().unwrap_or_default());
+            }
+        }
+        Node::Tables(tables) => {
+            let index = array_index(path, last, tables.len())?;
+            tables.remove(index);
+        }
+        Node::Leaf => {}
+    }
+    Ok(())
+}
+
+fn array_index(path: &[String], segment: &str, len: usize) -> Result<usize, TracedErr> {
+    let index = segment.parse::<usize>().map_err(|_| {
+        err!(
+            "Failed to remove '{}': '{}' indexes an array so must be a number.",
+            path.join("."),
+            segment
+        )
+    })?;
+    if index >= len {
+        return Err(err!(
+            "Failed to remove '{}': index {} is out of bounds for an array of {} item{}.",
+            path.join("."),
+            index,
+            len,
+            if len == 1 { "" } else { "s" }
+        ));
+    }
+    Ok(index)
+}
+
+/// Merge with the semantics of a json merge patch: nulls remove, mappings merge recursively and anything else replaces.
+/// Arrays only replace with the default strategy.
+fn merge(
+    table: &mut dyn TableLike,
+    patch: &serde_json::Map<String, serde_json::Value>,
+    strategy: ArrayStrategy,
+) -> Result<(), TracedErr> {
+    for (key, value) in patch.iter() {
+        match value {
+            serde_json::Value::Null => {
+                table.remove(key);
+            }
+            serde_json::Value::Object(nested) => {
+                if let Some(existing) = table.get_mut(key).and_then(Item::as_table_like_mut) {
+                    merge(existing, nested, strategy)?;
+                } else {
+                    // A table only holding child tables doesn't need its own header, an empty one still does.
+                    // Inserting into an inline table converts it:
+                    let mut new_table = Table::new();
+                    merge(&mut new_table, nested, strategy)?;
+                    let has_child_tables = new_table
+                        .iter()
+                        .any(|(_, item)| item.is_table() || item.is_array_of_tables());
+                    new_table.set_implicit(has_child_tables);
+                    table.insert(key, Item::Table(new_table));
+                }
+            }
+            serde_json::Value::Array(items)
+                if strategy != ArrayStrategy::Replace
+                    && table.get(key).is_some_and(|existing| {
+                        existing.is_array_of_tables() || existing.is_array()
+                    }) =>
+            {
+                if let Some(existing) = table.get_mut(key) {
+                    merge_array(key, existing, items, strategy)?;
+                }
+            }
+            value => {
+                let mut item = to_item(value)?;
+                match table.get_mut(key) {
+                    // Replaced in place so the key keeps its position and comments, the value keeps any trailing comment:
+                    Some(existing) => {
+                        if let (Item::Value(old), Item::Value(new)) = (&*existing, &mut item) {
+                            *new.decor_mut() = old.decor().clone();
                         }
-                    } else if let Some(obj) = depth_obj.get_mut(key) {
-                        depth_obj = obj;
-                    } else {
-                        break;
+                        *existing = item;
+                    }
+                    None => {
+                        table.insert(key, item);
                     }
                 }
             }
         }
     }
+    Ok(())
+}
 
-    Ok(toml::to_string_pretty(&jsonified)?)
+/// Update an existing array with the items of the patch's array, for the append and merge-by-index strategies.
+fn merge_array(
+    key: &str,
+    existing: &mut Item,
+    items: &[serde_json::Value],
+    strategy: ArrayStrategy,
+) -> Result<(), TracedErr> {
+    match existing {
+        Item::ArrayOfTables(tables) => {
+            for (index, item) in items.iter().enumerate() {
+                let Some(patch) = item.as_object() else {
+                    return Err(err!(
+                        "Can't add '{}' to the array of tables '{}', only mappings.",
+                        item,
+                        key
+                    ));
+                };
+                match tables.get_mut(index) {
+                    Some(table) if strategy == ArrayStrategy::MergeByIndex => {
+                        merge(table, patch, strategy)?
+                    }
+                    _ => {
+                        let mut table = Table::new();
+                        merge(&mut table, patch, strategy)?;
+                        tables.push(table);
+                    }
+                }
+            }
+        }
+        Item::Value(Value::Array(array)) => {
+            for (index, item) in items.iter().enumerate() {
+                match array.get_mut(index) {
+                    Some(old) if strategy == ArrayStrategy::MergeByIndex => match (old, item) {
+                        (Value::InlineTable(table), serde_json::Value::Object(patch)) => {
+                            merge(table, patch, strategy)?
+                        }
+                        (old, item) => {
+                            let mut new = to_value(item)?;
+                            *new.decor_mut() = old.decor().clone();
+                            *old = new;
+                        }
+                    },
+                    _ => array.push(to_value(item)?),
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn to_item(value: &serde_json::Value) -> Result<Item, TracedErr> {
+    match value {
+        // Arrays of mappings are written as [[tables]], like toml's own serializer:
+        serde_json::Value::Array(items)
+            if !items.is_empty() && items.iter().all(serde_json::Value::is_object) =>
+        {
+            let mut tables = ArrayOfTables::new();
+            for item in items.iter().filter_map(serde_json::Value::as_object) {
+                let mut table = Table::new();
+                merge(&mut table, item, ArrayStrategy::Replace)?;
+                tables.push(table);
+            }
+            Ok(Item::ArrayOfTables(tables))
+        }
+        value => Ok(Item::Value(to_value(value)?)),
+    }
+}
+
+fn to_value(value: &serde_json::Value) -> Result<Value, TracedErr> {
+    Ok(match value {
+        serde_json::Value::Null => {
+            return Err(err!("Toml has no null value, it can't be in an array."))
+        }
+        serde_json::Value::Bool(value) => Value::from(*value),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(int), _) => Value::from(int),
+            (None, _) if number.is_u64() => {
+                return Err(err!("Integer '{}' is too large for toml.", number))
+            }
+            (None, Some(float)) => Value::from(float),
+            (None, None) => return Err(err!("Unsupported number '{}'.", number)),
+        },
+        serde_json::Value::String(value) => Value::from(value.as_str()),
+        serde_json::Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(to_value)
+                .collect::<Result<Array, TracedErr>>()?,
+        ),
+        serde_json::Value::Object(map) => {
+            let mut table = InlineTable::new();
+            for (key, value) in map.iter().filter(|(_, value)| !value.is_null()) {
+                table.insert(key, to_value(value)?);
+            }
+            Value::InlineTable(table)
+        }
+    })
 }
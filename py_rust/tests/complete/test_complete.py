@@ -0,0 +1,78 @@
+import os
+import shutil
+import subprocess
+
+import pytest
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+
+
+def complete(root: str, config: str, target: str, prefix: str = "") -> "list[str]":
+    res = cli.run(["etch", "__complete", target, prefix, "--root", root, "--config", config])
+    return [line for line in res.splitlines() if line]
+
+
+def test_complete_fixture_project():
+    """Confirm the hidden completion entry point lists template paths."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("Hello", full_name="foo.etch.txt")
+        manager.tmpfile("Hello", full_name="bar.etch.txt")
+        manager.tmpfile("Hello", full_name="excluded.etch.txt")
+        manager.tmpfile("Not a template", full_name="plain.txt")
+        marker = os.path.join(manager.root_dir, "setup_ran.txt")
+        config = str(
+            manager.create_cfg(
+                {
+                    "exclude": ["excluded.etch.txt"],
+                    "setup_commands": ["touch {}".format(marker)],
+                    "context": {
+                        "static": {"FOO": {"value": "foo"}},
+                        "env": {"BAR": {"default": "bar"}},
+                        "cli": {"BAZ": {"commands": ["touch {}".format(marker)]}},
+                    },
+                }
+            )
+        )
+
+        assert complete(manager.root_dir, config, "only") == ["bar.etch.txt", "foo.etch.txt"]
+        assert complete(manager.root_dir, config, "only", "fo") == ["foo.etch.txt"]
+
+        # Completion must never run setup or cli commands:
+        assert not os.path.exists(marker)
+
+
+def test_complete_degrades_silently():
+    """Any error should produce no suggestions rather than failing."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("Hello", full_name="foo.etch.txt")
+        broken = str(manager.tmpfile("lafdldfa//$$ : foo ", suffix=".toml"))
+
+        assert complete(manager.root_dir, broken, "only") == []
+        assert complete(manager.root_dir, "madeup.toml", "only") == []
+
+
+@pytest.mark.parametrize("shell", ["bash", "zsh", "fish"])
+def test_completions_script(shell: str):
+    """Each shell's script should be printed, calling back into __complete for --only."""
+    assert "etch __complete only" in cli.run(["etch", "completions", shell])
+
+
+@pytest.mark.skipif(shutil.which("bash") is None, reason="bash isn't installed")
+def test_completions_bash_only():
+    """The bash script should complete --only with the templates under the root given first."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("Hello", full_name="foo.etch.txt")
+        manager.tmpfile("Hello", full_name="bar.etch.txt")
+        config = str(manager.create_cfg({}))
+        script = cli.run(["etch", "completions", "bash"])
+        line = 'COMP_WORDS=(etch "{}" --config "{}" --only fo); COMP_CWORD=5; _etch'.format(
+            manager.root_dir, config
+        )
+        result = subprocess.run(
+            ["bash", "-c", '{}\n{}\nprintf "%s\\n" "${{COMPREPLY[@]}}"'.format(script, line)],
+            capture_output=True,
+            text=True,
+            check=True,
+        )
+        assert result.stdout.splitlines() == ["foo.etch.txt"]
@@ -0,0 +1,75 @@
+import os
+
+import pytest
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+
+
+def test_explain():
+    """Explain should report exclusion, junk, the matched marker and the output path."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("Hello", full_name="foo.etch.txt")
+        manager.tmpfile("Hello", full_name="plain.txt")
+        manager.tmpfile("Hello", full_name="foo.etch.txt~")
+        excluded = manager.tmpdir(name="excluded")
+        manager.tmpfile("Hello", parent=excluded, full_name="bar.etch.txt")
+        manager.tmpfile("ignored.etch.txt", full_name=".etchignore")
+        manager.tmpfile("Hello", full_name="ignored.etch.txt")
+        marker = os.path.join(manager.root_dir, "setup_ran.txt")
+        config = str(
+            manager.create_cfg(
+                {"exclude": ["excluded"], "setup_commands": ["touch {}".format(marker)]}
+            )
+        )
+
+        def explain(name: str) -> list[str]:
+            return cli.run(
+                [
+                    "etch",
+                    "explain",
+                    os.path.join(manager.root_dir, name),
+                    "--root",
+                    str(manager.root_dir),
+                    "--config",
+                    config,
+                ]
+            ).splitlines()
+
+        assert explain("foo.etch.txt") == [
+            "Path:     foo.etch.txt",
+            "Excluded: no",
+            "Junk:     no",
+            "Template: yes, matched the 'etch' marker",
+            "Output:   foo.txt",
+            "Renders:  yes",
+        ]
+
+        lines = explain("plain.txt")
+        assert "Template: no, the filename contains none of the markers: 'etch'" in lines
+        assert lines[-1] == "Renders:  no"
+
+        lines = explain("foo.etch.txt~")
+        assert "Junk:     yes" in lines
+        assert lines[-1] == "Renders:  no"
+
+        lines = explain("excluded/bar.etch.txt")
+        assert (
+            "Excluded: yes, by the pattern 'excluded' at [exclude.0] "
+            "(via its parent directory 'excluded')"
+        ) in lines
+        assert "Output:   excluded/bar.txt" in lines
+        assert lines[-1] == "Renders:  no"
+
+        lines = explain("ignored.etch.txt")
+        assert lines[1].startswith(
+            "Excluded: yes, by the pattern 'ignored.etch.txt' in ignore file"
+        )
+        assert lines[-1] == "Renders:  no"
+
+        # Paths outside the root are rejected:
+        with pytest.raises(ValueError, match="is not inside the root"):
+            explain("..")
+
+        # Side-effect free:
+        assert not os.path.exists(marker)
@@ -13,14 +13,71 @@ class RenderResult(tp.TypedDict):
 def render(
     root: tp.Union[str, pathlib.Path],
     config_file: tp.Optional[tp.Union[str, os.PathLike[str]]] = None,
+    no_discover: bool = False,
     force: bool = False,
+    no_lockfile: bool = False,
+    lockfile: tp.Optional[tp.Union[str, pathlib.Path]] = None,
+    out_dir: tp.Optional[tp.Union[str, pathlib.Path]] = None,
     verbose: bool = False,
+    variant: tp.Optional[str] = None,
+    profile: tp.Optional[str] = None,
+    skip_path_checks: bool = False,
+    strict: bool = False,
+    allow_overwrite_sources: bool = False,
+    check_vars: bool = False,
+    gitignore: bool = False,
+    follow_symlinks: bool = False,
+    only: tp.Optional[list[str]] = None,
+    env_files: tp.Optional[list[tp.Union[str, pathlib.Path]]] = None,
+    extra_roots: tp.Optional[list[tp.Union[str, pathlib.Path]]] = None,
 ) -> RenderResult:
-    args = ["etch", "--debug", root]
+    args = ["etch", "--debug", root, *(extra_roots or [])]
 
     if config_file is not None:
         args += ["--config", str(config_file)]
 
+    if no_discover:
+        args += ["--no-discover"]
+
+    if variant is not None:
+        args += ["--variant", variant]
+
+    if profile is not None:
+        args += ["--profile", profile]
+
+    if skip_path_checks:
+        args += ["--skip-path-checks"]
+
+    if strict:
+        args += ["--strict"]
+
+    if allow_overwrite_sources:
+        args += ["--allow-overwrite-sources"]
+
+    if check_vars:
+        args += ["--check-vars"]
+
+    if no_lockfile:
+        args += ["--no-lockfile"]
+
+    if lockfile is not None:
+        args += ["--lockfile", str(lockfile)]
+
+    if out_dir is not None:
+        args += ["--out-dir", str(out_dir)]
+
+    if gitignore:
+        args += ["--gitignore"]
+
+    if follow_symlinks:
+        args += ["--follow-symlinks"]
+
+    for pattern in only or []:
+        args += ["--only", pattern]
+
+    for env_file in env_files or []:
+        args += ["--env-file", str(env_file)]
+
     if force:
         args.insert(1, "--force")
     if verbose:
@@ -52,9 +109,9 @@ def init(root: tp.Union[str, pathlib.Path]):
         print(total_output)
 
 
-def run(args: list[str]) -> str:
+def run(args: list[str], cwd: tp.Optional[tp.Union[str, pathlib.Path]] = None) -> str:
     """Run an arbitrary command, returning stdout and err combined. Raises ValueError on non-zero exit code."""
-    p1 = subprocess.run(args, capture_output=True, text=True)
+    p1 = subprocess.run(args, capture_output=True, text=True, cwd=cwd)
     total_output = f"{p1.stdout}\n{p1.stderr}".strip()
     if p1.returncode != 0:
         raise ValueError(total_output)
@@ -19,6 +19,49 @@ class StaticCtx(tp.TypedDict):
     coerce: tp.NotRequired[Coerce_T]
 
 
+class SetupCommand(tp.TypedDict):
+    command: str
+    allow_error: tp.NotRequired[bool]
+    parallel_group: tp.NotRequired[str]
+    cwd: tp.NotRequired[str]
+
+
+class FinishCommand(tp.TypedDict):
+    command: str
+    only_if_written: tp.NotRequired[bool]
+    cwd: tp.NotRequired[str]
+
+
+class PostWriteHook(tp.TypedDict):
+    match: str
+    command: str
+
+
+class InPlace(tp.TypedDict):
+    match: str
+    start: tp.NotRequired[str]
+    template_end: tp.NotRequired[str]
+    end: tp.NotRequired[str]
+    line_prefix: tp.NotRequired[str]
+
+
+class EngineOverride(tp.TypedDict):
+    match: str
+    variable_start: tp.NotRequired[str]
+    variable_end: tp.NotRequired[str]
+    block_start: tp.NotRequired[str]
+    block_end: tp.NotRequired[str]
+    comment_start: tp.NotRequired[str]
+    comment_end: tp.NotRequired[str]
+    line_statement_prefix: tp.NotRequired[str]
+    line_comment_prefix: tp.NotRequired[str]
+
+
+class ManagedRegion(tp.TypedDict):
+    begin: str
+    end: str
+
+
 class Engine(tp.TypedDict):
     variable_start: tp.NotRequired[str]
     variable_end: tp.NotRequired[str]
@@ -26,20 +69,80 @@ class Engine(tp.TypedDict):
     block_end: tp.NotRequired[str]
     comment_start: tp.NotRequired[str]
     comment_end: tp.NotRequired[str]
+    line_statement_prefix: tp.NotRequired[str]
+    line_comment_prefix: tp.NotRequired[str]
     keep_trailing_newline: tp.NotRequired[bool]
+    trim_blocks: tp.NotRequired[bool]
+    lstrip_blocks: tp.NotRequired[bool]
     allow_undefined: tp.NotRequired[bool]
+    max_undefined: tp.NotRequired[int]
+    max_fuel: tp.NotRequired[int]
+    max_include_depth: tp.NotRequired[int]
     custom_extensions: tp.NotRequired[list[str]]
+    include_paths: tp.NotRequired[list[str]]
+    include_dirs: tp.NotRequired[list[str]]
+    expose_process_env: tp.NotRequired[tp.Literal["none", "allowlist", "all"]]
+    env_allowlist: tp.NotRequired[list[str]]
+    builtin_globals: tp.NotRequired[bool]
+    allow_env_in_templates: tp.NotRequired[bool]
+    fixed_timestamp: tp.NotRequired[str]
+    line_endings: tp.NotRequired[tp.Literal["lf", "crlf", "preserve"]]
+    auto_escape: tp.NotRequired[dict[str, bool]]
+    overrides: tp.NotRequired[list[EngineOverride]]
+    managed_regions: tp.NotRequired[list[ManagedRegion]]
+
+
+class CliMultiKey(tp.TypedDict):
+    path: str
+    coerce: tp.NotRequired[Coerce_T]
+
+
+class CliMultiCtx(tp.TypedDict):
+    commands: list[str]
+    parse: tp.Literal["json", "yaml", "toml"]
+    keys: dict[str, tp.Union[str, CliMultiKey]]
 
 
 class InputContext(tp.TypedDict):
     static: tp.NotRequired[dict[str, StaticCtx]]
     cli: tp.NotRequired[dict[str, CliCtx]]
     env: tp.NotRequired[dict[str, EnvCtx]]
+    cli_multi: tp.NotRequired[dict[str, CliMultiCtx]]
+
+
+class Profile(tp.TypedDict):
+    context: tp.NotRequired[InputContext]
+    exclude: tp.NotRequired[list[str]]
+    engine: tp.NotRequired[Engine]
+
+
+class TemplateMatcher(tp.TypedDict):
+    pattern: str
+    replacement: str
 
 
 class InputConfig(tp.TypedDict):
+    extends: tp.NotRequired[tp.Union[str, list[str]]]
+    profiles: tp.NotRequired[dict[str, Profile]]
     ignore_files: tp.NotRequired[list[str]]
-    setup_commands: tp.NotRequired[list[str]]
+    use_gitignore: tp.NotRequired[bool]
+    follow_symlinks: tp.NotRequired[bool]
+    max_depth: tp.NotRequired[int]
+    ignore_junk: tp.NotRequired[bool]
+    junk_patterns: tp.NotRequired[list[str]]
+    template_marker: tp.NotRequired[tp.Union[str, list[str]]]
+    template_matchers: tp.NotRequired[list[TemplateMatcher]]
+    partial_prefix: tp.NotRequired[str]
+    hash_algo: tp.NotRequired[tp.Literal["fnv1a", "sha256"]]
+    max_template_size: tp.NotRequired[int]
+    on_binary: tp.NotRequired[tp.Literal["error", "skip"]]
+    lockfile: tp.NotRequired[str]
+    env_files: tp.NotRequired[list[str]]
+    out_dir: tp.NotRequired[str]
+    setup_commands: tp.NotRequired[list[tp.Union[str, SetupCommand]]]
+    finish_commands: tp.NotRequired[list[tp.Union[str, FinishCommand]]]
+    post_write: tp.NotRequired[list[PostWriteHook]]
+    in_place: tp.NotRequired[list[InPlace]]
     exclude: tp.NotRequired[list[str]]
     engine: tp.NotRequired[Engine]
     context: tp.NotRequired[InputContext]
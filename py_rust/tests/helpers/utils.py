@@ -2,6 +2,8 @@ import pathlib
 import re
 import typing as tp
 
+import etcher as etch
+
 from . import cli
 from .tmp_file_manager import TmpFileManager
 
@@ -26,6 +28,14 @@ def get_lockfile_path(root: tp.Union[str, pathlib.Path]) -> pathlib.Path:
     return pathlib.Path(root).joinpath(f"./{_LOCK_FILENAME}")
 
 
+def lock_entry(output: str, source: str, algo: str = "fnv1a") -> dict[str, str]:
+    """The lockfile entry for a template rendering source to output."""
+    return {
+        "output_hash": etch._hash_contents(output, algo=algo),
+        "source_hash": etch._hash_contents(source, algo=algo),
+    }
+
+
 def check_single(
     manager: TmpFileManager,
     config_file: tp.Union[str, pathlib.Path],
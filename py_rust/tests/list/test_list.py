@@ -0,0 +1,46 @@
+import json
+import os
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+
+
+def test_list():
+    """Templates and their output paths should be listed without rendering or running commands."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("Hello", full_name="foo.etch.txt")
+        manager.tmpfile("Hello", full_name="bar.etch")
+        manager.tmpfile("Hello", full_name="excluded.etch.txt")
+        manager.tmpfile("Not a template", full_name="plain.txt")
+        sub = manager.tmpdir(name="sub")
+        manager.tmpfile("Hello", parent=sub, full_name="nested.etch.json")
+        marker = os.path.join(manager.root_dir, "setup_ran.txt")
+        config = str(
+            manager.create_cfg(
+                {
+                    "exclude": ["excluded.etch.txt"],
+                    "setup_commands": ["touch {}".format(marker)],
+                }
+            )
+        )
+
+        output = cli.run(["etch", "list", str(manager.root_dir), "--config", config])
+        assert output.splitlines() == [
+            "bar.etch -> bar",
+            "foo.etch.txt -> foo.txt",
+            "sub/nested.etch.json -> sub/nested.json",
+        ]
+
+        output = cli.run(
+            ["etch", "list", str(manager.root_dir), "--config", config, "--output-format", "json"]
+        )
+        assert json.loads(output) == [
+            {"template": "bar.etch", "output": "bar"},
+            {"template": "foo.etch.txt", "output": "foo.txt"},
+            {"template": "sub/nested.etch.json", "output": "sub/nested.json"},
+        ]
+
+        # Nothing rendered and no commands run:
+        assert not os.path.exists(os.path.join(manager.root_dir, "foo.txt"))
+        assert not os.path.exists(os.path.join(manager.root_dir, ".etch.lock"))
+        assert not os.path.exists(marker)
@@ -1,11 +1,15 @@
+import os
 import re
 import typing as tp
+from unittest import mock
 
+import etcher as etch
 import pytest
 
+from ..helpers import cli
 from ..helpers.tmp_file_manager import TmpFileManager
 from ..helpers.types import StaticCtx
-from ..helpers.utils import check_single
+from ..helpers.utils import check_single, remove_template
 
 DEFAULT_MODULE = """import etcher as etch
 @etch.register_function
@@ -112,6 +116,484 @@ def test_custom_func_various(template_src: str, static_ctx: dict[str, StaticCtx]
         )
 
 
+FILTER_MODULE = """import etcher as etch
+@etch.register_filter
+def shout(value, suffix="!"):
+    return "{}{}".format(value.upper(), suffix)
+
+@etch.register_filter
+def total(values, start=0):
+    return start + sum(values)
+"""
+
+
+@pytest.mark.parametrize(
+    "template_src,expected",
+    [
+        ("{{ 'hello' | shout }}", "HELLO!"),
+        ("{{ 'hello' | shout('?') }}", "HELLO?"),
+        ("{{ 'hello' | shout(suffix='?') }}", "HELLO?"),
+        ("{{ [1, 2, 3] | total(start=4) }}", "10"),
+        # Chained with a built in filter:
+        ("{{ 'hello' | shout | lower }}", "hello!"),
+        # Filters don't take the function namespace:
+        ("{% if shout is undefined %}NOT A FUNC{% endif %}", "NOT A FUNC"),
+    ],
+)
+def test_custom_filters(template_src: str, expected: str):
+    """User defined custom filters receive the piped value as the first arg."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(FILTER_MODULE, suffix=".py")
+        check_single(
+            manager,
+            manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+            template_src,
+            expected,
+        )
+
+
+def test_custom_filter_builtin_clash():
+    """Custom filters shouldn't silently replace built in filters."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_filter
+def upper(value):
+    return value
+""",
+            suffix=".py",
+        )
+        with pytest.raises(ValueError, match="as it clashes with the built in filter 'upper'."):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+                "{{ 'hello' | upper }}",
+                "HELLO",
+            )
+
+
+TEST_MODULE = """import etcher as etch
+@etch.register_test
+def internal(value):
+    return value.endswith(".internal")
+
+@etch.register_test
+def longer_than(value, length):
+    return len(value) > length
+
+@etch.register_test
+def has_items(value):
+    return value
+
+@etch.register_test
+def broken(value):
+    raise ValueError("I AM AN ERROR")
+"""
+
+
+@pytest.mark.parametrize(
+    "template_src,expected",
+    [
+        ("{% if 'db.internal' is internal %}YES{% else %}NO{% endif %}", "YES"),
+        ("{% if 'db.public' is internal %}YES{% else %}NO{% endif %}", "NO"),
+        ("{% if 'db.public' is not internal %}YES{% else %}NO{% endif %}", "YES"),
+        ("{% if 'abc' is longer_than(2) %}YES{% else %}NO{% endif %}", "YES"),
+        ("{{ ['a.internal', 'b.public'] | select('internal') | join(',') }}", "a.internal"),
+        # Non bool results are coerced with python truthiness:
+        ("{% if 'yes' is has_items %}YES{% else %}NO{% endif %}", "YES"),
+        ("{% if '' is has_items %}YES{% else %}NO{% endif %}", "NO"),
+        ("{% if [] is has_items %}YES{% else %}NO{% endif %}", "NO"),
+        ("{% if {'a': 1} is has_items %}YES{% else %}NO{% endif %}", "YES"),
+    ],
+)
+def test_custom_tests(template_src: str, expected: str):
+    """User defined custom tests receive the tested value as the first arg."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(TEST_MODULE, suffix=".py")
+        check_single(
+            manager,
+            manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+            template_src,
+            expected,
+        )
+
+
+def test_custom_test_error():
+    """Errors raised by custom tests should name the test."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(TEST_MODULE, suffix=".py")
+        with pytest.raises(
+            ValueError,
+            match=re.escape("Failed to call custom test 'broken'. Err: 'ValueError: I AM AN ERROR'"),
+        ):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+                "{% if 1 is broken %}{% endif %}",
+                "",
+            )
+
+
+def test_custom_ext_traceback():
+    """Exceptions raised by extensions should show the full python traceback under the summary."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+
+def _innermost(value):
+    raise ValueError("deep failure {}".format(value))
+
+def _helper(value):
+    return _innermost(value)
+
+@etch.register_filter
+def explode(value):
+    return _helper(value)
+""",
+            suffix=".py",
+        )
+        manager.tmpfile("line one\n{{ 'x' | explode }}", full_name="boom.etch.txt")
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+            )
+        msg = str(exc_info.value)
+        assert "[boom.etch.txt]: Failed to render template" in msg
+        assert "Failed to call custom filter 'explode'" in msg
+        assert "boom.etch.txt:2" in msg
+        assert "Traceback (most recent call last):" in msg
+        # Every frame down to where it was raised:
+        assert msg.index("in explode") < msg.index("in _helper") < msg.index("in _innermost")
+        assert "ValueError: deep failure x" in msg
+
+
+def test_custom_func_number_fidelity():
+    """Integers should keep their full 64 bit range through custom functions, floats staying floats."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import datetime
+import decimal
+import etcher as etch
+
+@etch.register_function
+def ident(value):
+    return value
+
+@etch.register_function
+def types(*values):
+    return ",".join(type(value).__name__ for value in values)
+
+@etch.register_function
+def huge():
+    return 2**64
+
+@etch.register_function
+def price():
+    return decimal.Decimal("1.10")
+
+@etch.register_function
+def day():
+    return datetime.date(2024, 1, 2)
+""",
+            suffix=".py",
+        )
+        cfg = manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}})
+        check_single(
+            manager,
+            cfg,
+            "{{ ident(9007199254740993) }} {{ ident(18446744073709551615) }} "
+            "{{ ident(2.0) }} {{ ident([1, 2.5]) }} {{ types(9007199254740993, 2.0) }} "
+            # No template equivalent, so stringified:
+            "{{ price() }} {{ day() }}",
+            "9007199254740993 18446744073709551615 2.0 [1, 2.5] int,float 1.10 2024-01-02",
+        )
+
+        manager.tmpfile("{{ huge() }}", full_name="huge.etch.txt")
+        with pytest.raises(ValueError, match="Integer '18446744073709551616' is too large"):
+            cli.render(manager.root_dir, cfg)
+
+
+@pytest.mark.parametrize(
+    "first,second,expected_err",
+    [
+        (
+            "register_function",
+            "register_filter",
+            "custom filter: 'foo.dupe' as 'dupe' is already registered as a custom function.",
+        ),
+        (
+            "register_filter",
+            "register_test",
+            "custom test: 'foo.dupe' as 'dupe' is already registered as a custom filter.",
+        ),
+        (
+            "register_test",
+            "register_function",
+            "custom function: 'foo.dupe' as 'dupe' is already registered as a custom test.",
+        ),
+    ],
+)
+def test_custom_callable_kind_clash(first: str, second: str, expected_err: str):
+    """The same name registered as different kinds of callable should be diagnosed."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.{}
+def dupe(value):
+    return value
+
+def dupe_2(value):
+    return value
+dupe_2.__name__ = "dupe"
+etch.{}(dupe_2)
+""".format(first, second),
+            full_name="foo.py",
+        )
+        with pytest.raises(ValueError, match=re.escape(expected_err)):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+                "",
+                "",
+            )
+
+
+def test_custom_test_builtin_clash():
+    """Custom tests shouldn't silently replace built in tests."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_test
+def odd(value):
+    return False
+""",
+            suffix=".py",
+        )
+        with pytest.raises(ValueError, match="as it clashes with the built in test 'odd'."):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+                "{% if 1 is odd %}{% endif %}",
+                "",
+            )
+
+
+def test_custom_ext_current_template():
+    """Custom functions can query which template is being rendered."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_function
+def which():
+    return etch.current_template()
+""",
+            suffix=".py",
+        )
+        template_1 = manager.tmpfile("{{ which() }}", full_name="one.etch.txt")
+        sub = manager.tmpdir(name="sub")
+        template_2 = manager.tmpfile("{{ which() }}", full_name="two.etch.txt", parent=sub)
+
+        cli.render(manager.root_dir, manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}))
+        with open(remove_template(template_1), "r") as file:
+            assert file.read() == "one.etch.txt"
+        with open(remove_template(template_2), "r") as file:
+            assert file.read() == os.path.join("sub", "two.etch.txt")
+
+    # Outside of rendering should raise:
+    with pytest.raises(ValueError, match="No template is being rendered"):
+        etch.current_template()
+
+
+def test_custom_func_needs_context():
+    """needs_context functions receive a copy of the context first, mutating it doesn't affect the render."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_function(needs_context=True)
+def shout_greeting(ctx, suffix):
+    ctx["greeting"] += suffix
+    return ctx["greeting"].upper()
+""",
+            suffix=".py",
+        )
+        config = manager.create_cfg(
+            {
+                "context": {"static": {"greeting": {"value": "hi"}}},
+                "engine": {"custom_extensions": [str(ext)]},
+            }
+        )
+        check_single(
+            manager,
+            config,
+            "{{ shout_greeting('!') }} {{ shout_greeting(suffix='?') }} {{ greeting }}",
+            "HI! HI? hi",
+        )
+
+
+def test_custom_func_without_name_attrs():
+    """Partials, lambdas and callable instances can be registered, with an explicit name when they lack __name__."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import functools
+import etcher as etch
+
+def join(sep, *parts):
+    return sep.join(parts)
+
+class Counter:
+    def __call__(self, value):
+        return len(value)
+
+etch.register_function(functools.partial(join, "-"), name="dash_join")
+etch.register_function(lambda value: value * 2, name="double")
+etch.register_function(Counter(), name="count")
+
+try:
+    etch.register_function(functools.partial(join, "+"))
+except Exception as e:
+    assert "has no __name__, pass name= explicitly" in str(e), e
+else:
+    raise AssertionError("Expected a missing name error")
+""",
+            suffix=".py",
+        )
+        check_single(
+            manager,
+            manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+            "{{ dash_join('a', 'b') }} {{ double(2) }} {{ count('abc') }}",
+            "a-b 4 3",
+        )
+
+
+def test_register_function_overwrite_and_clear():
+    """Re-registering should work with overwrite=True or after clearing, e.g. for notebooks and reruns."""
+
+    def first():
+        return "first"
+
+    def second():
+        return "second"
+
+    with TmpFileManager() as manager:
+        # Functions are only added to the environment when extensions are configured:
+        ext = manager.tmpfile("", suffix=".py")
+        template = manager.tmpfile("{{ reregistered() }}", suffix=".etch.txt")
+        config = manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}})
+
+        def render() -> str:
+            assert etch._render([manager.root_dir, "--config", str(config), "--force"])
+            with open(remove_template(template), "r") as file:
+                return file.read()
+
+        etch.register_function(first, name="reregistered")
+        with pytest.raises(Exception, match=re.escape("pass overwrite=True to replace it")):
+            etch.register_function(second, name="reregistered")
+        assert render() == "first"
+
+        etch.register_function(second, name="reregistered", overwrite=True)
+        assert render() == "second"
+
+        etch.clear_registered_functions()
+        etch.register_function(first, name="reregistered")
+        assert render() == "first"
+
+        etch.clear_registered_functions()
+
+
+def test_register_filter_and_test_overwrite_and_clear():
+    """Filters and tests should re-register with overwrite=True or after clearing, just like functions."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile("", suffix=".py")
+        template = manager.tmpfile("{{ 'a' | refilter }} {{ 'a' is retest }}", suffix=".etch.txt")
+        config = manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}})
+
+        def render() -> str:
+            assert etch._render([manager.root_dir, "--config", str(config), "--force"])
+            with open(remove_template(template), "r") as file:
+                return file.read()
+
+        etch.register_filter(lambda value: value + "1", name="refilter")
+        etch.register_test(lambda value: True, name="retest")
+        with pytest.raises(Exception, match=re.escape("pass overwrite=True to replace it")):
+            etch.register_filter(lambda value: value + "2", name="refilter")
+        with pytest.raises(Exception, match=re.escape("pass overwrite=True to replace it")):
+            etch.register_test(lambda value: False, name="retest")
+        assert render() == "a1 true"
+
+        # Also usable as a decorator:
+        @etch.register_filter(name="refilter", overwrite=True)
+        def _refilter(value):
+            return value + "2"
+
+        etch.register_test(lambda value: False, name="retest", overwrite=True)
+        assert render() == "a2 false"
+
+        etch.clear_registered_functions()
+        etch.register_filter(lambda value: value + "3", name="refilter")
+        etch.register_test(lambda value: True, name="retest")
+        assert render() == "a3 true"
+
+        etch.clear_registered_functions()
+
+
+def test_custom_ext_persists_between_renders():
+    """Registered callables should still resolve when a second environment is created in the same process, e.g. watch mode."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_function
+def persisted():
+    return "I PERSIST"
+""",
+            full_name="persist_between_renders_mod.py",
+        )
+        template = manager.tmpfile("{{ persisted() }}", suffix=".etch.txt")
+        config = manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}})
+
+        # The module import is cached after the first render, so the second relies on the registry persisting:
+        for _ in range(2):
+            assert etch._render([manager.root_dir, "--config", str(config), "--force"])
+            with open(remove_template(template), "r") as file:
+                assert file.read() == "I PERSIST"
+
+
+def test_context_scoped_to_render():
+    """Sequential renders in one process should each see their own context, only during the render."""
+    with TmpFileManager() as first, TmpFileManager() as second:
+        ext = first.tmpfile(
+            """import etcher as etch
+@etch.register_function
+def scoped_name():
+    return etch.context()["name"]
+
+@etch.register_function
+def mutated_name():
+    etch.context()["name"] = "mutated"
+    return etch.context()["name"]
+""",
+            full_name="scoped_context_mod.py",
+        )
+        for manager, name in [(first, "first"), (second, "second")]:
+            template = manager.tmpfile(
+                "{{ scoped_name() }} {{ mutated_name() }}", suffix=".etch.txt"
+            )
+            config = manager.create_cfg(
+                {
+                    "context": {"static": {"name": {"value": name}}},
+                    "engine": {"custom_extensions": [str(ext)]},
+                }
+            )
+            assert etch._render([manager.root_dir, "--config", str(config), "--force"])
+            with open(remove_template(template), "r") as file:
+                assert file.read() == "{} {}".format(name, name)
+
+        with pytest.raises(ValueError, match="Context not registered"):
+            etch.context()
+
+    etch.clear_registered_functions()
+
+
 def test_custom_pkg():
     """Confirm a pkg can be used as a custom extension."""
     with TmpFileManager() as manager:
@@ -265,6 +747,30 @@ def foo():
                 "I AM A FUNC I AM A STATIC VAR",
             )
 
+    # Custom func named env shouldn't replace the built in env() function, which is enabled by default:
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+@etch.register_function
+def env():
+    return "I AM A FUNC"
+""",
+            full_name="env_mod.py",
+            suffix=".py",
+        )
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "Failed to register custom function: 'env_mod.env' as it clashes with the built in 'env'."
+            ),
+        ):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+                "{{ env() }}",
+                "I AM A FUNC",
+            )
+
     # Duplicate custom funcs should raise:
     with TmpFileManager() as manager:
         pkg = manager.tmpdir(name="pkg")
@@ -305,6 +811,124 @@ def foo():
             )
 
 
+def test_context_processors():
+    """Processors should transform the whole context in registration order before rendering."""
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+
+@etch.register_context_processor
+def add_url(ctx):
+    return {**ctx, "url": "{}:{}".format(ctx["host"], ctx["port"])}
+
+def shout_host(ctx):
+    ctx["host"] = ctx["host"].upper()
+    return ctx
+
+etch.register_context_processor(shout_host)
+
+@etch.register_function
+def seen_host():
+    return etch.context()["host"]
+""",
+            suffix=".py",
+        )
+        manager.tmpfile("{{ url }} {{ host }} {{ seen_host() }}", full_name="out.etch.txt")
+        config = manager.create_cfg(
+            {
+                "context": {
+                    "static": {"host": {"value": "localhost"}, "port": {"value": 8080}}
+                },
+                "engine": {"custom_extensions": [str(ext)]},
+            }
+        )
+        debug = cli.render(manager.root_dir, config)["debug"]
+        with open(os.path.join(manager.root_dir, "out.txt")) as f:
+            assert f.read() == "localhost:8080 LOCALHOST LOCALHOST"
+        # The debug report shows the processed context:
+        assert debug["config"]["context"] == {
+            "host": "LOCALHOST",
+            "port": 8080,
+            "url": "localhost:8080",
+        }
+
+    with TmpFileManager() as manager:
+        ext = manager.tmpfile(
+            """import etcher as etch
+
+@etch.register_context_processor
+def listify(ctx):
+    return list(ctx)
+""",
+            full_name="bad_processor.py",
+        )
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "Context processor 'bad_processor.listify' returned a 'list', expected a mapping"
+            ),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}}),
+            )
+
+
+def test_custom_ext_package_and_module_name():
+    """Packages should import with relative imports working, dotted names should import from the environment."""
+    with TmpFileManager() as manager:
+        pkg = manager.tmpdir(name="helpers_pkg")
+        manager.tmpfile("from . import funcs\n", full_name="__init__.py", parent=pkg)
+        manager.tmpfile('GREETING = "Hello"\n', full_name="consts.py", parent=pkg)
+        manager.tmpfile(
+            """import etcher as etch
+from .consts import GREETING
+
+@etch.register_function
+def greet(name):
+    return "{}, {}!".format(GREETING, name)
+""",
+            full_name="funcs.py",
+            parent=pkg,
+        )
+
+        # Only importable through PYTHONPATH, like a module installed in the environment:
+        site = manager.tmpdir(name="site")
+        installed = manager.tmpdir(parent=str(site), name="etch_installed")
+        manager.tmpfile("", full_name="__init__.py", parent=installed)
+        manager.tmpfile(
+            """import etcher as etch
+
+@etch.register_filter
+def shout(value):
+    return value.upper()
+""",
+            full_name="ext.py",
+            parent=installed,
+        )
+
+        with mock.patch.dict(os.environ, {"PYTHONPATH": str(site)}):
+            check_single(
+                manager,
+                # Relative to the config, a path of the same name takes precedence over a module:
+                manager.create_cfg(
+                    {"engine": {"custom_extensions": ["helpers_pkg", "etch_installed.ext"]}}
+                ),
+                "{{ greet('World') | shout }}",
+                "HELLO, WORLD!",
+            )
+
+        with pytest.raises(
+            ValueError, match="Failed to import custom extension 'not_installed.ext'"
+        ):
+            check_single(
+                manager,
+                manager.create_cfg({"engine": {"custom_extensions": ["not_installed.ext"]}}),
+                "",
+                "",
+            )
+
+
 def _directory_is_not_a_pkg(manager: TmpFileManager):
     pkg = manager.tmpdir(name="pkg")
     manager.tmpfile(
@@ -360,7 +984,7 @@ def foo():
     return "I AM A FUNC"
 """,
             "{{ foo('HELLO', bar=3) }}",
-            "Failed to call custom filter 'foo'. Err: 'TypeError: foo() got an unexpected keyword argument 'bar'",
+            "Failed to call custom function 'foo'. Err: 'TypeError: foo() got an unexpected keyword argument 'bar'",
         ),
         # Error in custom func:
         (
@@ -371,7 +995,7 @@ def foo():
     return "I AM A FUNC"
 """,
             "{{ foo() }}",
-            "Failed to call custom filter 'foo'. Err: 'ValueError: I AM AN ERROR'",
+            "Failed to call custom function 'foo'. Err: 'ValueError: I AM AN ERROR'",
         ),
         # Unable to convert return to rust:
         (
@@ -383,7 +1007,7 @@ def foo():
     return Foo()
 """,
             "{{ foo() }}",
-            "Failed to call custom filter 'foo'. Err: 'Failed to convert python result to a rust-like value: 'unsupported type foo.<locals>.Foo'",
+            "Failed to call custom function 'foo'. Err: 'Failed to convert python result to a rust-like value: 'unsupported type foo.<locals>.Foo'",
         ),
     ],
 )
@@ -1,13 +1,16 @@
+import json
 import os
 import re
 import typing as tp
 from pathlib import Path
+from unittest import mock
 
 import pytest
 
+from ..helpers import cli
 from ..helpers.tmp_file_manager import TmpFileManager
 from ..helpers.types import Engine, InputConfig
-from ..helpers.utils import check_single
+from ..helpers.utils import check_single, get_lockfile_path, remove_template
 
 
 @pytest.mark.parametrize(
@@ -48,6 +51,100 @@ def test_include_other_template(
             )
 
 
+def test_include_paths():
+    """Includes should fall back to the include paths in order, resolved from the config file.
+
+    - The root takes precedence, then each include path in order.
+    - Files in include paths are never rendered themselves, even when they have the template marker.
+    """
+    with TmpFileManager() as manager:
+        with TmpFileManager() as shared_manager:
+            partials = shared_manager.tmpdir(name="partials")
+            other = shared_manager.tmpdir(name="other")
+            shared_manager.tmpfile("shared header", parent=partials, full_name="header.txt")
+            shared_manager.tmpfile("other header", parent=other, full_name="header.txt")
+            shared_manager.tmpfile("other footer", parent=other, full_name="footer.txt")
+            shared_manager.tmpfile("{{ 1 + 1 }}", parent=partials, full_name="macro.etch.txt")
+            config = shared_manager.create_cfg(
+                {"engine": {"include_paths": ["partials", str(other)]}}
+            )
+
+            check_single(
+                manager,
+                config,
+                "{% include 'header.txt' %}|{% include 'footer.txt' %}"
+                "|{% include 'macro.etch.txt' %}",
+                "shared header|other footer|2",
+            )
+
+            # The root takes precedence:
+            manager.tmpfile("root header", full_name="header.txt")
+            manager.tmpfile("{% include 'header.txt' %}", full_name="root.etch.txt")
+            cli.render(manager.root_dir, config)
+            with open(os.path.join(manager.root_dir, "root.txt"), "r") as file:
+                assert file.read() == "root header"
+
+            assert not os.path.exists(os.path.join(partials, "macro.txt"))
+
+            # Missing include paths should error against their config location:
+            with pytest.raises(ValueError, match=re.escape("[engine.include_paths.0]: Path '")):
+                cli.render(
+                    manager.root_dir,
+                    shared_manager.create_cfg({"engine": {"include_paths": ["madeup"]}}),
+                )
+
+
+def test_include_dirs_sibling():
+    """include_dirs is an alias of include_paths, e.g. for partials in a sibling of the root."""
+    with TmpFileManager() as manager:
+        project = manager.tmpdir(name="project")
+        partials = manager.tmpdir(parent=str(manager.tmpdir(name="shared")), name="partials")
+        manager.tmpfile("shared {{ 1 + 1 }}", parent=partials, full_name="header.etch.txt")
+        manager.tmpfile("{% include 'header.etch.txt' %}", parent=project, full_name="a.etch.txt")
+        config = manager.tmpfile(
+            '[engine]\ninclude_dirs = ["../shared/partials"]\n', parent=project, suffix=".toml"
+        )
+
+        result = cli.render(project, config)
+        assert result["debug"]["written"] == [os.path.join(project, "a.txt")]
+        with open(os.path.join(project, "a.txt"), "r") as file:
+            assert file.read() == "shared 2"
+
+        # Outside the root, so never rendered itself:
+        assert not os.path.exists(os.path.join(partials, "header.txt"))
+
+
+def test_partials():
+    """Templates starting with the partial prefix should only render through includes.
+
+    - Outputs rendered before they became partials are removed, unless edited since.
+    - An empty prefix renders every template.
+    """
+    with TmpFileManager() as manager:
+        manager.tmpfile("Header {{ title }}", full_name="_header.html.etch")
+        manager.tmpfile("Footer", full_name="_footer.html.etch")
+        manager.tmpfile("{% include '_header.html.etch' %}|page", full_name="page.html.etch")
+        ctx: InputConfig = {"context": {"static": {"title": {"value": "Home"}}}}
+
+        result = cli.render(manager.root_dir, manager.create_cfg({**ctx, "partial_prefix": ""}))
+        assert len(result["debug"]["written"]) == 3
+        assert result["debug"]["partials"] == []
+        with open(os.path.join(manager.root_dir, "_footer.html"), "w") as file:
+            file.write("Edited footer")
+
+        result = cli.render(manager.root_dir, manager.create_cfg(ctx))["debug"]
+        assert sorted(result["partials"]) == ["_footer.html.etch", "_header.html.etch"]
+        assert result["written"] == []
+        assert result["identical"] == ["page.html.etch"]
+        assert not os.path.exists(os.path.join(manager.root_dir, "_header.html"))
+        with open(os.path.join(manager.root_dir, "_footer.html"), "r") as file:
+            assert file.read() == "Edited footer"
+        with open(os.path.join(manager.root_dir, "page.html"), "r") as file:
+            assert file.read() == "Header Home|page"
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert list(json.load(file)["files"]["default"]) == ["page.html.etch"]
+
+
 @pytest.mark.parametrize(
     "template_src,config,expected",
     [
@@ -80,6 +177,18 @@ def test_engine_misc_syntax(template_src: str, config: InputConfig, expected: st
 DEFAULT_TEMPLATE_SRC = "Hello, {{ var }}!{# this is an ignored comment #}\nmybool is {% if mybool %}True{% else %}False{% endif %}\n"
 
 
+NESTED_BLOCKS_SRC = """services:
+{% for s in ['a', 'b'] %}
+  {% if s == 'a' %}
+  - {{ s }}: internal
+  {% else %}
+  - {{ s }}: public
+  {% endif %}
+{% endfor %}
+done
+"""
+
+
 @pytest.mark.parametrize(
     "template_src,engine_config,expected",
     [
@@ -136,6 +245,58 @@ DEFAULT_TEMPLATE_SRC = "Hello, {{ var }}!{# this is an ignored comment #}\nmyboo
             },
             DEFAULT_TEMPLATE_SRC,
         ),
+        # Block whitespace is kept by default:
+        (
+            "items:\n  {% for i in [1, 2] %}\n  - {{ i }}\n  {% endfor %}\n",
+            {},
+            "items:\n  \n  - 1\n  \n  - 2\n  \n",
+        ),
+        # trim_blocks removes the first newline after a block tag:
+        (
+            "items:\n  {% for i in [1, 2] %}\n  - {{ i }}\n  {% endfor %}\n",
+            {"trim_blocks": True},
+            "items:\n    - 1\n    - 2\n  ",
+        ),
+        # Line statements and comments when prefixes are set:
+        (
+            "# for i in [1, 2]\n- {{ var }} {{ i }}\n# endfor\n## a comment\ndone\n",
+            {"line_statement_prefix": "#", "line_comment_prefix": "##"},
+            "- World 1\n- World 2\ndone\n",
+        ),
+        # Unset by default:
+        (
+            "# for i in [1, 2]\n## a comment\n",
+            {},
+            "# for i in [1, 2]\n## a comment\n",
+        ),
+        # lstrip_blocks combined with trim_blocks gives clean yaml output:
+        (
+            "items:\n  {% for i in [1, 2] %}\n  - {{ i }}\n  {% endfor %}\n",
+            {"trim_blocks": True, "lstrip_blocks": True},
+            "items:\n  - 1\n  - 2\n",
+        ),
+        # Nested blocks, only trim_blocks leaves the indentation before tags behind:
+        (
+            NESTED_BLOCKS_SRC,
+            {"trim_blocks": True},
+            "services:\n    - a: internal\n      - b: public\n  done\n",
+        ),
+        (
+            NESTED_BLOCKS_SRC,
+            {"trim_blocks": True, "lstrip_blocks": True},
+            "services:\n  - a: internal\n  - b: public\ndone\n",
+        ),
+        # trim_blocks takes precedence over keep_trailing_newline when the template ends with a block tag:
+        (
+            "{% if true %}end{% endif %}\n",
+            {"trim_blocks": True, "keep_trailing_newline": True},
+            "end",
+        ),
+        (
+            "{% if true %}end{% endif %}\n",
+            {"keep_trailing_newline": True},
+            "end\n",
+        ),
     ],
 )
 def test_engine_config(template_src: str, engine_config: Engine, expected: str):
@@ -211,3 +372,470 @@ def test_allow_undefined(
         else:
             with pytest.raises(ValueError, match=re.escape(expected)):
                 check_single(manager, manager.create_cfg(config), template_src, expected)
+
+
+def test_check_vars():
+    """--check-vars should warn with every undefined variable up front, not just the first to fail."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("{{ known }} {{ missing }} {{ __rel_path__ }}", full_name="a.etch.txt")
+        manager.tmpfile("{{ also_missing }} {{ known.nested }}", full_name="b.etch.txt")
+        manager.tmpfile("{# etch: for item in items #}\n{{ item }}", full_name="{{ item }}.etch.txt")
+        config = manager.create_cfg(
+            {"context": {"static": {"known": {"value": "x"}, "items": {"value": [1, 2]}}}}
+        )
+        with pytest.raises(ValueError) as exc:
+            cli.render(manager.root_dir, config, check_vars=True)
+        assert "[a.etch.txt]: References undefined variable: missing\n" in str(exc.value)
+        assert "[b.etch.txt]: References undefined variables: also_missing, known.nested" in str(
+            exc.value
+        )
+        assert "{{ item }}.etch.txt]" not in str(exc.value)
+
+    with TmpFileManager() as manager:
+        manager.tmpfile("{{ known }}", full_name="a.etch.txt")
+        config = manager.create_cfg({"context": {"static": {"known": {"value": "x"}}}})
+        result = cli.render(manager.root_dir, config, check_vars=True)
+        assert "--check-vars found no undefined variables." in result["stdout"]
+
+
+@pytest.mark.parametrize(
+    "engine_config,template_src,expected,expected_is_err_match",
+    [
+        # Allowlisted names are exposed:
+        (
+            {"expose_process_env": "allowlist", "env_allowlist": ["ETCH_TEST_*"]},
+            "{{ env.ETCH_TEST_FOO }}",
+            "foo",
+            False,
+        ),
+        # Names outside the allowlist behave like any other undefined:
+        (
+            {"expose_process_env": "allowlist", "env_allowlist": ["ETCH_TEST_FOO"]},
+            "{{ env.ETCH_TEST_BAR }}",
+            "Failed to render template: 'undefined value",
+            True,
+        ),
+        # All mode exposes everything:
+        (
+            {"expose_process_env": "all"},
+            "{{ env.ETCH_TEST_FOO }} {{ env.ETCH_TEST_BAR }}",
+            "foo bar",
+            False,
+        ),
+        # Secret looking names are hidden in all mode:
+        (
+            {"expose_process_env": "all", "allow_undefined": True},
+            "{{ env.ETCH_TEST_TOKEN }}{{ env.ETCH_TEST_SECRET_KEY }}{{ env.ETCH_TEST_PASSWORD }}",
+            "",
+            False,
+        ),
+        # Unless explicitly allowlisted:
+        (
+            {"expose_process_env": "all", "env_allowlist": ["ETCH_TEST_TOKEN"]},
+            "{{ env.ETCH_TEST_TOKEN }}",
+            "tok",
+            False,
+        ),
+        # The env() function stays available alongside, reading any variable:
+        (
+            {"expose_process_env": "allowlist", "env_allowlist": ["ETCH_TEST_FOO"]},
+            "{{ env.ETCH_TEST_FOO }} {{ env('ETCH_TEST_BAR') }}",
+            "foo bar",
+            False,
+        ),
+        # Unless disabled, leaving only the exposed names:
+        (
+            {"expose_process_env": "all", "allow_env_in_templates": False},
+            "{{ env('ETCH_TEST_FOO') }}",
+            "env() failed: the function is disabled by [engine.allow_env_in_templates].",
+            True,
+        ),
+        # Nothing exposed by default:
+        (
+            {},
+            "{{ env.ETCH_TEST_FOO }}",
+            "Failed to render template: 'undefined value",
+            True,
+        ),
+    ],
+)
+def test_expose_process_env(
+    engine_config: Engine, template_src: str, expected: str, expected_is_err_match: bool
+):
+    """Confirm the process environment can be exposed under the reserved env global, alongside the env() function."""
+    with TmpFileManager() as manager:
+        with mock.patch.dict(
+            os.environ,
+            {
+                "ETCH_TEST_FOO": "foo",
+                "ETCH_TEST_BAR": "bar",
+                "ETCH_TEST_TOKEN": "tok",
+                "ETCH_TEST_SECRET_KEY": "secret",
+                "ETCH_TEST_PASSWORD": "pass",
+            },
+        ):
+            config = manager.create_cfg({"engine": engine_config})
+            if not expected_is_err_match:
+                check_single(manager, config, template_src, expected)
+            else:
+                with pytest.raises(ValueError, match=re.escape(expected)):
+                    check_single(manager, config, template_src, expected)
+
+
+def test_expose_process_env_debug_and_reserved():
+    """The exposed names should be reported in debug, and env can't be used as a context key when exposed."""
+    with TmpFileManager() as manager:
+        with mock.patch.dict(os.environ, {"ETCH_TEST_FOO": "foo", "ETCH_TEST_TOKEN": "tok"}):
+            result = cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "engine": {
+                            "expose_process_env": "allowlist",
+                            "env_allowlist": ["ETCH_TEST_*"],
+                        }
+                    }
+                ),
+            )
+            assert result["debug"]["exposed_env"] == ["ETCH_TEST_FOO", "ETCH_TEST_TOKEN"]
+
+    with TmpFileManager() as manager:
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[context.static.env]: 'env' is reserved when [engine.expose_process_env] is enabled."
+            ),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "context": {"static": {"env": {"value": "foo"}}},
+                        "engine": {"expose_process_env": "all"},
+                    }
+                ),
+            )
+
+
+def test_undefined_budget():
+    """Undefined values rendered empty in lenient mode are counted, reported and can fail the render past max_undefined."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile("{{ foo }}{{ obj.baz }}{{ obj.a }}{{ foo }}", suffix=".etch.txt")
+        config = {
+            "context": {"static": {"obj": {"value": {"a": 1}}}},
+            "engine": {"allow_undefined": True},
+        }
+        result = cli.render(manager.root_dir, manager.create_cfg(config))["debug"]
+        assert result["undefined"] == {
+            "total": 3,
+            "templates": [
+                {"template": template.name, "count": 3, "variables": ["foo", "obj.baz"]},
+            ],
+        }
+
+        # Within budget is fine:
+        config["engine"]["max_undefined"] = 3
+        cli.render(manager.root_dir, manager.create_cfg(config), force=True)
+
+        # Exceeding it fails:
+        config["engine"]["max_undefined"] = 2
+        with pytest.raises(
+            ValueError, match=re.escape("3 undefined values rendered empty, exceeding [engine.max_undefined] of 2.")
+        ):
+            cli.render(manager.root_dir, manager.create_cfg(config), force=True)
+
+    # Nothing is tracked in strict mode:
+    with TmpFileManager() as manager:
+        manager.tmpfile("{{ var }}", suffix=".etch.txt")
+        result = cli.render(
+            manager.root_dir, manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}})
+        )["debug"]
+        assert result["undefined"] is None
+
+
+def test_builtin_globals():
+    """Per-template path globals and __now__ should be available, with user context taking precedence and an opt out."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(
+            "{{ __template__ }}|{{ __output__ }}|{{ __rel_path__ }}|{{ __now__ }}",
+            full_name="foo.etch.txt",
+        )
+        cli.render(manager.root_dir, manager.create_cfg({}))
+        with open(remove_template(template), "r") as file:
+            tmpl, output, rel_path, now = file.read().split("|")
+        assert tmpl == str(template)
+        assert output == remove_template(template)
+        assert rel_path == "foo.etch.txt"
+        assert re.match(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z$", now), now
+
+    # User context should take precedence:
+    with TmpFileManager() as manager:
+        check_single(
+            manager,
+            manager.create_cfg(
+                {
+                    "context": {
+                        "static": {
+                            "__rel_path__": {"value": "MINE"},
+                            "__now__": {"value": "NOW"},
+                        }
+                    }
+                }
+            ),
+            "{{ __rel_path__ }} {{ __now__ }}",
+            "MINE NOW",
+        )
+
+    # Opting out should leave them undefined:
+    with TmpFileManager() as manager:
+        check_single(
+            manager,
+            manager.create_cfg({"engine": {"builtin_globals": False}}),
+            "{{ __rel_path__ is defined }} {{ __now__ is defined }}",
+            "false false",
+        )
+
+
+@pytest.mark.parametrize(
+    "auto_escape,file_type,expected",
+    [
+        # Disabled by default:
+        (None, "html", '<b>"hi"</b>'),
+        ({"html": True}, "html", "&lt;b&gt;&quot;hi&quot;&lt;&#x2f;b&gt;"),
+        # Only applies to the configured extensions:
+        ({"html": True}, "txt", '<b>"hi"</b>'),
+        ({"html": False}, "html", '<b>"hi"</b>'),
+        ({"xml": True}, "xml", "&lt;b&gt;&quot;hi&quot;&lt;&#x2f;b&gt;"),
+        ({"json": True}, "json", '"<b>\\"hi\\"</b>"'),
+    ],
+)
+def test_auto_escape(auto_escape: tp.Optional[dict[str, bool]], file_type: str, expected: str):
+    """Auto escaping should be off by default, enabled per output file extension."""
+    engine: Engine = {}
+    if auto_escape is not None:
+        engine["auto_escape"] = auto_escape
+    with TmpFileManager() as manager:
+        check_single(
+            manager,
+            manager.create_cfg(
+                {"context": {"static": {"var": {"value": '<b>"hi"</b>'}}}, "engine": engine}
+            ),
+            "{{ var }}",
+            expected,
+            file_type=file_type,
+        )
+
+
+def test_engine_overrides():
+    """Templates matching an override glob should render with its syntax, the last matching override winning."""
+    with TmpFileManager() as manager:
+        md = manager.tmpfile(content="# {{ var }}", full_name="doc.etch.md")
+        tex = manager.tmpfile(content="\\section{<< var >>}{{ untouched }}", full_name="doc.etch.tex")
+        sty = manager.tmpfile(content="[[ var ]] << var >>", full_name="doc.etch.sty")
+        result = cli.render(
+            manager.root_dir,
+            manager.create_cfg(
+                {
+                    "context": {"static": {"var": {"value": "World"}}},
+                    "engine": {
+                        "overrides": [
+                            {"match": "*.{tex,sty}", "variable_start": "<<", "variable_end": ">>"},
+                            {"match": "*.sty", "variable_start": "[[", "variable_end": "]]"},
+                        ]
+                    },
+                }
+            ),
+        )
+        with open(remove_template(md), "r") as file:
+            assert file.read() == "# World"
+        with open(remove_template(tex), "r") as file:
+            assert file.read() == "\\section{World}{{ untouched }}"
+        with open(remove_template(sty), "r") as file:
+            assert file.read() == "World << var >>"
+
+        # Templates using the base syntax aren't recorded:
+        assert result["debug"]["engine_overrides"] == {"doc.etch.tex": 0, "doc.etch.sty": 1}
+
+
+@pytest.mark.parametrize(
+    "engine,expected",
+    [
+        ({}, None),
+        ({"max_fuel": 100000000}, None),
+        ({"max_include_depth": 3}, None),
+        ({"max_fuel": 1000}, "Failed to render template, exceeded [engine.max_fuel] of 1000"),
+        (
+            {"max_include_depth": 2},
+            "Failed to render template, exceeded [engine.max_include_depth] of 2",
+        ),
+        (
+            {"max_include_depth": 50},
+            "[engine.max_include_depth]",
+        ),
+    ],
+)
+def test_execution_limits(engine: Engine, expected: tp.Optional[str]):
+    """Runaway templates should be stopped by the opt-in limits, naming the limit that was hit."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("{% include 'c.txt' %}", full_name="b.txt")
+        manager.tmpfile("{% include 'd.txt' %}", full_name="c.txt")
+        manager.tmpfile(
+            "{% for i in range(100) %}{% for j in range(100) %}{% endfor %}{% endfor %}done",
+            full_name="d.txt",
+        )
+        config = manager.create_cfg({"engine": engine})
+        if expected is None:
+            check_single(manager, config, "{% include 'b.txt' %}", "done")
+        else:
+            with pytest.raises(ValueError, match=re.escape(expected)):
+                check_single(manager, config, "{% include 'b.txt' %}", "done")
+
+
+def test_undefined_error_detail():
+    """Strict undefined errors should name the value, where it is with a snippet, and similar context keys."""
+    with TmpFileManager() as manager:
+        config = manager.create_cfg(
+            {"context": {"static": {"name": {"value": "Bob"}, "names": {"value": "Bobs"}}}}
+        )
+        manager.tmpfile("first\nHello {{ nme }}!\nlast", full_name="top.etch.txt")
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(manager.root_dir, config)
+        msg = str(exc_info.value)
+        assert "[top.etch.txt]: Failed to render template: 'undefined value" in msg
+        assert "Undefined value 'nme' at top.etch.txt:2:\n1 | first\n2 | Hello {{ nme }}!\n" in msg
+        assert "Similar context keys: 'name', 'names'" in msg
+        os.remove(os.path.join(manager.root_dir, "top.etch.txt"))
+
+        # Undefined values inside includes should point at the include, not just the outer template:
+        manager.tmpfile("{{ name }} {{ nmes.first }}", full_name="partial.txt")
+        manager.tmpfile("outer\n{% include 'partial.txt' %}", full_name="outer.etch.txt")
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(manager.root_dir, config)
+        msg = str(exc_info.value)
+        assert (
+            "Undefined value 'nmes.first' at partial.txt:1 (included from outer.etch.txt:2):\n"
+            "1 | {{ name }} {{ nmes.first }}"
+        ) in msg
+        assert "Similar context keys: 'names', 'name'" in msg
+
+
+def test_render_error_location():
+    """Render and syntax errors should point at the line and column with a snippet underlining the culprit."""
+    with TmpFileManager() as manager:
+        config = manager.create_cfg({})
+        manager.tmpfile("first\nHello {{ 1 + 'x' }} there", full_name="op.etch.txt")
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(manager.root_dir, config)
+        msg = str(exc_info.value)
+        assert "[op.etch.txt]: Failed to render template: 'invalid operation" in msg
+        assert (
+            "At op.etch.txt:2:10:\n"
+            "1 | first\n"
+            "2 | Hello {{ 1 + 'x' }} there\n"
+            "  |          ^^^^^^^"
+        ) in msg
+        os.remove(os.path.join(manager.root_dir, "op.etch.txt"))
+
+        manager.tmpfile("ok\n{% if x %}oops", full_name="syntax.etch.txt")
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(manager.root_dir, config)
+        msg = str(exc_info.value)
+        assert "[syntax.etch.txt]: Failed to compile template: 'syntax error" in msg
+        assert "At syntax.etch.txt:2:11:\n1 | ok\n2 | {% if x %}oops\n  |           ^^^^" in msg
+
+
+def test_allow_undefined_directive():
+    """A first line directive should make just that template lenient, and be stripped from the output."""
+    with TmpFileManager() as manager:
+        config = manager.create_cfg({"engine": {"comment_start": "<#", "comment_end": "#>"}})
+        manager.tmpfile("<# etch: allow_undefined #>\nHello {{ name }}!", full_name="docs.etch.txt")
+        manager.tmpfile("<# just a comment #>\nStrict", full_name="other.etch.txt")
+        cli.render(manager.root_dir, config)
+        with open(os.path.join(manager.root_dir, "docs.txt")) as f:
+            assert f.read() == "Hello !"
+        with open(os.path.join(manager.root_dir, "other.txt")) as f:
+            assert f.read() == "\nStrict"
+
+        # Other templates are still strict:
+        manager.tmpfile("Hello {{ name }}!", full_name="strict.etch.txt")
+        with pytest.raises(ValueError, match=re.escape("[strict.etch.txt]: Failed to render")):
+            cli.render(manager.root_dir, config)
+        os.remove(os.path.join(manager.root_dir, "strict.etch.txt"))
+
+        # Typos error rather than silently doing nothing:
+        manager.tmpfile("<# etch: allow_undefind #>\nHello", full_name="typo.etch.txt")
+        with pytest.raises(
+            ValueError,
+            match=re.escape("[typo.etch.txt]: Unknown etch directive 'allow_undefind'"),
+        ):
+            cli.render(manager.root_dir, config)
+
+
+def test_matrix_directive():
+    """A for directive should render the template once per list item, each tracked separately in the lockfile."""
+    with TmpFileManager() as manager:
+        root = Path(manager.root_dir)
+        manager.tmpfile(
+            "{# etch: for env in environments #}\nenv: {{ env }}, app: {{ app }}",
+            full_name="{{ env }}.etch.yml",
+        )
+
+        def render(environments: list[str]):
+            config = manager.create_cfg(
+                {
+                    "context": {
+                        "static": {
+                            "environments": {"value": environments},
+                            "app": {"value": "api"},
+                        }
+                    }
+                }
+            )
+            return cli.render(manager.root_dir, config)["debug"]["written"]
+
+        assert sorted(render(["dev", "prod"])) == [str(root / "dev.yml"), str(root / "prod.yml")]
+        with open(root / "prod.yml") as f:
+            assert f.read() == "env: prod, app: api"
+        with open(root / ".etch.lock") as f:
+            assert sorted(json.load(f)["files"]["default"]) == [
+                "{{ env }}.etch.yml[dev.yml]",
+                "{{ env }}.etch.yml[prod.yml]",
+            ]
+
+        # A shrinking list prunes the lockfile entry:
+        render(["dev"])
+        with open(root / ".etch.lock") as f:
+            assert list(json.load(f)["files"]["default"]) == ["{{ env }}.etch.yml[dev.yml]"]
+
+        # Instances must render to distinct outputs:
+        with pytest.raises(ValueError, match=re.escape("Multiple templates render to the same")):
+            render(["dev", "dev"])
+
+        manager.tmpfile("{# etch: for x in 5 #}\n", full_name="{{ x }}.etch.txt")
+        with pytest.raises(
+            ValueError,
+            match=re.escape("[{{ x }}.etch.txt]: The matrix list '5' should be a list"),
+        ):
+            render(["dev"])
+
+
+@pytest.mark.parametrize(
+    "line_endings,expected",
+    [
+        (None, b"a\nb\r\nc\n"),
+        ("preserve", b"a\nb\r\nc\n"),
+        ("lf", b"a\nb\nc\n"),
+        # Already correct endings aren't double converted:
+        ("crlf", b"a\r\nb\r\nc\r\n"),
+    ],
+)
+def test_line_endings(line_endings: tp.Optional[str], expected: bytes):
+    """Outputs should have their newlines normalized when configured, mixed endings left alone by default."""
+    with TmpFileManager() as manager:
+        template = Path(manager.root_dir) / "mixed.etch.txt"
+        template.write_bytes(b"a\n{{ 'b' }}\r\nc\n")
+        engine: Engine = {"keep_trailing_newline": True}
+        if line_endings is not None:
+            engine["line_endings"] = line_endings  # type: ignore
+        cli.render(manager.root_dir, manager.create_cfg({"engine": engine}))
+        assert (Path(manager.root_dir) / "mixed.txt").read_bytes() == expected
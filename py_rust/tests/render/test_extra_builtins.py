@@ -1,12 +1,16 @@
 """Test all the builtins that aren't enabled by default in minijinja, added from minijinja-contrib or included in etch directly."""
 
 import datetime as dt
+import os
 import re
+import tempfile
 import time
 import typing as tp
+from unittest import mock
 
 import pytest
 
+from ..helpers import cli
 from ..helpers.tmp_file_manager import TmpFileManager
 from ..helpers.types import StaticCtx
 from ..helpers.utils import check_single
@@ -19,6 +23,10 @@ class BuiltinTestcase(tp.TypedDict):
     static_ctx: tp.NotRequired[dict[str, StaticCtx]]
     # Defaults to "txt"
     file_type: tp.NotRequired[str]
+    # Extra files to create in the root before rendering, relative path to contents:
+    files: tp.NotRequired[dict[str, str]]
+    # The [engine] config to render with, e.g. to pin the render time:
+    engine: tp.NotRequired[dict]
 
 
 class BuiltinBase(tp.TypedDict):
@@ -167,17 +175,120 @@ ENGINE_BUILTINS: AllBuiltins = {
         },
     },
     "functions": {
-        # https://docs.rs/minijinja-contrib/latest/minijinja_contrib/globals/fn.now.html
         "now": {
-            "description": "Returns the current time in UTC as unix timestamp. To format this timestamp, use the `datetimeformat` filter.",
+            "description": "Returns the render time in UTC as an RFC 3339 string, pinned by engine.fixed_timestamp or the SOURCE_DATE_EPOCH env var when set. To format it, use the `datetimeformat` filter. Pass format='timestamp' for the unix timestamp in seconds instead, which is what now() returned before it was pinnable.",
             "tests": [
                 {
                     "input": "{{ now() }}",
+                    # Seconds precision in UTC e.g. 2023-12-10T11:21:29Z:
+                    "expected": lambda output: re.fullmatch(
+                        r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z", output
+                    )
+                    is not None,
+                },
+                {
+                    "input": "{{ now(format='timestamp') }}",
                     # Make sure 10 digits (seconds) is returned before the dot e.g. 1702207289.952969:
                     "expected": lambda output: re.match(r"\d{10}.", output) is not None,
+                },
+                {
+                    # Numeric, so arithmetic on it keeps working:
+                    "input": "{{ (now(format='timestamp') - 3600) | int }} {{ now('rfc3339') }}",
+                    "engine": {"fixed_timestamp": "2024-01-02T03:04:05Z"},
+                    "expected": "1704161045 2024-01-02T03:04:05Z",
+                },
+            ],
+        },
+        "today": {
+            "description": "Returns the UTC date of the render time as YYYY-MM-DD, pinned just like now().",
+            "tests": [
+                {
+                    "input": "{{ today() }}",
+                    "engine": {"fixed_timestamp": "2024-01-02T03:04:05Z"},
+                    "expected": "2024-01-02",
                 }
             ],
-        }
+        },
+        "read_file": {
+            "description": "Returns the contents of a file verbatim as a string, relative paths are resolved from the render root. Paths resolving outside the root are refused. Pass missing_ok=true to get an empty string for a missing file rather than an error.",
+            "tests": [
+                {
+                    "input": "{{ read_file('version.txt') }}",
+                    "files": {"version.txt": "1.2.3"},
+                    "expected": "1.2.3",
+                },
+                {
+                    "input": "{{ read_file('./version.txt') | trim }}",
+                    "files": {"version.txt": "  1.2.3\n"},
+                    "expected": "1.2.3",
+                },
+            ],
+        },
+        "glob": {
+            "description": "Returns the sorted paths relative to the root matching a glob pattern, always with forward slashes.",
+            "tests": [
+                {
+                    "input": "{{ glob('*.rs') | join(',') }}",
+                    "files": {"b.rs": "", "a.rs": "", "c.py": "", "src/d.rs": ""},
+                    "expected": "a.rs,b.rs",
+                },
+                {
+                    "input": "{% for f in glob('src/**/*.rs') %}{{ f }};{% endfor %}",
+                    "files": {"src/a.rs": "", "src/nested/b.rs": "", "src/c.py": "", "d.rs": ""},
+                    "expected": "src/a.rs;src/nested/b.rs;",
+                },
+                {
+                    "input": "{{ glob('*.madeup') | length }}",
+                    "expected": "0",
+                },
+            ],
+        },
+        "hash": {
+            "description": "Returns a hash of the stringified value, using the same fnv1a hash as the lockfile by default. Pass algorithm='sha256' for a sha256 hex digest.",
+            "tests": [
+                {
+                    "input": "{{ hash('hello') }}",
+                    "expected": "11831194018420276491",
+                },
+                {
+                    "input": "{{ hash(42) }} {{ hash([1, 2], 'fnv1a') }}",
+                    "expected": "571532774284038691 14519949274529468440",
+                },
+                {
+                    "input": "{{ hash('hello', algorithm='sha256') }}",
+                    "expected": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                },
+            ],
+        },
+        "uuid5": {
+            "description": "Returns a deterministic uuid derived from the namespace and name. The namespace can be a uuid or one of 'dns', 'url', 'oid' or 'x500'.",
+            "tests": [
+                {
+                    "input": "{{ uuid5('dns', 'example.com') }}",
+                    "expected": "cfbff0d1-9375-5685-968c-48ce8b15ae17",
+                },
+                {
+                    "input": "{{ uuid5('6ba7b810-9dad-11d1-80b4-00c04fd430c8', 'example.com') }}",
+                    "expected": "cfbff0d1-9375-5685-968c-48ce8b15ae17",
+                },
+                {
+                    "input": "{{ uuid5('url', 'https://example.com') }}",
+                    "expected": "4fd35a71-71ef-5a55-a9d9-aa75c889a6d0",
+                },
+            ],
+        },
+        "uuid4": {
+            "description": "Returns a random uuid. It changes on every render, so templates using it are always rewritten rather than identical in the lockfile.",
+            "tests": [
+                {
+                    "input": "{{ uuid4() }}",
+                    "expected": lambda output: re.fullmatch(
+                        r"[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}", output
+                    )
+                    is not None,
+                },
+            ],
+        },
     },
 }
 
@@ -203,10 +314,19 @@ def wait_for_new_minute():
 def test_extra_builtin_functions(name: str, info: FilterBuiltin, test_info: BuiltinTestcase):
     """Confirm all builtin expected filters work."""
     with TmpFileManager() as manager:
+        for filename, contents in test_info.get("files", {}).items():
+            parent = os.path.dirname(os.path.join(manager.root_dir, filename))
+            os.makedirs(parent, exist_ok=True)
+            manager.tmpfile(contents, parent=parent, full_name=os.path.basename(filename))
         wait_for_new_minute()
         check_single(
             manager,
-            manager.create_cfg({"context": {"static": test_info.get("static_ctx", {})}}),
+            manager.create_cfg(
+                {
+                    "context": {"static": test_info.get("static_ctx", {})},
+                    "engine": test_info.get("engine", {}),  # type: ignore
+                }
+            ),
             test_info["input"],
             test_info["expected"]
             if isinstance(test_info["expected"], str)
@@ -226,6 +346,10 @@ def test_extra_builtin_functions(name: str, info: FilterBuiltin, test_info: Buil
 def test_extra_builtin_filters(name: str, info: FilterBuiltin, test_info: BuiltinTestcase):
     """Confirm all builtin expected filters work."""
     with TmpFileManager() as manager:
+        for filename, contents in test_info.get("files", {}).items():
+            parent = os.path.dirname(os.path.join(manager.root_dir, filename))
+            os.makedirs(parent, exist_ok=True)
+            manager.tmpfile(contents, parent=parent, full_name=os.path.basename(filename))
         wait_for_new_minute()
         check_single(
             manager,
@@ -236,3 +360,233 @@ def test_extra_builtin_filters(name: str, info: FilterBuiltin, test_info: Builti
             else test_info["expected"],
             file_type=test_info.get("file_type", "txt"),
         )
+
+
+def test_read_file_missing():
+    """Missing files should raise with the attempted path, unless missing_ok is set."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape("read_file('madeup.txt') failed: '")):
+            check_single(
+                manager,
+                manager.create_cfg({}),
+                "{{ read_file('madeup.txt') }}",
+                "",
+            )
+
+    for template_src in [
+        "[{{ read_file('madeup.txt', true) }}]",
+        "[{{ read_file('madeup.txt', missing_ok=true) }}]",
+    ]:
+        with TmpFileManager() as manager:
+            check_single(manager, manager.create_cfg({}), template_src, "[]")
+
+
+def test_read_file_confined_to_root():
+    """Paths resolving outside the root should be refused, including through symlinks and when missing_ok."""
+    for path in ["../../etc/passwd", "/etc/passwd", "sub/../../outside.txt"]:
+        with TmpFileManager() as manager:
+            with pytest.raises(ValueError, match="paths must be relative to the root and can't escape it."):
+                check_single(
+                    manager,
+                    manager.create_cfg({}),
+                    "{{{{ read_file('{}', missing_ok=true) }}}}".format(path),
+                    "",
+                )
+
+    with TmpFileManager() as manager, tempfile.TemporaryDirectory() as outside:
+        secret = os.path.join(outside, "secret.txt")
+        with open(secret, "w") as file:
+            file.write("secret")
+        os.symlink(secret, os.path.join(manager.root_dir, "link.txt"))
+        with pytest.raises(ValueError, match="resolves outside the root."):
+            check_single(manager, manager.create_cfg({}), "{{ read_file('link.txt') }}", "")
+
+    # Within the root is fine, even when going up and back down:
+    with TmpFileManager() as manager:
+        manager.tmpfile("inner", full_name="inner.txt")
+        manager.tmpdir(name="sub")
+        check_single(
+            manager, manager.create_cfg({}), "{{ read_file('sub/../inner.txt') }}", "inner"
+        )
+
+
+def test_glob_bound_to_root():
+    """Patterns shouldn't be able to escape the root."""
+    with TmpFileManager() as manager:
+        for pattern in ["../*", "src/../../*", "/etc/*"]:
+            with pytest.raises(ValueError, match=re.escape("can't contain '..'")):
+                check_single(
+                    manager,
+                    manager.create_cfg({}),
+                    "{{{{ glob('{}') }}}}".format(pattern),
+                    "",
+                )
+
+
+@pytest.mark.parametrize(
+    "template_src,engine,expected",
+    [
+        ("{{ env('ETCH_TEST_VAR') }}", {}, "set"),
+        ("{{ env('ETCH_TEST_MISSING', 'fallback') }}", {}, "fallback"),
+        ("{{ env('ETCH_TEST_VAR', 'fallback') }}", {}, "set"),
+        (
+            "{{ env('ETCH_TEST_MISSING') }}",
+            {},
+            "env() failed: environment variable 'ETCH_TEST_MISSING' is not set and no default was given.",
+        ),
+        # Disabled, so env isn't a function:
+        ("{{ env('ETCH_TEST_VAR') }}", {"allow_env_in_templates": False}, "unknown function: env is unknown"),
+    ],
+)
+def test_env_function(template_src: str, engine: dict, expected: str):
+    """Process env vars should be readable directly from templates unless disabled."""
+    with mock.patch.dict(os.environ, {"ETCH_TEST_VAR": "set"}):
+        os.environ.pop("ETCH_TEST_MISSING", None)
+        with TmpFileManager() as manager:
+            config = manager.create_cfg({"engine": engine})  # type: ignore
+            if expected in ("set", "fallback"):
+                check_single(manager, config, template_src, expected)
+            else:
+                with pytest.raises(ValueError, match=re.escape(expected)):
+                    check_single(manager, config, template_src, "")
+
+
+@pytest.mark.parametrize(
+    "engine,source_date_epoch,expected",
+    [
+        ({"fixed_timestamp": "2024-01-02T03:04:05Z"}, None, "2024-01-02T03:04:05Z 2024-01-02"),
+        # Offsets are normalised to UTC:
+        ({"fixed_timestamp": "2024-01-02T03:04:05+05:00"}, None, "2024-01-01T22:04:05Z 2024-01-01"),
+        ({}, "1700000000", "2023-11-14T22:13:20Z 2023-11-14"),
+        # The config takes precedence over the env var:
+        (
+            {"fixed_timestamp": "2024-01-02T03:04:05Z"},
+            "1700000000",
+            "2024-01-02T03:04:05Z 2024-01-02",
+        ),
+    ],
+)
+def test_pinned_render_time(engine: dict, source_date_epoch: tp.Optional[str], expected: str):
+    """now(), today() and __now__ should be pinnable, so re-renders are identical not rewritten."""
+    env = {"SOURCE_DATE_EPOCH": source_date_epoch} if source_date_epoch is not None else {}
+    with mock.patch.dict(os.environ, env):
+        if source_date_epoch is None:
+            os.environ.pop("SOURCE_DATE_EPOCH", None)
+        with TmpFileManager() as manager:
+            config = manager.create_cfg({"engine": engine})  # type: ignore
+            template_src = "{{ now() }} {{ today() }}"
+            check_single(manager, config, template_src, expected)
+
+            # __now__ uses the same time:
+            manager.tmpfile("{{ __now__ }}", full_name="now.etch.txt")
+            cli.render(manager.root_dir, config)
+            with open(os.path.join(manager.root_dir, "now.txt"), "r") as file:
+                assert file.read() == expected.split(" ")[0]
+
+            # Nothing changes across repeated renders:
+            result = cli.render(manager.root_dir, config)["debug"]
+            assert result["written"] == []
+            assert len(result["identical"]) == 2
+
+
+def test_live_render_time():
+    """Without pinning, the live time is used."""
+    with mock.patch.dict(os.environ, {}):
+        os.environ.pop("SOURCE_DATE_EPOCH", None)
+        with TmpFileManager() as manager:
+            wait_for_new_minute()
+            check_single(
+                manager,
+                manager.create_cfg({}),
+                "{{ today() }} {{ now()|timeformat }}",
+                dt.datetime.utcnow().strftime("%Y-%m-%d %H:%M"),
+            )
+
+
+@pytest.mark.parametrize(
+    "engine,source_date_epoch,expected",
+    [
+        (
+            {"fixed_timestamp": "yesterday"},
+            None,
+            "[engine.fixed_timestamp]: Invalid RFC 3339 timestamp 'yesterday'",
+        ),
+        ({}, "soon", "Invalid SOURCE_DATE_EPOCH 'soon', expected an integer unix timestamp."),
+    ],
+)
+def test_invalid_render_time(engine: dict, source_date_epoch: tp.Optional[str], expected: str):
+    """Invalid pins should fail upfront rather than silently using the live time."""
+    env = {"SOURCE_DATE_EPOCH": source_date_epoch} if source_date_epoch is not None else {}
+    with mock.patch.dict(os.environ, env):
+        if source_date_epoch is None:
+            os.environ.pop("SOURCE_DATE_EPOCH", None)
+        with TmpFileManager() as manager:
+            config = manager.create_cfg({"engine": engine})  # type: ignore
+            with pytest.raises(ValueError, match=re.escape(expected)):
+                check_single(manager, config, "{{ now() }}", "")
+
+
+def test_fail_stops_run():
+    """fail() should abort the whole render with the template's path before anything is synced."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("fine", full_name="a.etch.txt")
+        manager.tmpfile(
+            "{% if mode == 'x' %}{{ fail('Mode {} needs {}', mode, 'y') }}{% endif %}",
+            full_name="b.etch.txt",
+        )
+        config = manager.create_cfg({"context": {"static": {"mode": {"value": "x"}}}})
+        with pytest.raises(ValueError, match=re.escape("[b.etch.txt]: Failed to render")) as exc:
+            cli.render(manager.root_dir, config)
+        assert "fail(): Mode x needs y" in str(exc.value)
+
+        # Nothing written, including the other template and the lockfile:
+        assert not os.path.exists(os.path.join(manager.root_dir, "a.txt"))
+        assert not os.path.exists(os.path.join(manager.root_dir, ".etch.lock"))
+
+    # Placeholders must match the args:
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape("has 2 '{}' placeholders but 1 arg was")):
+            check_single(manager, manager.create_cfg({}), "{{ fail('{} {}', 1) }}", "")
+
+
+def test_warn():
+    """warn() should log with the template's path and render nothing."""
+    with TmpFileManager() as manager:
+        manager.tmpfile("a{{ warn('Deprecated {} used', 'thing') }}b", full_name="a.etch.txt")
+        result = cli.render(manager.root_dir, manager.create_cfg({}))
+        assert "[a.etch.txt]: Deprecated thing used" in result["stdout"]
+        with open(os.path.join(manager.root_dir, "a.txt"), "r") as file:
+            assert file.read() == "ab"
+
+
+@pytest.mark.parametrize(
+    "template_src,expected",
+    [
+        (
+            "{{ hash('hello', 'md5') }}",
+            "hash() failed: unknown algorithm 'md5', expected 'fnv1a' or 'sha256'.",
+        ),
+        (
+            "{{ uuid5('madeup', 'example.com') }}",
+            "uuid5() failed: namespace 'madeup' isn't a uuid or one of 'dns', 'url', 'oid' or 'x500'",
+        ),
+    ],
+)
+def test_hash_uuid_invalid(template_src: str, expected: str):
+    """Unknown hash algorithms and uuid namespaces should error clearly."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape(expected)):
+            check_single(manager, manager.create_cfg({}), template_src, "")
+
+
+def test_glob_filtering():
+    """glob() should see the same files as the template search, excludes and ignore files applied."""
+    with TmpFileManager() as manager:
+        for filename in ["a.rs", "src/b.rs", "excluded/c.rs", "ignored/d.rs", "node_modules/e.rs"]:
+            parent = os.path.dirname(os.path.join(manager.root_dir, filename))
+            os.makedirs(parent, exist_ok=True)
+            manager.tmpfile("", parent=parent, full_name=os.path.basename(filename))
+        manager.tmpfile("ignored/\nnode_modules/\n", full_name=".etchignore")
+        config = manager.create_cfg({"exclude": ["excluded"]})
+        check_single(manager, config, "{{ glob('**/*.rs') | join(',') }}", "a.rs,src/b.rs")
+
@@ -1,3 +1,8 @@
+import json
+import os
+import re
+import sys
+import tempfile
 import typing as tp
 from pathlib import Path
 
@@ -6,7 +11,7 @@ import pytest
 from ..helpers import cli
 from ..helpers.tmp_file_manager import TmpFileManager
 from ..helpers.types import InputConfig
-from ..helpers.utils import check_single, remove_template
+from ..helpers.utils import check_single, get_lockfile_path, remove_template
 
 
 def test_single_basic():
@@ -43,6 +48,183 @@ def test_correct_matching(filename: str, should_match: bool, expected_out: str):
             assert len(written) == 0
 
 
+@pytest.mark.parametrize(
+    "filename,should_match,expected_out",
+    [
+        ("test.tmpl.txt", True, "test.txt"),
+        ("test.tmpl", True, "test"),
+        ("test.etch.txt", False, ""),
+        ("test.tmplx.txt", False, ""),
+    ],
+)
+def test_custom_template_marker(filename: str, should_match: bool, expected_out: str):
+    """Confirm a configured template marker replaces the default .etch."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name=filename)
+        result = cli.render(manager.root_dir, manager.create_cfg({"template_marker": "tmpl"}))
+        written = result["debug"]["written"]
+        if should_match:
+            assert len(written) == 1
+            assert Path(written[0]).name == expected_out
+        else:
+            assert len(written) == 0
+
+
+@pytest.mark.parametrize(
+    "filename,expected_out",
+    [
+        ("foo.tmpl", "foo"),
+        ("foo.etch.json", "foo.json"),
+        ("foo.json.etch", "foo.json"),
+        ("foo.tmpl.yml", "foo.yml"),
+        ("foo.json", None),
+        ("foo.etcher.json", None),
+    ],
+)
+def test_multiple_template_markers(filename: str, expected_out: tp.Optional[str]):
+    """Confirm multiple markers can be recognized at once, e.g. when migrating between markers."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name=filename)
+        result = cli.render(manager.root_dir, manager.create_cfg({"template_marker": ["tmpl", "etch"]}))
+        written = result["debug"]["written"]
+        if expected_out is not None:
+            assert len(written) == 1
+            assert Path(written[0]).name == expected_out
+        else:
+            assert len(written) == 0
+
+
+@pytest.mark.parametrize(
+    "filename,expected_out",
+    [
+        ("config.yml.j2", "config.yml"),
+        ("index.html.jinja", "index.html"),
+        # Markers are still recognised, tried first:
+        ("foo.etch.json", "foo.json"),
+        ("foo.j2.txt", None),
+        ("foo.yml", None),
+    ],
+)
+def test_template_matchers(filename: str, expected_out: tp.Optional[str]):
+    """Confirm regex template matchers identify templates, e.g. for suffixes editors already highlight."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name=filename)
+        result = cli.render(
+            manager.root_dir,
+            manager.create_cfg(
+                {"template_matchers": [{"pattern": r"^(.+)\.(j2|jinja)$", "replacement": "$1"}]}
+            ),
+        )
+        written = result["debug"]["written"]
+        if expected_out is not None:
+            assert len(written) == 1
+            assert Path(written[0]).name == expected_out
+        else:
+            assert len(written) == 0
+
+
+def test_output_path_collisions():
+    """Templates rendering to the same output should error upfront rather than silently clobbering each other."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="first", full_name="foo.etch.txt")
+        manager.tmpfile(content="second", full_name="foo.txt.etch")
+        manager.tmpfile(content="", full_name="bar.etch.txt")
+        with pytest.raises(
+            ValueError, match=re.escape("'foo.txt' is rendered by: 'foo.etch.txt', 'foo.txt.etch'")
+        ):
+            cli.render(manager.root_dir, manager.create_cfg({}))
+        assert not os.path.exists(os.path.join(manager.root_dir, "foo.txt"))
+
+    # Also applies across markers:
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name="foo.tmpl.txt")
+        manager.tmpfile(content="", full_name="foo.etch.txt")
+        with pytest.raises(
+            ValueError, match=re.escape("'foo.txt' is rendered by: 'foo.etch.txt', 'foo.tmpl.txt'")
+        ):
+            cli.render(manager.root_dir, manager.create_cfg({"template_marker": ["tmpl", "etch"]}))
+
+    # And to templated filenames, only known once rendered:
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name="foo.etch.txt")
+        manager.tmpfile(content="", full_name="{{ name }}.etch.txt")
+        with pytest.raises(
+            ValueError,
+            match=re.escape("'foo.txt' is rendered by: 'foo.etch.txt', '{{ name }}.etch.txt'"),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"context": {"static": {"name": {"value": "foo"}}}}),
+            )
+        assert not os.path.exists(os.path.join(manager.root_dir, "foo.txt"))
+
+
+def test_protected_outputs():
+    """Outputs shouldn't overwrite a config file or another template, unless explicitly allowed."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="exclude = []\n", full_name="config.etch.toml")
+        config = manager.tmpfile(content="", full_name="config.toml")
+        with pytest.raises(
+            ValueError,
+            match=r"Template 'config\.etch\.toml' would write its output to '.*config\.toml', "
+            r"which is a config file\. Pass --allow-overwrite-sources",
+        ):
+            cli.render(manager.root_dir, config)
+        assert config.read_text() == ""
+
+        cli.render(manager.root_dir, config, allow_overwrite_sources=True)
+        assert config.read_text() == "exclude = []\n"
+
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="", full_name="foo.j2")
+        template = manager.tmpfile(content="original", full_name="foo.etch.txt")
+        config = manager.create_cfg(
+            {"template_matchers": [{"pattern": "(.*)\\.j2$", "replacement": "$1.etch.txt"}]}
+        )
+        with pytest.raises(ValueError, match=re.escape("which is the template 'foo.etch.txt'.")):
+            cli.render(manager.root_dir, config)
+        assert template.read_text() == "original"
+
+
+def test_invalid_template_marker():
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match="must be non-empty and can't contain"):
+            cli.render(manager.root_dir, manager.create_cfg({"template_marker": ".tmpl"}))
+        with pytest.raises(ValueError, match=re.escape("[template_marker.1]: 'tmpl' is listed more than once.")):
+            cli.render(manager.root_dir, manager.create_cfg({"template_marker": ["tmpl", "tmpl"]}))
+        with pytest.raises(
+            ValueError, match=re.escape("[template_matchers.0]: Pattern '.*' matches an empty filename")
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"template_matchers": [{"pattern": ".*", "replacement": "x"}]}),
+            )
+        with pytest.raises(ValueError, match=re.escape("[template_matchers.0]: Invalid regex '(.j2'")):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"template_matchers": [{"pattern": "(.j2", "replacement": "$1"}]}),
+            )
+
+
+def test_junk_files_ignored():
+    """Editor backups and OS junk shouldn't be picked up as templates, unless ignore_junk is disabled."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="real", full_name="foo.etch.yml")
+        manager.tmpfile(content="backup", full_name="foo.etch.yml~")
+        manager.tmpfile(content="swap", full_name=".foo.etch.yml.swp")
+        manager.tmpfile(content="custom", full_name="foo.etch.yml.old")
+
+        result = cli.render(manager.root_dir, manager.create_cfg({"junk_patterns": ["*.old"]}))
+        assert result["debug"]["written"] == [remove_template(template)]
+        with open(remove_template(template), "r") as file:
+            assert file.read() == "real"
+
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="backup", full_name="foo.etch.yml~")
+        result = cli.render(manager.root_dir, manager.create_cfg({"ignore_junk": False}))
+        assert len(result["debug"]["written"]) == 1
+
+
 def test_multiple_mixed_templates():
     """Check multiple templates in the search directory are handled."""
     with TmpFileManager() as manager:
@@ -86,6 +268,10 @@ def test_multiple_mixed_templates():
                 "ignore_files": [manager.tmpfile(content="*.txt").name],
             },
         ),
+        (
+            "etchignore at root",
+            lambda manager: (manager.tmpfile(content="subdir/", full_name=".etchignore"), {})[1],
+        ),
         (
             "exclude direct name",
             lambda manager: {
@@ -107,7 +293,7 @@ def test_multiple_mixed_templates():
     ],
 )
 def test_ignore(desc: str, config_creator: tp.Callable[[TmpFileManager], InputConfig]):
-    """Confirm both ignore_files and exclude are respected when enabled."""
+    """Confirm ignore_files, .etchignore and exclude are all respected when enabled."""
     with TmpFileManager() as manager:
         contents = "Hello, {{ var }}!"
 
@@ -129,6 +315,9 @@ def test_ignore(desc: str, config_creator: tp.Callable[[TmpFileManager], InputCo
         assert result["debug"]["written"] == []
 
         # Should be included when ignore config isn't there:
+        etchignore = os.path.join(manager.root_dir, ".etchignore")
+        if os.path.exists(etchignore):
+            os.remove(etchignore)
         result = cli.render(
             manager.root_dir,
             manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}}),
@@ -136,6 +325,141 @@ def test_ignore(desc: str, config_creator: tp.Callable[[TmpFileManager], InputCo
         assert result["debug"]["written"] == [remove_template(template)]
 
 
+def test_gitignore_opt_in():
+    """.gitignore files should only be respected when enabled from the cli or config."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="subdir/\n", full_name=".gitignore")
+        template = manager.tmpfile(
+            content="", parent=manager.tmpdir(name="subdir"), full_name="test.etch.txt"
+        )
+        config = manager.create_cfg({})
+
+        # Off by default:
+        assert cli.render(manager.root_dir, config, force=True)["debug"]["written"] == [
+            remove_template(template)
+        ]
+
+        assert cli.render(manager.root_dir, config, force=True, gitignore=True)["debug"][
+            "written"
+        ] == []
+        assert (
+            cli.render(manager.root_dir, manager.create_cfg({"use_gitignore": True}), force=True)[
+                "debug"
+            ]["written"]
+            == []
+        )
+
+        # What git pruned is counted in the debug logs, the template and its output from the first render:
+        result = cli.render(manager.root_dir, config, force=True, gitignore=True, verbose=True)
+        assert "Gitignore pruned 2 files from the search." in result["stdout"]
+
+
+def test_follow_symlinks():
+    """Symlinked directories should only be searched when enabled from the cli or config."""
+    with TmpFileManager() as manager, tempfile.TemporaryDirectory() as shared:
+        manager.tmpfile(content="shared", parent=shared, full_name="header.etch.txt")
+        os.symlink(shared, os.path.join(manager.root_dir, "shared"), target_is_directory=True)
+        out_path = os.path.join(manager.root_dir, "shared", "header.txt")
+
+        # Off by default:
+        assert cli.render(manager.root_dir, manager.create_cfg({}))["debug"]["written"] == []
+
+        assert cli.render(manager.root_dir, manager.create_cfg({}), follow_symlinks=True)[
+            "debug"
+        ]["written"] == [out_path]
+        assert cli.render(
+            manager.root_dir, manager.create_cfg({"follow_symlinks": True}), force=True
+        )["debug"]["written"] == [out_path]
+        with open(out_path, "r") as file:
+            assert file.read() == "shared"
+
+
+def test_follow_symlinks_resolved():
+    """A template reached through links renders once next to the real file, and cycles error naming the link."""
+    with TmpFileManager() as manager:
+        real = manager.tmpdir(name="real")
+        manager.tmpfile(content="real", parent=real, full_name="a.etch.txt")
+        os.symlink(os.path.join(real, "a.etch.txt"), os.path.join(manager.root_dir, "link.etch.txt"))
+        os.symlink(real, os.path.join(manager.root_dir, "linked_dir"), target_is_directory=True)
+        config = manager.create_cfg({"follow_symlinks": True})
+
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == [
+            os.path.join(real, "a.txt")
+        ]
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert list(json.load(file)["files"]["default"]) == ["real/a.etch.txt"]
+
+        # Reachable through either real or linked_dir, whichever is walked first:
+        os.symlink(manager.root_dir, os.path.join(real, "loop"), target_is_directory=True)
+        with pytest.raises(ValueError, match=r"Symlink cycle: '.*/loop' links back"):
+            cli.render(manager.root_dir, config)
+
+
+def test_deeply_nested_template():
+    """Templates deep below the root should be keyed by their '/' separated path from the root."""
+    with TmpFileManager() as manager:
+        parent = Path(manager.root_dir)
+        for name in ["a", "b", "c", "d", "e", "f"]:
+            parent = manager.tmpdir(name=name, parent=str(parent))
+        template = manager.tmpfile(content="deep", parent=parent, full_name="deep.etch.txt")
+        result = cli.render(manager.root_dir, manager.create_cfg({}))
+        assert result["debug"]["written"] == [remove_template(template)]
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert list(json.load(file)["files"]["default"]) == ["a/b/c/d/e/f/deep.etch.txt"]
+
+
+@pytest.mark.skipif(sys.platform != "linux", reason="Other platforms reject non UTF-8 filenames.")
+def test_non_utf8_template_skipped():
+    """Templates whose path isn't valid UTF-8 should be skipped with a warning, not tracked under a lossy key."""
+    with TmpFileManager() as manager:
+        root = os.fsencode(manager.root_dir)
+        with open(os.path.join(root, b"bad\xff.etch.txt"), "wb") as file:
+            file.write(b"bad")
+        good = manager.tmpfile(content="good", full_name="good.etch.txt")
+        result = cli.render(manager.root_dir, manager.create_cfg({}))
+        assert result["debug"]["written"] == [remove_template(good)]
+        assert "its path isn't valid UTF-8 so can't be tracked in the lockfile." in result["stdout"]
+        assert not os.path.exists(os.path.join(root, b"bad\xff.txt"))
+
+
+def test_max_depth():
+    """Templates deeper than max_depth shouldn't be found."""
+    with TmpFileManager() as manager:
+        shallow = manager.tmpfile(content="", full_name="shallow.etch.txt")
+        nested = manager.tmpdir(name="b", parent=str(manager.tmpdir(name="a")))
+        manager.tmpfile(content="", parent=str(nested), full_name="deep.etch.txt")
+        result = cli.render(manager.root_dir, manager.create_cfg({"max_depth": 1}))
+        assert result["debug"]["written"] == [remove_template(shallow)]
+
+
+def test_binary_templates():
+    """Binary or oversized templates should error or be skipped, files that aren't templates are never checked."""
+    with TmpFileManager() as manager:
+        text = manager.tmpfile(content="Hello", full_name="text.etch.txt")
+        manager.tmpfile(content="x" * 200, full_name="big.etch.txt")
+        with open(os.path.join(manager.root_dir, "blob.etch.bin"), "wb") as file:
+            file.write(b"\x89PNG\r\n\x1a\n\x00\x00{{ x }}")
+        # Not a template, so never opened despite being binary and over the limit:
+        with open(os.path.join(manager.root_dir, "image.bin"), "wb") as file:
+            file.write(b"\x00" * 200)
+
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                '[blob.etch.bin]: Template looks like a binary file, it contains a NUL byte. Set on_binary = "skip"'
+            ),
+        ):
+            cli.render(manager.root_dir, manager.create_cfg({}))
+        assert not os.path.exists(remove_template(text))
+
+        result = cli.render(
+            manager.root_dir,
+            manager.create_cfg({"max_template_size": 100, "on_binary": "skip"}),
+        )
+        assert result["debug"]["written"] == [remove_template(text)]
+        assert not os.path.exists(os.path.join(manager.root_dir, "blob.bin"))
+
+
 def test_ignorefile_overriden_in_exclude():
     """Confirm an exclude whitelist pattern overrides an ignore file."""
     with TmpFileManager() as manager:
@@ -167,3 +491,38 @@ def test_ignorefile_overriden_in_exclude():
         )
 
         assert result["debug"]["written"] == [remove_template(template)]
+
+
+def test_templated_output_name():
+    """Context values in a template's filename should name its output, the lockfile still keyed by the template."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="{{ service }}", full_name="{{ service }}.deploy.etch.yml")
+        root = Path(manager.root_dir)
+
+        def render(service: str):
+            config = manager.create_cfg({"context": {"static": {"service": {"value": service}}}})
+            return cli.render(manager.root_dir, config)["debug"]["written"]
+
+        assert render("payments") == [str(root / "payments.deploy.yml")]
+        with open(root / "payments.deploy.yml", "r") as file:
+            assert file.read() == "payments"
+
+        # A rename rewrites under the same lockfile entry:
+        assert render("billing") == [str(root / "billing.deploy.yml")]
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            entry = json.load(file)["files"]["default"]["{{ service }}.deploy.etch.yml"]
+        assert entry["out_path"] == "billing.deploy.yml"
+
+        with pytest.raises(
+            ValueError, match=re.escape("'a/b.deploy.yml' contains a path separator.")
+        ):
+            render("a/b")
+
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[{{ missing }}.etch.txt]: Failed to render the output filename '{{ missing }}.txt'"
+            ),
+        ):
+            manager.tmpfile(content="", full_name="{{ missing }}.etch.txt")
+            render("payments")
@@ -0,0 +1,113 @@
+import json
+import os
+import re
+
+import pytest
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+from ..helpers.utils import get_lockfile_path
+
+README = """# Project
+<!-- etch:start
+Version: {{ version }}
+-->
+Version: old
+<!-- etch:end -->
+
+Untouched   text\twith odd spacing
+<!-- etch:start {{ version }} -->old<!-- etch:end -->
+"""
+
+
+def test_in_place():
+    """Only the regions should be rewritten, re-rendering from the template kept in the start marker."""
+    with TmpFileManager() as manager:
+        readme = manager.tmpfile(README, full_name="README.md")
+
+        def render(version: str):
+            config = manager.create_cfg(
+                {
+                    "context": {"static": {"version": {"value": version}}},
+                    "in_place": [{"match": "README.md"}],
+                }
+            )
+            return cli.render(manager.root_dir, config)["debug"]
+
+        assert render("1.0")["written"] == [str(readme)]
+        with open(readme, "r") as file:
+            assert file.read() == README.replace("Version: old", "Version: 1.0").replace(
+                "-->old<", "--> 1.0 <"
+            )
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert sorted(json.load(file)["files"]["default"]) == ["README.md#0", "README.md#1"]
+
+        # Nothing changed, so nothing written:
+        assert render("1.0")["written"] == []
+
+        # The templates survive rendering, so can be rendered again:
+        assert render("2.0")["written"] == [str(readme)]
+        with open(readme, "r") as file:
+            assert "Version: 2.0\n" in file.read()
+
+
+def test_in_place_line_prefix():
+    """A line prefix should let the template be commented out, e.g. in toml files."""
+    with TmpFileManager() as manager:
+        cargo_src = (
+            "[package]\n"
+            "# etch:start\n"
+            '# version = "{{ version }}"\n'
+            "# etch:output\n"
+            'version = "0.1.0"\n'
+            "# etch:end\n"
+            'edition = "2021"\n'
+        )
+        cargo = manager.tmpfile(cargo_src, full_name="Cargo.toml")
+        config = manager.create_cfg(
+            {
+                "context": {"static": {"version": {"value": "1.2.3"}}},
+                "in_place": [
+                    {
+                        "match": "Cargo.toml",
+                        "start": "# etch:start",
+                        "template_end": "# etch:output",
+                        "end": "# etch:end",
+                        "line_prefix": "# ",
+                    }
+                ],
+            }
+        )
+        cli.render(manager.root_dir, config)
+        with open(cargo, "r") as file:
+            assert file.read() == cargo_src.replace('version = "0.1.0"', 'version = "1.2.3"')
+
+
+@pytest.mark.parametrize(
+    "contents,expected_err",
+    [
+        (
+            "a\n<!-- etch:end -->\n",
+            "[README.md]: In-place end marker '<!-- etch:end -->' on line 2 has no start marker.",
+        ),
+        (
+            "a\n<!-- etch:start x -->\nb\n",
+            "[README.md]: In-place start marker on line 2 is never closed by an end marker '<!-- etch:end -->'.",
+        ),
+        (
+            "<!-- etch:start x -->\n<!-- etch:start y -->\n<!-- etch:end -->\n<!-- etch:end -->\n",
+            "[README.md]: Nested in-place start marker on line 2, inside the region started on line 1.",
+        ),
+    ],
+)
+def test_in_place_invalid_markers(contents: str, expected_err: str):
+    """Unbalanced or nested markers should error with the line numbers, leaving the file untouched."""
+    with TmpFileManager() as manager:
+        readme = manager.tmpfile(contents, full_name="README.md")
+        with pytest.raises(ValueError, match=re.escape(expected_err)):
+            cli.render(
+                manager.root_dir, manager.create_cfg({"in_place": [{"match": "README.md"}]})
+            )
+        with open(readme, "r") as file:
+            assert file.read() == contents
+        assert not os.path.exists(get_lockfile_path(manager.root_dir))
@@ -1,12 +1,18 @@
 import json
+import os
+import re
+import stat
+import sys
+import typing as tp
 from pathlib import Path
+from unittest import mock
 
 import etcher as etch
 import pytest
 
 from ..helpers import cli
 from ..helpers.tmp_file_manager import TmpFileManager
-from ..helpers.utils import get_lockfile_path, remove_template
+from ..helpers.utils import get_lockfile_path, lock_entry, remove_template
 
 
 @pytest.mark.parametrize(
@@ -85,10 +91,13 @@ def test_corrupt_lockfile(lock_contents: str):
         with open(lockfile_path, "r") as file:
             assert json.load(file) == {
                 "version": etch.__version__,  # type: ignore
+                "rendered_with": {"default": {"config_hash": mock.ANY}},
                 "files": {
-                    str(template.relative_to(manager.root_dir)): etch._hash_contents(
-                        "Hello, World!"
-                    ),
+                    "default": {
+                        str(template.relative_to(manager.root_dir)): lock_entry(
+                            "Hello, World!", contents
+                        ),
+                    },
                 },
             }
 
@@ -148,10 +157,471 @@ def test_lockfile_only_write_when_needed():
         with open(get_lockfile_path(manager.root_dir), "r") as file:
             assert json.load(file) == {
                 "version": etch.__version__,  # type: ignore
+                "rendered_with": {"default": {"config_hash": mock.ANY}},
                 "files": {
-                    # Should be relative to the root_dir as that's where the lockfile is stored:
-                    str(template1.relative_to(manager.root_dir)): etch._hash_contents(
-                        "Updated, World!"
-                    ),
+                    "default": {
+                        # Should be relative to the root_dir as that's where the lockfile is stored:
+                        str(template1.relative_to(manager.root_dir)): lock_entry(
+                            "Updated, World!", "Updated, {{ var }}!"
+                        ),
+                    },
                 },
             }
+
+
+def test_lockfile_variants():
+    """Confirm alternating variants don't churn each other's entries, and flat lockfiles are migrated."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="Hello, {{ var }}!", suffix=".etch.txt")
+        rel_path = str(template.relative_to(manager.root_dir))
+        hashed = etch._hash_contents("Hello, World!")
+        entry = lock_entry("Hello, World!", "Hello, {{ var }}!")
+        config = manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}})
+
+        # Legacy flat lockfile should be migrated into the default variant without rewriting:
+        with open(get_lockfile_path(manager.root_dir), "w") as file:
+            json.dump({"version": etch.__version__, "files": {rel_path: hashed}}, file)  # type: ignore
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == []
+
+        # Each variant should write once, then stay identical whilst alternating:
+        for variant, should_write in [
+            ("dev", True),
+            ("prod", True),
+            ("dev", False),
+            ("prod", False),
+            (None, False),
+        ]:
+            result = cli.render(manager.root_dir, config, variant=variant)
+            assert result["debug"]["written"] == (
+                [remove_template(template)] if should_write else []
+            ), variant
+
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert json.load(file) == {
+                "version": etch.__version__,  # type: ignore
+                "rendered_with": {
+                    variant: {"config_hash": mock.ANY} for variant in ["default", "dev", "prod"]
+                },
+                "files": {
+                    # The migrated entry gains its source hash on the next render:
+                    "default": {rel_path: entry},
+                    "dev": {rel_path: entry},
+                    "prod": {rel_path: entry},
+                },
+            }
+
+        # Pruning should only happen within the active variant:
+        template.unlink()
+        cli.render(manager.root_dir, config, variant="dev")
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert json.load(file)["files"] == {
+                "default": {rel_path: entry},
+                "prod": {rel_path: entry},
+            }
+
+
+def test_variant_profiles():
+    """Variants rendered with different profiles should each keep their own config hash, not rewrite each other's."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="{{ FOO }}", full_name="config.etch.txt")
+        config = manager.create_cfg(
+            {
+                "profiles": {
+                    "dev": {"context": {"static": {"FOO": {"value": "dev"}}}},
+                    "prod": {"context": {"static": {"FOO": {"value": "prod"}}}},
+                },
+            }
+        )
+
+        def render(variant: str) -> list[str]:
+            return cli.render(
+                manager.root_dir,
+                config,
+                profile=variant,
+                variant=variant,
+                out_dir=Path(manager.root_dir) / f"out_{variant}",
+            )["debug"]["written"]
+
+        assert len(render("dev")) == 1
+        assert len(render("prod")) == 1
+        for variant in ["dev", "prod", "dev", "prod"]:
+            assert render(variant) == [], variant
+
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            rendered_with = json.load(file)["rendered_with"]
+        assert rendered_with["dev"]["config_hash"] != rendered_with["prod"]["config_hash"]
+
+
+def test_variant_profiles_shared_output():
+    """Variants writing the same output should rewrite it when another variant overwrote it since."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="{{ FOO }}", full_name="config.etch.txt")
+        config = manager.create_cfg(
+            {
+                "profiles": {
+                    "dev": {"context": {"static": {"FOO": {"value": "dev"}}}},
+                    "prod": {"context": {"static": {"FOO": {"value": "prod"}}}},
+                },
+            }
+        )
+
+        def render(profile: str) -> str:
+            cli.render(manager.root_dir, config, profile=profile)
+            with open(remove_template(template), "r") as file:
+                return file.read()
+
+        for profile in ["prod", "dev", "prod", "prod", "dev"]:
+            assert render(profile) == profile
+
+        # Untouched since the variant's last render, so nothing to write:
+        assert cli.render(manager.root_dir, config, profile="dev")["debug"]["written"] == []
+
+
+def test_derived_variant():
+    """Without --variant, the variant should be derived from the profile and out_dir."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="{{ FOO }}", full_name="config.etch.txt")
+        config = manager.create_cfg(
+            {
+                "context": {"static": {"FOO": {"value": "base"}}},
+                "profiles": {
+                    "dev": {"context": {"static": {"FOO": {"value": "dev"}}}},
+                    "prod": {"context": {"static": {"FOO": {"value": "prod"}}}},
+                },
+            }
+        )
+
+        def render(profile: tp.Optional[str], out_dir: tp.Optional[str] = None) -> list[str]:
+            return cli.render(
+                manager.root_dir,
+                config,
+                profile=profile,
+                out_dir=Path(manager.root_dir) / out_dir if out_dir else None,
+            )["debug"]["written"]
+
+        runs = [("dev", "out_dev"), ("prod", "out_prod"), ("prod", None), (None, None)]
+        for profile, out_dir in runs:
+            assert len(render(profile, out_dir)) == 1, (profile, out_dir)
+        for profile, out_dir in runs[:2]:
+            assert render(profile, out_dir) == [], (profile, out_dir)
+        # Without an out_dir prod and the default share an output, so each rewrites what the other wrote:
+        assert len(render("prod")) == 1
+        assert len(render(None)) == 1
+        assert render(None) == []
+
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert sorted(json.load(file)["files"]) == [
+                "default",
+                "dev@out_dev",
+                "prod",
+                "prod@out_prod",
+            ]
+
+
+def test_untracked_output_overwrite():
+    """Overwriting an existing file etch never wrote should warn, or error under --strict."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="Hello, {{ var }}!", full_name="config.etch.toml")
+        output = Path(manager.root_dir) / "config.toml"
+        output.write_text("hand written")
+        config = manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}})
+
+        with pytest.raises(ValueError, match="which exists but was never written by etch"):
+            cli.render(manager.root_dir, config, strict=True)
+        assert output.read_text() == "hand written"
+
+        result = cli.render(manager.root_dir, config)
+        assert "which existed but was never written by etch" in result["stdout"]
+        assert output.read_text() == "Hello, World!"
+
+        # Now tracked, so rewrites are expected, even when forced or strict:
+        result = cli.render(manager.root_dir, config, force=True, strict=True)
+        assert "never written by etch" not in result["stdout"]
+
+
+def test_strict_corrupt_lockfile():
+    """A corrupt lockfile should start afresh with a warning, or error under --strict."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="Hello", full_name="out.etch.txt")
+        config = manager.create_cfg({})
+        cli.render(manager.root_dir, config)
+
+        lockfile = get_lockfile_path(manager.root_dir)
+        lockfile.write_text("{not json")
+        with pytest.raises(ValueError, match=re.escape("Refusing to start afresh under --strict")):
+            cli.render(manager.root_dir, config, strict=True)
+        assert lockfile.read_text() == "{not json"
+
+        result = cli.render(manager.root_dir, config)
+        assert "Starting lockfile afresh, failed to parse existing" in result["stdout"]
+        assert json.loads(lockfile.read_text())["files"]["default"]
+
+
+def test_only_filter():
+    """--only should render a subset, leaving other templates and their lockfile entries untouched."""
+    with TmpFileManager() as manager:
+        sub_a = manager.tmpdir(name="a")
+        sub_b = manager.tmpdir(name="b")
+        template_a = manager.tmpfile("A {{ var }}", parent=sub_a, full_name="x.etch.txt")
+        template_b = manager.tmpfile("B {{ var }}", parent=sub_b, full_name="y.etch.txt")
+
+        cli.render(
+            manager.root_dir,
+            manager.create_cfg({"context": {"static": {"var": {"value": "1"}}}}),
+        )
+
+        config = manager.create_cfg({"context": {"static": {"var": {"value": "2"}}}})
+        result = cli.render(manager.root_dir, config, only=["a/**"])
+        assert result["debug"]["written"] == [remove_template(template_a)]
+        with open(remove_template(template_b), "r") as file:
+            assert file.read() == "B 1"
+
+        # Filtered renders never prune, even inside the filter, a full render is needed:
+        template_a.unlink()
+        cli.render(manager.root_dir, config, only=["a/**"])
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert json.load(file)["files"] == {
+                "default": {
+                    "a/x.etch.txt": lock_entry("A 2", "A {{ var }}"),
+                    "b/y.etch.txt": lock_entry("B 1", "B {{ var }}"),
+                },
+            }
+
+        # Multiple globs can be given, and invalid ones error:
+        result = cli.render(manager.root_dir, config, only=["a/**", "b/*.txt"])
+        assert result["debug"]["written"] == [remove_template(template_b)]
+        with pytest.raises(ValueError, match=re.escape("Invalid --only glob 'a/['")):
+            cli.render(manager.root_dir, config, only=["a/["])
+
+        # A full render prunes as usual:
+        cli.render(manager.root_dir, config)
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert list(json.load(file)["files"]["default"].keys()) == ["b/y.etch.txt"]
+
+
+@pytest.mark.skipif(sys.platform == "win32", reason="Unix permissions only")
+def test_output_permissions():
+    """Outputs should take their template's permissions, also when unchanged since the last render."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile("#!/bin/sh\necho hi", full_name="deploy.etch.sh")
+        config = manager.create_cfg({})
+        os.chmod(template, 0o755)
+        cli.render(manager.root_dir, config)
+        out_path = remove_template(template)
+        assert stat.S_IMODE(os.stat(out_path).st_mode) == 0o755
+
+        # Permission changes carry over even though the contents are identical:
+        os.chmod(template, 0o644)
+        cli.render(manager.root_dir, config)
+        assert stat.S_IMODE(os.stat(out_path).st_mode) == 0o644
+
+        # Read-only templates still produce an output etch can rewrite:
+        os.chmod(template, 0o444)
+        cli.render(manager.root_dir, config)
+        assert stat.S_IMODE(os.stat(out_path).st_mode) == 0o644
+
+
+def test_hash_algo():
+    """The lockfile should record a non-default hash algorithm, and changing it should rewrite everything."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="Hello, {{ var }}!", suffix=".etch.txt")
+        rel_path = str(template.relative_to(manager.root_dir))
+        ctx = {"static": {"var": {"value": "World"}}}
+        sha_config = manager.create_cfg({"context": ctx, "hash_algo": "sha256"})
+        fnv_config = manager.create_cfg({"context": ctx})
+
+        def read_lockfile() -> dict:
+            with open(get_lockfile_path(manager.root_dir), "r") as file:
+                return json.load(file)
+
+        assert cli.render(manager.root_dir, sha_config)["debug"]["written"] == [
+            remove_template(template)
+        ]
+        assert read_lockfile() == {
+            "version": etch.__version__,  # type: ignore
+            "rendered_with": {"default": {"config_hash": mock.ANY}},
+            "hash_algo": "sha256",
+            "files": {
+                "default": {rel_path: lock_entry("Hello, World!", "Hello, {{ var }}!", "sha256")}
+            },
+        }
+        assert cli.render(manager.root_dir, sha_config)["debug"]["written"] == []
+
+        # Hashes from different algorithms can't be compared, so switching starts afresh:
+        assert cli.render(manager.root_dir, fnv_config)["debug"]["written"] == [
+            remove_template(template)
+        ]
+        assert read_lockfile() == {
+            "version": etch.__version__,  # type: ignore
+            "rendered_with": {"default": {"config_hash": mock.ANY}},
+            "files": {"default": {rel_path: lock_entry("Hello, World!", "Hello, {{ var }}!")}},
+        }
+
+
+def test_config_change_rewrites():
+    """Changing the config should rewrite every output, even those whose contents are unchanged."""
+    with TmpFileManager() as manager:
+        changes = manager.tmpfile(content="Hello, {{ var }}!", suffix=".etch.txt")
+        static = manager.tmpfile(content="Static", suffix=".etch.txt")
+        written = sorted([remove_template(changes), remove_template(static)])
+
+        def render(var: str, **kwargs) -> list[str]:
+            config = manager.create_cfg({"context": {"static": {"var": {"value": var}}}})
+            return sorted(cli.render(manager.root_dir, config, **kwargs)["debug"]["written"])
+
+        assert render("World") == written
+        assert render("World") == []
+        assert render("Earth") == written
+
+        # Templates filtered out of a run stay tracked, so are still rewritten once the full render runs:
+        assert render("Mars", only=[str(changes.relative_to(manager.root_dir))]) == [
+            remove_template(changes)
+        ]
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert len(json.load(file)["files"]["default"]) == 2
+        assert render("Mars") == written
+        assert render("Mars") == []
+
+
+def test_no_lockfile():
+    """--no-lockfile should always write, never creating or touching the lockfile."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="Hello", suffix=".etch.txt")
+        config = manager.create_cfg({})
+        lockfile_path = get_lockfile_path(manager.root_dir)
+
+        for _ in range(2):
+            result = cli.render(manager.root_dir, config, no_lockfile=True)["debug"]
+            assert result["written"] == [remove_template(template)]
+            assert result["lockfile_modified"] is False
+            assert not os.path.exists(lockfile_path)
+
+        # An existing lockfile is left as is:
+        cli.render(manager.root_dir, config)
+        with open(lockfile_path, "r") as file:
+            before = file.read()
+        manager.tmpfile(content="World", suffix=".etch.txt")
+        assert len(cli.render(manager.root_dir, config, no_lockfile=True)["debug"]["written"]) == 2
+        with open(lockfile_path, "r") as file:
+            assert file.read() == before
+
+
+def test_extension_change_rewrites():
+    """Editing a custom extension should rewrite every output, the templates using it are unchanged."""
+    with TmpFileManager() as manager:
+        ext_src = 'import etcher as etch\netch.register_function(lambda: "{}", name="ver")\n'
+        ext = manager.tmpfile(ext_src.format("v1"), suffix=".py")
+        uses = manager.tmpfile(content="{{ ver() }}", suffix=".etch.txt")
+        static = manager.tmpfile(content="Static", suffix=".etch.txt")
+        written = sorted([remove_template(uses), remove_template(static)])
+        config = manager.create_cfg({"engine": {"custom_extensions": [str(ext)]}})
+
+        def render() -> list[str]:
+            return sorted(cli.render(manager.root_dir, config)["debug"]["written"])
+
+        assert render() == written
+        assert render() == []
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            rendered_with = json.load(file)["rendered_with"]["default"]
+            assert list(rendered_with["extension_hashes"]) == [ext.name]
+
+        with open(ext, "w") as file:
+            file.write(ext_src.format("v2"))
+        assert render() == written
+        with open(remove_template(uses), "r") as file:
+            assert file.read() == "v2"
+        assert render() == []
+
+
+def test_lockfile_path():
+    """Roots sharing a directory should be able to keep their lockfiles elsewhere, the cli flag beating the config."""
+    with TmpFileManager() as manager:
+        locks = manager.tmpdir(name="locks")
+        root_a = manager.tmpdir(name="a")
+        root_b = manager.tmpdir(name="b")
+        template_a = manager.tmpfile(content="A", suffix=".etch.txt", parent=root_a)
+        template_b = manager.tmpfile(content="B", suffix=".etch.txt", parent=root_b)
+        # Relative to the config file:
+        config = manager.create_cfg({"lockfile": "locks/a.lock"})
+
+        assert cli.render(root_a, config)["debug"]["written"] == [remove_template(template_a)]
+        assert cli.render(root_a, config)["debug"]["written"] == []
+        assert os.path.exists(locks / "a.lock")
+        assert not os.path.exists(get_lockfile_path(root_a))
+
+        result = cli.render(root_b, config, lockfile=locks / "b.lock")["debug"]
+        assert result["written"] == [remove_template(template_b)]
+        with open(locks / "b.lock", "r") as file:
+            assert list(json.load(file)["files"]["default"]) == [template_b.name]
+        with open(locks / "a.lock", "r") as file:
+            assert list(json.load(file)["files"]["default"]) == [template_a.name]
+
+        with pytest.raises(ValueError, match=re.escape("[--lockfile]: Lockfile path")):
+            cli.render(root_b, config, lockfile=locks)
+
+
+def test_lockfile_path_excluded():
+    """A custom lockfile under the root should never be picked up as a template, even with a marker in its name."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="Hello", suffix=".etch.txt")
+        config = manager.create_cfg({"lockfile": "state.etch.lock"})
+
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == [
+            remove_template(template)
+        ]
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == []
+        assert os.path.exists(os.path.join(manager.root_dir, "state.etch.lock"))
+        assert not os.path.exists(os.path.join(manager.root_dir, "state.lock"))
+
+
+def test_source_hash():
+    """The template's own hash should be tracked, kept up to date even when the output is unchanged."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile(content="Hello, {{ var }}!", suffix=".etch.txt")
+        rel_path = str(template.relative_to(manager.root_dir))
+        config = manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}})
+
+        def read_entry() -> dict:
+            with open(get_lockfile_path(manager.root_dir), "r") as file:
+                return json.load(file)["files"]["default"][rel_path]
+
+        # Entries from before the source was tracked are still valid, just missing the source hash:
+        with open(get_lockfile_path(manager.root_dir), "w") as file:
+            json.dump(
+                {
+                    "version": etch.__version__,  # type: ignore
+                    "files": {"default": {rel_path: etch._hash_contents("Hello, World!")}},
+                },
+                file,
+            )
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == []
+        assert read_entry() == lock_entry("Hello, World!", "Hello, {{ var }}!")
+
+        # An edit rendering the same output isn't rewritten, but the source hash follows it:
+        template.write_text("Hello, {{ var }}!{# A comment #}")
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == []
+        assert read_entry() == lock_entry("Hello, World!", "Hello, {{ var }}!{# A comment #}")
+
+
+def test_multiple_roots():
+    """Every root should be rendered with the one config, each keeping its own lockfile."""
+    with TmpFileManager() as manager:
+        root_a = manager.tmpdir(name="a")
+        root_b = manager.tmpdir(name="b")
+        template_a = manager.tmpfile(content="A{{ var }}", suffix=".etch.txt", parent=root_a)
+        template_b = manager.tmpfile(content="B{{ var }}", suffix=".etch.txt", parent=root_b)
+        config = manager.create_cfg({"context": {"static": {"var": {"value": 1}}}})
+
+        result = cli.render(root_a, config, extra_roots=[root_b], verbose=True)
+        assert result["debug"]["written"] == [remove_template(template_a)]
+        assert "templates written across 2 roots" in result["stdout"]
+        with open(remove_template(template_b), "r") as file:
+            assert file.read() == "B1"
+        for root, template in [(root_a, template_a), (root_b, template_b)]:
+            with open(get_lockfile_path(root), "r") as file:
+                assert list(json.load(file)["files"]["default"]) == [template.name]
+
+        assert cli.render(root_a, config, extra_roots=[root_b])["debug"]["written"] == []
+
+        with pytest.raises(ValueError, match=re.escape("A custom lockfile can't be used")):
+            cli.render(root_a, config, extra_roots=[root_b], lockfile=root_a / "x.lock")
@@ -0,0 +1,86 @@
+import re
+
+import pytest
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+
+MARKERS = {"begin": "# ETCH:BEGIN", "end": "# ETCH:END"}
+
+
+def test_managed_regions():
+    """Only the lines between the markers should be replaced, hand edits elsewhere are kept."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="version = {{ version }}\n", full_name="settings.etch.conf")
+        output = manager.tmpfile(
+            content="# Hand written\nname = foo\n# ETCH:BEGIN\nversion = old\n# ETCH:END\ndebug = true\n",
+            full_name="settings.conf",
+        )
+
+        def render(version: str) -> list[str]:
+            config = manager.create_cfg(
+                {
+                    "context": {"static": {"version": {"value": version}}},
+                    "engine": {"managed_regions": [MARKERS]},
+                }
+            )
+            return cli.render(manager.root_dir, config)["debug"]["written"]
+
+        # Marked for etch by hand, so no warning about overwriting an untracked file:
+        assert render("1.0") == [str(output)]
+        assert output.read_text() == (
+            "# Hand written\nname = foo\n# ETCH:BEGIN\nversion = 1.0\n# ETCH:END\ndebug = true\n"
+        )
+
+        # Hand edits outside the region survive re-renders:
+        output.write_text(output.read_text().replace("debug = true", "debug = false"))
+        assert render("1.0") == []
+        assert render("2.0") == [str(output)]
+        assert output.read_text() == (
+            "# Hand written\nname = foo\n# ETCH:BEGIN\nversion = 2.0\n# ETCH:END\ndebug = false\n"
+        )
+
+
+def test_managed_regions_from_template():
+    """A template rendering the markers fills each region in order, and a new output is written whole."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(
+            content="a\n<!-- GEN -->\n{{ x }}\n<!-- /GEN -->\nb\n<!-- GEN -->\n{{ y }}\n<!-- /GEN -->\n",
+            full_name="page.etch.html",
+        )
+        config = manager.create_cfg(
+            {
+                "context": {"static": {"x": {"value": "X"}, "y": {"value": "Y"}}},
+                "engine": {
+                    "managed_regions": [MARKERS, {"begin": "<!-- GEN -->", "end": "<!-- /GEN -->"}]
+                },
+            }
+        )
+        cli.render(manager.root_dir, config)
+        output = manager.root_dir + "/page.html"
+        with open(output, "r") as file:
+            assert file.read() == "a\n<!-- GEN -->\nX\n<!-- /GEN -->\nb\n<!-- GEN -->\nY\n<!-- /GEN -->\n"
+
+        with open(output, "w") as file:
+            file.write("custom\n<!-- GEN -->\n<!-- /GEN -->\n")
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[page.etch.html]: The output has 1 managed regions between '<!-- GEN -->' and '<!-- /GEN -->', but the template renders 2."
+            ),
+        ):
+            cli.render(manager.root_dir, config, force=True)
+
+
+def test_managed_regions_unbalanced():
+    with TmpFileManager() as manager:
+        manager.tmpfile(content="generated\n", full_name="settings.etch.conf")
+        manager.tmpfile(content="# ETCH:BEGIN\nold\n", full_name="settings.conf")
+        config = manager.create_cfg({"engine": {"managed_regions": [MARKERS]}})
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[settings.etch.conf]: Managed region begin marker in the output on line 1 is never closed by an end marker '# ETCH:END'."
+            ),
+        ):
+            cli.render(manager.root_dir, config)
@@ -0,0 +1,45 @@
+import json
+import os
+import pathlib
+
+from ..helpers import cli
+from ..helpers.tmp_file_manager import TmpFileManager
+from ..helpers.utils import get_lockfile_path
+
+
+def test_out_dir():
+    """Outputs should mirror their templates under the out_dir, which is never searched itself."""
+    with TmpFileManager() as manager:
+        templates = manager.tmpdir(name="templates")
+        nested = manager.tmpdir(parent=str(templates), name="nested")
+        manager.tmpfile(content="A", full_name="a.etch.txt", parent=templates)
+        manager.tmpfile(content="B", full_name="b.etch.txt", parent=nested)
+        generated = pathlib.Path(manager.root_dir) / "generated"
+        config = manager.create_cfg({"out_dir": "generated"})
+
+        result = cli.render(manager.root_dir, config)["debug"]
+        assert sorted(result["written"]) == [
+            str(generated / "templates" / "a.txt"),
+            str(generated / "templates" / "nested" / "b.txt"),
+        ]
+        with open(generated / "templates" / "nested" / "b.txt", "r") as file:
+            assert file.read() == "B"
+        assert not os.path.exists(templates / "a.txt")
+        with open(get_lockfile_path(manager.root_dir), "r") as file:
+            assert (
+                json.load(file)["files"]["default@generated"]["templates/a.etch.txt"]["out_path"]
+                == "generated/templates/a.txt"
+            )
+
+        # A template in the out_dir isn't picked up:
+        manager.tmpfile(content="C", full_name="c.etch.txt", parent=generated)
+        assert cli.render(manager.root_dir, config)["debug"]["written"] == []
+
+        # Moving the out_dir rewrites the outputs even though they're unchanged, the cli flag winning.
+        # The config's out_dir still holds outputs so isn't searched either:
+        elsewhere = pathlib.Path(manager.root_dir) / "elsewhere"
+        result = cli.render(manager.root_dir, config, out_dir=elsewhere)["debug"]
+        assert sorted(result["written"]) == [
+            str(elsewhere / "templates" / "a.txt"),
+            str(elsewhere / "templates" / "nested" / "b.txt"),
+        ]
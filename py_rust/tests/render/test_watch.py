@@ -0,0 +1,67 @@
+import os
+import signal
+import subprocess
+import time
+import typing as tp
+
+from ..helpers.tmp_file_manager import TmpFileManager
+
+
+def _wait_for(check: tp.Callable[[], bool], timeout: float = 10.0):
+    start = time.time()
+    while not check():
+        if time.time() - start > timeout:
+            raise TimeoutError("Condition not met in time.")
+        time.sleep(0.05)
+
+
+def _read(path: str) -> tp.Optional[str]:
+    if not os.path.exists(path):
+        return None
+    with open(path, "r") as file:
+        return file.read()
+
+
+def test_watch():
+    """--watch should re-render on template changes and reload the config when it changes."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile("Hello, {{ var }}!", full_name="hello.etch.txt")
+        config = manager.create_cfg({"context": {"static": {"var": {"value": "World"}}}})
+        out_path = os.path.join(manager.root_dir, "hello.txt")
+
+        proc = subprocess.Popen(
+            ["etch", "--watch", str(manager.root_dir), "--config", str(config)],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.STDOUT,
+            text=True,
+        )
+        try:
+            _wait_for(lambda: _read(out_path) == "Hello, World!")
+
+            # Template changes are picked up:
+            with open(template, "w") as file:
+                file.write("Bye, {{ var }}!")
+            _wait_for(lambda: _read(out_path) == "Bye, World!")
+
+            # Config changes are picked up:
+            with open(config, "w") as file:
+                file.write('[context.static]\nvar = { value = "Etch" }\n')
+            _wait_for(lambda: _read(out_path) == "Bye, Etch!")
+
+            # A broken template is reported without ending the watch:
+            with open(template, "w") as file:
+                file.write("{{ madeup }}")
+            time.sleep(1)
+            assert proc.poll() is None
+            assert _read(out_path) == "Bye, Etch!"
+
+            with open(template, "w") as file:
+                file.write("Fixed, {{ var }}!")
+            _wait_for(lambda: _read(out_path) == "Fixed, Etch!")
+
+            # Ctrl+C stops cleanly:
+            proc.send_signal(signal.SIGINT)
+            assert proc.wait(timeout=10) == 0
+        finally:
+            proc.kill()
+            proc.wait()
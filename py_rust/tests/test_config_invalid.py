@@ -1,3 +1,4 @@
+import os
 import re
 import typing as tp
 
@@ -210,6 +211,222 @@ def test_incorrect_config():
             )
 
 
+@pytest.mark.parametrize(
+    "contents,expected",
+    [
+        ("exclue = ['foo']", "[root]: Unknown property: 'exclue'. Did you mean 'exclude'?"),
+        ("[engine]\nallow_undefned = true", "[engine]: Unknown property: 'allow_undefned'. Did you mean 'allow_undefined'?"),
+        ("[context.statc]", "[context]: Unknown property: 'statc'. Did you mean 'static'?"),
+        (
+            "[context.static]\nFOO = { valeu = 'bar' }",
+            "[context.static.FOO]: Unknown property: 'valeu'. Did you mean 'value'?",
+        ),
+        (
+            "[context.env]\nFOO = { env_nme = 'BAR' }",
+            "[context.env.FOO]: Unknown property: 'env_nme'. Did you mean 'env_name'?",
+        ),
+        (
+            "[context.cli]\nFOO = { commands = ['echo 1'], corece = 'int' }",
+            "[context.cli.FOO]: Unknown property: 'corece'. Did you mean 'coerce'?",
+        ),
+        (
+            "[context.cli_multi.tool]\ncommands = ['echo 1']\nparse = 'json'\nkey = { FOO = 'foo' }",
+            "[context.cli_multi.tool]: Unknown property: 'key'. Did you mean 'keys'?",
+        ),
+        (
+            "[profiles.dev]\nexclue = ['foo']",
+            "[profiles.dev]: Unknown property: 'exclue'. Did you mean 'exclude'?",
+        ),
+        # Nothing close enough shouldn't suggest anything:
+        ("[engine]\nmadeup = true", "[engine]: Unknown property: 'madeup'."),
+    ],
+)
+def test_unknown_key_suggestions(contents: str, expected: str):
+    """Confirm unknown keys at every nesting level name their location and suggest the closest known key."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape(expected)) as exc_info:
+            cli.render(manager.root_dir, manager.tmpfile(contents, suffix=".toml"))
+        if "Did you mean" not in expected:
+            assert "Did you mean" not in str(exc_info.value)
+
+
+def test_error_source_location():
+    """Schema errors should point to the file, line and column of the offending value."""
+    with TmpFileManager() as manager:
+        config = manager.tmpfile(
+            "[context.static]\nFOO = { value = 'bar' }\n\n[engine]\nallow_undefined = true\nkeep_trailing_newline = 'yes'\n",
+            suffix=".toml",
+        )
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(manager.root_dir, config)
+        msg = str(exc_info.value)
+        assert "[engine.keep_trailing_newline]: Expected a boolean." in msg
+        # The path is canonicalized, so only match the filename:
+        assert re.search(r"--> .*{}:6:25".format(re.escape(config.name)), msg), msg
+        assert "6 | keep_trailing_newline = 'yes'" in msg
+        assert "  |                         ^^^^^" in msg
+
+        # Unknown properties should point at the property itself:
+        config = manager.tmpfile("[engine]\n\nallow_undefned = true\n", suffix=".toml")
+        with pytest.raises(ValueError, match=re.escape("{}:3:18".format(config.name))):
+            cli.render(manager.root_dir, config)
+
+
+def test_extends_cycle():
+    """Confirm cycles in extended configs are detected with the chain printed."""
+    with TmpFileManager() as manager:
+        manager.tmpfile('extends = "b.toml"', full_name="a.toml")
+        manager.tmpfile('extends = "a.toml"', full_name="b.toml")
+        with pytest.raises(
+            ValueError, match=r"Cycle detected in config extends: '.*a\.toml' -> '.*b\.toml' -> '.*a\.toml'"
+        ):
+            cli.render(manager.root_dir, "a.toml")
+
+        # Missing parents should name the file:
+        manager.tmpfile('extends = "madeup.toml"', full_name="c.toml")
+        with pytest.raises(ValueError, match="Error reading extended config from"):
+            cli.render(manager.root_dir, "c.toml")
+
+
+def test_env_interpolation_missing_var():
+    """Unset vars without a default should name the config key they're in."""
+    with TmpFileManager() as manager:
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[engine.custom_extensions.1]: Environment variable 'ETCH_MADEUP_VAR' is not set and has no default."
+            ),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.tmpfile(
+                    "[engine]\ncustom_extensions = ['${ETCH_MADEUP_VAR:-a.py}', '${ETCH_MADEUP_VAR}/ext.py']\n",
+                    suffix=".toml",
+                ),
+            )
+
+
+def test_duplicate_context_keys():
+    """Keys defined by more than one context source should error, listing each source."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError) as exc_info:
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "context": {
+                            "static": {"FOO": {"value": "a"}, "BAR": {"value": "b"}, "OK": {"value": "c"}},
+                            "env": {"FOO": {"default": "a"}},
+                            "cli": {"BAR": {"commands": ["echo b"]}},
+                            "cli_multi": {
+                                "tool": {
+                                    "commands": ["echo '{}'"],
+                                    "parse": "json",
+                                    "keys": {"FOO": "foo"},
+                                }
+                            },
+                        }
+                    }
+                ),
+            )
+        msg = str(exc_info.value)
+        assert "Duplicate context keys" in msg
+        assert "'BAR' is defined in: [context.static.BAR], [context.cli.BAR]" in msg
+        assert (
+            "'FOO' is defined in: [context.static.FOO], [context.env.FOO], [context.cli_multi.tool.keys.FOO]"
+            in msg
+        )
+        assert "'OK'" not in msg
+
+    # Reserved names can't be used in any source:
+    with TmpFileManager() as manager:
+        with pytest.raises(
+            ValueError,
+            match=re.escape("[context.cli.etch_profile]: 'etch_profile' is reserved for the active profile name."),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"context": {"cli": {"etch_profile": {"commands": ["echo a"]}}}}),
+            )
+
+
+@pytest.mark.parametrize(
+    "engine,expected",
+    [
+        (
+            {"variable_start": "{{", "comment_start": "{{"},
+            "[engine.variable_start] and [engine.comment_start] are both '{{', starts must be distinct.",
+        ),
+        (
+            {"block_start": "[[", "variable_start": "[[", "variable_end": "]]"},
+            "[engine.block_start] and [engine.variable_start] are both '[[', starts must be distinct.",
+        ),
+        (
+            {"comment_start": "##", "comment_end": "##"},
+            "[engine.comment_start] and [engine.comment_end] are both '##', a start must differ from its end.",
+        ),
+        (
+            {"variable_start": "{", "variable_end": "}"},
+            "[engine.variable_start] '{' is a prefix of [engine.block_start] '{%', which makes parsing ambiguous.",
+        ),
+        (
+            {"comment_start": "<", "comment_end": ">", "block_start": "<%", "block_end": "%>"},
+            "[engine.comment_start] '<' is a prefix of [engine.block_start] '<%', which makes parsing ambiguous.",
+        ),
+        (
+            {"variable_start": "<%", "variable_end": "%>", "block_start": "<%=", "block_end": "=%>"},
+            "[engine.variable_start] '<%' is a prefix of [engine.block_start] '<%=', which makes parsing ambiguous.",
+        ),
+        ({"block_end": ""}, "[engine.block_end]: Cannot be empty or whitespace."),
+        ({"variable_start": "  "}, "[engine.variable_start]: Cannot be empty or whitespace."),
+        ({"block_start": "{\n%"}, "[engine.block_start]: Cannot contain newlines."),
+        # Overrides are validated merged over the base syntax:
+        (
+            {"overrides": [{"match": "*.tex", "variable_start": "{%"}]},
+            "[engine.overrides.0.block_start] and [engine.overrides.0.variable_start] are both '{%', starts must be distinct.",
+        ),
+        (
+            {"overrides": [{"match": "*.tex"}, {"match": "src/[abc"}]},
+            "[engine.overrides.1.match]: Invalid glob 'src/[abc'",
+        ),
+    ],
+)
+def test_delimiter_validation(engine: dict, expected: str):
+    """Engine delimiters should be validated when the config is loaded, naming the offending keys."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape(expected)):
+            cli.render(manager.root_dir, manager.create_cfg({"engine": engine}))
+
+
+@pytest.mark.parametrize(
+    "engine,expected",
+    [
+        (
+            {"line_statement_prefix": "{%"},
+            "[engine.line_statement_prefix]: '{%' collides with [engine.block_start] '{%'.",
+        ),
+        (
+            {"line_comment_prefix": "{"},
+            "[engine.line_comment_prefix]: '{' collides with [engine.block_start] '{%'.",
+        ),
+        (
+            {"line_statement_prefix": "#", "comment_start": "#{"},
+            "[engine.line_statement_prefix]: '#' collides with [engine.comment_start] '#{'.",
+        ),
+        (
+            {"line_statement_prefix": "#", "line_comment_prefix": "#"},
+            "[engine.line_statement_prefix]: '#' collides with [engine.line_comment_prefix] '#'.",
+        ),
+        ({"line_comment_prefix": " "}, "[engine.line_comment_prefix]: Cannot be empty or whitespace."),
+    ],
+)
+def test_line_prefix_collisions(engine: dict, expected: str):
+    """Line statement and comment prefixes can't be ambiguous with the block/variable/comment delimiters."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape(expected)):
+            cli.render(manager.root_dir, manager.create_cfg({"engine": engine}))
+
+
 def test_missing_env_var():
     """Confirm missing env vars included in context raise nice error when no default."""
     with TmpFileManager() as manager:
@@ -274,6 +491,33 @@ def test_invalid_coersion(
             )
 
 
+def test_cli_multi_missing_path():
+    """Missing paths in cli_multi should name the key, the path and the parsed output."""
+    with TmpFileManager() as manager:
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[context.cli_multi.tool.keys.SHA]: Path 'build.sha' not found in parsed output: '{\"version\":\"1\"}'"
+            ),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "context": {
+                            "cli_multi": {
+                                "tool": {
+                                    "commands": ["echo '{\"version\": \"1\"}'"],
+                                    "parse": "json",
+                                    "keys": {"SHA": "build.sha"},
+                                }
+                            }
+                        }
+                    }
+                ),
+            )
+
+
 def test_unrecognised_root():
     """Check an unrecognized root raises."""
     with TmpFileManager() as manager:
@@ -337,6 +581,62 @@ def test_unrecognised_user_extension():
             )
 
 
+def test_path_checks_resolved():
+    """Path errors should show the location and the resolved absolute path, unless checks are skipped."""
+    with TmpFileManager() as manager:
+        config = manager.create_cfg(
+            {
+                "context": {"static": {"var": {"value": "World"}}},
+                "ignore_files": ["madeup.txt"],
+            }
+        )
+        expected = os.path.join(os.path.realpath(os.path.dirname(config)), "madeup.txt")
+        with pytest.raises(
+            ValueError,
+            match=r"\[ignore_files.0\]: Path '{}' does not exist.".format(re.escape(expected)),
+        ):
+            cli.render(manager.root_dir, config)
+
+        # The escape hatch should allow the missing path through:
+        cli.render(manager.root_dir, config, skip_path_checks=True)
+
+        # An extension that isn't readable should error upfront:
+        ext = manager.tmpfile(content="", suffix=".py")
+        os.chmod(ext, 0)
+        try:
+            if os.access(ext, os.R_OK):
+                pytest.skip("Running with permissions that bypass file modes.")
+            with pytest.raises(
+                ValueError, match=r"\[engine.custom_extensions.0\]: Path '.*' is not readable"
+            ):
+                cli.render(
+                    manager.root_dir,
+                    manager.create_cfg(
+                        {
+                            "context": {"static": {"var": {"value": "World"}}},
+                            "engine": {"custom_extensions": [str(ext)]},
+                        }
+                    ),
+                )
+        finally:
+            os.chmod(ext, 0o644)
+
+
+def test_invalid_exclude_pattern():
+    """A malformed exclude should be reported with its index and pattern when loading the config."""
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match=re.escape("[exclude.1]: Invalid pattern 'src/[abc'")):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "context": {"static": {"var": {"value": "World"}}},
+                        "exclude": ["*.py", "src/[abc"],
+                    }
+                ),
+            )
+
+
 def test_direct_file():
     """Should raise error when direct file passed."""
     with TmpFileManager() as manager:
@@ -1,5 +1,6 @@
 import json
 import os
+import re
 import time
 import typing as tp
 from unittest import mock
@@ -10,7 +11,7 @@ import pytest
 from .helpers import cli
 from .helpers.tmp_file_manager import TmpFileManager
 from .helpers.types import InputConfig
-from .helpers.utils import check_single
+from .helpers.utils import check_single, remove_template
 
 
 def cfg_str(config: InputConfig) -> str:
@@ -128,14 +129,31 @@ def cfg_str(config: InputConfig) -> str:
             ),
             {
                 "allow_undefined": True,
+                "max_undefined": None,
+                "max_fuel": None,
+                "max_include_depth": None,
                 "keep_trailing_newline": False,
+                "trim_blocks": False,
+                "lstrip_blocks": False,
                 "block_start": "{%",
                 "block_end": "%}",
                 "variable_start": "{{",
                 "variable_end": "}}",
                 "comment_start": "{#",
                 "comment_end": "#}",
+                "line_statement_prefix": None,
+                "line_comment_prefix": None,
                 "custom_extensions": [],
+                "include_paths": [],
+                "expose_process_env": "none",
+                "env_allowlist": [],
+                "builtin_globals": True,
+                "allow_env_in_templates": True,
+                "fixed_timestamp": None,
+                "line_endings": "preserve",
+                "auto_escape": {},
+                "overrides": [],
+                "managed_regions": [],
             },
         ),
     ],
@@ -181,6 +199,492 @@ def test_setup_commands():
         assert not os.path.exists(tmpfile)
 
 
+def test_setup_commands_options():
+    """Confirm setup command tables: parallel groups run concurrently, allowed errors don't fail, cwd is respected."""
+    with TmpFileManager() as manager:
+        cwd = manager.tmpdir(name="cmd_cwd")
+        before = time.time()
+        check_single(
+            manager,
+            manager.create_cfg(
+                {
+                    "setup_commands": [
+                        {"command": "sleep 0.5", "parallel_group": "gen"},
+                        {"command": "sleep 0.5", "parallel_group": "gen"},
+                        {"command": "sleep 0.5", "parallel_group": "gen"},
+                        {"command": "false", "allow_error": True},
+                        {"command": "touch made.txt", "cwd": str(cwd)},
+                    ]
+                }
+            ),
+            "",
+            "",
+        )
+        time_taken = time.time() - before
+        # Should be just above 0.5, but allow decent leeway:
+        assert time_taken < 1.2
+
+        assert os.path.exists(os.path.join(cwd, "made.txt"))
+
+    # Commands run in order, a group only runs once the commands listed before it have finished:
+    with TmpFileManager() as manager:
+        marker = os.path.join(manager.root_dir, "first.txt")
+        copied = os.path.join(manager.root_dir, "copied.txt")
+        check_single(
+            manager,
+            manager.create_cfg(
+                {
+                    "setup_commands": [
+                        "sh -c 'sleep 0.2 && echo first > {}'".format(marker),
+                        {"command": "cp {} {}".format(marker, copied), "parallel_group": "gen"},
+                        {"command": "true", "parallel_group": "gen"},
+                    ]
+                }
+            ),
+            "",
+            "",
+        )
+        with open(copied, "r") as file:
+            assert file.read() == "first\n"
+
+    # A group split by another command would have to run out of order, so is rejected:
+    with TmpFileManager() as manager:
+        with pytest.raises(
+            ValueError,
+            match=re.escape(
+                "[setup_commands.2]: Commands in parallel_group 'gen' must be listed consecutively"
+            ),
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "setup_commands": [
+                            {"command": "true", "parallel_group": "gen"},
+                            "true",
+                            {"command": "true", "parallel_group": "gen"},
+                        ]
+                    }
+                ),
+            )
+
+    # Errors should still fail the render when not allowed:
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match="Setup command 'false' returned non zero exit code: 1"):
+            cli.render(manager.root_dir, manager.create_cfg({"setup_commands": [{"command": "false"}]}))
+
+
+def test_finish_commands():
+    """Confirm finish commands run after rendering with the written files exported, and only_if_written is respected."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile("Hello, World!", suffix=".etch.txt")
+        marker = os.path.join(manager.root_dir, "finished.txt")
+        config = manager.create_cfg(
+            {
+                "finish_commands": [
+                    {
+                        "command": "sh -c 'printf \"%s\\n%s\" \"$ETCH_WRITTEN_COUNT\" \"$ETCH_WRITTEN_FILES\" > {}'".format(
+                            marker
+                        ),
+                        "only_if_written": True,
+                    }
+                ]
+            }
+        )
+
+        cli.render(manager.root_dir, config)
+        with open(marker, "r") as file:
+            assert file.read() == "1\n{}".format(remove_template(template))
+
+        # Nothing written second time round, so shouldn't run:
+        os.remove(marker)
+        cli.render(manager.root_dir, config)
+        assert not os.path.exists(marker)
+
+    # Failures should fail the render with the command's stderr:
+    with TmpFileManager() as manager:
+        with pytest.raises(ValueError, match="I FAILED"):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"finish_commands": ["sh -c 'echo I FAILED >&2; exit 1'"]}),
+            )
+
+
+def test_post_write_hooks():
+    """Confirm post write hooks run on matching written templates only, and never on identical ones."""
+    with TmpFileManager() as manager:
+        py_template = manager.tmpfile("x = 1", suffix=".etch.py")
+        manager.tmpfile("Hello, World!", suffix=".etch.txt")
+        log = os.path.join(manager.root_dir, "hooks.log")
+        config = manager.create_cfg(
+            {
+                "post_write": [
+                    {"match": "*.py", "command": "sh -c 'echo \"$0\" >> {}' {{path}}".format(log)}
+                ]
+            }
+        )
+
+        cli.render(manager.root_dir, config)
+        with open(log, "r") as file:
+            assert file.read() == "{}\n".format(remove_template(py_template))
+
+        # Identical second time round, so shouldn't run again:
+        cli.render(manager.root_dir, config)
+        with open(log, "r") as file:
+            assert file.read() == "{}\n".format(remove_template(py_template))
+
+    # Failures should name the template and command:
+    with TmpFileManager() as manager:
+        manager.tmpfile("x = 1", full_name="foo.etch.py")
+        with pytest.raises(
+            ValueError, match="Post write hook 'false' failed for template 'foo.etch.py'"
+        ):
+            cli.render(
+                manager.root_dir,
+                manager.create_cfg({"post_write": [{"match": "*.py", "command": "false"}]}),
+            )
+
+
+def test_extends():
+    """Confirm extended configs are deep merged underneath, with relative paths resolved from the declaring file."""
+    with TmpFileManager() as manager:
+        shared = manager.tmpdir(name="shared")
+        manager.tmpfile("ignored.etch.txt", parent=shared, full_name="base_ignore.txt")
+        base = manager.tmpfile(
+            etch._toml_update(
+                "",
+                update={
+                    "exclude": ["foo.bar"],
+                    "ignore_files": ["base_ignore.txt"],
+                    "context": {
+                        "static": {"FOO": {"value": "base_foo"}, "BAR": {"value": "base_bar"}},
+                        "env": {"BAZ": {"default": "base_baz"}},
+                    },
+                },
+            ),
+            parent=shared,
+            full_name="etch.base.toml",
+        )
+        config = manager.tmpfile(
+            'extends = "shared/etch.base.toml"\n'
+            + cfg_str(
+                {
+                    "exclude": ["baz.qux"],
+                    "context": {
+                        "static": {"FOO": {"value": "child_foo"}, "BAZ": {"value": "child_baz"}}
+                    },
+                }
+            ),
+            suffix=".toml",
+        )
+
+        debug = cli.render(manager.root_dir, config)["debug"]
+        assert debug["config"]["context"] == {
+            "FOO": "child_foo",
+            "BAR": "base_bar",
+            "BAZ": "child_baz",
+        }
+        assert debug["config"]["exclude"] == ["foo.bar", "baz.qux"]
+        assert debug["config"]["ignore_files"] == [
+            os.path.join(os.path.realpath(shared), "base_ignore.txt")
+        ]
+        assert debug["extends_chain"] == [
+            os.path.realpath(base),
+            os.path.realpath(config),
+        ]
+
+
+def test_extends_include_paths():
+    """Include paths from an extended config should resolve from the declaring file, not the child."""
+    with TmpFileManager() as manager:
+        shared = manager.tmpdir(name="shared")
+        partials = manager.tmpdir(parent=str(shared), name="partials")
+        manager.tmpfile("shared header", parent=partials, full_name="header.txt")
+        manager.tmpfile(
+            cfg_str({"engine": {"include_paths": ["partials"]}}),
+            parent=shared,
+            full_name="etch.base.toml",
+        )
+        config = manager.tmpfile('extends = "shared/etch.base.toml"\n', suffix=".toml")
+
+        debug = cli.render(manager.root_dir, config)["debug"]
+        assert debug["config"]["engine"]["include_paths"] == [os.path.realpath(partials)]
+        check_single(manager, config, "{% include 'header.txt' %}", "shared header")
+
+
+def test_profiles():
+    """Confirm the selected profile is merged over the base, from the cli or ETCH_PROFILE, and exposed to templates."""
+    with TmpFileManager() as manager:
+        template = manager.tmpfile("{{ FOO }} {{ etch_profile }}", suffix=".etch.txt")
+        config = manager.create_cfg(
+            {
+                "exclude": ["foo.bar"],
+                "context": {"static": {"FOO": {"value": "base"}}},
+                "profiles": {
+                    "prod": {
+                        "exclude": ["baz.qux"],
+                        "context": {"static": {"FOO": {"value": "prod"}}},
+                        "engine": {"keep_trailing_newline": False},
+                    },
+                    "dev": {"context": {"env": {"FOO": {"default": "dev"}}}},
+                },
+            }
+        )
+
+        def read_out() -> str:
+            with open(remove_template(template), "r") as file:
+                return file.read()
+
+        debug = cli.render(manager.root_dir, config)["debug"]
+        assert debug["config"]["profile"] is None
+        assert read_out() == "base none"
+
+        debug = cli.render(manager.root_dir, config, profile="prod")["debug"]
+        assert debug["config"]["profile"] == "prod"
+        assert debug["config"]["exclude"] == ["foo.bar", "baz.qux"]
+        assert debug["config"]["engine"]["keep_trailing_newline"] is False
+        assert read_out() == "prod prod"
+
+        with mock.patch.dict(os.environ, {"ETCH_PROFILE": "dev"}):
+            cli.render(manager.root_dir, config)
+            assert read_out() == "dev dev"
+
+            # The cli takes precedence over the env var:
+            cli.render(manager.root_dir, config, profile="prod")
+            assert read_out() == "prod prod"
+
+        # Unknown profiles should list the available:
+        with pytest.raises(
+            ValueError, match=re.escape("Profile 'staging' not found in [profiles]. Available profiles:")
+        ):
+            cli.render(manager.root_dir, config, profile="staging")
+
+
+def test_pyproject_config():
+    """Confirm the [tool.etch] table of pyproject.toml is used when the default config is missing, or when passed explicitly."""
+    with TmpFileManager() as manager:
+        pyproject = manager.tmpfile(
+            "[project]\nname = 'foo'\n\n[tool.etch.context.static]\nFOO = { value = 'from_pyproject' }\n",
+            full_name="pyproject.toml",
+        )
+        assert cli.render(manager.root_dir)["debug"]["config"]["context"] == {
+            "FOO": "from_pyproject"
+        }
+        assert cli.render(manager.root_dir, pyproject)["debug"]["config"]["context"] == {
+            "FOO": "from_pyproject"
+        }
+
+        # The default config file should still take precedence when it exists:
+        manager.tmpfile(
+            cfg_str({"context": {"static": {"FOO": {"value": "from_default"}}}}),
+            full_name="etch.config.toml",
+        )
+        assert cli.render(manager.root_dir)["debug"]["config"]["context"] == {
+            "FOO": "from_default"
+        }
+
+    # Errors should name the file and table:
+    with TmpFileManager() as manager:
+        manager.tmpfile("[tool.etch]\nfoo = 'bar'\n", full_name="pyproject.toml")
+        with pytest.raises(ValueError, match=re.escape("[tool.etch] table of")) as exc_info:
+            cli.render(manager.root_dir)
+        assert "[root]: Unknown property: 'foo'." in str(exc_info.value)
+
+    with TmpFileManager() as manager:
+        manager.tmpfile("[project]\nname = 'foo'\n", full_name="pyproject.toml")
+        with pytest.raises(ValueError, match=re.escape("No [tool.etch] table found.")):
+            cli.render(manager.root_dir)
+
+
+def test_env_interpolation():
+    """Confirm ${VAR} and ${VAR:-default} are substituted in config string values, with $${ as an escape."""
+    with TmpFileManager() as manager:
+        ext_dir = manager.tmpdir(name="plugins")
+        manager.tmpfile(
+            "import etcher as etch\n\n@etch.register_function\ndef plugin():\n    return 'plugged'\n",
+            parent=ext_dir,
+            full_name="ext.py",
+        )
+        with mock.patch.dict(os.environ, {"PLUGIN_DIR": str(ext_dir), "ETCH_GREETING": "hello"}):
+            result = cli.render(
+                manager.root_dir,
+                manager.create_cfg(
+                    {
+                        "context": {
+                            "static": {
+                                "GREETING": {"value": "${ETCH_GREETING} world"},
+                                "FALLBACK": {"value": "${ETCH_MADEUP_VAR:-fallback}"},
+                                "LITERAL": {"value": "$${ETCH_GREETING} costs $5"},
+                            }
+                        },
+                        "engine": {"custom_extensions": ["${PLUGIN_DIR}/ext.py"]},
+                    }
+                ),
+            )["debug"]
+        assert result["config"]["context"] == {
+            "GREETING": "hello world",
+            "FALLBACK": "fallback",
+            "LITERAL": "${ETCH_GREETING} costs $5",
+        }
+        assert result["config"]["engine"]["custom_extensions"] == [str(ext_dir / "ext.py")]
+
+
+def test_env_files():
+    """Env files should fill in env context the environment doesn't have, cli files winning over config files."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(
+            "# Defaults\n"
+            "export ETCH_DOTENV_A='from config'\n"
+            'ETCH_DOTENV_B="line\\nbreak" # comment\n'
+            "ETCH_DOTENV_C=kept out\n",
+            full_name=".env",
+        )
+        cli_env = manager.tmpfile("ETCH_DOTENV_A=from cli\n", full_name="cli.env")
+        config = manager.create_cfg(
+            {
+                "env_files": [".env"],
+                "context": {
+                    "env": {
+                        "A": {"env_name": "ETCH_DOTENV_A"},
+                        "B": {"env_name": "ETCH_DOTENV_B"},
+                        "C": {"env_name": "ETCH_DOTENV_C"},
+                    }
+                },
+            }
+        )
+        with mock.patch.dict(os.environ, {"ETCH_DOTENV_C": "from env"}):
+            assert cli.render(manager.root_dir, config)["debug"]["config"]["context"] == {
+                "A": "from config",
+                "B": "line\nbreak",
+                "C": "from env",
+            }
+            result = cli.render(manager.root_dir, config, env_files=[cli_env])["debug"]
+            assert result["config"]["context"]["A"] == "from cli"
+
+        bad_env = manager.tmpfile("NOT A VAR\n", full_name="bad.env")
+        with pytest.raises(ValueError, match=re.escape("bad.env:1]: Expected KEY=VALUE.")):
+            cli.render(manager.root_dir, config, env_files=[bad_env])
+
+
+def test_config_discovery():
+    """Confirm the default config is searched for in parent directories when missing from the root."""
+    with TmpFileManager() as manager:
+        manager.tmpfile(
+            cfg_str({"context": {"static": {"FOO": {"value": "from_parent"}}}}),
+            full_name="etch.config.toml",
+        )
+        nested = manager.tmpdir(name="nested")
+        template = manager.tmpfile("{{ FOO }}", suffix=".etch.txt", parent=nested)
+
+        # When the root is left at its default, the config's directory becomes the root:
+        res = cli.run(["etch", "--debug"], cwd=nested)
+        assert "Discovered config at" in res
+        assert os.path.exists(os.path.join(manager.root_dir, "etcher_debug.json"))
+        with open(remove_template(template), "r") as file:
+            assert file.read() == "from_parent"
+
+        # An explicit root should be kept, with the parent config still used:
+        result = cli.render(nested)["debug"]
+        assert result["config"]["context"] == {"FOO": "from_parent"}
+        assert os.path.exists(os.path.join(nested, ".etch.lock"))
+
+        # The search can be turned off, but the config that's used is always logged:
+        with pytest.raises(ValueError, match="Error reading config file from"):
+            cli.render(nested, no_discover=True)
+        stdout = cli.render(nested, verbose=True)["stdout"]
+        found = os.path.join(os.path.realpath(manager.root_dir), "etch.config.toml")
+        assert "Using config '{}'.".format(found) in stdout
+
+        # An explicit config path should never be searched for:
+        with pytest.raises(ValueError, match="Error reading config file from"):
+            cli.render(nested, "madeup.toml")
+
+        # The search should stop at the top of a repo:
+        os.mkdir(os.path.join(nested, ".git"))
+        with pytest.raises(ValueError, match="Error reading config file from"):
+            cli.render(nested)
+
+
+def test_config_env_var():
+    """ETCH_CONFIG should replace the default config, the --config flag still taking precedence."""
+    with TmpFileManager() as manager:
+        env_config = manager.create_cfg({"context": {"static": {"FOO": {"value": "from_env"}}}})
+        flag_config = manager.create_cfg({"context": {"static": {"FOO": {"value": "from_flag"}}}})
+        with mock.patch.dict(os.environ, {"ETCH_CONFIG": str(env_config)}):
+            result = cli.render(manager.root_dir)["debug"]
+            assert result["config"]["context"] == {"FOO": "from_env"}
+
+            result = cli.render(manager.root_dir, flag_config)["debug"]
+            assert result["config"]["context"] == {"FOO": "from_flag"}
+
+
+def test_exclude_never_matches_warning():
+    """Excludes that can never match anything under the root should warn, but not error."""
+    with TmpFileManager() as manager:
+        stdout = cli.render(
+            manager.root_dir,
+            manager.create_cfg(
+                {
+                    "context": {"static": {"FOO": {"value": "bar"}}},
+                    "exclude": ["../sibling/*.txt", str(manager.root_dir), "*.py"],
+                }
+            ),
+        )["stdout"]
+        assert "[exclude.0]: Pattern '../sibling/*.txt' can never match" in stdout
+        assert "[exclude.1]: Pattern '{}' looks like an absolute filesystem path".format(manager.root_dir) in stdout
+        assert "[exclude.2]" not in stdout
+
+
+@pytest.mark.parametrize(
+    "parse,output",
+    [
+        ("json", '{"version": "1.2.3", "build": {"sha": "abc123", "number": "42"}, "tags": ["a", "b"]}'),
+        ("yaml", "version: 1.2.3\nbuild:\n  sha: abc123\n  number: '42'\ntags:\n  - a\n  - b\n"),
+        ("toml", 'version = "1.2.3"\ntags = ["a", "b"]\n[build]\nsha = "abc123"\nnumber = "42"\n'),
+    ],
+)
+def test_cli_multi(parse: str, output: str):
+    """Confirm cli_multi runs its commands once, extracting each key from the parsed output."""
+    with TmpFileManager() as manager:
+        data = manager.tmpfile(output, suffix=".txt")
+        counter = os.path.join(manager.root_dir, "counter.txt")
+        tool = manager.tmpfile(
+            "echo run >> {}\ncat {}\n".format(counter, data),
+            suffix=".sh",
+        )
+        debug = cli.render(
+            manager.root_dir,
+            manager.create_cfg(
+                {
+                    "context": {
+                        "cli_multi": {
+                            "tool_info": {
+                                "commands": ["sh {}".format(tool)],
+                                "parse": parse,
+                                "keys": {
+                                    "VERSION": "version",
+                                    "SHA": "build.sha",
+                                    "BUILD_NUMBER": {"path": "build.number", "coerce": "int"},
+                                    "FIRST_TAG": "tags.0",
+                                },
+                            }
+                        }
+                    }
+                }
+            ),
+        )["debug"]
+        assert debug["config"]["context"] == {
+            "VERSION": "1.2.3",
+            "SHA": "abc123",
+            "BUILD_NUMBER": 42,
+            "FIRST_TAG": "a",
+        }
+
+        # Should only have run the tool once:
+        with open(counter, "r") as file:
+            assert file.read() == "run\n"
+
+
 def test_parallelized_context_cli_commands():
     """Confirm cli commands are processed in parallel for different variables.
 
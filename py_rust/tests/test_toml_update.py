@@ -0,0 +1,148 @@
+import re
+import typing as tp
+
+import pytest
+
+import etcher as etch
+
+INITIAL = """# The project config
+name = "old" # Renamed often
+
+[context.static.FOO]
+# Shown in the header
+value = "foo"
+
+[context.static.BAR]
+value = "bar"
+"""
+
+
+def test_toml_update_keeps_comments():
+    """Comments, key order and formatting outside what's updated or removed should survive."""
+    assert etch._toml_update(
+        INITIAL,
+        update={"name": "new", "context": {"static": {"BAZ": {"value": "baz"}}}},
+        remove=[["context", "static", "BAR"]],
+    ) == (
+        "# The project config\n"
+        'name = "new" # Renamed often\n'
+        "\n"
+        "[context.static.FOO]\n"
+        "# Shown in the header\n"
+        'value = "foo"\n'
+        "\n"
+        "[context.static.BAZ]\n"
+        'value = "baz"\n'
+    )
+
+
+def test_toml_update_from_empty():
+    """Arrays of mappings should be written as arrays of tables, parent headers left implicit."""
+    update = {"exclude": ["a"], "engine": {"overrides": [{"match": "*.tex"}]}}
+    assert etch._toml_update("", update=update) == (
+        'exclude = ["a"]\n\n[[engine.overrides]]\nmatch = "*.tex"\n'
+    )
+    # Empty tables have nothing else to show them:
+    assert etch._toml_update("", update={"context": {"env": {"FOO": {}}}}) == "[context.env.FOO]\n"
+
+
+def test_toml_update_remove_array_index():
+    """Numeric path segments should remove array elements, whether inline or arrays of tables."""
+    initial = (
+        "ports = [1, 2, 3] # open\n"
+        "\n"
+        "[[servers]]\n"
+        'name = "a"\n'
+        "\n"
+        "[[servers]]\n"
+        'name = "b"\n'
+        'hosts = ["x", "y"]\n'
+    )
+    assert etch._toml_update(
+        initial, remove=[["ports", "0"], ["servers", "1", "hosts", "0"], ["servers", "0"]]
+    ) == ('ports = [2, 3] # open\n\n[[servers]]\nname = "b"\nhosts = ["y"]\n')
+
+    with pytest.raises(
+        ValueError,
+        match=re.escape(
+            "Failed to remove 'servers.2': index 2 is out of bounds for an array of 2 items."
+        ),
+    ):
+        etch._toml_update(initial, remove=[["servers", "2"]])
+    with pytest.raises(
+        ValueError,
+        match=re.escape("Failed to remove 'ports.x': 'x' indexes an array so must be a number."),
+    ):
+        etch._toml_update(initial, remove=[["ports", "x"]])
+
+
+@pytest.mark.parametrize(
+    "array_strategy,expected",
+    [
+        (
+            None,
+            'deps = ["c"] # keep\n\n[[servers]]\nport = 1\n\n[[servers]]\nname = "b"\n',
+        ),
+        (
+            "append",
+            'deps = ["a", "b", "c"] # keep\n\n[[servers]]\nname = "a"\n\n'
+            '[[servers]]\nport = 1\n\n[[servers]]\nname = "b"\n',
+        ),
+        (
+            "merge-by-index",
+            'deps = ["c", "b"] # keep\n\n[[servers]]\nname = "a"\nport = 1\n\n'
+            '[[servers]]\nname = "b"\n',
+        ),
+    ],
+)
+def test_toml_update_array_strategy(array_strategy: tp.Optional[str], expected: str):
+    """Arrays should be replaced by default, or appended to or merged by index when asked."""
+    initial = 'deps = ["a", "b"] # keep\n\n[[servers]]\nname = "a"\n'
+    update = {"deps": ["c"], "servers": [{"port": 1}, {"name": "b"}]}
+    assert etch._toml_update(initial, update=update, array_strategy=array_strategy) == expected
+
+    with pytest.raises(
+        ValueError,
+        match=re.escape("Can't add '1' to the array of tables 'servers', only mappings."),
+    ):
+        etch._toml_update(initial, update={"servers": [1]}, array_strategy="append")
+
+
+def test_toml_update_sort_keys():
+    """Keys should be sorted recursively with headers following, the comment above the first key staying at the top."""
+    initial = (
+        "# top\n"
+        "zeta = 1\n"
+        "alpha = { z = 1, a = [{ y = 2, b = 3 }] }\n"
+        "\n"
+        "[zz]\n"
+        "b = 1\n"
+        "a = 2 # note\n"
+        "\n"
+        "[aa.inner]\n"
+        "x = 1\n"
+        "\n"
+        "[aa]\n"
+        "c = 1\n"
+    )
+    assert etch._toml_update(initial, update={"mid": {"q": 1, "p": 2}}, sort_keys=True) == (
+        "# top\n"
+        "alpha = { a = [{ b = 3, y = 2 }], z = 1 }\n"
+        "zeta = 1\n"
+        "\n"
+        "[aa]\n"
+        "c = 1\n"
+        "\n"
+        "[aa.inner]\n"
+        "x = 1\n"
+        "\n"
+        "[mid]\n"
+        "p = 2\n"
+        "q = 1\n"
+        "\n"
+        "[zz]\n"
+        "a = 2 # note\n"
+        "b = 1\n"
+    )
+    # Left alone by default:
+    assert etch._toml_update(initial) == initial
@@ -1,3 +1,5 @@
+import json
+
 import etcher as etch
 import pytest
 
@@ -19,3 +21,26 @@ def test_cli_version(args: list[str]):
     assert res.startswith("etch {}".format(etch.__version__)), res
     # Should be including the path to the executable in brackets at the end:
     assert res.endswith("etch)"), res
+
+
+@pytest.mark.parametrize(
+    "args",
+    [
+        ["version", "--output", "json"],
+        ["version", "--output-format", "json"],
+        ["--version", "--output", "json"],
+    ],
+)
+def test_cli_version_json(args: list[str]):
+    """Confirm the json version output exposes the machine readable capabilities contract."""
+    res = json.loads(cli.run(["etch", *args]))
+    assert res["version"] == etch.__version__
+    assert res["version_info"].startswith(etch.__version__)
+    # Hidden subcommands shouldn't be advertised:
+    assert res["subcommands"] == ["render", "init", "list", "explain", "version", "completions"]
+    assert res["lockfile_schema_version"] == 3
+    assert res["debug_schema_version"] == 1
+    assert res["config_schema_version"] == 1
+    for capability in ["extends", "profiles", "lockfile_variants", "read_file", "version_json"]:
+        assert capability in res["capabilities"], capability
+    assert res["capabilities"] == sorted(res["capabilities"])